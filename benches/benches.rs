@@ -1,7 +1,14 @@
 use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
-use kvs::engine::{KvsEngine, kvs::KvStore, sled::SledKvsEngine};
+use kvs::engine::{
+    KvsEngine,
+    kvs::{IndexKind, KvStore},
+    sled::SledKvsEngine,
+};
 use rand::prelude::*;
 use sled;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -77,5 +84,53 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+/// Gets while another thread keeps writing, so the logs are sealed and
+/// compacted meanwhile, for each index backend
+fn index_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_bench");
+    let kinds = [
+        ("ordered", IndexKind::Ordered),
+        ("concurrent", IndexKind::Concurrent),
+        ("sharded", IndexKind::Sharded(16)),
+    ];
+    for (name, kind) in kinds {
+        group.bench_function(format!("get_while_writing_{}", name), |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let store = KvStore::builder()
+                .index(kind)
+                .open(temp_dir.path())
+                .unwrap();
+            for key_i in 1..(1 << 12) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let stop = Arc::new(AtomicBool::new(false));
+            let writer = {
+                let store = store.clone();
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let mut i = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        store
+                            .set(format!("key{}", i % (1 << 12) + 1), i.to_string())
+                            .unwrap();
+                        i += 1;
+                    }
+                })
+            };
+            let mut rng = rand::rng();
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.random_range(1..(1 << 12))))
+                    .unwrap();
+            });
+            stop.store(true, Ordering::Relaxed);
+            writer.join().unwrap();
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, get_bench, index_bench);
 criterion_main!(benches);
@@ -0,0 +1,147 @@
+//! Authentication of clients, against the credentials a deployment already has
+//!
+//! A server started with an `Authenticator` refuses every request but `Auth`
+//! and `Ping` until the connection sends credentials it accepts. The backends
+//! here cover a file of tokens, an htpasswd-style file and an external
+//! command, which may in turn call an HTTP service, e.g. with `curl`.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sha2::{Digest, Sha256};
+
+use crate::error::{KvsError, Result};
+
+/// Scheme of the digests in a password file
+const SHA256_SCHEME: &str = "{SHA256}";
+
+/// Checks the credentials clients send with `Auth`
+pub trait Authenticator: Send + Sync {
+    /// `Ok(false)` rejects the credentials, an error means the backend failed
+    fn authenticate(&self, user: Option<&str>, secret: &str) -> Result<bool>;
+}
+
+/// Build an authenticator from its description on the command line
+///
+/// `token-file:PATH`, `htpasswd:PATH` or `command:CMD`, see the backends.
+pub fn from_spec(spec: &str) -> Result<Box<dyn Authenticator>> {
+    match spec.split_once(':') {
+        Some(("token-file", path)) => Ok(Box::new(TokenFile::new(path))),
+        Some(("htpasswd", path)) => Ok(Box::new(PasswordFile::new(path))),
+        Some(("command", command)) => Ok(Box::new(CommandHook::new(command))),
+        _ => Err(KvsError::StringError(format!(
+            "invalid authenticator {:?}, expect token-file:PATH, htpasswd:PATH or command:CMD",
+            spec
+        ))),
+    }
+}
+
+/// One token per line, any of them is accepted whatever the user
+///
+/// Blank lines and lines starting with `#` are skipped. The file is read on
+/// every attempt, so tokens are rotated without a restart.
+pub struct TokenFile {
+    path: PathBuf,
+}
+
+impl TokenFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Authenticator for TokenFile {
+    fn authenticate(&self, _user: Option<&str>, secret: &str) -> Result<bool> {
+        let content = fs::read_to_string(&self.path)?;
+        let digest = Sha256::digest(secret.as_bytes());
+        // every line is compared, so the time taken tells nothing
+        Ok(entries(&content).fold(false, |found, token| {
+            found | same(&Sha256::digest(token.as_bytes()), &digest)
+        }))
+    }
+}
+
+/// `user:{SHA256}digest` per line, like an htpasswd file
+///
+/// The digest is the base64 SHA-256 of the password, e.g. the output of
+/// `printf %s "$PASSWORD" | openssl dgst -sha256 -binary | base64`. Read on
+/// every attempt, like `TokenFile`.
+pub struct PasswordFile {
+    path: PathBuf,
+}
+
+impl PasswordFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn authenticate(&self, user: Option<&str>, secret: &str) -> Result<bool> {
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        let content = fs::read_to_string(&self.path)?;
+        let stored = entries(&content)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| *name == user)
+            .map(|(_, hash)| hash);
+        let stored = match stored.and_then(|hash| hash.strip_prefix(SHA256_SCHEME)) {
+            Some(digest) => STANDARD.decode(digest).map_err(|e| {
+                KvsError::StringError(format!("invalid digest of user {}: {}", user, e))
+            })?,
+            None => return Ok(false),
+        };
+        Ok(same(&stored, &Sha256::digest(secret.as_bytes())))
+    }
+}
+
+/// Run a shell command per attempt, exit status 0 accepts the credentials
+///
+/// The user is in `KVS_AUTH_USER`, empty without one, and the secret is
+/// written to the standard input, so neither shows up in the process list.
+pub struct CommandHook {
+    command: String,
+}
+
+impl CommandHook {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+        }
+    }
+}
+
+impl Authenticator for CommandHook {
+    fn authenticate(&self, user: Option<&str>, secret: &str) -> Result<bool> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .env("KVS_AUTH_USER", user.unwrap_or_default())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        // a hook which does not read its input closes the pipe early
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(secret.as_bytes());
+        }
+        Ok(child.wait()?.success())
+    }
+}
+
+/// Lines which are neither blank nor comments
+fn entries(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Compare in constant time, digests of the same length leak nothing
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,18 +1,37 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::{Shell, generate};
+use clap_mangen::Man;
 use log::trace;
 use std::env;
+use std::io;
 use std::net::TcpStream;
+use std::ops::Bound;
+use std::time::UNIX_EPOCH;
 
 use kvs::error::{KvsError, Result};
 use kvs::protocol::*;
 
-use kvs::client;
+use kvs::client::{self, KvsClient};
+use kvs::engine::kvs::{ChangeEvent, Envelope, WatchFilter, WriteKind};
 
 fn main() -> Result<()> {
     env_logger::init();
 
     let cli = Cli::parse();
 
+    // generated for packagers, no server is involved
+    match cli.command {
+        Some(Commands::Completions { shell }) => {
+            generate(shell, &mut Cli::command(), "kvs-client", &mut io::stdout());
+            return Ok(());
+        }
+        Some(Commands::Man) => {
+            Man::new(Cli::command().name("kvs-client")).render(&mut io::stdout())?;
+            return Ok(());
+        }
+        _ => {}
+    }
+
     run(cli)?;
 
     Ok(())
@@ -29,14 +48,38 @@ fn main() -> Result<()> {
 #[command(name = env!("CARGO_PKG_NAME"))]
 #[command(about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Cli {
+    /// Comma separated, the primary first, then its standbys to fail over to
     #[arg(
         short,
         long = "addr",
         value_name = "IP-Port",
         default_value = "127.0.0.1:4000",
+        value_delimiter = ',',
         global = true
     )]
-    ip: String,
+    ip: Vec<String>,
+
+    /// Database to run the command against
+    #[arg(long, value_name = "N", default_value_t = 0, global = true)]
+    db: usize,
+
+    /// Bucket of the database to run the command against, keys of other
+    /// buckets are out of sight
+    #[arg(long, value_name = "NAME", env = "KVS_NAMESPACE", global = true)]
+    namespace: Option<String>,
+
+    /// Idempotency token of a write, retrying the write with the same token
+    /// gets the first response instead of applying it again
+    #[arg(long, global = true)]
+    token: Option<String>,
+
+    /// User to authenticate as, for a server with a password file
+    #[arg(long, env = "KVS_USER", global = true)]
+    user: Option<String>,
+
+    /// Token or password sent before the command, to a server with `--auth`
+    #[arg(long, env = "KVS_SECRET", hide_env_values = true, global = true)]
+    secret: Option<String>,
 
     #[command(subcommand)]
     command: Option<Commands>,
@@ -45,25 +88,191 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Set <key, value> pair
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        /// MIME type of the value, e.g. application/json
+        #[arg(long, value_name = "TYPE")]
+        content_type: Option<String>,
+        /// The value is base64 encoded gzip, `get` prints it decompressed
+        #[arg(long)]
+        compressed: bool,
+    },
     /// Search the value for key
-    Get { key: String },
+    Get {
+        key: String,
+        /// The value is base64 encoded gzip, print it decompressed
+        #[arg(long)]
+        decompress: bool,
+        /// Print a value stored compressed as is
+        #[arg(long, conflicts_with = "decompress")]
+        raw: bool,
+        /// Only print the field of a JSON value at this pointer, e.g. /user/name
+        #[arg(long, value_name = "POINTER")]
+        json_pointer: Option<String>,
+    },
     /// Remove the <key, value> pair if exists
     Rm { key: String },
+    /// Set <key, value> pair only if key does not exist, e.g. to take a lock
+    #[command(visible_alias = "setnx")]
+    SetIfAbsent { key: String, value: String },
+    /// Set <key, value> pair only if key already exists
+    SetIfPresent { key: String, value: String },
+    /// Set <key, value> pair only if the current value meets the condition,
+    /// e.g. "value < 10" or "not exists or value == pending"
+    SetWhen {
+        key: String,
+        value: String,
+        condition: String,
+    },
+    /// Add delta to the integer value of the key, print the new value
+    /// A missing key counts as 0.
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// Remove the key and print its old value
+    GetDel { key: String },
+    /// Set <key, value> pair and print the old value
+    GetSet { key: String, value: String },
+    /// Show size, version, modify time and ttl of the key
+    Stat { key: String },
+    /// Remove all keys in [start, end), print how many are removed
+    RmRange { start: String, end: String },
+    /// Remove all keys starting with prefix, print how many are removed
+    RmPrefix { prefix: String },
+    /// Remove every key of the database, print how many are removed
+    /// Only served by a server started with `--allow-flush-all`.
+    FlushAll,
+    /// Count the keys starting with prefix
+    CountPrefix { prefix: String },
+    /// List the keys matching a glob pattern, `*` and `?` are wildcards
+    Keys { pattern: String },
+    /// Print every <key, value> pair, fetched page by page with a cursor
+    Scan {
+        /// Only the keys matching this glob pattern
+        #[arg(long)]
+        pattern: Option<String>,
+        /// Number of pairs per page
+        #[arg(long, default_value_t = 100)]
+        count: usize,
+    },
+    /// Print every <key, value> pair whose key starts with prefix
+    ScanPrefix { prefix: String },
+    /// Print the writes to the keys starting with any of the prefixes as
+    /// they happen, until interrupted; without a prefix nor a key, to any key
+    Watch {
+        prefixes: Vec<String>,
+        /// Watch this key too, may be repeated
+        #[arg(long, value_name = "KEY")]
+        key: Vec<String>,
+        /// Only the sets
+        #[arg(long)]
+        sets: bool,
+        /// Only the removals
+        #[arg(long, conflicts_with = "sets")]
+        removes: bool,
+        /// Only the sets whose value matches this regex
+        #[arg(long, value_name = "REGEX")]
+        value: Option<String>,
+    },
+    /// Turn a standby server into a primary that accepts traffic
+    Promote,
+    /// Close the connections of the server, clients retry after a delay
+    Drain {
+        /// Delay clients are told to wait, in milliseconds
+        #[arg(long)]
+        retry_after: Option<u64>,
+    },
+    /// Serve requests again after a drain
+    Resume,
+    /// Stop a long-running scan or replication, its id is printed by `info`
+    Cancel { id: u64 },
+    /// Print the requests, bytes in/out and operations of every database
+    Info,
+    /// Merge the logs of the database now, e.g. during off-peak hours
+    Compact,
+    /// Print the keys, logs and garbage of the database
+    Stats {
+        /// Also estimate the bytes of the keys starting with it
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Change the log filter of the server without a restart, e.g. `kvs=trace`
+    LogFilter { filter: String },
+    /// Print the completion script for a shell, e.g. `completions bash`
+    Completions { shell: Shell },
+    /// Print the man page, in roff
+    #[command(hide = true)]
+    Man,
 }
 
 fn run(cli: Cli) -> Result<()> {
-    let stream = TcpStream::connect(cli.ip)?;
+    // a standby fails the health probe, so it is promoted at the first address
+    let mut stream = match cli.command {
+        // a draining server fails the probe of the failover client
+        Some(
+            Commands::Promote | Commands::Drain { .. } | Commands::Resume | Commands::Cancel { .. },
+        ) => TcpStream::connect(&cli.ip[0])?,
+        _ => KvsClient::new(cli.ip)
+            .on_failover(|from, to| {
+                eprintln!("Server {} is unavailable, fail over to {}", from, to);
+            })
+            .connect()?,
+    };
     trace!("Success: Connects to the server");
+    if let Some(secret) = cli.secret {
+        client::authenticate(cli.user, secret, &mut stream)?;
+    }
+    if cli.db != 0 {
+        client::select(cli.db, &mut stream)?;
+    }
+    let scope = |request: Request| match &cli.namespace {
+        Some(namespace) => request.in_namespace(namespace.clone()),
+        None => request,
+    };
+    // only writes need to be protected against a double apply
+    let wrap = |request: Request| match &cli.token {
+        Some(token) => scope(request).with_token(token.clone()),
+        None => scope(request),
+    };
 
     match cli.command {
-        Some(Commands::Set { key, value }) => {
-            let request = Request::Set { key, value };
+        Some(Commands::Set {
+            key,
+            value,
+            content_type,
+            compressed,
+        }) => {
+            let envelope = Envelope {
+                content_type,
+                compressed,
+                ..Envelope::default()
+            };
+            let request = match envelope.is_plain() {
+                true => wrap(Request::Set { key, value }),
+                false => wrap(Request::SetWithEnvelope {
+                    key,
+                    value,
+                    envelope,
+                }),
+            };
             client::send_and_recv(request, stream)?;
             trace!("Success set");
         }
-        Some(Commands::Get { key }) => {
-            let request = Request::Get { key };
+        Some(Commands::Get {
+            key,
+            decompress,
+            raw,
+            json_pointer,
+        }) => {
+            let modifiers = ReadModifiers {
+                decompress,
+                raw,
+                json_pointer,
+            };
+            let request = scope(Request::Get { key, modifiers });
             let result = client::send_and_recv(request, stream)?;
             if let Some(val) = result {
                 trace!("Success get");
@@ -74,10 +283,258 @@ fn run(cli: Cli) -> Result<()> {
             }
         }
         Some(Commands::Rm { key }) => {
-            let request = Request::Rm { key };
+            let request = wrap(Request::Rm { key });
             client::send_and_recv(request, stream)?;
             trace!("Success remove");
         }
+        Some(Commands::SetIfAbsent { key, value }) => {
+            let request = wrap(Request::SetIfAbsent { key, value });
+            if !client::send_and_recv_flag(request, stream)? {
+                trace!("SetIfAbsent: key is already in the store");
+                println!("Key already exists");
+            }
+        }
+        Some(Commands::SetIfPresent { key, value }) => {
+            let request = wrap(Request::SetIfPresent { key, value });
+            if !client::send_and_recv_flag(request, stream)? {
+                trace!("SetIfPresent: key is not in the store");
+                println!("Key not found");
+            }
+        }
+        Some(Commands::SetWhen {
+            key,
+            value,
+            condition,
+        }) => {
+            let request = wrap(Request::SetWhen {
+                key,
+                value,
+                condition,
+            });
+            if !client::send_and_recv_flag(request, stream)? {
+                trace!("SetWhen: condition is not met");
+                println!("Condition not met");
+            }
+        }
+        Some(Commands::Incr { key, delta }) => {
+            let request = wrap(Request::Incr { key, delta });
+            println!("{}", client::send_and_recv_incr(request, stream)?);
+        }
+        Some(Commands::GetDel { key }) => {
+            let request = wrap(Request::GetDel { key });
+            print_old_value(client::send_and_recv(request, stream)?);
+        }
+        Some(Commands::GetSet { key, value }) => {
+            let request = wrap(Request::GetSet { key, value });
+            print_old_value(client::send_and_recv(request, stream)?);
+        }
+        Some(Commands::Stat { key }) => {
+            let request = scope(Request::Stat { key });
+            match client::send_and_recv_stat(request, stream)? {
+                Some(meta) => {
+                    println!("size: {}", meta.value_size);
+                    println!("version: {}", meta.version);
+                    match meta
+                        .last_modified
+                        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    {
+                        Some(t) => println!("last modified: {}", t.as_secs()),
+                        None => println!("last modified: unknown"),
+                    }
+                    match meta.ttl {
+                        Some(ttl) => println!("ttl: {}", ttl.as_secs()),
+                        None => println!("ttl: none"),
+                    }
+                    if let Some(content_type) = meta.envelope.content_type {
+                        println!("content type: {}", content_type);
+                    }
+                    if meta.envelope.compressed {
+                        println!("compressed: yes");
+                    }
+                }
+                None => println!("Key not found"),
+            }
+        }
+        Some(Commands::RmRange { start, end }) => {
+            let request = wrap(Request::RmRange {
+                start: Bound::Included(start),
+                end: Bound::Excluded(end),
+            });
+            println!("{}", client::send_and_recv_count(request, stream)?);
+        }
+        Some(Commands::RmPrefix { prefix }) => {
+            let request = wrap(Request::RmPrefix { prefix });
+            println!("{}", client::send_and_recv_count(request, stream)?);
+        }
+        Some(Commands::FlushAll) => {
+            let request = wrap(Request::FlushAll);
+            println!("{}", client::send_and_recv_count(request, stream)?);
+        }
+        Some(Commands::CountPrefix { prefix }) => {
+            let request = scope(Request::CountPrefix { prefix });
+            println!("{}", client::send_and_recv_count(request, stream)?);
+        }
+        Some(Commands::Keys { pattern }) => {
+            let request = scope(Request::Keys { pattern });
+            for key in client::send_and_recv_keys(request, stream)? {
+                println!("{}", key);
+            }
+        }
+        Some(Commands::Scan { pattern, count }) => {
+            let mut cursor = None;
+            loop {
+                let request = scope(Request::Scan {
+                    cursor,
+                    count,
+                    pattern: pattern.clone(),
+                });
+                let page = client::send_and_recv_scan(request, stream.try_clone()?)?;
+                for (key, value) in page.entries {
+                    println!("{} {}", key, value);
+                }
+                cursor = page.cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+        }
+        Some(Commands::ScanPrefix { prefix }) => {
+            let request = scope(Request::ScanPrefix { prefix });
+            for (key, value) in client::send_and_recv_pairs(request, stream)? {
+                println!("{} {}", key, value);
+            }
+        }
+        Some(Commands::Watch {
+            prefixes,
+            key,
+            sets,
+            removes,
+            value,
+        }) => {
+            let only = match (sets, removes) {
+                (true, _) => Some(WriteKind::Set),
+                (_, true) => Some(WriteKind::Remove),
+                _ => None,
+            };
+            let filter = WatchFilter {
+                keys: key,
+                prefixes,
+                only,
+                value,
+            };
+            // the database is selected already
+            client::watch_filtered(0, filter, stream, |event| {
+                match event {
+                    ChangeEvent::Set { key, value } => println!("set {} {}", key, value),
+                    ChangeEvent::Rm { key } => println!("rm {}", key),
+                    ChangeEvent::RmRange { start, end } => {
+                        println!("rm-range {:?} {:?}", start, end)
+                    }
+                    ChangeEvent::RmPrefix { prefix } => println!("rm-prefix {}", prefix),
+                }
+                Ok(())
+            })?;
+        }
+        Some(Commands::Promote) => {
+            client::send_and_recv(Request::Promote, stream)?;
+            trace!("Success promote");
+        }
+        Some(Commands::Drain { retry_after }) => {
+            let request = Request::Drain {
+                retry_after_ms: retry_after,
+            };
+            client::send_and_recv(request, stream)?;
+        }
+        Some(Commands::Resume) => {
+            client::send_and_recv(Request::Resume, stream)?;
+        }
+        Some(Commands::Cancel { id }) => {
+            client::send_and_recv(Request::Cancel { id }, stream)?;
+        }
+        Some(Commands::Compact) => {
+            client::send_and_recv(Request::Compact, stream)?;
+        }
+        Some(Commands::Stats { prefix }) => {
+            let (stats, size) = client::stats(prefix, stream)?;
+            println!("keys: {}", stats.keys);
+            println!("segments: {}", stats.segments);
+            println!("disk bytes: {}", stats.disk_bytes);
+            if let Some(quota) = stats.disk_quota {
+                println!("disk quota: {}", quota);
+            }
+            println!("dead bytes: {}", stats.dead_bytes);
+            match stats
+                .last_compaction
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            {
+                Some(t) => println!("last compaction: {}", t.as_secs()),
+                None => println!("last compaction: never"),
+            }
+            let growth = &stats.growth;
+            for (window, counts) in [
+                ("minute", growth.last_minute),
+                ("hour", growth.last_hour),
+                ("day", growth.last_day),
+            ] {
+                println!(
+                    "last {}: writes {} bytes_written {} bytes_reclaimed {}",
+                    window, counts.writes, counts.bytes_written, counts.bytes_reclaimed
+                );
+            }
+            match growth.full_in {
+                Some(t) => println!("disk full in: {}s", t.as_secs()),
+                None => println!("disk full in: never"),
+            }
+            if let Some(size) = size {
+                println!("prefix keys: {}", size.keys);
+                println!(
+                    "prefix bytes: {} (from {} samples)",
+                    size.bytes, size.samples
+                );
+            }
+        }
+        Some(Commands::LogFilter { filter }) => {
+            client::send_and_recv(Request::SetLogFilter { filter }, stream)?;
+        }
+        Some(Commands::Info) => {
+            for (db, info) in client::info(stream)?.iter().enumerate() {
+                let traffic = &info.traffic;
+                println!(
+                    "db{} requests {} bytes_in {} bytes_out {}",
+                    db, traffic.requests, traffic.bytes_in, traffic.bytes_out
+                );
+                if let Some(sync) = &info.sync {
+                    // batches of 1, 2-3, 4-7... writes, empty buckets left out
+                    let batches: Vec<String> = sync
+                        .batch_sizes
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, n)| **n > 0)
+                        .map(|(bucket, n)| format!("{}:{}", 1u64 << bucket, n))
+                        .collect();
+                    println!(
+                        "db{} syncs {} synced_writes {} batches {}",
+                        db,
+                        sync.syncs,
+                        sync.synced_writes,
+                        batches.join(",")
+                    );
+                }
+                for op in &info.operations {
+                    println!(
+                        "db{} operation {} {} elapsed {:?}{}",
+                        db,
+                        op.id,
+                        op.op,
+                        op.elapsed,
+                        if op.cancelled { " cancelled" } else { "" }
+                    );
+                }
+            }
+        }
+        Some(Commands::Completions { .. } | Commands::Man) => {
+            unreachable!("handled without a server")
+        }
         None => {
             trace!("Unrecognized command");
             return Err(KvsError::UnexpectedType);
@@ -85,3 +542,10 @@ fn run(cli: Cli) -> Result<()> {
     }
     Ok(())
 }
+
+fn print_old_value(old: Option<String>) {
+    match old {
+        Some(val) => println!("{}", val),
+        None => println!("Key not found"),
+    }
+}
@@ -1,88 +1,524 @@
-use kvs::engine::kvs::KvStore;
+use kvs::engine::kvs::{KvStore, MemoryBudget, Verify};
 // use kvs::engine::sled::SledKvsEngine;
 
-use clap::Parser;
-use kvs::error::Result;
-use kvs::thread_pool::ThreadPool;
-use log::trace;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::{Shell, generate};
+use clap_mangen::Man;
+use kvs::error::{KvsError, Result};
+use kvs::limits::Limits;
+use kvs::logging;
+use kvs::manifest::{LogLayout, Manifest};
+use kvs::transport::Listener;
+use log::{debug, info, trace, warn};
+use serde::Deserialize;
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
 use std::env;
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
-use std::net::TcpListener;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::sync::mpsc::{Sender, channel};
+use std::thread;
+use std::time::Duration;
 
-use kvs::server;
+use kvs::auth::{self, Authenticator};
+use kvs::client;
+use kvs::server::{self, ConnectionLimit, Drain};
+use kvs::validate::Validators;
 
-const THREAD_POOL_SIZE: usize = 16;
-const REGULAR_CHECK: i32 = 5;
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: &str = "kvs";
+const REPLICATION_RETRY: Duration = Duration::from_secs(1);
+/// An `--addr` with this prefix is the path of a unix socket
+const UNIX_PREFIX: &str = "unix:";
 
 fn main() -> Result<()> {
-    env_logger::init();
-
     let cli = Cli::parse();
 
+    // generated for packagers, the server does not start
+    match cli.command {
+        Some(Commands::Completions { shell }) => {
+            generate(shell, &mut Cli::command(), "kvs-server", &mut io::stdout());
+            return Ok(());
+        }
+        Some(Commands::Man) => {
+            Man::new(Cli::command().name("kvs-server")).render(&mut io::stdout())?;
+            return Ok(());
+        }
+        None => {}
+    }
+
     run(cli)?;
 
     Ok(())
 }
 
+// Every setting is taken from, in order of precedence,
+// the flag, the `KVS_*` environment variable, the config file, the default.
 #[derive(Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(name = env!("CARGO_PKG_NAME"))]
 #[command(about = env!("CARGO_PKG_DESCRIPTION"))]
+#[command(after_help = "Precedence: flags > KVS_* environment variables > config file > defaults")]
 struct Cli {
+    /// Repeat to listen on several addresses, `unix:PATH` is a unix socket
+    /// [default: 127.0.0.1:4000]
     #[arg(
         short,
         long = "addr",
         value_name = "IP-Port",
-        default_value = "127.0.0.1:4000"
+        env = "KVS_ADDR",
+        value_delimiter = ','
     )]
-    ip: String,
+    ip: Vec<String>,
+
+    /// [default: kvs]
+    #[arg(short, long = "engine", value_name = "ENGINE-NAME", env = "KVS_ENGINE")]
+    engine: Option<String>,
+
+    /// Directory holding the data [default: current directory]
+    #[arg(long, value_name = "DIR", env = "KVS_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Ignored, every connection is served on its own thread, up to
+    /// `max_connections` of them
+    #[arg(long, value_name = "N", env = "KVS_THREADS")]
+    threads: Option<usize>,
+
+    /// Number of independent databases, picked by clients with `Select` [default: 1]
+    #[arg(long, value_name = "N", env = "KVS_DATABASES")]
+    databases: Option<usize>,
+
+    /// JSON file with any of `addr`, `engine`, `data_dir`, `threads`, `databases`,
+    /// `log_format`, `standby_of`, `region`, `peer`, `sync_interval`, `sync_window`, `sync_batch`,
+    /// `log_shards`, `memory_limit`, `value_cache`, `disk_quota`, `coalesce_reads`,
+    /// `verify_on_start`, `auth`, `validate` and `limits`
+    #[arg(long, value_name = "FILE", env = "KVS_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Format of the server logs on stderr [default: text]
+    #[arg(long, value_name = "FORMAT", env = "KVS_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Write `READY=1` to this file descriptor once the server accepts connections
+    #[arg(long, value_name = "FD", env = "KVS_READY_FD")]
+    ready_fd: Option<i32>,
+
+    /// Run as a warm standby following the primary at this address, until promoted
+    #[arg(long, value_name = "IP-Port", env = "KVS_STANDBY_OF")]
+    standby_of: Option<String>,
 
+    /// Name of the region of this server, for active/active replication
+    #[arg(long, value_name = "NAME", env = "KVS_REGION")]
+    region: Option<String>,
+
+    /// Repeat to replicate the writes of the server of another region, both
+    /// ways once it peers back; the last writer of a key wins
     #[arg(
-        short,
-        long = "engine",
-        value_name = "ENGINE-NAME",
-        default_value = "kvs"
+        long,
+        value_name = "IP-Port",
+        env = "KVS_PEER",
+        value_delimiter = ',',
+        requires = "region"
+    )]
+    peer: Vec<String>,
+
+    /// Sync every write to disk before answering, at most one sync per this many ms
+    #[arg(long, value_name = "MS", env = "KVS_SYNC_INTERVAL")]
+    sync_interval: Option<u64>,
+
+    /// Like `--sync-interval`, in microseconds: the latency writes may wait to share a sync
+    #[arg(
+        long,
+        value_name = "US",
+        env = "KVS_SYNC_WINDOW",
+        conflicts_with = "sync_interval"
     )]
+    sync_window: Option<u64>,
+
+    /// Sync as soon as this many writes wait, before the interval is over
+    #[arg(long, value_name = "N", env = "KVS_SYNC_BATCH")]
+    sync_batch: Option<u64>,
+
+    /// Spread the logs of a new data directory over this many subdirectories [default: 1]
+    #[arg(long, value_name = "N", env = "KVS_LOG_SHARDS")]
+    log_shards: Option<usize>,
+
+    /// Soft limit on the memory of all databases, in MiB, past it caches are shrunk
+    #[arg(long, value_name = "MIB", env = "KVS_MEMORY_LIMIT")]
+    memory_limit: Option<usize>,
+
+    /// Cache the values read lately, up to this many MiB per database
+    #[arg(long, value_name = "MIB", env = "KVS_VALUE_CACHE")]
+    value_cache: Option<usize>,
+
+    /// Most MiB the logs of each database may take, sets over it fail
+    /// once compacting every log does not make room
+    #[arg(long, value_name = "MIB", env = "KVS_DISK_QUOTA")]
+    disk_quota: Option<u64>,
+
+    /// Let concurrent gets of the same key share one read, for hot keys
+    #[arg(long, env = "KVS_COALESCE_READS")]
+    coalesce_reads: bool,
+
+    /// Check every log before serving and refuse to start on corruption,
+    /// `--verify-on-start=repair` drops the corrupted records instead
+    #[arg(
+        long,
+        value_name = "MODE",
+        env = "KVS_VERIFY_ON_START",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "check"
+    )]
+    verify_on_start: Option<VerifyOnStart>,
+
+    /// Require clients to authenticate, with `token-file:PATH`, `htpasswd:PATH`
+    /// or `command:CMD`
+    #[arg(long, value_name = "BACKEND", env = "KVS_AUTH")]
+    auth: Option<String>,
+
+    /// Repeat to refuse sets under a prefix whose value is not valid, with
+    /// `PREFIX=json`, `PREFIX=integer` or `PREFIX=schema:PATH`
+    #[arg(long, value_name = "RULE", env = "KVS_VALIDATE", value_delimiter = ',')]
+    validate: Vec<String>,
+
+    /// Accept `FlushAll`, which empties a database, e.g. to reset a test server
+    #[arg(long, env = "KVS_ALLOW_FLUSH_ALL")]
+    allow_flush_all: bool,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print the completion script for a shell, e.g. `completions bash`
+    Completions { shell: Shell },
+    /// Print the man page, in roff
+    #[command(hide = true)]
+    Man,
+}
+
+#[derive(Clone, Copy, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum VerifyOnStart {
+    Check,
+    Repair,
+}
+
+impl From<VerifyOnStart> for Verify {
+    fn from(mode: VerifyOnStart) -> Self {
+        match mode {
+            VerifyOnStart::Check => Verify::Check,
+            VerifyOnStart::Repair => Verify::Repair,
+        }
+    }
+}
+
+/// Settings read from the `--config` file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    addr: Option<Addrs>,
+    engine: Option<String>,
+    data_dir: Option<PathBuf>,
+    threads: Option<usize>,
+    databases: Option<usize>,
+    log_format: Option<LogFormat>,
+    standby_of: Option<String>,
+    region: Option<String>,
+    peer: Option<Addrs>,
+    sync_interval: Option<u64>,
+    sync_window: Option<u64>,
+    sync_batch: Option<u64>,
+    log_shards: Option<usize>,
+    memory_limit: Option<usize>,
+    value_cache: Option<usize>,
+    disk_quota: Option<u64>,
+    coalesce_reads: Option<bool>,
+    verify_on_start: Option<VerifyOnStart>,
+    auth: Option<String>,
+    validate: Option<Vec<String>>,
+    /// Any of `max_key`, `max_value`, `max_batch`, `max_open_segments`,
+    /// `max_connections` and `flush_all`, the others keep their default
+    limits: Option<Limits>,
+}
+
+/// One address or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Addrs {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Settings after all sources are merged
+struct Settings {
+    addrs: Vec<String>,
     engine: String,
+    data_dir: PathBuf,
+    threads: Option<usize>,
+    databases: usize,
+    log_format: LogFormat,
+    ready_fd: Option<i32>,
+    standby_of: Option<String>,
+    region: Option<String>,
+    peers: Vec<String>,
+    sync_interval: Option<Duration>,
+    sync_batch: Option<u64>,
+    log_shards: usize,
+    // in bytes
+    memory_limit: Option<usize>,
+    // in bytes
+    value_cache: Option<usize>,
+    // in bytes
+    disk_quota: Option<u64>,
+    coalesce_reads: bool,
+    verify: Verify,
+    auth: Option<String>,
+    validate: Vec<String>,
+    limits: Limits,
 }
 
-fn run(cli: Cli) -> Result<()> {
-    let dir = env::current_dir()?;
-    // We need a meta info to record the last format
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(dir.join("meta"))?;
-
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-
-    if !content.is_empty() && content != cli.engine {
-        eprintln!(
-            "Error: Previous format is {}, Current is {}",
-            content, cli.engine
-        );
-        exit(1);
+impl Settings {
+    /// clap already prefers the flag over the environment,
+    /// the config file and the defaults fill in what is left.
+    fn resolve(cli: Cli) -> Result<Self> {
+        let file = match &cli.config {
+            Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+            None => FileConfig::default(),
+        };
+        let data_dir = match cli.data_dir.or(file.data_dir) {
+            Some(dir) => dir,
+            None => env::current_dir()?,
+        };
+        let limits = file.limits.unwrap_or_default();
+        let addrs = match (cli.ip, file.addr) {
+            (flags, _) if !flags.is_empty() => flags,
+            (_, Some(Addrs::One(addr))) => vec![addr],
+            (_, Some(Addrs::Many(addrs))) if !addrs.is_empty() => addrs,
+            _ => vec![DEFAULT_ADDR.to_owned()],
+        };
+        let peers = match (cli.peer, file.peer) {
+            (flags, _) if !flags.is_empty() => flags,
+            (_, Some(Addrs::One(addr))) => vec![addr],
+            (_, Some(Addrs::Many(addrs))) => addrs,
+            (_, None) => Vec::new(),
+        };
+        let region = cli.region.or(file.region);
+        if !peers.is_empty() && region.is_none() {
+            return Err(KvsError::StringError(
+                "a peer needs the region of this server".to_owned(),
+            ));
+        }
+        Ok(Self {
+            addrs,
+            engine: cli
+                .engine
+                .or(file.engine)
+                .unwrap_or(DEFAULT_ENGINE.to_owned()),
+            data_dir,
+            threads: cli.threads.or(file.threads),
+            databases: cli.databases.or(file.databases).unwrap_or(1).max(1),
+            log_format: cli.log_format.or(file.log_format).unwrap_or_default(),
+            ready_fd: cli.ready_fd,
+            standby_of: cli.standby_of.or(file.standby_of),
+            region,
+            peers,
+            sync_interval: cli
+                .sync_window
+                .map(Duration::from_micros)
+                .or(cli.sync_interval.map(Duration::from_millis))
+                .or(file.sync_window.map(Duration::from_micros))
+                .or(file.sync_interval.map(Duration::from_millis)),
+            sync_batch: cli.sync_batch.or(file.sync_batch),
+            log_shards: cli.log_shards.or(file.log_shards).unwrap_or(1),
+            memory_limit: cli
+                .memory_limit
+                .or(file.memory_limit)
+                .map(|mib| mib.saturating_mul(1024 * 1024)),
+            value_cache: cli
+                .value_cache
+                .or(file.value_cache)
+                .map(|mib| mib.saturating_mul(1024 * 1024)),
+            disk_quota: cli
+                .disk_quota
+                .or(file.disk_quota)
+                .map(|mib| mib.saturating_mul(1024 * 1024)),
+            coalesce_reads: cli.coalesce_reads || file.coalesce_reads.unwrap_or_default(),
+            verify: cli
+                .verify_on_start
+                .or(file.verify_on_start)
+                .map_or(Verify::AfterCrash, Verify::from),
+            auth: cli.auth.or(file.auth),
+            validate: match (cli.validate, file.validate) {
+                (flags, _) if !flags.is_empty() => flags,
+                (_, rules) => rules.unwrap_or_default(),
+            },
+            limits: Limits {
+                flush_all: cli.allow_flush_all || limits.flush_all,
+                ..limits
+            },
+        })
+    }
+}
+
+/// Log filtering starts from `RUST_LOG`, `SetLogFilter` and SIGUSR1 change it
+fn init_logger(format: LogFormat) -> Result<()> {
+    logging::init(move |builder| {
+        if let LogFormat::Json = format {
+            builder.format(|buf, record| {
+                let line = serde_json::json!({
+                    "ts": buf.timestamp_millis().to_string(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "msg": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            });
+        }
+    })
+}
+
+/// Switch the log filter to `trace` and back on every SIGUSR1
+fn toggle_verbose_on_signal() -> Result<()> {
+    let mut signals = Signals::new([SIGUSR1])?;
+    thread::Builder::new()
+        .name("kvs-signals".to_owned())
+        .spawn(move || {
+            for _ in signals.forever() {
+                match logging::toggle_verbose() {
+                    Ok(filter) => info!("log filter is now {:?}", filter),
+                    Err(e) => warn!("Fail to change the log filter: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Tell whoever started us that connections are accepted now
+///
+/// A line is always printed to stdout. With `--ready-fd` `READY=1` is also
+/// written to that descriptor, and under systemd (`NOTIFY_SOCKET`) it is sent
+/// as an sd_notify message.
+fn notify_ready(addr: &str, ready_fd: Option<i32>) -> Result<()> {
+    println!("Ready to accept connections on {}", addr);
+    io::stdout().flush()?;
+
+    if let Some(fd) = ready_fd {
+        // SAFETY: the descriptor is handed to us for this single message
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(b"READY=1\n")?;
+    }
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        let socket = UnixDatagram::unbound()?;
+        if let Err(e) = socket.send_to(b"READY=1", &path) {
+            warn!("Fail to notify {:?}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+/// Apply the changes of database `db` on `primary` to `kvs`, until it is promoted
+///
+/// A lost connection is retried, the primary sends its whole content again.
+fn follow_primary(primary: String, db: usize, kvs: KvStore) {
+    while kvs.is_standby() {
+        let result = TcpStream::connect(&primary)
+            .map_err(KvsError::from)
+            .and_then(|stream| client::follow(db, stream, |change| kvs.apply(change)));
+        if !kvs.is_standby() {
+            break;
+        }
+        match result {
+            Ok(()) => warn!("Primary {} closed the replication of db {}", primary, db),
+            Err(e) => warn!("Replication of db {} from {} failed: {}", db, primary, e),
+        }
+        thread::sleep(REPLICATION_RETRY);
     }
+    trace!("Database {} is promoted, stop following {}", db, primary);
+}
 
-    if content.is_empty() {
-        file.write_all(cli.engine.as_bytes())?;
+/// Apply the writes of database `db` of the peer region at `peer`, forever
+/// Losing the connection, e.g. to a partition, only delays the changes.
+fn follow_peer(peer: String, region: String, db: usize, kvs: KvStore) {
+    loop {
+        let result = TcpStream::connect(&peer)
+            .map_err(KvsError::from)
+            .and_then(|stream| {
+                client::follow_region(db, region.clone(), stream, |change| {
+                    kvs.apply_remote(change).map(|_| ())
+                })
+            });
+        match result {
+            Ok(()) => warn!("Peer {} closed the replication of db {}", peer, db),
+            Err(e) => warn!("Replication of db {} from peer {} failed: {}", db, peer, e),
+        }
+        thread::sleep(REPLICATION_RETRY);
     }
+}
 
-    file.flush()?;
+fn run(cli: Cli) -> Result<()> {
+    let settings = Settings::resolve(cli)?;
+    init_logger(settings.log_format)?;
+    toggle_verbose_on_signal()?;
+    if let Some(threads) = settings.threads {
+        warn!(
+            "Ignore {} worker threads, every connection has its own thread",
+            threads
+        );
+    }
+    let dir = settings.data_dir;
+    fs::create_dir_all(&dir)?;
+    // The manifest records the engine and format of the directory
+    match Manifest::load(&dir)? {
+        Some(manifest) => {
+            if let Err(e) = manifest.check(&settings.engine) {
+                eprintln!("Error: {}", e);
+                exit(1);
+            }
+        }
+        None => Manifest::new(&settings.engine).store(&dir)?,
+    }
 
     trace!("Version of kvs-server: {}", env!("CARGO_PKG_VERSION"));
     trace!("Server Configuration:");
-    trace!("\t IP:Port is {}", cli.ip);
-    trace!("\t Engine type is {}", cli.engine);
+    trace!("\t IP:Port is {}", settings.addrs.join(", "));
+    trace!("\t Engine type is {}", settings.engine);
+    trace!("\t Data directory is {:?}", dir);
+    trace!("\t Databases: {}", settings.databases);
+    trace!("\t Standby of: {:?}", settings.standby_of);
+    trace!("\t Region: {:?}", settings.region);
+    trace!("\t Peers: {:?}", settings.peers);
+    trace!("\t Sync interval: {:?}", settings.sync_interval);
+    trace!("\t Sync batch: {:?}", settings.sync_batch);
+    trace!("\t Log shards: {}", settings.log_shards);
+    trace!("\t Memory limit: {:?}", settings.memory_limit);
+    trace!("\t Value cache: {:?}", settings.value_cache);
+    trace!("\t Disk quota: {:?}", settings.disk_quota);
+    trace!("\t Coalesce reads: {}", settings.coalesce_reads);
+    trace!("\t Verify on start: {:?}", settings.verify);
+    trace!("\t Authentication: {:?}", settings.auth);
+    trace!("\t Validators: {:?}", settings.validate);
+    trace!("\t Limits: {:?}", settings.limits);
 
-    // Monitor the IP:Port and Respond
-    let listener = TcpListener::bind(cli.ip)?;
-    trace!("Server starts to monitor the network address");
-    assert_eq!(cli.engine, String::from("kvs"));
+    assert_eq!(settings.engine, String::from("kvs"));
     // ! We now assume the engine will always be `kvstore`
     // let mut engine: Box<dyn KvsEngine> = match cli.engine.as_str() {
     //     "kvs" => match KvStore::new() {
@@ -108,28 +544,164 @@ fn run(cli: Cli) -> Result<()> {
     //     _ => return Err(KvsError::UnexpectedType),
     // };
 
-    let kvs = KvStore::new()?;
-    let mut pool = ThreadPool::new(THREAD_POOL_SIZE);
-    let mut cnt = 0;
-    for stream in listener.incoming() {
-        cnt = (cnt + 1) % REGULAR_CHECK;
-        if cnt == 0 {
-            pool.poll();
-        }
-        match stream {
-            Ok(s) => {
-                trace!("receive a command");
-                let cur_kvs = kvs.clone();
-                pool.spawn(Box::new(move || {
-                    server::handle_stream(s, cur_kvs);
-                }));
+    // database 0 lives in the data directory itself, so existing data stays
+    // visible, the others get their own subdirectory
+    // one budget for all databases, so the limit holds for the whole process
+    let memory_budget = settings.memory_limit.map(MemoryBudget::new);
+    let mut databases = Vec::with_capacity(settings.databases);
+    for db in 0..settings.databases {
+        let path = match db {
+            0 => dir.clone(),
+            _ => dir.join(format!("db{}", db)),
+        };
+        fs::create_dir_all(&path)?;
+        let layout = LogLayout {
+            shards: settings.log_shards,
+            ..LogLayout::default()
+        };
+        let mut builder = KvStore::builder()
+            .standby(settings.standby_of.is_some())
+            .layout(layout)
+            .limits(settings.limits)
+            .verify(settings.verify)
+            .coalesce_reads(settings.coalesce_reads);
+        if let Some(interval) = settings.sync_interval {
+            builder = builder.durable(interval);
+        }
+        if let Some(writes) = settings.sync_batch {
+            builder = builder.sync_batch(writes);
+        }
+        if let Some(budget) = &memory_budget {
+            builder = builder.memory_budget(budget);
+        }
+        if let Some(bytes) = settings.value_cache {
+            builder = builder.value_cache(bytes);
+        }
+        if let Some(bytes) = settings.disk_quota {
+            builder = builder.disk_quota(bytes).compact_over_quota(true);
+        }
+        if let Some(region) = &settings.region {
+            builder = builder.region(region.clone());
+        }
+        let kvs = builder.open(path)?;
+        let events = kvs.subscribe();
+        thread::spawn(move || server::log_events(events));
+        if let Some(primary) = settings.standby_of.clone() {
+            let kvs = kvs.clone();
+            thread::spawn(move || follow_primary(primary, db, kvs));
+        }
+        for peer in settings.peers.iter() {
+            let (peer, region, kvs) = (peer.clone(), kvs.region(), kvs.clone());
+            thread::spawn(move || follow_peer(peer, region, db, kvs));
+        }
+        databases.push(kvs);
+    }
+    // bound once every log is replayed, so clients never wait on a silent port,
+    // every listener has its own accepting thread, and every connection too
+    let connections = ConnectionLimit::new(settings.limits.max_connections);
+    let auth: Option<Arc<dyn Authenticator>> = match &settings.auth {
+        Some(spec) => Some(auth::from_spec(spec)?.into()),
+        None => None,
+    };
+    let shared = Shared {
+        databases,
+        connections,
+        auth,
+        validators: Arc::new(Validators::from_specs(&settings.validate)?),
+        drain: Drain::default(),
+    };
+    let (errors, failed) = channel();
+    for addr in settings.addrs.iter() {
+        let shared = shared.clone();
+        let errors = errors.clone();
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => {
+                let listener = bind_unix(Path::new(path))?;
+                spawn_acceptor(listener, addr.clone(), shared, errors)?;
             }
-            Err(e) => {
-                trace!("Fail to receive from listerner");
-                return Err(e.into());
+            None => {
+                let listener = TcpListener::bind(addr)?;
+                spawn_acceptor(listener, addr.clone(), shared, errors)?;
             }
         }
     }
+    drop(errors);
+    trace!("Server starts to monitor the network addresses");
+    notify_ready(&settings.addrs.join(", "), settings.ready_fd)?;
+    // an accept error stops the server
+    match failed.recv() {
+        Ok(e) => Err(e.into()),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Bind the unix socket at `path`, replacing the one a previous run left
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(UnixListener::bind(path)?)
+}
 
+/// What the connections of every listener share
+#[derive(Clone)]
+struct Shared {
+    databases: Vec<KvStore>,
+    connections: ConnectionLimit,
+    auth: Option<Arc<dyn Authenticator>>,
+    validators: Arc<Validators>,
+    drain: Drain,
+}
+
+/// Accept the connections of `listener` on a thread named after `addr`
+/// Each one is served on its own thread until the client hangs up, so an idle
+/// connection holds no worker another client waits for. An accept error is
+/// sent to `errors`.
+fn spawn_acceptor<L: Listener>(
+    listener: L,
+    addr: String,
+    shared: Shared,
+    errors: Sender<io::Error>,
+) -> Result<()> {
+    thread::Builder::new()
+        .name(format!("accept {}", addr))
+        .spawn(move || {
+            loop {
+                let stream = match listener.accept() {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Fail to accept on {}: {}", addr, e);
+                        let _ = errors.send(e);
+                        return;
+                    }
+                };
+                debug!("accept a connection on {}", addr);
+                let Some(slot) = shared.connections.acquire() else {
+                    server::refuse(stream, &shared.connections);
+                    continue;
+                };
+                let shared = shared.clone();
+                let listener_addr = addr.clone();
+                let served = thread::Builder::new()
+                    .name(format!("serve {}", addr))
+                    .spawn(move || {
+                        server::handle_stream(
+                            stream,
+                            shared.databases,
+                            shared.auth.as_deref(),
+                            &shared.validators,
+                            &shared.drain,
+                        );
+                        trace!("connection on {} closed", listener_addr);
+                        drop(slot);
+                    });
+                if let Err(e) = served {
+                    warn!("Fail to spawn a thread for a connection on {}: {}", addr, e);
+                }
+            }
+        })?;
     Ok(())
 }
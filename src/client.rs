@@ -1,32 +1,203 @@
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader};
 use std::net::TcpStream;
+use std::time::Duration;
 
+use log::warn;
+use serde::de::DeserializeOwned;
+
+use crate::engine::kvs::{
+    Change, ChangeEvent, KeyMetadata, RegionChange, ScanPage, SizeEstimate, StoreStats, WatchFilter,
+};
 use crate::protocol::*;
+use crate::transport::{Stream, Tcp, Transport};
 
-use super::error::Result;
+use super::error::{KvsError, Result};
 
-pub fn send_and_recv(rq: Request, stream: TcpStream) -> Result<Option<String>> {
-    let s = serde_json::to_string(&rq)?;
-    let mut writer = BufWriter::new(&stream);
-    writer.write_all(s.as_bytes())?;
-    writer.write_all(b"\n")?;
-    writer.flush()?;
+/// Send one request, and read back one line of response
+/// A server which hangs up instead is an io error, so `KvsClient` fails over.
+fn exchange<S: Stream>(rq: &Request, stream: &mut S) -> Result<String> {
+    let mut s = serde_json::to_string(rq)?;
+    s.push('\n');
+    stream.write_all(s.as_bytes())?;
 
     let mut response = Vec::new();
-    let mut reader = BufReader::new(&stream);
-    reader.read_until(b'\n', &mut response)?;
+    let mut reader = BufReader::new(stream);
+    if reader.read_until(b'\n', &mut response)? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok(String::from_utf8(response)?)
+}
+
+/// Time allowed to probe one address, once connected
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Called with the old and the new address after a failover
+pub type FailoverCallback = Box<dyn FnMut(&str, &str) + Send>;
+
+/// Connects to the first healthy server of an ordered list of addresses
+///
+/// The list is the primary followed by its standbys. An address is healthy
+/// when it accepts a connection and answers `Ping`, which a standby refuses
+/// until it is promoted. The client sticks to the address it last used, and
+/// only moves on, in list order, when that one fails.
+pub struct KvsClient<T: Transport = Tcp> {
+    addrs: Vec<String>,
+    current: usize,
+    on_failover: Option<FailoverCallback>,
+    // user and secret sent on every new connection
+    credentials: Option<(Option<String>, String)>,
+    transport: T,
+}
+
+impl KvsClient {
+    pub fn new(addrs: Vec<String>) -> Self {
+        Self::with_transport(addrs, Tcp)
+    }
+}
+
+impl<T: Transport> KvsClient<T> {
+    /// Connect over `transport`, e.g. a simulated network
+    pub fn with_transport(addrs: Vec<String>, transport: T) -> Self {
+        Self {
+            addrs,
+            current: 0,
+            on_failover: None,
+            credentials: None,
+            transport,
+        }
+    }
 
-    let response = String::from_utf8(response)?;
+    /// Call `f` with the old and the new address whenever the client fails over
+    pub fn on_failover(mut self, f: impl FnMut(&str, &str) + Send + 'static) -> Self {
+        self.on_failover = Some(Box::new(f));
+        self
+    }
+
+    /// Authenticate every connection with `user` and `secret`
+    pub fn credentials(mut self, user: Option<String>, secret: String) -> Self {
+        self.credentials = Some((user, secret));
+        self
+    }
+
+    /// Address used by the next request
+    pub fn current(&self) -> Option<&str> {
+        self.addrs.get(self.current).map(String::as_str)
+    }
+
+    /// Connect to a healthy address, failing over if needed
+    pub fn connect(&mut self) -> Result<T::Stream> {
+        let mut last_err = KvsError::StringError("no server address".to_owned());
+        for i in 0..self.addrs.len() {
+            let idx = (self.current + i) % self.addrs.len();
+            let stream = probe_with(&self.transport, &self.addrs[idx]).and_then(|mut stream| {
+                if let Some((user, secret)) = &self.credentials {
+                    authenticate(user.clone(), secret.clone(), &mut stream)?;
+                }
+                Ok(stream)
+            });
+            match stream {
+                Ok(stream) => {
+                    self.switch_to(idx);
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    warn!("Server {} is unhealthy: {}", self.addrs[idx], e);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
 
-    match rq {
-        Request::Get { key: _ } => {
+    /// Run a request on a healthy address
+    ///
+    /// When the request fails with an io error, the connection is lost and it
+    /// is run again on the next healthy address, so `f` may run more than once.
+    pub fn call<R>(&mut self, mut f: impl FnMut(T::Stream) -> Result<R>) -> Result<R> {
+        let mut attempts = 0;
+        loop {
+            let stream = self.connect()?;
+            attempts += 1;
+            match f(stream) {
+                Err(KvsError::IoError(e)) if attempts < self.addrs.len() => {
+                    warn!("Lost connection to {:?}: {}", self.current(), e);
+                    self.current = (self.current + 1) % self.addrs.len();
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn switch_to(&mut self, idx: usize) {
+        if idx == self.current {
+            return;
+        }
+        let from = self.current;
+        self.current = idx;
+        if let Some(f) = self.on_failover.as_mut() {
+            f(&self.addrs[from], &self.addrs[idx]);
+        }
+    }
+}
+
+/// Connect to `addr` and check that it serves traffic
+/// The connection is returned for the next requests.
+pub fn probe(addr: &str) -> Result<TcpStream> {
+    probe_with(&Tcp, addr)
+}
+
+/// Probe `addr` over `transport`
+pub fn probe_with<T: Transport>(transport: &T, addr: &str) -> Result<T::Stream> {
+    let mut stream = transport.connect(addr)?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    let response = exchange(&Request::Ping, &mut stream)?;
+    stream.set_read_timeout(None)?;
+    match serde_json::from_str(&response)? {
+        SetResponse::Ok => Ok(stream),
+        SetResponse::Err(e) => Err(e.into()),
+    }
+}
+
+/// Send credentials, every later request on `stream` is made as that client
+pub fn authenticate<S: Stream>(user: Option<String>, secret: String, stream: &mut S) -> Result<()> {
+    let response = exchange(&Request::Auth { user, secret }, stream)?;
+    match serde_json::from_str(&response)? {
+        SetResponse::Ok => Ok(()),
+        SetResponse::Err(e) => Err(e.into()),
+    }
+}
+
+/// Switch the connection to database `db`
+/// Every later request on `stream` goes to that database.
+pub fn select<S: Stream>(db: usize, stream: &mut S) -> Result<()> {
+    let response = exchange(&Request::Select { db }, stream)?;
+    match serde_json::from_str(&response)? {
+        SetResponse::Ok => Ok(()),
+        SetResponse::Err(e) => Err(e.into()),
+    }
+}
+
+pub fn send_and_recv<S: Stream>(rq: Request, mut stream: S) -> Result<Option<String>> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::Get { .. } | Request::GetDel { .. } | Request::GetSet { .. } => {
             let result: GetResponse = serde_json::from_str(&response)?;
             match result {
                 GetResponse::Ok(s) => Ok(s),
                 GetResponse::Err(e) => Err(e.into()),
             }
         }
-        Request::Set { key: _, value: _ } => {
+        Request::Set { .. }
+        | Request::SetWithEnvelope { .. }
+        | Request::Promote
+        | Request::Drain { .. }
+        | Request::Resume
+        | Request::Cancel { .. }
+        | Request::SetLogFilter { .. }
+        | Request::Auth { .. }
+        | Request::Compact => {
             let result: SetResponse = serde_json::from_str(&response)?;
             match result {
                 SetResponse::Ok => Ok(None),
@@ -40,5 +211,218 @@ pub fn send_and_recv(rq: Request, stream: TcpStream) -> Result<Option<String>> {
                 RmResponse::Err(e) => Err(e.into()),
             }
         }
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+/// Used by requests whose response carries a flag, e.g. conditional set
+pub fn send_and_recv_flag<S: Stream>(rq: Request, mut stream: S) -> Result<bool> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::SetIfAbsent { .. } | Request::SetIfPresent { .. } | Request::SetWhen { .. } => {
+            let result: SetIfResponse = serde_json::from_str(&response)?;
+            match result {
+                SetIfResponse::Ok(b) => Ok(b),
+                SetIfResponse::Err(e) => Err(e.into()),
+            }
+        }
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+/// Used by `Incr`, return the new value
+pub fn send_and_recv_incr<S: Stream>(rq: Request, mut stream: S) -> Result<i64> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::Incr { .. } => match serde_json::from_str(&response)? {
+            IncrResponse::Ok(n) => Ok(n),
+            IncrResponse::Err(e) => Err(e.into()),
+        },
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+pub fn send_and_recv_stat<S: Stream>(rq: Request, mut stream: S) -> Result<Option<KeyMetadata>> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::Stat { .. } => {
+            let result: StatResponse = serde_json::from_str(&response)?;
+            match result {
+                StatResponse::Ok(m) => Ok(m),
+                StatResponse::Err(e) => Err(e.into()),
+            }
+        }
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+/// Used by bulk requests whose response is the number of affected keys
+pub fn send_and_recv_count<S: Stream>(rq: Request, mut stream: S) -> Result<usize> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::RmRange { .. }
+        | Request::RmPrefix { .. }
+        | Request::CountPrefix { .. }
+        | Request::FlushAll => {
+            let result: CountResponse = serde_json::from_str(&response)?;
+            match result {
+                CountResponse::Ok(n) => Ok(n),
+                CountResponse::Err(e) => Err(e.into()),
+            }
+        }
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+pub fn send_and_recv_keys<S: Stream>(rq: Request, mut stream: S) -> Result<Vec<String>> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::Keys { .. } => {
+            let result: KeysResponse = serde_json::from_str(&response)?;
+            match result {
+                KeysResponse::Ok(keys) => Ok(keys),
+                KeysResponse::Err(e) => Err(e.into()),
+            }
+        }
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+pub fn send_and_recv_scan<S: Stream>(rq: Request, mut stream: S) -> Result<ScanPage> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::Scan { .. } => {
+            let result: ScanResponse = serde_json::from_str(&response)?;
+            match result {
+                ScanResponse::Ok(page) => Ok(page),
+                ScanResponse::Err(e) => Err(e.into()),
+            }
+        }
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+pub fn send_and_recv_pairs<S: Stream>(rq: Request, mut stream: S) -> Result<Vec<(String, String)>> {
+    let response = exchange(&rq, &mut stream)?;
+
+    match rq.inner() {
+        Request::ScanPrefix { .. } => {
+            let result: PairsResponse = serde_json::from_str(&response)?;
+            match result {
+                PairsResponse::Ok(pairs) => Ok(pairs),
+                PairsResponse::Err(e) => Err(e.into()),
+            }
+        }
+        _ => Err(KvsError::UnexpectedType),
+    }
+}
+
+/// Status of the selected database, and the size of the keys under `prefix`
+pub fn stats<S: Stream>(
+    prefix: Option<String>,
+    mut stream: S,
+) -> Result<(StoreStats, Option<SizeEstimate>)> {
+    let response = exchange(&Request::Stats { prefix }, &mut stream)?;
+    match serde_json::from_str(&response)? {
+        StatsResponse::Ok { stats, prefix } => Ok((*stats, prefix)),
+        StatsResponse::Err(e) => Err(e.into()),
+    }
+}
+
+/// Info of every database of the server, indexed by its number
+pub fn info<S: Stream>(mut stream: S) -> Result<Vec<DatabaseInfo>> {
+    let response = exchange(&Request::Info, &mut stream)?;
+    match serde_json::from_str(&response)? {
+        InfoResponse::Ok(info) => Ok(info),
+        InfoResponse::Err(e) => Err(e.into()),
+    }
+}
+
+/// Follow the changefeed of database `db` on a primary
+///
+/// Every pair of the primary comes first, then each change as it happens.
+/// Return when the primary hangs up, or `apply` fails.
+pub fn follow<S: Stream>(
+    db: usize,
+    stream: S,
+    apply: impl FnMut(Change) -> Result<()>,
+) -> Result<()> {
+    follow_with(db, Request::Replicate, stream, apply)
+}
+
+/// Follow the changes of database `db` in a peer region, like `follow`
+/// The changes made in `region` are not sent back.
+pub fn follow_region<S: Stream>(
+    db: usize,
+    region: String,
+    stream: S,
+    apply: impl FnMut(RegionChange) -> Result<()>,
+) -> Result<()> {
+    follow_with(db, Request::Peer { region }, stream, apply)
+}
+
+/// Follow the writes to the keys of database `db` starting with `prefix`
+/// Return when the server hangs up, or `apply` fails.
+pub fn watch<S: Stream>(
+    db: usize,
+    prefix: String,
+    stream: S,
+    apply: impl FnMut(ChangeEvent) -> Result<()>,
+) -> Result<()> {
+    let request = Request::Watch {
+        prefix,
+        filter: WatchFilter::default(),
+    };
+    follow_with(db, request, stream, apply)
+}
+
+/// Follow the writes to the keys of database `db` which `filter` lets
+/// through, like `watch`
+/// The filter runs on the server, the writes left out are never sent.
+pub fn watch_filtered<S: Stream>(
+    db: usize,
+    filter: WatchFilter,
+    stream: S,
+    apply: impl FnMut(ChangeEvent) -> Result<()>,
+) -> Result<()> {
+    let request = Request::Watch {
+        prefix: String::new(),
+        filter,
+    };
+    follow_with(db, request, stream, apply)
+}
+
+fn follow_with<S: Stream, T: DeserializeOwned>(
+    db: usize,
+    request: Request,
+    mut stream: S,
+    mut apply: impl FnMut(T) -> Result<()>,
+) -> Result<()> {
+    if db != 0 {
+        select(db, &mut stream)?;
+    }
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = line?;
+        let change: T = match serde_json::from_str(&line) {
+            Ok(change) => change,
+            // a request refused before the stream starts gets one error line
+            Err(_) => match serde_json::from_str(&line) {
+                Ok(SetResponse::Err(e)) => return Err(e.into()),
+                _ => return Err(KvsError::StringError(line)),
+            },
+        };
+        apply(change)?;
     }
+    Ok(())
 }
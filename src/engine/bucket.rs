@@ -0,0 +1,141 @@
+//! Named keyspaces inside one store, see `KvStore::bucket`
+//!
+//! A bucket keeps its keys in the store under a reserved prefix, the name
+//! between two NUL chars, so all buckets share the logs, the writer and the
+//! compaction of the store while their keys never meet. Keys go in and come
+//! out of a bucket without the prefix. The store itself still sees every
+//! key, the ones of its buckets included.
+
+use std::ops::{Bound, RangeBounds};
+
+use super::kvs::KvStore;
+use super::pattern::Pattern;
+use super::{KvsEngine, ScanIter, prefix_range};
+use crate::error::{KvsError, Result};
+
+/// Starts and ends the name of a bucket in the keys of the store
+const MARK: char = '\u{0}';
+
+/// The keys of one bucket of a `KvStore`
+#[derive(Clone)]
+pub struct Bucket {
+    store: KvStore,
+    // the name between two marks
+    prefix: String,
+}
+
+impl Bucket {
+    /// A non-empty `name` without NUL, there is nothing to create
+    pub(crate) fn new(store: KvStore, name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains(MARK) {
+            return Err(KvsError::InvalidBucket(name.to_owned()));
+        }
+        Ok(Self {
+            store,
+            prefix: format!("{}{}{}", MARK, name, MARK),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.prefix[1..self.prefix.len() - 1]
+    }
+
+    /// The key of the store holding `key`
+    fn inner(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn outer(&self, key: String) -> String {
+        key[self.prefix.len()..].to_owned()
+    }
+
+    /// The keys of the store holding the keys of `range`
+    fn inner_range(&self, range: impl RangeBounds<String>) -> (Bound<String>, Bound<String>) {
+        let (first, after) = prefix_range(&self.prefix);
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(self.inner(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.inner(key)),
+            Bound::Unbounded => first,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(self.inner(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.inner(key)),
+            Bound::Unbounded => after,
+        };
+        (start, end)
+    }
+}
+
+impl KvsEngine for Bucket {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(self.inner(&key), value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(self.inner(&key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(self.inner(&key))
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.store.set_if_absent(self.inner(&key), value)
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.store.set_if_present(self.inner(&key), value)
+    }
+
+    fn take(&self, key: String) -> Result<Option<String>> {
+        self.store.take(self.inner(&key))
+    }
+
+    fn insert(&self, key: String, value: String) -> Result<Option<String>> {
+        self.store.insert(self.inner(&key), value)
+    }
+
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<usize> {
+        let (start, end) = self.inner_range(range);
+        self.store.remove_range((start, end))
+    }
+
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        self.store.remove_prefix(&self.inner(prefix))
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.store.count_prefix(&self.inner(prefix))
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.store.count_prefix(&self.prefix)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.store.contains_key(&self.inner(key))
+    }
+
+    fn keys(&self, pattern: &Pattern) -> Result<Vec<String>> {
+        // every key of the bucket sharing the literal prefix of the pattern
+        let literal = self
+            .inner(pattern.prefix())
+            .replace('\\', "\\\\")
+            .replace('*', "\\*")
+            .replace('?', "\\?");
+        let keys = self.store.keys(&Pattern::new(&format!("{}*", literal)))?;
+        Ok(keys
+            .into_iter()
+            .map(|key| self.outer(key))
+            .filter(|key| pattern.matches(key))
+            .collect())
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<ScanIter> {
+        let bucket = self.clone();
+        let pairs = self.store.scan(self.inner_range(range))?;
+        Ok(Box::new(pairs.map(move |pair| {
+            pair.map(|(key, value)| (bucket.outer(key), value))
+        })))
+    }
+}
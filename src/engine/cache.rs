@@ -0,0 +1,81 @@
+//! A bounded cache of the values read lately, shared by all readers of a store
+//!
+//! A value is cached along with the log position it was read at. A set or a
+//! remove gives the key a new position in the index, so the cached value no
+//! longer matches and is dropped on its next lookup, nothing has to tell the
+//! cache about writes. Past its capacity the least recently used values go.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lru::LruCache;
+
+/// Log version and offset of a record
+pub(crate) type Position = (usize, usize);
+
+pub(crate) struct ValueCache {
+    // in bytes of keys and values
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+}
+
+struct Lru {
+    used: usize,
+    values: LruCache<String, (Position, String)>,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru {
+                used: 0,
+                values: LruCache::unbounded(),
+            }),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// The value of `key` if it was cached when read at `at`
+    pub fn get(&self, key: &str, at: Position) -> Option<String> {
+        let mut lru = self.lru.lock().unwrap();
+        let value = match lru.values.get(key) {
+            Some((pos, value)) if *pos == at => value.clone(),
+            Some(_) => {
+                // overwritten or moved by compaction since
+                let (_, old) = lru.values.pop(key).unwrap();
+                lru.used -= key.len() + old.len();
+                return None;
+            }
+            None => return None,
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    /// Cache `value` of `key`, read at `at`
+    /// A value larger than the whole cache is not kept.
+    pub fn insert(&self, key: &str, at: Position, value: &str) {
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        if let Some((_, old)) = lru.values.put(key.to_owned(), (at, value.to_owned())) {
+            lru.used -= key.len() + old.len();
+        }
+        lru.used += size;
+        while lru.used > self.capacity {
+            let Some((k, (_, v))) = lru.values.pop_lru() else {
+                break;
+            };
+            lru.used -= k.len() + v.len();
+        }
+    }
+
+    /// Reads answered by the cache so far
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,190 @@
+//! Values stored in chunks, see `KvStore::set_from_reader`
+//!
+//! A value read from a stream is cut into chunks of at most
+//! `KvStoreBuilder::chunk_size` bytes, each set under a reserved key made of
+//! the key, an id of the write and the chunk number. Once all are written,
+//! the key is set to a small pointer naming the id and the number of chunks,
+//! with the `CHUNKED` content type in its envelope, and the chunks of other
+//! writes of the key are removed. A crash before the pointer is set leaves
+//! chunks nobody points to, the next chunked write of the key removes them.
+//! Neither side ever holds more than one chunk in memory.
+
+use std::io::{self, Read};
+use std::mem;
+use std::ops::Bound;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use super::KvsEngine;
+use super::kvs::KvStore;
+use super::prefix_range;
+use crate::error::Result;
+
+/// Content type of the pointer to the chunks of a value
+pub(crate) const CHUNKED: &str = "application/vnd.kvs.chunks+json";
+
+/// Starts the keys holding chunks, among the `RESERVED` ones
+const CHUNK_PREFIX: &str = "\u{10FFFF}\u{1}";
+/// Digits of the id and of the number in a chunk key
+const CHUNK_DIGITS: usize = 20;
+
+/// The value of a chunked key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) struct Chunks {
+    pub id: u64,
+    pub count: u64,
+    /// Bytes of the whole value
+    pub len: u64,
+}
+
+/// Key of chunk `n` of the write `id` of `key`
+/// Both are zero padded, so the chunks of a write sort in order.
+pub(crate) fn chunk_key(key: &str, id: u64, n: u64) -> String {
+    format!(
+        "{}{}\u{0}{:0width$}\u{0}{:0width$}",
+        CHUNK_PREFIX,
+        key,
+        id,
+        n,
+        width = CHUNK_DIGITS
+    )
+}
+
+/// Ranges of the chunks of `key`, but those of the write `keep`
+pub(crate) fn chunk_ranges(key: &str, keep: Option<u64>) -> Vec<(Bound<String>, Bound<String>)> {
+    let (start, end) = prefix_range(&format!("{}{}\u{0}", CHUNK_PREFIX, key));
+    let Some(id) = keep else {
+        return vec![(start, end)];
+    };
+    let (kept, after) = prefix_range(&format!(
+        "{}{}\u{0}{:0width$}\u{0}",
+        CHUNK_PREFIX,
+        key,
+        id,
+        width = CHUNK_DIGITS
+    ));
+    let after = match after {
+        Bound::Excluded(after) => Bound::Included(after),
+        after => after,
+    };
+    let before = match kept {
+        Bound::Included(kept) => Bound::Excluded(kept),
+        kept => kept,
+    };
+    vec![(start, before), (after, end)]
+}
+
+/// Cuts a stream into chunks of at most `size` bytes, between characters
+pub(crate) struct Chunker<R> {
+    input: R,
+    size: usize,
+    // the start of a character cut by the previous chunk
+    carry: Vec<u8>,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(input: R, size: usize) -> Self {
+        Self {
+            input,
+            // room for the longest character
+            size: size.max(4),
+            carry: Vec::new(),
+        }
+    }
+
+    /// `None` once the stream is over
+    pub fn next_chunk(&mut self) -> Result<Option<String>> {
+        let mut buf = mem::take(&mut self.carry);
+        let want = self.size - buf.len();
+        let read = (&mut self.input).take(want as u64).read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if let Err(e) = str::from_utf8(&buf)
+            && e.error_len().is_none()
+            && read == want
+        {
+            self.carry = buf.split_off(e.valid_up_to());
+        }
+        // invalid UTF-8 fails here, so does a character cut by the end
+        Ok(Some(String::from_utf8(buf)?))
+    }
+}
+
+/// Read in the chunks `pointer` names, the value of `key` in `store`
+pub(crate) fn read_chunked<E: KvsEngine>(store: E, key: &str, pointer: &str) -> Result<String> {
+    let chunks: Chunks = serde_json::from_str(pointer)?;
+    let mut value = String::with_capacity(chunks.len as usize);
+    ValueReader::chunked(store, key.to_owned(), chunks).read_to_string(&mut value)?;
+    Ok(value)
+}
+
+/// Reads a value from the store, one chunk at a time, see `KvStore::get_reader`
+///
+/// A value replaced while it is read fails the read with an error, the
+/// chunks of the old one being removed.
+pub struct ValueReader<E = KvStore> {
+    store: E,
+    key: String,
+    chunks: Option<Chunks>,
+    next: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<E: KvsEngine> ValueReader<E> {
+    pub(crate) fn plain(store: E, key: String, value: String) -> Self {
+        Self {
+            store,
+            key,
+            chunks: None,
+            next: 0,
+            buf: value.into_bytes(),
+            pos: 0,
+        }
+    }
+
+    pub(crate) fn chunked(store: E, key: String, chunks: Chunks) -> Self {
+        Self {
+            store,
+            key,
+            chunks: Some(chunks),
+            next: 0,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Bytes of the whole value
+    pub fn len(&self) -> u64 {
+        self.chunks.map_or(self.buf.len() as u64, |c| c.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<E: KvsEngine> Read for ValueReader<E> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let Some(chunks) = self.chunks.filter(|c| self.next < c.count) else {
+                return Ok(0);
+            };
+            let key = chunk_key(&self.key, chunks.id, self.next);
+            let chunk = self
+                .store
+                .get(key)
+                .map_err(|e| io::Error::other(e.to_string()))?
+                .ok_or_else(|| io::Error::other(format!("value of {} was replaced", self.key)))?;
+            self.buf = chunk.into_bytes();
+            self.pos = 0;
+            self.next += 1;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
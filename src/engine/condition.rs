@@ -0,0 +1,208 @@
+use std::cmp::Ordering;
+
+use crate::error::{KvsError, Result};
+
+/// A condition on the current value of a key, checked before a write
+///
+/// ```text
+/// expr    := and ("or" and)*
+/// and     := unary ("and" unary)*
+/// unary   := "not" unary | "(" expr ")" | "exists" | "value" op literal
+/// op      := "<" | "<=" | ">" | ">=" | "==" | "!="
+/// literal := a number, a bare word or a "quoted string"
+/// ```
+///
+/// Values and literals which both parse as numbers are compared as numbers,
+/// anything else as strings. A comparison with a missing key is false, e.g.
+/// `not exists or value < 10`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Exists,
+    Compare(Op, String),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+impl Condition {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let cond = parser.expr()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(cond),
+            Some(t) => Err(invalid(format!("unexpected {:?}", t))),
+        }
+    }
+
+    /// Check the condition against `value`, `None` if the key is missing
+    pub fn eval(&self, value: Option<&str>) -> bool {
+        match self {
+            Condition::Exists => value.is_some(),
+            Condition::Compare(op, literal) => value.is_some_and(|v| {
+                let ord = match (v.parse::<f64>(), literal.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => a.partial_cmp(&b),
+                    _ => Some(v.cmp(literal.as_str())),
+                };
+                match (op, ord) {
+                    (_, None) => *op == Op::Ne,
+                    (Op::Lt, Some(o)) => o == Ordering::Less,
+                    (Op::Le, Some(o)) => o != Ordering::Greater,
+                    (Op::Gt, Some(o)) => o == Ordering::Greater,
+                    (Op::Ge, Some(o)) => o != Ordering::Less,
+                    (Op::Eq, Some(o)) => o == Ordering::Equal,
+                    (Op::Ne, Some(o)) => o != Ordering::Equal,
+                }
+            }),
+            Condition::Not(c) => !c.eval(value),
+            Condition::And(a, b) => a.eval(value) && b.eval(value),
+            Condition::Or(a, b) => a.eval(value) || b.eval(value),
+        }
+    }
+}
+
+fn invalid(msg: String) -> KvsError {
+    KvsError::InvalidCondition(msg)
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '<' | '>' | '=' | '!' => {
+                chars.next();
+                let eq = chars.next_if_eq(&'=').is_some();
+                let op = match (c, eq) {
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    ('=', true) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    _ => return Err(invalid(format!("unknown operator {}", c))),
+                };
+                tokens.push(Token::Op(op));
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // `\"` and `\\` inside a quoted string
+                        Some('\\') => s.extend(chars.next()),
+                        Some(c) => s.push(c),
+                        None => return Err(invalid("unterminated string".to_owned())),
+                    }
+                }
+                tokens.push(Token::Quoted(s));
+            }
+            _ => {
+                let mut s = String::new();
+                while let Some(c) =
+                    chars.next_if(|c| !c.is_whitespace() && !"()<>=!\"".contains(*c))
+                {
+                    s.push(c);
+                }
+                tokens.push(Token::Word(s));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// Consume the keyword `word` if it comes next
+    fn keyword(&mut self, word: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(word) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expr(&mut self) -> Result<Condition> {
+        let mut cond = self.and()?;
+        while self.keyword("or") {
+            cond = Condition::Or(Box::new(cond), Box::new(self.and()?));
+        }
+        Ok(cond)
+    }
+
+    fn and(&mut self) -> Result<Condition> {
+        let mut cond = self.unary()?;
+        while self.keyword("and") {
+            cond = Condition::And(Box::new(cond), Box::new(self.unary()?));
+        }
+        Ok(cond)
+    }
+
+    fn unary(&mut self) -> Result<Condition> {
+        if self.keyword("not") {
+            return Ok(Condition::Not(Box::new(self.unary()?)));
+        }
+        if self.keyword("exists") {
+            return Ok(Condition::Exists);
+        }
+        if self.keyword("value") {
+            let op = match self.next() {
+                Some(Token::Op(op)) => op,
+                t => return Err(invalid(format!("expect an operator, got {:?}", t))),
+            };
+            return match self.next() {
+                Some(Token::Word(s) | Token::Quoted(s)) => Ok(Condition::Compare(op, s)),
+                t => Err(invalid(format!("expect a literal, got {:?}", t))),
+            };
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let cond = self.expr()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(cond),
+                    t => Err(invalid(format!("expect ), got {:?}", t))),
+                }
+            }
+            t => Err(invalid(format!("expect a condition, got {:?}", t))),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! A dump of every pair of an engine, to move data between engines
+//!
+//! The dump is line-delimited JSON: a header with the version of the format,
+//! then one `{"key":..,"value":..}` object per pair, in key order. It is
+//! written from a scan and read back with plain sets, so any engine may take
+//! the dump of any other, e.g. of a kvs store into sled, or into a store of
+//! a newer log format. Only pairs go into it: expiries, envelopes and kept
+//! versions of a `KvStore` are not carried over.
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use super::KvsEngine;
+use crate::error::{KvsError, Result};
+
+/// Version of the dump format written, and the newest one read
+pub const DUMP_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    dump_version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Write every pair of `engine` to `out`, return the number of pairs
+pub fn export<E: KvsEngine>(engine: &E, mut out: impl Write) -> Result<usize> {
+    write_line(
+        &mut out,
+        &Header {
+            dump_version: DUMP_VERSION,
+        },
+    )?;
+    let mut pairs = 0;
+    for pair in engine.scan(..)? {
+        let (key, value) = pair?;
+        write_line(&mut out, &Pair { key, value })?;
+        pairs += 1;
+    }
+    out.flush()?;
+    Ok(pairs)
+}
+
+/// Set every pair of the dump read from `input` in `engine`, return the
+/// number of pairs
+///
+/// The keys of the engine which are not in the dump are left alone. A dump
+/// cut short sets the pairs before the cut, then fails.
+pub fn import<E: KvsEngine>(engine: &E, input: impl Read) -> Result<usize> {
+    let mut lines = BufReader::new(input).lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| KvsError::StringError("dump is empty".to_owned()))??;
+    let header: Header = serde_json::from_str(&header)?;
+    if header.dump_version > DUMP_VERSION {
+        return Err(KvsError::IncompatibleStore(format!(
+            "dump format version {} is newer than {}",
+            header.dump_version, DUMP_VERSION
+        )));
+    }
+    let mut pairs = 0;
+    for line in lines {
+        let Pair { key, value } = serde_json::from_str(&line?)?;
+        engine.set(key, value)?;
+        pairs += 1;
+    }
+    Ok(pairs)
+}
+
+fn write_line<T: Serialize>(out: &mut impl Write, item: &T) -> Result<()> {
+    serde_json::to_writer(&mut *out, item)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Reads of the same record running at once, done only once
+//!
+//! When many gets of a hot key arrive together, e.g. right after a write made
+//! its cached value stale, the first one reads the record and the others wait
+//! for its result instead of each going to the log. A read is joined only at
+//! the same log position, so a get never sees an older value than the index
+//! gave it.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::cache::Position;
+use crate::error::{KvsError, Result};
+
+pub(crate) struct Flights {
+    running: Mutex<HashMap<(String, Position), Arc<Flight>>>,
+    joined: AtomicU64,
+}
+
+#[derive(Default)]
+struct Flight {
+    // `None` until the read is over, an error is passed on as its message
+    result: Mutex<Option<std::result::Result<String, String>>>,
+    done: Condvar,
+}
+
+/// The read a get runs for the others, lands its flight even on a panic
+struct Leader<'a> {
+    flights: &'a Flights,
+    id: (String, Position),
+    flight: Arc<Flight>,
+}
+
+impl Flights {
+    pub fn new() -> Self {
+        Self {
+            running: Mutex::new(HashMap::new()),
+            joined: AtomicU64::new(0),
+        }
+    }
+
+    /// Run `read` of `key` at `at`, or wait for the same read already running
+    pub fn read(
+        &self,
+        key: &str,
+        at: Position,
+        read: impl FnOnce() -> Result<String>,
+    ) -> Result<String> {
+        let id = (key.to_owned(), at);
+        let (flight, first) = {
+            let mut running = self.running.lock().unwrap();
+            match running.get(&id) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Arc::<Flight>::default();
+                    running.insert(id.clone(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+        if !first {
+            self.joined.fetch_add(1, Ordering::Relaxed);
+            let mut result = flight.result.lock().unwrap();
+            while result.is_none() {
+                result = flight.done.wait(result).unwrap();
+            }
+            return result.clone().unwrap().map_err(KvsError::StringError);
+        }
+        let leader = Leader {
+            flights: self,
+            id,
+            flight,
+        };
+        let result = read();
+        leader.land(
+            result
+                .as_ref()
+                .map(String::clone)
+                .map_err(|e| e.to_string()),
+        );
+        result
+    }
+
+    /// Gets which waited for the read of another one so far
+    pub fn joined(&self) -> u64 {
+        self.joined.load(Ordering::Relaxed)
+    }
+}
+
+impl Leader<'_> {
+    fn land(&self, result: std::result::Result<String, String>) {
+        let mut slot = self.flight.result.lock().unwrap();
+        if slot.is_some() {
+            return;
+        }
+        // later gets read again, they may come after a write
+        self.flights.running.lock().unwrap().remove(&self.id);
+        *slot = Some(result);
+        self.flight.done.notify_all();
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.land(Err("the shared read of the value failed".to_owned()));
+    }
+}
@@ -0,0 +1,169 @@
+//! Writes and reclaimed bytes of a store over time, to forecast its disk
+//!
+//! Counts are kept per minute for the last hour and per hour for the last
+//! week. They are saved next to the manifest whenever a minute is over and
+//! when the store is closed, so a restart keeps them, and a crash loses at
+//! most the minute it happened in.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// Name of the saved counts inside the data directory
+pub const GROWTH_FILE: &str = "growth.json";
+
+const MINUTE: u64 = 60_000;
+const HOUR: u64 = 60 * MINUTE;
+/// Buckets of each length kept, older ones are dropped
+const MINUTES: usize = 60;
+const HOURS: usize = 7 * 24;
+
+/// What a store took in and gave back over some time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteCounts {
+    /// Records appended, every write of a batch counts
+    pub writes: u64,
+    /// Bytes appended to the logs
+    pub bytes_written: u64,
+    /// Bytes freed on disk by compaction
+    pub bytes_reclaimed: u64,
+}
+
+impl WriteCounts {
+    fn add(&mut self, other: &WriteCounts) {
+        self.writes += other.writes;
+        self.bytes_written += other.bytes_written;
+        self.bytes_reclaimed += other.bytes_reclaimed;
+    }
+
+    /// Bytes the logs grew by, negative when compaction freed more
+    pub fn net_bytes(&self) -> i64 {
+        self.bytes_written as i64 - self.bytes_reclaimed as i64
+    }
+}
+
+/// Counts of the last minute, hour and day, see `KvStore::stats`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GrowthStats {
+    pub last_minute: WriteCounts,
+    pub last_hour: WriteCounts,
+    pub last_day: WriteCounts,
+    /// When the disk fills at the net growth of the last hour, `None` while
+    /// the logs do not grow
+    pub full_in: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct Bucket {
+    // in milliseconds since the unix epoch, a multiple of its length
+    start: u64,
+    counts: WriteCounts,
+}
+
+/// Counts per minute and per hour, oldest first
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct WriteHistory {
+    minutes: VecDeque<Bucket>,
+    hours: VecDeque<Bucket>,
+}
+
+impl WriteHistory {
+    /// The counts saved in the data directory `dir`, none if it has no file
+    /// A damaged file is logged and starts the counts over.
+    pub fn load(dir: &Path) -> Self {
+        let content = match fs::read_to_string(dir.join(GROWTH_FILE)) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("Fail to read the write history, start it over: {}", e);
+            Self::default()
+        })
+    }
+
+    /// Write the counts to the data directory `dir`, replacing the old ones
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", GROWTH_FILE));
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(tmp, dir.join(GROWTH_FILE))?;
+        Ok(())
+    }
+
+    /// Add `counts` at `now`, in milliseconds
+    /// Return whether a new minute started, a good time to save the counts.
+    pub fn record(&mut self, now: u64, counts: WriteCounts) -> bool {
+        let rolled = add(&mut self.minutes, now, MINUTE, MINUTES, &counts);
+        add(&mut self.hours, now, HOUR, HOURS, &counts);
+        rolled
+    }
+
+    /// Counts of the windows ending at `now`, with `available` bytes on disk
+    pub fn stats(&self, now: u64, available: u64) -> GrowthStats {
+        let last_hour = sum(&self.minutes, now, HOUR);
+        // the hour so far is a partial bucket, the rate is over the time seen
+        let seen = self
+            .minutes
+            .iter()
+            .find(|b| b.start + HOUR > now)
+            .map_or(0, |b| now.saturating_sub(b.start))
+            .max(MINUTE);
+        let full_in = match last_hour.net_bytes() {
+            net if net > 0 => {
+                let millis = available as u128 * seen as u128 / net as u128;
+                Some(Duration::from_millis(millis.min(u64::MAX as u128) as u64))
+            }
+            _ => None,
+        };
+        GrowthStats {
+            last_minute: sum(&self.minutes, now, MINUTE),
+            last_hour,
+            last_day: sum(&self.hours, now, 24 * HOUR),
+            full_in,
+        }
+    }
+}
+
+/// Add `counts` to the bucket of `len` holding `now`, keep at most `keep`
+/// Return whether the bucket is a new one.
+fn add(
+    buckets: &mut VecDeque<Bucket>,
+    now: u64,
+    len: u64,
+    keep: usize,
+    counts: &WriteCounts,
+) -> bool {
+    let start = now - now % len;
+    let rolled = match buckets.back_mut() {
+        Some(last) if last.start >= start => {
+            // a clock going back is added to the latest bucket
+            last.counts.add(counts);
+            false
+        }
+        _ => {
+            buckets.push_back(Bucket {
+                start,
+                counts: *counts,
+            });
+            true
+        }
+    };
+    while buckets.len() > keep {
+        buckets.pop_front();
+    }
+    rolled
+}
+
+/// Counts of the buckets started in the `span` before `now`
+fn sum(buckets: &VecDeque<Bucket>, now: u64, span: u64) -> WriteCounts {
+    let mut total = WriteCounts::default();
+    for bucket in buckets.iter().rev().take_while(|b| b.start + span > now) {
+        total.add(&bucket.counts);
+    }
+    total
+}
@@ -0,0 +1,529 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use arc_swap::ArcSwap;
+use crossbeam_skiplist::SkipMap;
+use imbl::OrdMap;
+use serde::{Deserialize, Serialize};
+
+use super::memory::ENTRY_OVERHEAD;
+use super::{RESERVED, is_empty_range, is_reserved};
+
+/// Which structure backs the in-memory index (keydir) of a `KvStore`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// Persistent ordered map behind an atomic pointer.
+    /// Every write copies a path of the tree and publishes a new snapshot,
+    /// so scans and range removals see one consistent view.
+    #[default]
+    Ordered,
+    /// Lock-free skiplist updated in place.
+    /// Writes are cheaper and scale with the thread count, but a scan may
+    /// observe a range removal half done.
+    Concurrent,
+    /// Ordered maps, one per shard of the keys by hash.
+    /// A write copies a path of a smaller tree, scans merge the shards in
+    /// order. Readers never block either, but like the skiplist they may
+    /// observe a batch or a range removal half done, and the index can not
+    /// be pinned without a copy.
+    Sharded(usize),
+}
+
+/// Keys of an ordered keydir pinned at some point in time
+pub(crate) type Snapshot<V> = Arc<OrdMap<String, V>>;
+
+/// Cut `end` before the keys the store keeps for itself, see `RESERVED`
+/// Every listing and count goes through it, only `reserved_keys` and
+/// `all_keys` reach those keys.
+fn before_reserved(end: Bound<String>) -> Bound<String> {
+    match end {
+        Bound::Included(key) | Bound::Excluded(key) if key.as_str() >= RESERVED => {
+            Bound::Excluded(RESERVED.to_owned())
+        }
+        Bound::Unbounded => Bound::Excluded(RESERVED.to_owned()),
+        end => end,
+    }
+}
+
+/// Up to `count` keys of `map` in the range accepted by `filter`, in order
+pub(crate) fn page<V: Clone>(
+    map: &OrdMap<String, V>,
+    start: Bound<String>,
+    end: Bound<String>,
+    count: usize,
+    filter: impl Fn(&str) -> bool,
+) -> Vec<String> {
+    let end = before_reserved(end);
+    if is_empty_range(&start, &end) {
+        return Vec::new();
+    }
+    map.range((start, end))
+        .filter(|(k, _)| filter(k))
+        .take(count)
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+/// The keydir, mapping each key to where its value lives
+///
+/// Readers never block on it. Mutations must come from one thread at a time,
+/// the store guarantees this by only mutating under the writer lock.
+pub(crate) struct KeyDir<V> {
+    map: Map<V>,
+    // bytes of all keys, to estimate the memory of the index
+    key_bytes: AtomicUsize,
+    // keys the store keeps for itself, left out of `len`
+    reserved: AtomicUsize,
+}
+
+enum Map<V> {
+    Ordered(ArcSwap<OrdMap<String, V>>),
+    Concurrent(ArcSwap<SkipMap<String, V>>),
+    Sharded(Vec<ArcSwap<OrdMap<String, V>>>),
+}
+
+/// Shard of `key` among `shards`
+fn shard_of(key: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// The entries of every shard in the range, merged in key order
+fn merged<'a, V>(
+    shards: &'a [Arc<OrdMap<String, V>>],
+    start: &Bound<String>,
+    end: &Bound<String>,
+) -> impl Iterator<Item = (&'a String, &'a V)> {
+    let mut heads: Vec<_> = shards
+        .iter()
+        .map(|shard| shard.range((start.clone(), end.clone())).peekable())
+        .collect();
+    std::iter::from_fn(move || {
+        let (next, _) = heads
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, head)| head.peek().map(|(key, _)| (i, *key)))
+            .min_by(|a, b| a.1.cmp(b.1))?;
+        heads[next].next()
+    })
+}
+
+impl<V: Clone + Send + Sync + 'static> KeyDir<V> {
+    pub fn new(kind: IndexKind) -> Self {
+        let map = match kind {
+            IndexKind::Ordered => Map::Ordered(ArcSwap::from_pointee(OrdMap::new())),
+            IndexKind::Concurrent => Map::Concurrent(ArcSwap::from_pointee(SkipMap::new())),
+            IndexKind::Sharded(shards) => Map::Sharded(
+                (0..shards.max(1))
+                    .map(|_| ArcSwap::from_pointee(OrdMap::new()))
+                    .collect(),
+            ),
+        };
+        Self {
+            map,
+            key_bytes: AtomicUsize::new(0),
+            reserved: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<V> {
+        match &self.map {
+            Map::Ordered(map) => map.load().get(key).cloned(),
+            Map::Concurrent(map) => map.load().get(key).map(|e| e.value().clone()),
+            Map::Sharded(shards) => shards[shard_of(key, shards.len())].load().get(key).cloned(),
+        }
+    }
+
+    /// Number of keys, the reserved ones aside
+    pub fn len(&self) -> usize {
+        self.total() - self.reserved()
+    }
+
+    /// Number of keys the store keeps for itself, see `RESERVED`
+    pub fn reserved(&self) -> usize {
+        self.reserved.load(Ordering::SeqCst)
+    }
+
+    fn total(&self) -> usize {
+        match &self.map {
+            Map::Ordered(map) => map.load().len(),
+            Map::Concurrent(map) => map.load().len(),
+            Map::Sharded(shards) => shards.iter().map(|shard| shard.load().len()).sum(),
+        }
+    }
+
+    /// The current map of every shard
+    fn load_shards(shards: &[ArcSwap<OrdMap<String, V>>]) -> Vec<Arc<OrdMap<String, V>>> {
+        shards.iter().map(ArcSwap::load_full).collect()
+    }
+
+    /// Estimated bytes used by the keys and their entries
+    pub fn memory(&self) -> usize {
+        self.key_bytes.load(Ordering::SeqCst) + self.total() * ENTRY_OVERHEAD
+    }
+
+    fn account(&self, key: &str, added: bool, removed: bool) {
+        self.account_len(key.len(), is_reserved(key), added, removed);
+    }
+
+    fn account_len(&self, len: usize, reserved: bool, added: bool, removed: bool) {
+        if added && !removed {
+            self.key_bytes.fetch_add(len, Ordering::SeqCst);
+            if reserved {
+                self.reserved.fetch_add(1, Ordering::SeqCst);
+            }
+        } else if removed && !added {
+            self.key_bytes.fetch_sub(len, Ordering::SeqCst);
+            if reserved {
+                self.reserved.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Return the old value of `key`
+    pub fn insert(&self, key: String, value: V) -> Option<V> {
+        let (len, reserved) = (key.len(), is_reserved(&key));
+        let old = match &self.map {
+            Map::Ordered(map) => Self::update(map, |mp| mp.insert(key, value)),
+            Map::Concurrent(map) => {
+                let map = map.load();
+                let old = map.get(&key).map(|e| e.value().clone());
+                map.insert(key, value);
+                old
+            }
+            Map::Sharded(shards) => {
+                let shard = &shards[shard_of(&key, shards.len())];
+                Self::update(shard, |mp| mp.insert(key, value))
+            }
+        };
+        self.account_len(len, reserved, true, old.is_some());
+        old
+    }
+
+    /// Return the old value of `key`
+    pub fn remove(&self, key: &str) -> Option<V> {
+        let old = match &self.map {
+            Map::Ordered(map) => Self::update(map, |mp| mp.remove(key)),
+            Map::Concurrent(map) => map.load().remove(key).map(|e| e.value().clone()),
+            Map::Sharded(shards) => {
+                Self::update(&shards[shard_of(key, shards.len())], |mp| mp.remove(key))
+            }
+        };
+        self.account(key, false, old.is_some());
+        old
+    }
+
+    /// Remove all of `keys`, return the old values of those present
+    pub fn remove_all(&self, keys: &[String]) -> Vec<V> {
+        match &self.map {
+            Map::Ordered(map) => Self::update(map, |mp| {
+                keys.iter()
+                    .filter_map(|k| {
+                        let old = mp.remove(k);
+                        self.account(k, false, old.is_some());
+                        old
+                    })
+                    .collect()
+            }),
+            Map::Concurrent(map) => {
+                let map = map.load();
+                keys.iter()
+                    .filter_map(|k| {
+                        let old = map.remove(k).map(|e| e.value().clone());
+                        self.account(k, false, old.is_some());
+                        old
+                    })
+                    .collect()
+            }
+            // one new map per shard, not per key
+            Map::Sharded(shards) => {
+                let mut by_shard = vec![Vec::new(); shards.len()];
+                for key in keys {
+                    by_shard[shard_of(key, shards.len())].push(key);
+                }
+                let mut olds = Vec::new();
+                for (shard, keys) in shards.iter().zip(by_shard) {
+                    if keys.is_empty() {
+                        continue;
+                    }
+                    Self::update(shard, |mp| {
+                        for k in keys {
+                            let old = mp.remove(k);
+                            self.account(k, false, old.is_some());
+                            olds.extend(old);
+                        }
+                    });
+                }
+                olds
+            }
+        }
+    }
+
+    /// Insert, or remove on `None`, every key in order
+    /// Return the old values. With the ordered map readers see either none
+    /// or all of the updates.
+    pub fn apply(&self, updates: Vec<(String, Option<V>)>) -> Vec<Option<V>> {
+        match &self.map {
+            Map::Ordered(map) => Self::update(map, |mp| {
+                updates
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let added = value.is_some();
+                        let old = match value {
+                            Some(value) => mp.insert(key.clone(), value),
+                            None => mp.remove(&key),
+                        };
+                        self.account(&key, added, old.is_some());
+                        old
+                    })
+                    .collect()
+            }),
+            Map::Concurrent(_) | Map::Sharded(_) => updates
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Some(value) => self.insert(key, value),
+                    None => self.remove(&key),
+                })
+                .collect(),
+        }
+    }
+
+    /// Collect the keys that fall into the range
+    pub fn keys_in_range(&self, start: Bound<String>, end: Bound<String>) -> Vec<String> {
+        self.keys_matching(start, end, |_| true)
+    }
+
+    /// Collect the keys the store keeps for itself in the range
+    pub fn reserved_keys(&self, start: Bound<String>, end: Bound<String>) -> Vec<String> {
+        let start = match start {
+            Bound::Included(key) | Bound::Excluded(key) if key.as_str() < RESERVED => {
+                Bound::Included(RESERVED.to_owned())
+            }
+            Bound::Unbounded => Bound::Included(RESERVED.to_owned()),
+            start => start,
+        };
+        self.collect(start, end, |_| true)
+    }
+
+    /// Collect the keys a removal of the range drops: only reserved keys if
+    /// it starts among them, only the others otherwise
+    pub fn removed_by(&self, start: Bound<String>, end: Bound<String>) -> Vec<String> {
+        match &start {
+            Bound::Included(key) | Bound::Excluded(key) if is_reserved(key) => {
+                self.reserved_keys(start, end)
+            }
+            _ => self.keys_in_range(start, end),
+        }
+    }
+
+    /// Collect every key, the reserved ones too
+    pub fn all_keys(&self) -> Vec<String> {
+        self.collect(Bound::Unbounded, Bound::Unbounded, |_| true)
+    }
+
+    /// Collect the keys in the range accepted by `filter`, in order
+    pub fn keys_matching(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        self.collect(start, before_reserved(end), filter)
+    }
+
+    fn collect(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        if is_empty_range(&start, &end) {
+            return Vec::new();
+        }
+        match &self.map {
+            Map::Ordered(map) => map
+                .load()
+                .range((start, end))
+                .filter(|(k, _)| filter(k))
+                .map(|(k, _)| k.clone())
+                .collect(),
+            Map::Concurrent(map) => map
+                .load()
+                .range((start, end))
+                .filter(|e| filter(e.key()))
+                .map(|e| e.key().clone())
+                .collect(),
+            Map::Sharded(shards) => merged(&Self::load_shards(shards), &start, &end)
+                .filter(|(k, _)| filter(k))
+                .map(|(k, _)| k.clone())
+                .collect(),
+        }
+    }
+
+    /// Count the keys in the range without collecting them
+    pub fn count_range(&self, start: Bound<String>, end: Bound<String>) -> usize {
+        let end = before_reserved(end);
+        if is_empty_range(&start, &end) {
+            return 0;
+        }
+        match &self.map {
+            Map::Ordered(map) => map.load().range((start, end)).count(),
+            Map::Concurrent(map) => map.load().range((start, end)).count(),
+            Map::Sharded(shards) => shards
+                .iter()
+                .map(|shard| shard.load().range((start.clone(), end.clone())).count())
+                .sum(),
+        }
+    }
+
+    /// Count the entries in the range accepted by `filter`
+    pub fn count_matching(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        filter: impl Fn(&str, &V) -> bool,
+    ) -> usize {
+        let end = before_reserved(end);
+        if is_empty_range(&start, &end) {
+            return 0;
+        }
+        match &self.map {
+            Map::Ordered(map) => map
+                .load()
+                .range((start, end))
+                .filter(|(k, v)| filter(k, v))
+                .count(),
+            Map::Concurrent(map) => map
+                .load()
+                .range((start, end))
+                .filter(|e| filter(e.key(), e.value()))
+                .count(),
+            Map::Sharded(shards) => shards
+                .iter()
+                .map(|shard| {
+                    shard
+                        .load()
+                        .range((start.clone(), end.clone()))
+                        .filter(|(k, v)| filter(k, v))
+                        .count()
+                })
+                .sum(),
+        }
+    }
+
+    /// Every `stride`-th entry in the range, the values of the others are
+    /// never cloned
+    pub fn sample_range(&self, start: Bound<String>, end: Bound<String>, stride: usize) -> Vec<V> {
+        let end = before_reserved(end);
+        if is_empty_range(&start, &end) {
+            return Vec::new();
+        }
+        let stride = stride.max(1);
+        match &self.map {
+            Map::Ordered(map) => map
+                .load()
+                .range((start, end))
+                .step_by(stride)
+                .map(|(_, v)| v.clone())
+                .collect(),
+            Map::Concurrent(map) => map
+                .load()
+                .range((start, end))
+                .step_by(stride)
+                .map(|e| e.value().clone())
+                .collect(),
+            Map::Sharded(shards) => merged(&Self::load_shards(shards), &start, &end)
+                .step_by(stride)
+                .map(|(_, v)| v.clone())
+                .collect(),
+        }
+    }
+
+    /// Pin the current keys, if the backend can do it cheaply
+    /// Only the ordered map can, a skiplist or shards are always read live.
+    pub fn snapshot(&self) -> Option<Snapshot<V>> {
+        match &self.map {
+            Map::Ordered(map) => Some(map.load_full()),
+            Map::Concurrent(_) | Map::Sharded(_) => None,
+        }
+    }
+
+    /// Pin the current keys, a skiplist is copied into an ordered map, shards
+    /// are joined into one
+    pub fn pin(&self) -> Snapshot<V> {
+        match &self.map {
+            Map::Ordered(map) => map.load_full(),
+            Map::Concurrent(map) => Arc::new(
+                map.load()
+                    .iter()
+                    .map(|e| (e.key().clone(), e.value().clone()))
+                    .collect(),
+            ),
+            Map::Sharded(shards) => Arc::new(OrdMap::unions(
+                shards.iter().map(|shard| OrdMap::clone(&shard.load())),
+            )),
+        }
+    }
+
+    /// A keydir over pinned keys, never mutated
+    pub fn frozen(keys: Snapshot<V>) -> Self {
+        let reserved = keys.range(RESERVED.to_owned()..).count();
+        Self {
+            map: Map::Ordered(ArcSwap::new(keys)),
+            key_bytes: AtomicUsize::new(0),
+            reserved: AtomicUsize::new(reserved),
+        }
+    }
+
+    /// Up to `count` keys in the range accepted by `filter`, in order
+    pub fn page(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        count: usize,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        let end = before_reserved(end);
+        match &self.map {
+            Map::Ordered(map) => page(&map.load(), start, end, count, filter),
+            Map::Sharded(shards) => {
+                if is_empty_range(&start, &end) {
+                    return Vec::new();
+                }
+                merged(&Self::load_shards(shards), &start, &end)
+                    .filter(|(k, _)| filter(k))
+                    .take(count)
+                    .map(|(k, _)| k.clone())
+                    .collect()
+            }
+            Map::Concurrent(map) => {
+                if is_empty_range(&start, &end) {
+                    return Vec::new();
+                }
+                map.load()
+                    .range((start, end))
+                    .filter(|e| filter(e.key()))
+                    .take(count)
+                    .map(|e| e.key().clone())
+                    .collect()
+            }
+        }
+    }
+
+    /// Apply `f` to a copy of the map and publish it as the new snapshot.
+    /// Cloning only shares the tree nodes, and since there is one writer
+    /// no update can be lost.
+    fn update<T>(
+        map: &ArcSwap<OrdMap<String, V>>,
+        f: impl FnOnce(&mut OrdMap<String, V>) -> T,
+    ) -> T {
+        let mut mp = OrdMap::clone(&map.load());
+        let ret = f(&mut mp);
+        map.store(Arc::new(mp));
+        ret
+    }
+}
@@ -3,9 +3,18 @@
 //! You can store, query, and remove key value pair.
 //!
 
+pub use super::bucket::Bucket;
+use super::cache::ValueCache;
+pub use super::chunk::ValueReader;
+use super::chunk::{self, Chunker, Chunks};
+use super::condition::Condition;
+use super::flight::Flights;
+use super::growth::WriteHistory;
+pub use super::growth::{GrowthStats, WriteCounts};
 /// BitCask Config
 ///
-/// All log is in `log/` sub dir
+/// All log is in `log/` sub dir, possibly sharded into subdirectories by the
+/// `LogLayout` in the manifest
 ///
 /// Active log will be written into a `active.log`. Append only. Flush if exceed the threshold.
 /// After that, it will be renamed into `<version>.log`, and will be read-only
@@ -18,52 +27,150 @@
 ///
 /// We need to assign each old log a version, so that we can find it
 ///
-use super::KvsEngine;
+pub use super::keydir::IndexKind;
+use super::keydir::{self, KeyDir, Snapshot};
+pub use super::memory::MemoryBudget;
+use super::memory::MemoryShare;
+pub use super::merge::MergeOperator;
+use super::merge::{self, MAX_OPERANDS};
+pub use super::metrics::{Histogram, LatencyStats};
+use super::metrics::{Metrics, Operation};
+use super::pattern::Pattern;
+use super::syncer::Syncer;
+pub use super::syncer::{BATCH_BUCKETS, SyncStats};
+pub use super::txn::Txn;
+pub use super::watch::{ChangeEvent, WatchFilter, WriteKind};
+use super::watch::{Matcher, Watchers};
+use super::{KvsEngine, ScanIter, is_reserved, prefix_range};
 use crate::error::KvsError;
 use crate::error::Result;
-use log::trace;
+use crate::limits::Limits;
+use crate::manifest::{FORMAT_VERSION, LogLayout, Manifest};
+use log::{info, trace, warn};
+use lru::LruCache;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::BTreeMap;
+#[cfg(feature = "mmap")]
+use std::collections::hash_map::Entry;
+use std::fmt;
 use std::fs::{self, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom};
-use std::path::PathBuf;
-use std::sync::RwLock;
-use std::sync::atomic::AtomicU32;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::iter;
+use std::mem;
+use std::num::NonZeroUsize;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::Condvar;
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{HashMap, HashSet, VecDeque},
     env,
     fs::File,
     io::Write,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
 };
 
-/// The maximum size of sum of size of old logs
-/// Compact happens in init stage, i.e. offline compaction
-const THRESHOLD: usize = 40 * 1024; // 1GB
+/// Sealed logs with a smaller share of live bytes are compacted
+const COMPACTION_THRESHOLD: f64 = 0.5;
 const ACTIVE_THRESHOLD: usize = 1024; // 32KB
 
+/// High-water marks of write throttling
+/// Writes are delayed beyond the slowdown mark, and stalled at the stop mark
+const SLOWDOWN_SEGMENTS: usize = 64;
+const STOP_SEGMENTS: usize = 128;
+const SLOWDOWN_DEAD_BYTES: usize = 64 * 1024;
+const STOP_DEAD_BYTES: usize = 128 * 1024;
+const MAX_WRITE_DELAY: Duration = Duration::from_millis(1);
+
+/// Writes are refused once free disk space drops below the reserve
+const DISK_RESERVE: u64 = 16 * 1024 * 1024; // 16MB
+/// Free disk space is queried again once this many bytes were appended, or
+/// this long after the last query, and before every compaction
+const SPACE_CHECK_BYTES: u64 = 4 * 1024 * 1024; // 4MB
+const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest chunk of a value written by `KvStore::set_from_reader`
+const CHUNK_SIZE: usize = 1024 * 1024; // 1MB
+
+/// Engine name recorded in the manifest
+const ENGINE_NAME: &str = "kvs";
+
+/// A scan cursor pins its keys for this long after its last page
+const SCAN_CURSOR_TTL: Duration = Duration::from_secs(60);
+
+/// Keys taken from the index at a time by a range scan
+const RANGE_SCAN_BATCH: usize = 128;
+
+/// Holding the writer lock longer than this is logged as slow
+const SLOW_WRITER_HOLD: Duration = Duration::from_millis(100);
+
+/// Default least time between two syncs of a durable store
+pub const SYNC_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Number of idempotency tokens whose response is remembered
+const TOKEN_WINDOW: usize = 10_000;
+
+/// Least time between two log lines about the replay progress
+const RECOVERY_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Writes between two checks of the memory budget
+const MEMORY_CHECK_INTERVAL: u64 = 256;
+
+/// Index entries `KvStore::estimate_size` reads at most
+const SIZE_SAMPLES: usize = 1024;
+
+/// Removed keys whose time is remembered, so an older remote set of one of
+/// them is a conflict instead of bringing it back
+const REMOVAL_WINDOW: usize = 100_000;
+
+/// Remote changes which lost to a newer local write, one JSON object per line
+const CONFLICT_LOG: &str = "conflicts.jsonl";
+
+/// Starts the keys holding the previous versions of a key, see
+/// `KvStoreBuilder::keep_versions`, among the `RESERVED` ones
+const HISTORY_PREFIX: &str = "\u{10FFFF}\u{0}";
+/// Digits of the version at the end of such a key
+const HISTORY_DIGITS: usize = 20;
+
+/// First byte of a log of binary records, the format version bringing them
+/// Logs written before hold JSON lines, which start with `{`.
+const BINARY_LOG: u8 = 3;
+
+/// First byte of a log of binary records whose payloads are zstd frames
+const ZSTD_LOG: u8 = 4;
+
+/// Length, its complement and the CRC32 of the payload of a binary record
+const RECORD_HEADER: usize = 12;
+
 /// Rust thread spawn requires FnOnce(), therefore if we distribute each TCP connection
 /// to a corresponding thread, we need to clone a KvStore object. Some data should
 /// be shared, while others can be self-owned.
 
 /// dir - Since it is read only, just put it inside a Arc is enough
 /// writer - Write operation should be exclusive. Arc<Mutex>, enforcing one instance
-/// entry_to_index - Must ensure one instance, Arc<Mutex>
-///                     Read operation can be parallel.
-///                     Write should be exclusive. ==> Arc<RwLock> should be better
-///                  A step further. We can encapsulate the value inside a RwLock.
-///                     We only acquire the write lock of the hashmap when we need
-///                     to `compact`. We then acquire the R/W lock for value holding
-///                     the read lock of the hashmap.
+/// entry_to_index - Must ensure one instance. Readers never block, even during compaction.
+///                     By default they load an immutable snapshot through ArcSwap, the
+///                     only writer clones the persistent map (cheap, shares nodes),
+///                     modifies the copy and publishes it with a single pointer swap.
+///                     See `IndexKind` for the skiplist and sharded alternatives.
 /// ver_to_file - only used in `get` and `compact`. One key observation is that the map
 ///                 may not be synced. Each kvstore can have its own map. Better read
 ///                 perf. We can use a version atomic to periodically remove outdated entry. (lazy clean)
+///                 It is an LRU bounded by `read_cache`, so many logs never exhaust
+///                 the file descriptors.
 
 /// A smart design pattern. Separate reader from writer. Reader can be parallelized. Writers
 /// share the same instance.
 ///
-/// Set/Remove - First get the writer lock, then the entry_to_index lock
+/// Set/Remove - First get the writer lock, then publish a new entry_to_index snapshot
 
 /// A key value store
 #[derive(Clone)]
@@ -75,66 +182,538 @@ pub struct KvStore {
     // every kv store has its own reader
     kv_reader: KvStoreReader,
     // used in get
-    entry_to_index: Arc<RwLock<BTreeMap<String, RwLock<InMemIndex>>>>,
+    entry_to_index: Arc<Index>,
+    // keys pinned by open scan cursors
+    scans: Arc<Mutex<ScanCursors>>,
+    // logs kept for open snapshots
+    pins: Arc<Mutex<Pins>>,
+    // prefixes removed at once, whose keys are dropped lazily
+    purges: Arc<Purges>,
+    // a standby only applies the changefeed of a primary
+    standby: Arc<AtomicBool>,
+    // bytes exchanged with the clients of this store
+    traffic: Arc<Mutex<Traffic>>,
+    // who holds the writer lock, for diagnosing contention
+    leases: Arc<Mutex<Leases>>,
+    // responses of the latest requests carrying an idempotency token
+    tokens: Arc<Mutex<Tokens>>,
+    // long-running operations, which may be cancelled
+    operations: Arc<Mutex<Operations>>,
+    // gets of missing keys, see `StoreStats::index_misses`
+    misses: Arc<Misses>,
+    limits: Limits,
+    // folds the operands of `merge`, if any
+    merge: Option<MergeOperator>,
+    // latency histograms, only recorded with the `metrics` feature
+    metrics: Arc<Metrics>,
+}
+
+/// Gets of missing keys, by whether the index alone answered them
+///
+/// The index holds every key, so it is an exact existence filter: a key it
+/// lacks is missing without a read. A key it has may still be gone by the
+/// time its record is read, those reads are its false positives.
+#[derive(Default)]
+struct Misses {
+    index: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl Misses {
+    fn count(&self, read: bool) {
+        let counter = match read {
+            true => &self.false_positives,
+            false => &self.index,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// The last `TOKEN_WINDOW` tokens, oldest first, and their response
+/// `None` while the request is still running.
+#[derive(Default)]
+struct Tokens {
+    order: VecDeque<String>,
+    responses: HashMap<String, Option<Vec<u8>>>,
+}
+
+/// Ids of long-running operations, unique across the stores of the process
+static NEXT_OPERATION: AtomicU64 = AtomicU64::new(1);
+
+/// Running operations by id: what they do, since when, and whether they
+/// are asked to stop
+type Operations = BTreeMap<u64, (String, Instant, Arc<AtomicBool>)>;
+
+/// A long-running operation, e.g. a full scan, see `KvStore::start_operation`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OperationInfo {
+    pub id: u64,
+    /// What it does, e.g. `scan prefix "user:"`
+    pub op: String,
+    /// How long it runs so far
+    pub elapsed: Duration,
+    /// It is cancelled, and stops at its next step
+    pub cancelled: bool,
+}
+
+/// Registration of a running operation, dropped once it is over
+pub struct OperationGuard {
+    id: u64,
+    cancelled: Arc<AtomicBool>,
+    operations: Arc<Mutex<Operations>>,
+}
+
+impl OperationGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Fail with `KvsError::Cancelled` once `KvStore::cancel_operation` asked
+    /// the operation to stop; call it between two steps
+    pub fn check(&self) -> Result<()> {
+        match self.cancelled.load(Ordering::SeqCst) {
+            true => Err(KvsError::Cancelled(self.id)),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        self.operations.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// A hold of the writer lock by one operation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WriterLease {
+    /// The operation, e.g. `set`
+    pub op: String,
+    /// Name or id of the thread, the server runs a connection on one thread
+    pub thread: String,
+    /// How long the lock is held, so far if it still is
+    pub held: Duration,
+}
+
+/// Holders of the writer lock
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriterStats {
+    pub holder: Option<WriterLease>,
+    /// The longest hold since the store was opened
+    pub longest: Option<WriterLease>,
+}
+
+#[derive(Default)]
+struct Leases {
+    holder: Option<(&'static str, String, Instant)>,
+    longest: Option<WriterLease>,
+}
+
+/// The writer lock, recording its holder until it is released
+struct WriterGuard<'a> {
+    writer: MutexGuard<'a, KvStoreWriter>,
+    leases: &'a Mutex<Leases>,
+    op: &'static str,
+    since: Instant,
+}
+
+impl Deref for WriterGuard<'_> {
+    type Target = KvStoreWriter;
+
+    fn deref(&self) -> &KvStoreWriter {
+        &self.writer
+    }
+}
+
+impl DerefMut for WriterGuard<'_> {
+    fn deref_mut(&mut self) -> &mut KvStoreWriter {
+        &mut self.writer
+    }
+}
+
+impl Drop for WriterGuard<'_> {
+    fn drop(&mut self) {
+        let held = self.since.elapsed();
+        let mut leases = self.leases.lock().unwrap();
+        let thread = match leases.holder.take() {
+            Some((_, thread, _)) => thread,
+            None => thread_label(),
+        };
+        if held >= SLOW_WRITER_HOLD {
+            warn!(
+                "writer held for {}ms by op {} on {}",
+                held.as_millis(),
+                self.op,
+                thread
+            );
+        }
+        if leases.longest.as_ref().is_none_or(|l| held > l.held) {
+            leases.longest = Some(WriterLease {
+                op: self.op.to_owned(),
+                thread,
+                held,
+            });
+        }
+    }
+}
+
+fn thread_label() -> String {
+    let current = thread::current();
+    match current.name() {
+        Some(name) => name.to_owned(),
+        None => format!("{:?}", current.id()),
+    }
+}
+
+/// Write generations of the logs, shared by the writer and all readers
+///
+/// Only the active log is appended to, and its generation is bumped after
+/// every append. A reader whose buffer of a log predates the latest write to
+/// it drops the buffer, so a write is visible to every clone once `set`
+/// returns, while an unchanged log keeps its buffer between reads.
+#[derive(Default)]
+struct Generations {
+    active: AtomicUsize,
+    current: AtomicU64,
+    // `current` when the last active log was sealed, bounds every older log
+    sealed: AtomicU64,
+}
+
+impl Generations {
+    fn bump(&self) {
+        self.current.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Called before the first write to `active`
+    fn seal(&self, active: usize) {
+        self.sealed
+            .store(self.current.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.active.store(active, Ordering::SeqCst);
+    }
+
+    /// Generation of the latest write to log `version`, or a later one
+    fn latest(&self, version: usize) -> u64 {
+        if version == self.active.load(Ordering::SeqCst) {
+            self.current.load(Ordering::SeqCst)
+        } else {
+            self.sealed.load(Ordering::SeqCst)
+        }
+    }
 }
 
 pub struct KvStoreReader {
     dir: Arc<PathBuf>,
+    layout: LogLayout,
     min_version: Arc<AtomicU32>,
-    ver_to_file: RefCell<HashMap<usize, BufReader<File>>>,
+    ver_to_file: RefCell<LruCache<usize, BufReader<File>>>,
+    generations: Arc<Generations>,
+    // generation each buffered log was read at, and the position reached
+    seen: RefCell<HashMap<usize, (u64, usize)>>,
+    // record format of each log read so far
+    formats: RefCell<HashMap<usize, RecordFormat>>,
+    // number of corrupted values detected, shared by all readers
+    corruptions: Arc<AtomicU64>,
+    // merges concurrent reads of all readers
+    scheduler: Arc<ReadScheduler>,
+    // logs kept open in `ver_to_file`
+    max_open: usize,
+    // only one log is kept open while the budget is exceeded
+    memory: Option<MemoryShare>,
+    // logs open in `ver_to_file` as last counted in the usage
+    counted: Cell<usize>,
+    // values read lately, shared by all readers
+    cache: Option<Arc<ValueCache>>,
+    // reads of a record running at once, joined instead of repeated
+    flights: Option<Arc<Flights>>,
+    // sealed logs mapped so far
+    #[cfg(feature = "mmap")]
+    maps: RefCell<HashMap<usize, Mmap>>,
 }
 
 impl Clone for KvStoreReader {
     fn clone(&self) -> Self {
         Self {
             dir: Arc::clone(&self.dir),
+            layout: self.layout.clone(),
             min_version: Arc::clone(&self.min_version),
-            ver_to_file: RefCell::new(HashMap::new()),
+            ver_to_file: RefCell::new(LruCache::unbounded()),
+            generations: Arc::clone(&self.generations),
+            seen: RefCell::new(HashMap::new()),
+            formats: RefCell::new(HashMap::new()),
+            corruptions: Arc::clone(&self.corruptions),
+            scheduler: Arc::clone(&self.scheduler),
+            max_open: self.max_open,
+            memory: self.memory.clone(),
+            counted: Cell::new(0),
+            cache: self.cache.clone(),
+            flights: self.flights.clone(),
+            #[cfg(feature = "mmap")]
+            maps: RefCell::new(HashMap::new()),
         }
     }
 }
 
+impl Drop for KvStoreReader {
+    fn drop(&mut self) {
+        self.count_open(0);
+    }
+}
+
 impl KvStoreReader {
     /// KvStore Reader will be created after the writer
-    pub fn new(
+    fn new(
         dir: Arc<PathBuf>,
+        layout: LogLayout,
         min_version: Arc<AtomicU32>,
-        ver_to_file: HashMap<usize, BufReader<File>>,
+        generations: Arc<Generations>,
+        mut ver_to_file: HashMap<usize, BufReader<File>>,
+        max_open: usize,
+        memory: Option<MemoryShare>,
     ) -> Result<Self> {
-        Ok(Self {
+        let mut versions: Vec<usize> = ver_to_file.keys().copied().collect();
+        versions.sort_unstable();
+        // the newest logs are the most likely to be read first
+        let mut open = LruCache::unbounded();
+        for v in versions {
+            open.put(v, ver_to_file.remove(&v).unwrap());
+        }
+        while open.len() > max_open {
+            open.pop_lru();
+        }
+        let reader = Self {
             dir,
+            layout,
             min_version,
-            ver_to_file: RefCell::new(ver_to_file),
-        })
+            ver_to_file: RefCell::new(open),
+            generations,
+            seen: RefCell::new(HashMap::new()),
+            formats: RefCell::new(HashMap::new()),
+            corruptions: Arc::new(AtomicU64::new(0)),
+            scheduler: Arc::new(ReadScheduler::default()),
+            max_open,
+            memory,
+            counted: Cell::new(0),
+            cache: None,
+            flights: None,
+            #[cfg(feature = "mmap")]
+            maps: RefCell::new(HashMap::new()),
+        };
+        reader.count_open(reader.ver_to_file.borrow().len());
+        Ok(reader)
+    }
+
+    /// Record that `open` logs are open now in the usage of the store
+    fn count_open(&self, open: usize) {
+        if let Some(memory) = &self.memory {
+            let counted = self.counted.replace(open);
+            memory.usage.readers.fetch_add(open, Ordering::SeqCst);
+            memory.usage.readers.fetch_sub(counted, Ordering::SeqCst);
+        }
+    }
+
+    /// Read the value of `key` at `index`
+    ///
+    /// The record must be a `Set` of the same key, and match its checksums.
+    /// Otherwise `KvsError::Corruption` is returned rather than a wrong value.
+    /// With a value cache, a value read at the same position is not read again.
+    /// With coalescing, gets of the same record at once share one read.
+    pub fn get(&self, key: &str, index: InMemIndex) -> Result<String> {
+        self.clean()?;
+        let at = (index.version, index.start_pos);
+        if let Some(value) = self.cache.as_ref().and_then(|c| c.get(key, at)) {
+            return Ok(value);
+        }
+        match &self.flights {
+            Some(flights) => flights.read(key, at, || self.read(key, index)),
+            None => self.read(key, index),
+        }
+    }
+
+    /// `get` past the value cache
+    fn read(&self, key: &str, index: InMemIndex) -> Result<String> {
+        let at = (index.version, index.start_pos);
+        let value = match self.get_mapped(key, &index) {
+            Some(value) => value?,
+            None => {
+                let ans = self
+                    .scheduler
+                    .read(index.version, index.start_pos, |positions| {
+                        self.sweep(index.version, positions)
+                    })?;
+                self.decode(key, index.version, ans)?
+            }
+        };
+        if let Some(cache) = &self.cache {
+            cache.insert(key, at, &value);
+        }
+        Ok(value)
+    }
+
+    /// Decode the value of `key` straight from a mapping of its log
+    ///
+    /// `None` sends the read down the seek and read path: the active log
+    /// still grows, and a log mapped before the record was written is
+    /// mapped again on the next read.
+    #[cfg(feature = "mmap")]
+    fn get_mapped(&self, key: &str, index: &InMemIndex) -> Option<Result<String>> {
+        if index.version == self.generations.active.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut maps = self.maps.borrow_mut();
+        let map = match maps.entry(index.version) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => match self.map(index.version) {
+                Ok(map) => e.insert(map),
+                Err(err) => return Some(Err(err)),
+            },
+        };
+        let Some(record) = map.get(index.start_pos..index.start_pos + index.rec_len) else {
+            maps.remove(&index.version);
+            return None;
+        };
+        let format = RecordFormat::of(map.first().copied());
+        Some(self.decode(key, index.version, decode_record(record, format)))
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn get_mapped(&self, _key: &str, _index: &InMemIndex) -> Option<Result<String>> {
+        None
+    }
+
+    /// Map log `version` read-only
+    #[cfg(feature = "mmap")]
+    fn map(&self, version: usize) -> Result<Mmap> {
+        let file = File::open(self.layout.path(&self.dir, version))?;
+        // SAFETY: a sealed log is never written again, compaction deletes it
+        // instead, which leaves the mapping valid until it is dropped
+        Ok(unsafe { Mmap::map(&file)? })
     }
 
-    pub fn get(&self, index: InMemIndex) -> Result<String> {
+    /// Read the values of several keys, with one sweep per log
+    ///
+    /// The lookups are sorted by (version, offset), so the values living in
+    /// the same log are read in one forward pass instead of a seek each.
+    /// Results come back in the order of `lookups`.
+    fn get_many(&self, lookups: &[(&str, InMemIndex)]) -> Result<Vec<Result<String>>> {
         self.clean()?;
-        let flag = self.ver_to_file.borrow().contains_key(&index.version);
-        let mut ans = String::new();
+        let mut order: Vec<usize> = (0..lookups.len()).collect();
+        order.sort_unstable_by_key(|&i| (lookups[i].1.version, lookups[i].1.start_pos));
+        let mut results: Vec<Option<Result<String>>> = lookups.iter().map(|_| None).collect();
+        for group in order.chunk_by(|&a, &b| lookups[a].1.version == lookups[b].1.version) {
+            let version = lookups[group[0]].1.version;
+            let positions: Vec<usize> = group.iter().map(|&i| lookups[i].1.start_pos).collect();
+            for (&i, record) in group.iter().zip(self.sweep(version, &positions)) {
+                results[i] = Some(record.and_then(|r| self.decode(lookups[i].0, version, r)));
+            }
+        }
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
 
-        let mut reader = self.ver_to_file.borrow_mut();
+    /// Check that `record`, read from log `version`, is the value of `key`
+    /// `None` is a record which did not match its checksum.
+    fn decode(&self, key: &str, version: usize, record: Option<Op>) -> Result<String> {
+        match record {
+            Some(Op::Set {
+                key: k, value, crc, ..
+            }) if k == key && crc.is_none_or(|crc| crc == crc32fast::hash(value.as_bytes())) => {
+                Ok(value)
+            }
+            _ => {
+                self.corruptions.fetch_add(1, Ordering::SeqCst);
+                Err(KvsError::Corruption {
+                    key: key.to_owned(),
+                    segment: version,
+                })
+            }
+        }
+    }
 
-        if flag {
-            let reader = reader.get_mut(&index.version).unwrap();
-            reader.seek(SeekFrom::Start(index.start_pos as u64))?;
-            reader.read_line(&mut ans)?;
-        } else {
-            let mut cur_reader = self.load(index.version)?;
-            cur_reader.seek(SeekFrom::Start(index.start_pos as u64))?;
-            cur_reader.read_line(&mut ans)?;
-            reader.insert(index.version, cur_reader);
+    /// Read the records at `positions` of log `version` in one pass
+    ///
+    /// `positions` is sorted, so the reader only moves forward and can skip
+    /// within its buffer instead of issuing a new seek for every record.
+    /// A record which does not match its checksum is read as `None`.
+    fn sweep(&self, version: usize, positions: &[usize]) -> Vec<Result<Option<Op>>> {
+        // loaded before reading, so a write racing with us bumps it again
+        let latest = self.generations.latest(version);
+        let mut readers = self.ver_to_file.borrow_mut();
+        let max_open = match &self.memory {
+            Some(memory) if memory.budget.under_pressure() => 1,
+            _ => self.max_open.max(1),
+        };
+        // marks the log as read lately, it is not closed below
+        let loading = usize::from(!readers.promote(&version));
+        // the logs read least recently are closed first
+        while readers.len() + loading > max_open {
+            let (closed, _) = readers.pop_lru().unwrap();
+            self.seen.borrow_mut().remove(&closed);
         }
-        let op = serde_json::from_str(&ans)?;
-        match op {
-            Op::Rm { key: _ } => Err(KvsError::UnexpectedType),
-            Op::Set { key: _, value } => Ok(value),
+        if loading == 1 {
+            match self.load(version) {
+                Ok(r) => {
+                    readers.put(version, r);
+                }
+                Err(err) => {
+                    return positions
+                        .iter()
+                        .map(|_| Err(io::Error::other(err.to_string()).into()))
+                        .collect();
+                }
+            }
+        }
+        let reader = readers.get_mut(&version).unwrap();
+
+        // the first byte of a log tells its format, reading it moves the reader
+        let mut formats = self.formats.borrow_mut();
+        let mut seen = self.seen.borrow_mut();
+        let format = match formats.get(&version) {
+            Some(&format) => format,
+            None => match detect(reader) {
+                Ok(format) => {
+                    seen.remove(&version);
+                    formats.insert(version, format);
+                    format
+                }
+                Err(err) => {
+                    return positions
+                        .iter()
+                        .map(|_| Err(io::Error::other(err.to_string()).into()))
+                        .collect();
+                }
+            },
+        };
+        // the buffer is kept unless the log was written since it was filled,
+        // seeking to an absolute position drops it
+        let mut cur = match seen.remove(&version) {
+            Some((generation, pos)) if generation >= latest => Some(pos),
+            _ => None,
+        };
+        let results = positions
+            .iter()
+            .map(|&pos| {
+                match cur {
+                    Some(cur) => reader.seek_relative(pos as i64 - cur as i64)?,
+                    None => {
+                        reader.seek(SeekFrom::Start(pos as u64))?;
+                    }
+                }
+                let mut raw = Vec::new();
+                let n = read_raw(reader, format, &mut raw);
+                cur = n.as_ref().ok().map(|n| pos + n);
+                n?;
+                Ok(decode_record(&raw, format))
+            })
+            .collect();
+        if let Some(cur) = cur {
+            seen.insert(version, (latest, cur));
         }
+        self.count_open(readers.len());
+        results
     }
 
     /// load log/`id`.log into self.ver_to_file
     fn load(&self, id: usize) -> Result<BufReader<File>> {
-        let path = self.dir.join(format!("log/{}.log", id));
+        let path = self.layout.path(&self.dir, id);
         let file = OpenOptions::new().read(true).open(path)?;
         let reader = BufReader::new(file);
 
@@ -147,56 +726,314 @@ impl KvStoreReader {
 
         let version = self.min_version.load(Ordering::SeqCst) as usize;
 
-        for &k in mp.keys() {
+        for (&k, _) in mp.iter() {
             if k < version {
                 vc.push(k);
             }
         }
 
+        let mut seen = self.seen.borrow_mut();
         for k in vc {
-            mp.remove(&k);
+            mp.pop(&k);
+            seen.remove(&k);
         }
+        self.formats.borrow_mut().retain(|&k, _| k >= version);
+        self.count_open(mp.len());
+        #[cfg(feature = "mmap")]
+        self.maps.borrow_mut().retain(|&k, _| k >= version);
 
         Ok(())
     }
 }
 
+/// Merges concurrent reads of the same log file
+///
+/// The first reader of a log file becomes the leader. It takes every read
+/// queued for that file meanwhile, and serves them with one sweep in offset
+/// order. Then it hands the lead to a read queued during the sweep, if any,
+/// and returns, so no get sweeps for others forever. The others just wait for
+/// a leader to hand over their records.
+#[derive(Default)]
+struct ReadScheduler {
+    queues: Mutex<HashMap<usize, SegmentQueue>>,
+    // reads served by the sweep of another read
+    merged: AtomicU64,
+}
+
+#[derive(Default)]
+struct SegmentQueue {
+    // a leader is sweeping this log file
+    busy: bool,
+    pending: Vec<(usize, Arc<ReadSlot>)>,
+}
+
+/// What a waiting read is handed
+enum Turn {
+    Record(Result<Option<Op>>),
+    // sweep the queue, the previous leader is done
+    Lead,
+}
+
+#[derive(Default)]
+struct ReadSlot {
+    turn: Mutex<Option<Turn>>,
+    ready: Condvar,
+}
+
+/// The sweep of a leader, which passes the lead on even on a panic
+struct Sweep<'a> {
+    scheduler: &'a ReadScheduler,
+    version: usize,
+    // the reads not served yet
+    batch: Vec<(usize, Arc<ReadSlot>)>,
+}
+
+impl ReadSlot {
+    fn hand(&self, turn: Turn) {
+        *self.turn.lock().unwrap_or_else(PoisonError::into_inner) = Some(turn);
+        self.ready.notify_one();
+    }
+
+    fn wait(&self) -> Turn {
+        let mut turn = self.turn.lock().unwrap();
+        loop {
+            match turn.take() {
+                Some(t) => return t,
+                None => turn = self.ready.wait(turn).unwrap(),
+            }
+        }
+    }
+}
+
+impl ReadScheduler {
+    /// Read the record at `pos` of log `version`
+    /// `sweep` reads a batch of sorted positions, it is only called by the leader
+    fn read(
+        &self,
+        version: usize,
+        pos: usize,
+        sweep: impl Fn(&[usize]) -> Vec<Result<Option<Op>>>,
+    ) -> Result<Option<Op>> {
+        let slot = Arc::new(ReadSlot::default());
+        {
+            let mut queues = self.queues.lock().unwrap();
+            let queue = queues.entry(version).or_default();
+            queue.pending.push((pos, Arc::clone(&slot)));
+            if queue.busy {
+                drop(queues);
+                match slot.wait() {
+                    Turn::Record(record) => {
+                        self.merged.fetch_add(1, Ordering::Relaxed);
+                        return record;
+                    }
+                    Turn::Lead => {}
+                }
+            } else {
+                queue.busy = true;
+            }
+        }
+
+        // our own read is in the batch
+        let pending = {
+            let mut queues = self.queues.lock().unwrap();
+            mem::take(&mut queues.get_mut(&version).unwrap().pending)
+        };
+        let mut leader = Sweep {
+            scheduler: self,
+            version,
+            batch: pending,
+        };
+        leader.batch.sort_unstable_by_key(|(pos, _)| *pos);
+        let positions: Vec<usize> = leader.batch.iter().map(|(pos, _)| *pos).collect();
+        let records = sweep(&positions);
+        for ((_, waiting), record) in leader.batch.drain(..).zip(records) {
+            waiting.hand(Turn::Record(record));
+        }
+        drop(leader);
+        match slot.wait() {
+            Turn::Record(record) => record,
+            Turn::Lead => unreachable!("a leader is handed its record"),
+        }
+    }
+
+    /// Reads served by the sweep of another read so far
+    fn merged(&self) -> u64 {
+        self.merged.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Sweep<'_> {
+    fn drop(&mut self) {
+        // the reads left are those of a sweep which panicked
+        for (_, waiting) in self.batch.drain(..) {
+            let err = KvsError::StringError("the merged read of the log failed".to_owned());
+            waiting.hand(Turn::Record(Err(err)));
+        }
+        let mut queues = self
+            .scheduler
+            .queues
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let queue = queues.get_mut(&self.version).unwrap();
+        match queue.pending.first() {
+            Some((_, next)) => next.hand(Turn::Lead),
+            None => {
+                queues.remove(&self.version);
+            }
+        }
+    }
+}
+
 pub struct KvStoreWriter {
     min_version: Arc<AtomicU32>,
-    entry_to_index: Arc<RwLock<BTreeMap<String, RwLock<InMemIndex>>>>,
+    generations: Arc<Generations>,
+    entry_to_index: Arc<Index>,
     current_ver: usize,
     current_len: usize,
-    old_log_len: usize,
+    // size and garbage of every log
+    garbage: Garbage,
+    // sealed logs less live than this are compacted
+    compaction_threshold: f64,
+    // of the logs compaction writes, the active log is never compressed
+    compression: Compression,
+    // previous values each key keeps, 0 keeps none
+    keep_versions: usize,
+    // the active log is sealed once this long
+    active_log_size: usize,
+    write_status: WriteStatus,
+    disk_reserve: u64,
+    // read-only degraded mode, set when free space drops below the reserve
+    disk_full: bool,
+    // free disk space when last queried, `None` before the first query
+    free_space: Option<(Instant, u64)>,
+    // bytes appended since free disk space was queried
+    unchecked_bytes: u64,
+    // bytes all logs may take, sets over it fail
+    disk_quota: Option<u64>,
+    // compact every log before failing a set over the quota
+    compact_over_quota: bool,
+    // values read from a stream are cut into chunks this long
+    chunk_size: usize,
+    // id of the next chunked write or merge operand, unique even across
+    // restarts
+    next_id: u64,
+    // keys with merge operands not folded yet, and how many
+    merging: HashMap<String, usize>,
+    // bytes/sec budget of compaction I/O, `None` means unlimited
+    compaction_rate: Option<u64>,
+    // total time compaction has slept to respect the budget
+    compaction_throttled: Duration,
+    // when the last compaction was swapped in, not persisted
+    last_compaction: Option<SystemTime>,
+    // store a checksum with every value written from now on
+    value_checksum: bool,
+    limits: Limits,
+    // hints of the records in the active log, written out when it is sealed
+    hints: Vec<Hint>,
+    // the compaction running in the background, if any
+    compaction: Option<Compaction>,
+    // receivers of engine events, dropped once they hang up
+    subscribers: Vec<Sender<EngineEvent>>,
+    // receivers of every applied change, used for replication
+    feed: Vec<Sender<Change>>,
+    // receivers of every applied change with its time and region
+    region_feed: Vec<Sender<RegionChange>>,
+    // receivers of the durable changes under a prefix, started by the first
+    watchers: Option<Watchers>,
+    // stamped on the changes of local writes
+    region: String,
+    // region and time of the remote change being applied
+    remote: Option<(String, u64)>,
+    // time of the latest removals, by key
+    removed: LruCache<String, u64>,
+    // the previous run did not close the store, the logs were verified on open
+    unclean_shutdown: bool,
+    // corrupted records dropped by that verification
+    skipped_records: usize,
+    manifest: Manifest,
+    // number of records appended so far
+    written: u64,
+    // counts per minute and hour, saved across restarts
+    growth: WriteHistory,
+    // makes appended records durable, `None` leaves it to the OS
+    syncer: Option<Arc<Syncer>>,
+    // share of a memory budget, if the store has one
+    memory: Option<MemoryShare>,
+    // the budget was exceeded at the last check
+    memory_pressure: bool,
+    // open snapshots, and the compacted logs they still read
+    pins: Arc<Mutex<Pins>>,
+    purges: Arc<Purges>,
+    metrics: Arc<Metrics>,
     dir: Arc<PathBuf>,
     writer: BufWriter<File>,
 }
 
+/// Open logs by version, their versions in order, and their total length
+type OpenLogs = (HashMap<usize, BufReader<File>>, Vec<usize>, u64);
+
 impl KvStoreWriter {
-    fn traverse_dir(dir: &PathBuf) -> Result<(HashMap<usize, BufReader<File>>, Vec<usize>, u64)> {
+    /// Open every log of the store in `dir`
+    /// Files which are not logs, e.g. editor backups, are skipped.
+    fn traverse_dir(dir: &Path, layout: &LogLayout) -> Result<OpenLogs> {
+        let mut ver_to_file = HashMap::new();
+        let mut version_list = Vec::new();
+        let mut total_len = 0;
+        for log_dir in layout.dirs(dir) {
+            for file in fs::read_dir(&log_dir)? {
+                let file = file?;
+                trace!("Read a file {:?}", file.file_name());
+                let version = file
+                    .file_name()
+                    .to_str()
+                    .and_then(LogLayout::parse)
+                    .filter(|&v| layout.path(dir, v) == file.path());
+                let cur_ver = match version {
+                    Some(v) if file.file_type()?.is_file() => v,
+                    _ if is_hint(&file.path()) => continue,
+                    _ if file.path().to_string_lossy().ends_with(".log.tmp") => {
+                        warn!("Remove unfinished compaction output {:?}", file.path());
+                        fs::remove_file(file.path())?;
+                        continue;
+                    }
+                    _ => {
+                        warn!("Skip foreign file {:?} among the logs", file.path());
+                        continue;
+                    }
+                };
+                let open_file = OpenOptions::new().read(true).open(file.path())?;
+                total_len += open_file.metadata()?.len();
+                trace!("current file has version {}", cur_ver);
+                version_list.push(cur_ver);
+                ver_to_file.insert(cur_ver, BufReader::new(open_file));
+            }
+        }
+        version_list.sort_unstable();
+        Ok((ver_to_file, version_list, total_len))
+    }
+
+    /// Open the logs named by the manifest, without listing the directory
+    /// The active log may be missing, the process died before creating it.
+    fn open_listed(dir: &Path, layout: &LogLayout, manifest: &Manifest) -> Result<OpenLogs> {
         let mut ver_to_file = HashMap::new();
         let mut version_list = Vec::new();
         let mut total_len = 0;
-        for file in fs::read_dir(&dir)? {
-            let file = file?;
-            trace!("Read a file {:?}", file.file_name());
-            let open_file = OpenOptions::new().read(true).open(file.path())?;
-            total_len += open_file.metadata().unwrap().len();
-            let path = file.path();
-            assert!(path.is_file());
-            let name = path
-                .file_name()
-                .expect("The name is invalid")
-                .to_str()
-                .unwrap();
-            let cur_ver: usize = name
-                .split(".")
-                .take(1)
-                .next()
-                .expect("Fail to get the version part of an old log file")
-                .parse()?;
-            trace!("current file has version {}", cur_ver);
-            version_list.push(cur_ver);
-            ver_to_file.insert(cur_ver, BufReader::new(open_file));
+        let active = manifest.active.filter(|v| !manifest.sealed.contains(v));
+        for v in manifest.sealed.iter().copied().chain(active) {
+            let open_file = match OpenOptions::new().read(true).open(layout.path(dir, v)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && Some(v) == active => continue,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(KvsError::StringError(format!(
+                        "log {} named by the manifest is missing",
+                        v
+                    )));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            total_len += open_file.metadata()?.len();
+            version_list.push(v);
+            ver_to_file.insert(v, BufReader::new(open_file));
         }
         version_list.sort_unstable();
         Ok((ver_to_file, version_list, total_len))
@@ -205,364 +1042,5092 @@ impl KvStoreWriter {
     pub fn new(
         path: impl Into<PathBuf>,
         ver_to_file: &mut HashMap<usize, BufReader<File>>,
+        index_kind: IndexKind,
+        layout: LogLayout,
+        verify: Verify,
+        on_recovery: Option<&RecoveryCallback>,
     ) -> Result<Self> {
         let path: PathBuf = path.into();
-        let log_subdir = path.join("log");
-
-        if !log_subdir.exists() {
-            trace!("Create a directory {:?}", log_subdir);
-            fs::create_dir(&log_subdir)?;
+        let mut manifest = match Manifest::load(&path)? {
+            Some(manifest) => {
+                manifest.check(ENGINE_NAME)?;
+                manifest
+            }
+            None => Manifest::new(ENGINE_NAME),
+        };
+        // the layout is picked when the first log is created, and kept after
+        if !manifest.layout.root(&path).exists() {
+            manifest.layout = layout;
+        }
+        let layout = manifest.layout.clone();
+        for log_dir in layout.dirs(&path) {
+            fs::create_dir_all(log_dir)?;
+        }
+        // a compaction swapped in its output, then the process died before
+        // all the logs it replaced were deleted
+        for v in mem::take(&mut manifest.obsolete) {
+            warn!("Remove log {} left over by a compaction", v);
+            remove_log(&path, &layout, v)?;
+        }
+        // the process died while a compaction ran, its output is no log of
+        // the store unless it was swapped in
+        if let Some(v) = manifest.compacting.take()
+            && !manifest.sealed.contains(&v)
+        {
+            warn!("Remove log {} left over by an unfinished compaction", v);
+            remove_log(&path, &layout, v)?;
+            match fs::remove_file(layout.path(&path, v).with_extension("log.tmp")) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        // a crash may leave torn values behind, check every checksum
+        let unclean_shutdown = !manifest.clean_shutdown;
+        if unclean_shutdown {
+            warn!(
+                "Store in {:?} was not closed cleanly, verify the logs",
+                path
+            );
         }
+        let verify_logs = unclean_shutdown || verify != Verify::AfterCrash;
+        let mut skipped_records = 0;
 
         let mut max_old_version = 0;
 
-        let (mut v_to_f, version_list, total_len) = Self::traverse_dir(&log_subdir)?;
+        // a store written before the manifest named its logs lists them
+        let (mut v_to_f, version_list, total_len) = match manifest.active {
+            Some(_) => Self::open_listed(&path, &layout, &manifest)?,
+            None => Self::traverse_dir(&path, &layout)?,
+        };
 
         if !version_list.is_empty() {
             max_old_version = *version_list.last().unwrap();
         }
 
-        let mut entry_to_index: BTreeMap<String, RwLock<InMemIndex>> = BTreeMap::new();
-
-        for v in version_list.iter() {
-            let reader = BufReader::new(v_to_f.get(v).unwrap().get_ref().try_clone()?);
-            let mut offset = 0_usize;
+        let entry_to_index = Index::new(index_kind);
+        let mut garbage = Garbage::default();
+        let now = now_millis();
+        let started = Instant::now();
+        let mut last_logged = started;
+        let mut replayed = 0;
 
-            for line in reader.lines() {
-                match line {
-                    Ok(s) => {
-                        let op: Op = serde_json::from_str(&s)?;
+        for (done, v) in version_list.iter().enumerate() {
+            let file = v_to_f.get(v).unwrap().get_ref();
+            let log_len = file.metadata()?.len();
+            replayed += log_len;
+            // a sealed log never changes, unless it is damaged
+            let resized = manifest.sizes.get(v).is_some_and(|&size| size != log_len);
+            if resized {
+                warn!("Log {} is not of the length it was sealed at, verify it", v);
+            }
+            let verify_logs = verify_logs || resized;
+            // after a crash the hints may be stale, every log is verified
+            let hints = match verify_logs {
+                false => read_hints(&layout.hint_path(&path, *v), log_len),
+                true => None,
+            };
+            let hints = match hints {
+                Some(hints) => hints,
+                None => {
+                    // a check fails on the first corrupted record
+                    let skipped =
+                        (verify_logs && verify != Verify::Check).then_some(&mut skipped_records);
+                    let LogRecords {
+                        records,
+                        dropped,
+                        torn_at,
+                    } = read_records(file, *v, skipped)?;
+                    garbage.kill(*v, dropped);
+                    // the process died in the middle of an append, cut it off so
+                    // the log ends with a whole record again
+                    if let Some(len) = torn_at {
+                        warn!("Truncate log {} to {} bytes, after a torn write", v, len);
+                        let log = OpenOptions::new()
+                            .write(true)
+                            .open(layout.path(&path, *v))?;
+                        log.set_len(len as u64)?;
+                        log.sync_data()?;
+                    }
+                    let mut hints = Vec::with_capacity(records.len());
+                    for (op, offset, rec_len) in records {
                         match op {
-                            Op::Set { key, value: _ } => {
-                                entry_to_index
-                                    .entry(key)
-                                    .and_modify(|cur| {
-                                        let cur = cur.get_mut().expect(
-                                            "Fail to get the RwLock instance in entry to index",
-                                        );
-                                        cur.version = *v;
-                                        cur.start_pos = offset;
-                                    })
-                                    .or_insert(RwLock::new(InMemIndex {
-                                        version: *v,
-                                        start_pos: offset,
-                                    }));
-                            }
-                            Op::Rm { key } => {
-                                entry_to_index
-                                    .remove(&key)
-                                    .expect("remove an invalid key from a map");
+                            Op::Set {
+                                key,
+                                value,
+                                crc: Some(crc),
+                                ..
+                            } if verify_logs && crc != crc32fast::hash(value.as_bytes()) => {
+                                if verify == Verify::Check {
+                                    return Err(KvsError::Corruption { key, segment: *v });
+                                }
+                                warn!("Skip corrupted value of key {} in log {}", key, v);
+                                skipped_records += 1;
+                                hints.push(Hint::Dead { rec_len });
                             }
+                            op => hints.push(Hint::of(op, offset, rec_len)),
                         }
-                        offset += s.len() + 1;
-                    }
-                    Err(e) => {
-                        return Err(e.into());
                     }
+                    hints
                 }
+            };
+
+            // the format byte holds no record, it is neither live nor dead
+            let header = detect(&mut BufReader::new(file))?.header_len();
+            garbage.grow(*v, file.metadata()?.len() as usize - header);
+            replay(&entry_to_index, &mut garbage, *v, hints, now);
+
+            let elapsed = started.elapsed();
+            let progress = RecoveryProgress {
+                segments_done: done + 1,
+                segments_total: version_list.len(),
+                keys_indexed: entry_to_index.len(),
+                elapsed,
+                // the logs left take as long per byte as the ones replayed
+                eta: (replayed > 0).then(|| {
+                    elapsed.mul_f64(total_len.saturating_sub(replayed) as f64 / replayed as f64)
+                }),
+            };
+            if progress.segments_done == progress.segments_total
+                || last_logged.elapsed() >= RECOVERY_LOG_INTERVAL
+            {
+                last_logged = Instant::now();
+                info!(
+                    "Replayed {}/{} logs of {:?}, {} keys, {:?} left",
+                    progress.segments_done,
+                    progress.segments_total,
+                    path,
+                    progress.keys_indexed,
+                    progress.eta.unwrap_or_default()
+                );
+            }
+            if let Some(f) = on_recovery {
+                f(&progress);
             }
         }
 
         max_old_version += 1;
 
+        // cleared until the store is dropped, so a crash is noticed next time
+        manifest.format_version = FORMAT_VERSION;
+        manifest.clean_shutdown = false;
+        manifest.sizes = version_list
+            .iter()
+            .map(|v| Ok((*v, v_to_f[v].get_ref().metadata()?.len())))
+            .collect::<Result<_>>()?;
+        manifest.sealed = version_list;
+        // named before it is created, so the next open finds it
+        manifest.active = Some(max_old_version);
+        manifest.store(&path)?;
+
         let cur_file = OpenOptions::new()
             .create(true)
             .append(true)
             .read(true)
-            .open(log_subdir.join(format!("{}.log", max_old_version)))?;
+            .open(layout.path(&path, max_old_version))?;
         trace!("Create a new active log");
         let reader = BufReader::new(cur_file.try_clone()?);
-        let writer = BufWriter::new(cur_file);
+        let mut writer = BufWriter::new(cur_file);
+        let current_len = start_log(&mut writer, Compression::None)?;
         v_to_f.insert(max_old_version, reader);
+        let generations = Arc::new(Generations::default());
+        generations.seal(max_old_version);
+        garbage.grow(max_old_version, 0);
 
         *ver_to_file = v_to_f;
 
         Ok(Self {
             min_version: Arc::new(AtomicU32::new(0)),
-            entry_to_index: Arc::new(RwLock::new(entry_to_index)),
+            generations,
+            entry_to_index: Arc::new(entry_to_index),
             current_ver: max_old_version,
-            current_len: 0,
-            old_log_len: total_len as usize,
+            current_len,
+            garbage,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compression: Compression::None,
+            keep_versions: 0,
+            active_log_size: ACTIVE_THRESHOLD,
+            write_status: WriteStatus::Normal,
+            disk_reserve: DISK_RESERVE,
+            disk_full: false,
+            free_space: None,
+            unchecked_bytes: 0,
+            disk_quota: None,
+            compact_over_quota: false,
+            chunk_size: CHUNK_SIZE,
+            next_id: now_millis() * 1000,
+            merging: HashMap::new(),
+            compaction_rate: None,
+            compaction_throttled: Duration::ZERO,
+            last_compaction: None,
+            value_checksum: false,
+            limits: Limits::default(),
+            hints: Vec::new(),
+            compaction: None,
+            subscribers: Vec::new(),
+            feed: Vec::new(),
+            region_feed: Vec::new(),
+            watchers: None,
+            region: String::new(),
+            remote: None,
+            removed: LruCache::new(NonZeroUsize::new(REMOVAL_WINDOW).unwrap()),
+            unclean_shutdown,
+            skipped_records,
+            growth: WriteHistory::load(&path),
+            manifest,
+            written: 0,
+            syncer: None,
+            memory: None,
+            memory_pressure: false,
+            pins: Arc::new(Mutex::new(Pins::default())),
+            purges: Arc::new(Purges::default()),
+            metrics: Arc::new(Metrics::default()),
             dir: Arc::new(path),
             writer,
         })
     }
 
+    /// Append one record to the active log
+    /// Return the start position and the length of the record
+    fn append(&mut self, op: &Op) -> Result<(usize, usize)> {
+        let serial = encode(op, Compression::None)?;
+        let pos = self.append_raw(&serial)?;
+        self.record_growth(WriteCounts {
+            writes: 1,
+            bytes_written: serial.len() as u64,
+            ..WriteCounts::default()
+        });
+        Ok((pos, serial.len()))
+    }
+
+    /// Append serialized records with one write, return where they start
+    fn append_raw(&mut self, serial: &[u8]) -> Result<usize> {
+        self.check_space(serial.len() as u64, false)?;
+        let pos = self.writer.seek(SeekFrom::End(0))? as usize;
+        self.writer.write_all(serial)?;
+        self.writer.flush()?;
+        self.unchecked_bytes += serial.len() as u64;
+        self.generations.bump();
+        self.current_len += serial.len();
+        self.garbage.grow(self.current_ver, serial.len());
+        self.written += 1;
+        Ok(pos)
+    }
+
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        if self.merging.contains_key(&key) {
+            // drops the operands along
+            let mut batch = WriteBatch::new();
+            batch.set(key, value);
+            return self.apply_batch(batch);
+        }
+        self.set_expiring(key, value, None)
+    }
+
+    /// Set `key` until `expires`, in milliseconds since the unix epoch
+    /// The expiry is part of the record, so it survives a restart.
+    pub fn set_expiring(&mut self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        self.set_enveloped(key, value, expires, Envelope::default())
+    }
+
+    /// Set `key` along with the envelope of its value
+    /// The checksum of the envelope is replaced by the one of the store.
+    pub fn set_enveloped(
+        &mut self,
+        key: String,
+        value: String,
+        expires: Option<u64>,
+        mut envelope: Envelope,
+    ) -> Result<()> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&value)?;
+        self.throttle()?;
+        self.check_quota((key.len() + value.len()) as u64)?;
+        let len = value.len();
+        let ts = self.stamp();
+        envelope.checksum = self
+            .value_checksum
+            .then(|| crc32fast::hash(value.as_bytes()));
         let op: Op = Op::Set {
             key: key.clone(),
             value,
+            ts,
+            crc: envelope.checksum,
+            expires,
+            content_type: envelope.content_type.clone(),
+            compressed: envelope.compressed,
         };
-        let mut serial = serde_json::to_string(&op)?;
-        serial.push('\n');
-        self.current_len += serial.len();
-        let pos = self.writer.seek(SeekFrom::End(0))? as usize;
-        self.writer.write_all(serial.as_bytes())?;
-        self.writer.flush()?;
-        {
-            let mut mp = self
-                .entry_to_index
-                .write()
-                .expect("Fail to fetch the read lock");
-            let version = self.current_ver;
-
-            mp.entry(key)
-                .and_modify(|lock| {
-                    let mut v = lock.write().expect("Fail to get the exclusive key in set");
-                    *v = InMemIndex {
-                        version,
-                        start_pos: pos,
-                    };
-                })
-                .or_insert(RwLock::new(InMemIndex {
-                    version,
-                    start_pos: pos,
-                }));
+        let (pos, rec_len) = self.append(&op)?;
+        self.hints.push(Hint::Set {
+            key: key.clone(),
+            offset: pos,
+            len,
+            rec_len,
+            ts,
+            expires,
+            envelope: envelope.clone(),
+        });
+        let index = InMemIndex {
+            version: self.current_ver,
+            start_pos: pos,
+            len,
+            rec_len,
+            ts,
+            expires,
+            envelope,
+        };
+        let chunked = index.envelope.is_chunked();
+        let mut replaced_chunks = false;
+        if let Some(old) = self.entry_to_index.insert(key, index) {
+            self.garbage.kill(old.version, old.rec_len);
+            replaced_chunks = old.envelope.is_chunked() && !chunked;
+        }
+        if expires.is_some() {
+            // once expired it hides the older values of its key
+            self.garbage.tombstone(self.current_ver, 0);
+        }
+        self.publish_change(&op);
+        if replaced_chunks && let Op::Set { key, .. } = &op {
+            self.drop_chunks(key, None)?;
         }
 
         self.to_flush()
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        {
-            let reader = self.entry_to_index.read().unwrap();
-            if !reader.contains_key(&key) {
-                return Err(KvsError::KeyNotFound);
-            }
+        if self.merging.contains_key(&key) {
+            // drops the operands along
+            let mut batch = WriteBatch::new();
+            batch.remove(key);
+            return self.apply_batch(batch);
         }
-        {
-            let mut writer = self.entry_to_index.write().unwrap();
-            writer.remove(&key);
+        if !self.live(&key) {
+            return Err(KvsError::KeyNotFound);
         }
+        self.throttle()?;
 
-        let cur_op = Op::Rm { key };
-        let mut serial = serde_json::to_string(&cur_op)?;
-        serial.push('\n');
-        self.current_len += serial.len();
-        self.writer.write_all(serial.as_bytes())?;
-        self.writer.flush()?;
+        // log first, so a failed append leaves the index untouched
+        let cur_op = Op::Rm { key: key.clone() };
+        let (_, rec_len) = self.append(&cur_op)?;
+        self.garbage.tombstone(self.current_ver, rec_len);
+        self.hints.push(Hint::Rm {
+            key: key.clone(),
+            rec_len,
+        });
+        let old = self.entry_to_index.remove(&key).unwrap();
+        self.garbage.kill(old.version, old.rec_len);
+        self.publish_change(&cur_op);
+        if old.envelope.is_chunked() {
+            self.drop_chunks(&key, None)?;
+        }
+        self.removed.put(key, self.stamp());
 
         self.to_flush()
     }
 
-    /// Wrapper on whether to flush the active log or not
-    fn to_flush(&mut self) -> Result<()> {
-        if self.current_len >= ACTIVE_THRESHOLD {
-            trace!("current active log length is {}", self.current_len);
-            self.flush()
-        } else {
-            Ok(())
+    /// Remove the chunks of `key`, but those of the write `keep`
+    fn drop_chunks(&mut self, key: &str, keep: Option<u64>) -> Result<()> {
+        for (start, end) in chunk::chunk_ranges(key, keep) {
+            self.remove_range(start, end)?;
         }
+        Ok(())
     }
 
-    /// Flush a full active log into disk
-    /// Rename it, and open a new active log
-    fn flush(&mut self) -> Result<()> {
-        self.writer.flush()?;
-        self.old_log_len += self.current_len;
-        self.current_len = 0;
-        if self.old_log_len >= THRESHOLD {
-            self.compact()?;
-        }
+    /// Id of a new chunked write or merge operand
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
 
+    /// Append `operand` to the operands of `key`, return how many it has
+    pub fn merge(&mut self, key: String, operand: String) -> Result<usize> {
+        self.limits.check_key(&key)?;
+        let seq = self.next_id();
+        self.set(merge::operand_key(&key, seq), operand)?;
+        let pending = self.merging.entry(key).or_default();
+        *pending += 1;
+        Ok(*pending)
+    }
+
+    /// Point `key` to the chunks of the write `chunks.id`, and remove the
+    /// chunks of its other writes
+    /// Fails with `KvsError::Conflict` if another write of the key finished
+    /// meanwhile, which removed some of these chunks.
+    fn finish_chunked(&mut self, key: String, chunks: Chunks) -> Result<()> {
+        for n in 0..chunks.count {
+            if !self.live(&chunk::chunk_key(&key, chunks.id, n)) {
+                return Err(KvsError::Conflict(key));
+            }
+        }
+        let envelope = Envelope {
+            content_type: Some(chunk::CHUNKED.to_owned()),
+            ..Envelope::default()
+        };
+        let pointer = serde_json::to_string(&chunks)?;
+        self.set_enveloped(key.clone(), pointer, None, envelope)?;
+        self.drop_chunks(&key, Some(chunks.id))
+    }
+
+    /// Remove all keys between the bounds with one range tombstone
+    ///
+    /// With the default ordered index the keys are dropped in one new
+    /// snapshot, so readers see either all of them or none of them. The
+    /// tombstone covers every key, so no record is written per key.
+    pub fn remove_range(&mut self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        let merged: Vec<String> = self
+            .merging
+            .keys()
+            .filter(|key| (start.as_ref(), end.as_ref()).contains(*key))
+            .cloned()
+            .collect();
+        let removed = self.remove_keys(start, end)?;
+        // the operands of the merged keys go after them
+        for key in merged {
+            self.merging.remove(&key);
+            let (start, end) = merge::operand_range(&key);
+            self.remove_keys(start, end)?;
+        }
+        Ok(removed)
+    }
+
+    fn remove_keys(&mut self, start: Bound<String>, end: Bound<String>) -> Result<usize> {
+        self.throttle()?;
+        let keys = self.entry_to_index.removed_by(start.clone(), end.clone());
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        let cur_op = Op::RmRange {
+            start: start.clone(),
+            end: end.clone(),
+        };
+        let (_, rec_len) = self.append(&cur_op)?;
+        self.garbage.tombstone(self.current_ver, rec_len);
+        self.hints.push(Hint::RmRange {
+            start,
+            end,
+            rec_len,
+        });
+
+        // expired and purged keys are dropped as well, but not counted
+        let removed = keys.iter().filter(|key| self.live(key)).count();
+        for old in self.entry_to_index.remove_all(&keys) {
+            self.garbage.kill(old.version, old.rec_len);
+        }
+        let ts = self.stamp();
+        for key in keys {
+            self.removed.put(key, ts);
+        }
+        self.publish_change(&cur_op);
+
+        self.to_flush()?;
+        Ok(removed)
+    }
+
+    /// Append every write of `batch` after a header, then apply them to the
+    /// index in one step
+    ///
+    /// The records go out in one write. If a crash cuts them short, the
+    /// header tells the next open to drop the whole batch.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.limits.check_batch(batch.len())?;
+        let mut need = 0;
+        for change in batch.changes.iter() {
+            if let (Some(key), Some(value)) = (change.key(), change.value()) {
+                self.limits.check_key(key)?;
+                self.limits.check_value(value)?;
+                need += key.len() + value.len();
+            }
+        }
+        self.throttle()?;
+        if need > 0 {
+            self.check_quota(need as u64)?;
+        }
+        let ts = self.stamp();
+        // presence of the keys the batch already wrote
+        let mut present: HashMap<String, bool> = HashMap::new();
+        let mut ops = Vec::with_capacity(batch.changes.len());
+        // keys written whole, whose operands are dropped in the batch
+        let mut merged = Vec::new();
+        for change in batch.changes {
+            if let Some(key) = change.key()
+                && self.merging.contains_key(key)
+                && !merged.iter().any(|k| k == key)
+            {
+                merged.push(key.to_owned());
+            }
+            let (key, value, expires, envelope) = match change {
+                Change::Set { key, value } => (key, value, None, Envelope::default()),
+                Change::SetExpiring {
+                    key,
+                    value,
+                    expires,
+                } => (key, value, Some(expires), Envelope::default()),
+                Change::SetEnveloped {
+                    key,
+                    value,
+                    expires,
+                    envelope,
+                } => (key, value, expires, envelope),
+                Change::Remove { key } => {
+                    let live = match present.get(&key) {
+                        Some(&live) => live,
+                        None => self.live(&key),
+                    };
+                    // removing a missing key is a no-op
+                    if live {
+                        present.insert(key.clone(), false);
+                        ops.push(Op::Rm { key });
+                    }
+                    continue;
+                }
+                _ => unreachable!("a batch only holds sets and removes"),
+            };
+            present.insert(key.clone(), true);
+            let crc = self
+                .value_checksum
+                .then(|| crc32fast::hash(value.as_bytes()));
+            ops.push(Op::Set {
+                key,
+                value,
+                ts,
+                crc,
+                expires,
+                content_type: envelope.content_type,
+                compressed: envelope.compressed,
+            });
+        }
+        for key in merged.iter() {
+            let (start, end) = merge::operand_range(key);
+            for operand in self.entry_to_index.reserved_keys(start, end) {
+                ops.push(Op::Rm { key: operand });
+            }
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let mut serial = encode(&Op::Batch { len: ops.len() }, Compression::None)?;
+        let header_len = serial.len();
+        let mut records = Vec::with_capacity(ops.len());
+        for op in ops.iter() {
+            let start = serial.len();
+            serial.extend_from_slice(&encode(op, Compression::None)?);
+            records.push((start, serial.len() - start));
+        }
+        let pos = self.append_raw(&serial)?;
+        self.record_growth(WriteCounts {
+            writes: ops.len() as u64,
+            bytes_written: serial.len() as u64,
+            ..WriteCounts::default()
+        });
+        self.garbage.kill(self.current_ver, header_len);
+        self.hints.push(Hint::Dead {
+            rec_len: header_len,
+        });
+
+        let mut updates = Vec::with_capacity(ops.len());
+        for (op, (start, rec_len)) in ops.iter().zip(records) {
+            match op {
+                Op::Set {
+                    key,
+                    value,
+                    expires,
+                    ..
+                } => {
+                    let envelope = op.envelope();
+                    self.hints.push(Hint::Set {
+                        key: key.clone(),
+                        offset: pos + start,
+                        len: value.len(),
+                        rec_len,
+                        ts,
+                        expires: *expires,
+                        envelope: envelope.clone(),
+                    });
+                    if expires.is_some() {
+                        // once expired it hides the older values of its key
+                        self.garbage.tombstone(self.current_ver, 0);
+                    }
+                    let index = InMemIndex {
+                        version: self.current_ver,
+                        start_pos: pos + start,
+                        len: value.len(),
+                        rec_len,
+                        ts,
+                        expires: *expires,
+                        envelope,
+                    };
+                    updates.push((key.clone(), Some(index)));
+                }
+                Op::Rm { key } => {
+                    self.garbage.tombstone(self.current_ver, rec_len);
+                    self.hints.push(Hint::Rm {
+                        key: key.clone(),
+                        rec_len,
+                    });
+                    self.removed.put(key.clone(), ts);
+                    updates.push((key.clone(), None));
+                }
+                _ => unreachable!("a batch only holds sets and removes"),
+            }
+        }
+        // keys whose chunks are left behind by a plain value
+        let mut replaced_chunks = Vec::new();
+        for (op, old) in ops.iter().zip(self.entry_to_index.apply(updates)) {
+            let Some(old) = old else {
+                continue;
+            };
+            self.garbage.kill(old.version, old.rec_len);
+            if old.envelope.is_chunked()
+                && let Op::Set { key, .. } = op
+                && !op.envelope().is_chunked()
+            {
+                replaced_chunks.push(key.clone());
+            }
+        }
+        for key in merged {
+            self.merging.remove(&key);
+        }
+        for op in ops.iter() {
+            self.publish_change(op);
+        }
+        for key in replaced_chunks {
+            self.drop_chunks(&key, None)?;
+        }
+
+        self.to_flush()
+    }
+
+    /// Drop `key` from the index if it expired or was purged, and tell the
+    /// subscribers of an expiry
+    /// No record is needed, the expiry is in the record of the value and the
+    /// purge has its own.
+    fn expire(&mut self, key: &str) {
+        let Some(index) = self.entry_to_index.get(key) else {
+            return;
+        };
+        let purged = self.purges.covers(key, &index);
+        if purged || index.expired(now_millis()) {
+            let old = self.entry_to_index.remove(key).unwrap();
+            self.garbage.kill(old.version, old.rec_len);
+            if !purged {
+                self.publish(EngineEvent::Expired {
+                    key: key.to_owned(),
+                });
+            }
+        }
+    }
+
+    /// Whether `key` is in the index, neither expired nor purged
+    fn live(&self, key: &str) -> bool {
+        let now = now_millis();
+        self.entry_to_index
+            .get(key)
+            .is_some_and(|index| index.visible(key, now, &self.purges))
+    }
+
+    /// Timestamp of a new record, later than any purge
+    /// A purge covers the records up to its own millisecond, so the writes
+    /// which follow it in the same millisecond must not share it.
+    fn stamp(&self) -> u64 {
+        match &self.remote {
+            // a remote change keeps the time it was written at
+            Some((_, ts)) => *ts,
+            None => now_millis().max(self.purges.latest() + 1),
+        }
+    }
+
+    /// Remove every key starting with `prefix` with one prefix tombstone
+    ///
+    /// Nothing is taken out of the index: the keys written before the purge
+    /// read as missing from now on, each is dropped on its next read, and
+    /// compaction leaves their records out. Return the number of keys under
+    /// the prefix not purged before, expired ones too.
+    pub fn purge_prefix(&mut self, prefix: String) -> Result<usize> {
+        self.throttle()?;
+        let (start, end) = prefix_range(&prefix);
+        let keys = self.purges.count_range(&self.entry_to_index, start, end);
+        if keys == 0 {
+            return Ok(0);
+        }
+        let ts = self.stamp();
+        let cur_op = Op::Purge {
+            prefix: prefix.clone(),
+            ts,
+        };
+        let (_, rec_len) = self.append(&cur_op)?;
+        self.garbage.tombstone(self.current_ver, rec_len);
+        self.hints.push(Hint::Purge {
+            prefix: prefix.clone(),
+            rec_len,
+        });
+        self.purges.add(Purge {
+            prefix,
+            ts,
+            version: self.current_ver,
+        });
+        self.publish_change(&cur_op);
+
+        self.to_flush()?;
+        Ok(keys)
+    }
+
+    /// Graduated write throttling
+    ///
+    /// Once the sealed logs or dead bytes pass the slowdown mark, each write
+    /// sleeps a little longer the closer we get to the stop mark. At the stop
+    /// mark writes are stalled until a compaction catches up.
+    fn throttle(&mut self) -> Result<()> {
+        self.finish_compaction(false)?;
+        let pressure = f64::max(
+            Self::pressure(self.segments(), SLOWDOWN_SEGMENTS, STOP_SEGMENTS),
+            Self::pressure(self.garbage.dead(), SLOWDOWN_DEAD_BYTES, STOP_DEAD_BYTES),
+        );
+        if pressure >= 1.0 {
+            self.write_status = WriteStatus::Stalled;
+            trace!("writes stalled, compact before accepting more");
+            if self.compaction.is_none() {
+                self.seal()?;
+                // every sealed log, however live, to bring the count down
+                let result = self.start_compaction(true);
+                self.open_active()?;
+                result?;
+            }
+            self.finish_compaction(true)?;
+            self.write_status = WriteStatus::Normal;
+        } else if pressure > 0.0 {
+            self.write_status = WriteStatus::Delayed;
+            thread::sleep(MAX_WRITE_DELAY.mul_f64(pressure));
+        } else {
+            self.write_status = WriteStatus::Normal;
+        }
+        Ok(())
+    }
+
+    /// Make sure `need` more bytes can be written while keeping the reserve
+    /// Otherwise switch to read-only mode until space is freed
+    ///
+    /// The free space is only queried with `refresh`, for a large write, when
+    /// the last query is stale or while read-only; otherwise the bytes
+    /// appended since are taken off the last answer.
+    fn check_space(&mut self, need: u64, refresh: bool) -> Result<()> {
+        let stale = match self.free_space {
+            Some((at, _)) => at.elapsed() >= SPACE_CHECK_INTERVAL,
+            None => true,
+        };
+        let large = self.unchecked_bytes.saturating_add(need) >= SPACE_CHECK_BYTES;
+        if refresh || stale || large || self.disk_full {
+            let free = fs2::available_space(self.dir.as_path())?;
+            self.free_space = Some((Instant::now(), free));
+            self.unchecked_bytes = 0;
+        }
+        let available = self
+            .free_space
+            .map_or(0, |(_, free)| free.saturating_sub(self.unchecked_bytes));
+        let full = available < self.disk_reserve.saturating_add(need);
+        if full && !self.disk_full {
+            warn!("only {} bytes left on disk, switch to read-only", available);
+        }
+        self.disk_full = full;
+        if full {
+            Err(KvsError::DiskFull)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Bytes of all logs, garbage included
+    fn log_bytes(&self) -> u64 {
+        self.garbage.logs.values().map(|u| u.len as u64).sum()
+    }
+
+    /// Make sure a set of about `need` bytes keeps the logs within the quota
+    /// Only sets are checked, removals are what frees space.
+    fn check_quota(&mut self, need: u64) -> Result<()> {
+        let Some(quota) = self.disk_quota else {
+            return Ok(());
+        };
+        if self.log_bytes().saturating_add(need) <= quota {
+            return Ok(());
+        }
+        if self.compact_over_quota && self.garbage.dead() > 0 {
+            warn!("logs are over the quota of {} bytes, compact them", quota);
+            self.compact()?;
+        }
+        let used = self.log_bytes();
+        if used.saturating_add(need) <= quota {
+            Ok(())
+        } else {
+            Err(KvsError::QuotaExceeded { used, quota })
+        }
+    }
+
+    /// How far `cur` is from `slowdown` to `stop`, 0 means no pressure
+    fn pressure(cur: usize, slowdown: usize, stop: usize) -> f64 {
+        if cur <= slowdown {
+            0.0
+        } else if cur >= stop {
+            1.0
+        } else {
+            (cur - slowdown) as f64 / (stop - slowdown) as f64
+        }
+    }
+
+    /// Wrapper on whether to flush the active log or not
+    fn to_flush(&mut self) -> Result<()> {
+        if self.current_len >= self.active_log_size {
+            trace!("current active log length is {}", self.current_len);
+            self.flush()
+        } else {
+            self.check_memory()
+        }
+    }
+
+    /// Count the memory of the index and of the hints in the usage of the store
+    fn count_memory(&self) {
+        if let Some(memory) = &self.memory {
+            let usage = &memory.usage;
+            usage
+                .index
+                .store(self.entry_to_index.memory(), Ordering::SeqCst);
+            usage.buffers.store(
+                self.hints.capacity() * mem::size_of::<Hint>(),
+                Ordering::SeqCst,
+            );
+        }
+    }
+
+    /// Count the memory after every write, check the budget every
+    /// `MEMORY_CHECK_INTERVAL` writes
+    /// Going over it seals the active log, which writes its hints out, and
+    /// makes the readers close every log but the one they read.
+    fn check_memory(&mut self) -> Result<()> {
+        self.count_memory();
+        let budget = match &self.memory {
+            Some(memory) if self.written.is_multiple_of(MEMORY_CHECK_INTERVAL) => {
+                memory.budget.clone()
+            }
+            _ => return Ok(()),
+        };
+        let pressure = budget.check();
+        let entered = pressure && !self.memory_pressure;
+        self.memory_pressure = pressure;
+        if entered {
+            warn!(
+                "memory usage is over the budget of {} bytes, shrink caches",
+                budget.limit()
+            );
+            if !self.hints.is_empty() {
+                return self.flush();
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush a full active log into disk
+    /// Seal it, start a compaction if the old logs grew too large, and open
+    /// a new active log
+    fn flush(&mut self) -> Result<()> {
+        self.seal()?;
+        if self.compaction.is_none() {
+            match self.start_compaction(false) {
+                Ok(()) => {}
+                Err(KvsError::DiskFull) => warn!("not enough disk space, skip compaction"),
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.open_active()
+    }
+
+    /// Number of sealed logs
+    fn segments(&self) -> usize {
+        self.garbage.logs.len().saturating_sub(1)
+    }
+
+    /// Make the active log durable and read-only, with its hints
+    fn seal(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        // sealed logs are durable, compaction may delete them next
+        self.writer.get_ref().sync_data()?;
+        let hints = mem::take(&mut self.hints);
+        let hint_path = self.manifest.layout.hint_path(&self.dir, self.current_ver);
+        write_hints(&hint_path, self.current_len, &hints)?;
+        // replicas pick the log up from here
+        self.manifest.sealed.push(self.current_ver);
+        let len = self.writer.get_ref().metadata()?.len();
+        self.manifest.sizes.insert(self.current_ver, len);
+        self.manifest.store(&self.dir)?;
+        self.current_len = 0;
+        self.publish(EngineEvent::SegmentSealed {
+            version: self.current_ver,
+        });
+        Ok(())
+    }
+
+    /// Put the logs of the store into the empty directory `target`, with a
+    /// manifest, see `KvStore::checkpoint`
+    fn checkpoint(&mut self, target: &Path) -> Result<()> {
+        if fs::read_dir(target).is_ok_and(|mut files| files.next().is_some()) {
+            return Err(KvsError::StoreExists(target.display().to_string()));
+        }
+        self.writer.flush()?;
+        let layout = self.manifest.layout.clone();
+        for log_dir in layout.dirs(target) {
+            fs::create_dir_all(log_dir)?;
+        }
+        for &v in self.garbage.logs.keys() {
+            let from = layout.path(&self.dir, v);
+            // the output of a running compaction, not swapped in yet
+            if !from.exists() {
+                continue;
+            }
+            let to = layout.path(target, v);
+            if v == self.current_ver {
+                // still appended to, a link would see the writes to come
+                fs::copy(&from, &to)?;
+                File::open(&to)?.sync_data()?;
+                continue;
+            }
+            // sealed logs never change, until compaction deletes them
+            link_or_copy(&from, &to)?;
+            let hint = layout.hint_path(&self.dir, v);
+            if hint.exists() {
+                link_or_copy(&hint, &layout.hint_path(target, v))?;
+            }
+        }
+        let mut manifest = self.manifest.clone();
+        manifest.clean_shutdown = true;
+        manifest.obsolete.clear();
+        manifest.compacting = None;
+        manifest
+            .sealed
+            .retain(|v| self.garbage.logs.contains_key(v));
+        manifest
+            .sizes
+            .retain(|v, _| self.garbage.logs.contains_key(v));
+        manifest.store(target)?;
+        sync_parent(&layout.path(target, self.current_ver))?;
+        Ok(())
+    }
+
+    /// Open a new active log with the next version
+    fn open_active(&mut self) -> Result<()> {
         self.current_ver += 1;
         trace!("Flush old log, and create {}.log", self.current_ver);
+        self.manifest.active = Some(self.current_ver);
+        self.manifest.store(&self.dir)?;
+        let path = self.manifest.layout.path(&self.dir, self.current_ver);
         let cur_file = OpenOptions::new()
             .create(true)
             .append(true)
             .read(true)
-            .open(self.dir.join(format!("log/{}.log", self.current_ver)))?;
+            .open(&path)?;
+        sync_parent(&path)?;
+        if let Some(syncer) = &self.syncer {
+            syncer.rotate(cur_file.try_clone()?, self.written);
+        }
         self.writer = BufWriter::new(cur_file);
+        self.current_len = start_log(&mut self.writer, Compression::None)?;
+        self.generations.seal(self.current_ver);
+        self.garbage.grow(self.current_ver, 0);
         Ok(())
     }
 
-    /// Compact all old logs into one
+    /// Seal the active log, compact every log and wait for it
     fn compact(&mut self) -> Result<()> {
-        trace!("Begin compacting");
-        let mut entry_to_index = self.entry_to_index.write().unwrap();
-        let base_dir = self.dir.join("log");
+        self.finish_compaction(true)?;
+        // the output must be newer than any log written meanwhile
+        self.seal()?;
+        let result = self.start_compaction(true);
+        self.open_active()?;
+        result?;
+        self.finish_compaction(true)
+    }
 
-        let (mut list, order, ..) = Self::traverse_dir(&base_dir)?;
+    /// Remove every key, then compact every log down to an empty one
+    ///
+    /// The range tombstone reaches the replicas like any other write, and
+    /// the compaction output holds no record, so nothing of the old logs is
+    /// left once the snapshots reading them are closed.
+    fn clear(&mut self) -> Result<usize> {
+        let removed = self.remove_range(Bound::Unbounded, Bound::Unbounded)?;
+        self.compact()?;
+        Ok(removed)
+    }
 
+    /// Compact the sealed logs with too much garbage, on a thread of its own
+    ///
+    /// With `all` every sealed log is merged, however live. Only the records
+    /// still in the index are kept, no later record refers to their keys, so
+    /// the new log may take the next version even if older logs stay. Writes
+    /// go on while it is merged, `finish_compaction` swaps it in later.
+    fn start_compaction(&mut self, all: bool) -> Result<()> {
+        let order = self.garbage.pick(self.compaction_threshold, all);
+        if order.is_empty() {
+            return Ok(());
+        }
+        let inputs_len: usize = order.iter().map(|v| self.garbage.logs[v].len).sum();
+        // compaction writes at most as much as it reads
+        self.check_space(inputs_len as u64, true)?;
+        trace!("Begin compacting logs {:?}", order);
+        let layout = self.manifest.layout.clone();
+        let logs = order
+            .iter()
+            .map(|v| Ok((*v, File::open(layout.path(&self.dir, *v))?)))
+            .collect::<Result<Vec<_>>>()?;
         self.current_ver += 1;
-        let new_log = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .read(true)
-            .open(base_dir.join(format!("{}.log", self.current_ver)))?;
-        trace!(
-            "All compacted entries will be written into {}.log",
-            self.current_ver
-        );
-        let mut writer = BufWriter::new(new_log);
-        let mut dict: HashMap<String, String> = HashMap::new();
+        self.manifest.compacting = Some(self.current_ver);
+        self.manifest.store(&self.dir)?;
+        let job = CompactionJob {
+            dir: Arc::clone(&self.dir),
+            layout,
+            index: Arc::clone(&self.entry_to_index),
+            logs,
+            output: self.current_ver,
+            rate: self.compaction_rate,
+            skip_corrupted: self.skipped_records > 0,
+            purges: Arc::clone(&self.purges),
+            compression: self.compression,
+        };
+        let (tx, rx) = channel();
+        let metrics = Arc::clone(&self.metrics);
+        thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                let _ = tx.send(metrics.time(Operation::Compaction, || job.run()));
+            })?;
+        self.publish(EngineEvent::CompactionStarted { logs: order.len() });
+        self.compaction = Some(Compaction {
+            inputs: order,
+            output: self.current_ver,
+            done: rx,
+        });
+        Ok(())
+    }
 
-        for ver in order {
-            trace!("current log version is {}", ver);
-            let mut cur_reader = list.remove(&ver).unwrap();
-            cur_reader.seek(SeekFrom::Start(0))?;
-            for line in cur_reader.lines() {
-                match line {
-                    Ok(s) => {
-                        let op: Op = serde_json::from_str(&s)?;
-                        match op {
-                            Op::Set { key, value } => {
-                                trace!("set {} to {}", key, value);
-                                dict.insert(key, value);
-                            }
-                            Op::Rm { key } => {
-                                trace!("remove {}", key);
-                                dict.remove(&key).unwrap();
-                            }
-                        }
-                    }
-                    Err(e) => return Err(e.into()),
-                }
+    /// Swap in the log of a finished compaction, then delete the old logs
+    ///
+    /// Only the entries still pointing at the old logs move to the new one,
+    /// the keys written or removed meanwhile keep their newer entry. With
+    /// `wait` the writer blocks until the running compaction is over.
+    fn finish_compaction(&mut self, wait: bool) -> Result<()> {
+        let compacted = match &self.compaction {
+            Some(c) if wait => c.done.recv().map_err(|_| TryRecvError::Disconnected),
+            Some(c) => c.done.try_recv(),
+            None => return Ok(()),
+        };
+        let compacted = match compacted {
+            Ok(compacted) => compacted,
+            Err(TryRecvError::Empty) => return Ok(()),
+            Err(TryRecvError::Disconnected) => Err(KvsError::StringError(
+                "compaction thread panicked".to_owned(),
+            )),
+        };
+        let Compaction { inputs, output, .. } = self.compaction.take().unwrap();
+        self.manifest.compacting = None;
+        let compacted = compacted?;
+        self.compaction_throttled += compacted.throttled;
+
+        let mut usage = LogUsage {
+            len: compacted.len,
+            ..LogUsage::default()
+        };
+        let mut updates = Vec::with_capacity(compacted.entries.len());
+        for (key, entry) in compacted.entries {
+            usage.tombstones |= entry.expires.is_some();
+            match self.entry_to_index.get(&key) {
+                Some(cur) if cur.version < output => updates.push((key, Some(entry))),
+                // overwritten since, so dead in the new log too
+                _ => usage.dead += entry.rec_len,
+            }
+        }
+        let mut expired = Vec::new();
+        for key in compacted.expired {
+            if self
+                .entry_to_index
+                .get(&key)
+                .is_some_and(|cur| cur.version < output)
+            {
+                updates.push((key.clone(), None));
+                expired.push(key);
+            }
+        }
+        for key in compacted.purged {
+            if self
+                .entry_to_index
+                .get(&key)
+                .is_some_and(|cur| self.purges.covers(&key, &cur))
+            {
+                updates.push((key, None));
             }
+        }
+        self.entry_to_index.apply(updates);
+        // snapshots taken from now on no longer read the inputs
+        let epoch = {
+            let mut pins = self.pins.lock().unwrap();
+            pins.epoch += 1;
+            pins.epoch
+        };
+        let mut reclaimed = 0_usize;
+        for ver in inputs.iter() {
+            reclaimed += self.garbage.logs.remove(ver).map_or(0, |u| u.len);
+        }
+        self.garbage.logs.insert(output, usage);
+        let oldest = self.garbage.logs.keys().next().copied().unwrap_or(output);
+        self.min_version.store(oldest as u32, Ordering::SeqCst);
+        // no log written before those purges is left to hold their keys
+        self.purges.forget(oldest);
+        // expired and purged keys left the index
+        self.count_memory();
+        let keys = self.entry_to_index.len();
+        self.publish(EngineEvent::IndexRebuilt { keys });
+        for key in expired {
+            self.publish(EngineEvent::Expired { key });
+        }
+
+        // replicas move to the new log before the old ones go away, and the
+        // next open finishes the deletion if we crash halfway
+        self.manifest.sealed.retain(|v| !inputs.contains(v));
+        self.manifest.sealed.push(output);
+        self.manifest.sealed.sort_unstable();
+        self.manifest.sizes.retain(|v, _| !inputs.contains(v));
+        let len = fs::metadata(self.manifest.layout.path(&self.dir, output))?.len();
+        self.manifest.sizes.insert(output, len);
+        self.manifest.obsolete.extend(inputs.iter().copied());
+        self.manifest.store(&self.dir)?;
+        self.pins
+            .lock()
+            .unwrap()
+            .replaced
+            .extend(inputs.iter().map(|&v| (v, epoch)));
+        self.remove_unpinned()?;
+        let reclaimed = reclaimed.saturating_sub(compacted.len) as u64;
+        self.record_growth(WriteCounts {
+            bytes_reclaimed: reclaimed,
+            ..WriteCounts::default()
+        });
+        self.last_compaction = Some(SystemTime::now());
+        self.publish(EngineEvent::CompactionFinished { reclaimed });
+
+        Ok(())
+    }
 
-            fs::remove_file(base_dir.join(format!("{}.log", ver)))?;
+    /// Delete the logs replaced by compaction which no snapshot reads anymore
+    fn remove_unpinned(&mut self) -> Result<()> {
+        let logs = self.pins.lock().unwrap().unpinned();
+        if logs.is_empty() {
+            return Ok(());
         }
+        // oldest first, so a tombstone outlives the values it hides
+        for ver in logs.iter() {
+            remove_log(&self.dir, &self.manifest.layout, *ver)?;
+        }
+        self.manifest.obsolete.retain(|v| !logs.contains(v));
+        self.manifest.store(&self.dir)
+    }
 
-        let mut offset = 0_usize;
-        entry_to_index.clear();
-        for (k, v) in dict.into_iter() {
-            entry_to_index.insert(
-                k.clone(),
-                RwLock::new(InMemIndex {
-                    version: self.current_ver,
-                    start_pos: offset,
-                }),
-            );
-            let op = Op::Set { key: k, value: v };
-            let info = serde_json::to_string(&op)?;
-            writer.write_all(info.as_bytes())?;
-            writer.write_all(b"\n")?;
-            offset += info.len() + 1;
+    /// Count `counts` in the write history, saved once a minute is over
+    fn record_growth(&mut self, counts: WriteCounts) {
+        if self.growth.record(now_millis(), counts)
+            && let Err(e) = self.growth.store(&self.dir)
+        {
+            warn!("Fail to save the write history: {}", e);
         }
-        writer.flush()?;
-        self.min_version
-            .store(self.current_ver as u32, Ordering::SeqCst);
-        self.old_log_len = 0;
+    }
 
+    /// Send `event` to every subscriber still listening
+    fn publish(&mut self, event: EngineEvent) {
+        trace!("engine event {:?}", event);
+        self.subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Send an applied write to every changefeed receiver still listening
+    fn publish_change(&mut self, op: &Op) {
+        let watched = self.watchers.as_ref().is_some_and(|w| !w.is_empty());
+        if self.feed.is_empty() && self.region_feed.is_empty() && !watched {
+            return;
+        }
+        let change = match op {
+            Op::Set {
+                key,
+                value,
+                expires,
+                content_type,
+                compressed,
+                ..
+            } if content_type.is_some() || *compressed => Change::SetEnveloped {
+                key: key.clone(),
+                value: value.clone(),
+                expires: *expires,
+                envelope: op.envelope(),
+            },
+            Op::Set {
+                key,
+                value,
+                expires: None,
+                ..
+            } => Change::Set {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            Op::Set {
+                key,
+                value,
+                expires: Some(expires),
+                ..
+            } => Change::SetExpiring {
+                key: key.clone(),
+                value: value.clone(),
+                expires: *expires,
+            },
+            Op::Rm { key } => Change::Remove { key: key.clone() },
+            Op::RmRange { start, end } => Change::RemoveRange {
+                start: start.clone(),
+                end: end.clone(),
+            },
+            Op::Purge { prefix, .. } => Change::RemovePrefix {
+                prefix: prefix.clone(),
+            },
+            // the records of the batch follow
+            Op::Batch { .. } => return,
+        };
+        if !self.region_feed.is_empty() {
+            let ts = match op {
+                Op::Set { ts, .. } | Op::Purge { ts, .. } => *ts,
+                _ => self.stamp(),
+            };
+            let origin = match &self.remote {
+                Some((origin, _)) => origin.clone(),
+                None => self.region.clone(),
+            };
+            let stamped = RegionChange {
+                origin,
+                ts,
+                change: change.clone(),
+            };
+            self.region_feed
+                .retain(|tx| tx.send(stamped.clone()).is_ok());
+        }
+        if watched && let Some(watchers) = &self.watchers {
+            watchers.send(self.written, change.clone());
+        }
+        self.feed.retain(|tx| tx.send(change.clone()).is_ok());
+    }
+
+    /// Apply a change of a primary or of another region
+    /// Removing a missing key is not an error, the change may be applied twice.
+    fn apply_change(&mut self, change: Change) -> Result<()> {
+        match change {
+            Change::Set { key, value } => self.set(key, value),
+            Change::SetExpiring {
+                key,
+                value,
+                expires,
+            } => self.set_expiring(key, value, Some(expires)),
+            Change::SetEnveloped {
+                key,
+                value,
+                expires,
+                envelope,
+            } => self.set_enveloped(key, value, expires, envelope),
+            Change::Remove { key } => match self.remove(key) {
+                Err(KvsError::KeyNotFound) => Ok(()),
+                res => res,
+            },
+            Change::RemoveRange { start, end } => self.remove_range(start, end).map(|_| ()),
+            Change::RemovePrefix { prefix } => self.purge_prefix(prefix).map(|_| ()),
+        }
+    }
+
+    /// Append a remote change which lost to the local write at `local_ts`
+    fn log_conflict(&self, remote: &RegionChange, local_ts: u64) -> Result<()> {
+        warn!(
+            "Keep the local write of {:?}, newer than the one of region {}",
+            remote.change.key().unwrap_or_default(),
+            remote.origin
+        );
+        let conflict = Conflict {
+            at: now_millis(),
+            local_ts,
+            change: remote.clone(),
+        };
+        let mut line = serde_json::to_string(&conflict)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(CONFLICT_LOG))?
+            .write_all(line.as_bytes())?;
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Op {
-    Set { key: String, value: String },
-    Rm { key: String },
+/// Closing the store marks the shutdown clean in the manifest
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if let Some(syncer) = &self.syncer {
+            syncer.close();
+        }
+        if let Err(e) = self.finish_compaction(true) {
+            warn!("Fail to finish the compaction on close: {}", e);
+        }
+        if let Err(e) = self.growth.store(&self.dir) {
+            warn!("Fail to save the write history on close: {}", e);
+        }
+        if let Err(e) = self.writer.flush() {
+            warn!("Fail to flush the active log on close: {}", e);
+            return;
+        }
+        self.manifest.clean_shutdown = true;
+        // nothing is appended any more, replicas may read the active log too
+        if !self.manifest.sealed.contains(&self.current_ver) {
+            self.manifest.sealed.push(self.current_ver);
+        }
+        if let Ok(meta) = self.writer.get_ref().metadata() {
+            self.manifest.sizes.insert(self.current_ver, meta.len());
+        }
+        if let Err(e) = self.manifest.store(&self.dir) {
+            warn!("Fail to mark a clean shutdown: {}", e);
+        }
+    }
 }
 
-#[derive(Clone)]
-struct InMemIndex {
-    version: usize,
-    start_pos: usize,
+/// Size and garbage of a log
+#[derive(Debug, Clone, Copy, Default)]
+struct LogUsage {
+    len: usize,
+    // bytes of records which are overwritten or removed
+    dead: usize,
+    // holds removals or expiring values, which hide older records of their
+    // keys, so it is only compacted along with every older log
+    tombstones: bool,
+}
+
+impl LogUsage {
+    /// Share of the log which is still live, an empty log has none
+    fn live_ratio(&self) -> f64 {
+        if self.len == 0 {
+            return 0.0;
+        }
+        self.len.saturating_sub(self.dead) as f64 / self.len as f64
+    }
+}
+
+/// Usage of every log by version, the last one is the active log
+#[derive(Debug, Default)]
+struct Garbage {
+    logs: BTreeMap<usize, LogUsage>,
+}
+
+impl Garbage {
+    fn grow(&mut self, version: usize, bytes: usize) {
+        self.logs.entry(version).or_default().len += bytes;
+    }
+
+    /// `bytes` of log `version` are overwritten or removed
+    fn kill(&mut self, version: usize, bytes: usize) {
+        self.logs.entry(version).or_default().dead += bytes;
+    }
+
+    /// A record of `bytes` which hides older ones is in log `version`
+    fn tombstone(&mut self, version: usize, bytes: usize) {
+        let usage = self.logs.entry(version).or_default();
+        usage.dead += bytes;
+        usage.tombstones = true;
+    }
+
+    /// Bytes of garbage in all logs
+    fn dead(&self) -> usize {
+        self.logs.values().map(|u| u.dead).sum()
+    }
+
+    /// Sealed logs less live than `min_live`, or all with `all`
+    /// Call it while there is no active log.
+    fn pick(&self, min_live: f64, all: bool) -> Vec<usize> {
+        let picked: Vec<usize> = self
+            .logs
+            .iter()
+            .filter(|(_, u)| all || u.live_ratio() < min_live)
+            .map(|(v, _)| *v)
+            .collect();
+        // the records its tombstones hide must go in the same compaction
+        match picked.iter().rev().find(|v| self.logs[v].tombstones) {
+            Some(&newest) => self
+                .logs
+                .keys()
+                .filter(|&&v| v <= newest || picked.contains(&v))
+                .copied()
+                .collect(),
+            None => picked,
+        }
+    }
+}
+
+/// A bytes/sec budget
+/// The caller sleeps whenever it gets ahead of the budget
+struct RateLimiter {
+    rate: u64,
+    start: Instant,
+    consumed: u64,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    /// Account `bytes` of I/O, return how long the caller has slept
+    fn consume(&mut self, bytes: usize) -> Duration {
+        self.consumed += bytes as u64;
+        let expected = Duration::from_secs_f64(self.consumed as f64 / self.rate as f64);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+            expected - elapsed
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// A compaction handed to its thread
+struct CompactionJob {
+    dir: Arc<PathBuf>,
+    layout: LogLayout,
+    // tells which records are still live
+    index: Arc<Index>,
+    // sealed logs to merge, by version
+    logs: Vec<(usize, File)>,
+    output: usize,
+    // bytes/sec budget of its I/O
+    rate: Option<u64>,
+    // the open skipped corrupted records, none of them is in the index
+    skip_corrupted: bool,
+    // the records they cover are left out
+    purges: Arc<Purges>,
+    compression: Compression,
+}
+
+/// A compaction running on its thread, seen from the writer
+struct Compaction {
+    inputs: Vec<usize>,
+    output: usize,
+    done: Receiver<Result<Compacted>>,
 }
 
-impl KvsEngine for KvStore {
-    /// Map `key` to `value` in the kv store
+/// The new log of a compaction, not swapped in yet
+struct Compacted {
+    // index entries of every key in it
+    entries: Vec<(String, InMemIndex)>,
+    // keys left out because they expired
+    expired: Vec<String>,
+    // keys left out because a purge covers them
+    purged: Vec<String>,
+    // bytes of its records, the format byte aside
+    len: usize,
+    // time slept to respect the budget
+    throttled: Duration,
+}
+
+impl CompactionJob {
+    /// Merge the logs into the new one, written aside and renamed once it
+    /// is durable
+    fn run(self) -> Result<Compacted> {
+        let mut limiter = self.rate.map(RateLimiter::new);
+        let mut throttled = Duration::ZERO;
+        let mut throttle = |bytes| {
+            if let Some(limiter) = &mut limiter {
+                throttled += limiter.consume(bytes);
+            }
+        };
+
+        // only the `Set` records the index points at are kept, removals go
+        // as every older log is merged along with them
+        let mut live = Vec::new();
+        let mut skipped = 0;
+        for (ver, log) in self.logs.iter() {
+            trace!("current log version is {}", ver);
+            let skipped = self.skip_corrupted.then_some(&mut skipped);
+            for (op, offset, rec_len) in read_records(log, *ver, skipped)?.records {
+                throttle(rec_len);
+                if let Op::Set { ref key, .. } = op {
+                    let entry = self.index.get(key);
+                    if entry.is_some_and(|e| e.version == *ver && e.start_pos == offset) {
+                        trace!("keep {}", key);
+                        live.push((key.clone(), op));
+                    }
+                }
+            }
+        }
+
+        let new_path = self.layout.path(&self.dir, self.output);
+        let tmp_path = new_path.with_extension("log.tmp");
+        trace!(
+            "All compacted entries will be written into {}.log",
+            self.output
+        );
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let header = start_log(&mut writer, self.compression)?;
+        let mut offset = header;
+        let mut hints = Vec::new();
+        let mut entries = Vec::with_capacity(live.len());
+        let mut expired = Vec::new();
+        let mut purged = Vec::new();
+        let now = now_millis();
+        for (k, op) in live {
+            let (len, ts, expires) = match &op {
+                Op::Set {
+                    value, ts, expires, ..
+                } => (value.len(), *ts, *expires),
+                _ => unreachable!("only set records are kept"),
+            };
+            let envelope = op.envelope();
+            if expires.is_some_and(|expires| expires <= now) {
+                trace!("drop expired {}", k);
+                expired.push(k);
+                continue;
+            }
+            if self.purges.covers_record(&k, ts) {
+                trace!("drop purged {}", k);
+                purged.push(k);
+                continue;
+            }
+            let info = encode(&op, self.compression)?;
+            writer.write_all(&info)?;
+            hints.push(Hint::Set {
+                key: k.clone(),
+                offset,
+                len,
+                rec_len: info.len(),
+                ts,
+                expires,
+                envelope: envelope.clone(),
+            });
+            entries.push((
+                k,
+                InMemIndex {
+                    version: self.output,
+                    start_pos: offset,
+                    len,
+                    rec_len: info.len(),
+                    ts,
+                    expires,
+                    envelope,
+                },
+            ));
+            offset += info.len();
+            throttle(info.len());
+        }
+        writer.flush()?;
+        // the old logs are deleted once it is swapped in, only if it is durable
+        writer.get_ref().sync_data()?;
+        fs::rename(&tmp_path, &new_path)?;
+        sync_parent(&new_path)?;
+        write_hints(
+            &self.layout.hint_path(&self.dir, self.output),
+            offset,
+            &hints,
+        )?;
+
+        Ok(Compacted {
+            entries,
+            expired,
+            purged,
+            len: offset - header,
+            throttled,
+        })
+    }
+}
+
+/// `ts` is the write time in milliseconds since the unix epoch.
+/// Logs written before it was introduced deserialize it as 0. The binary
+/// records hold every field, the defaults only fill in old JSON lines.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Op {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        ts: u64,
+        /// CRC32 of the value, only written if value checksum is enabled
+        #[serde(default)]
+        crc: Option<u32>,
+        /// Expiry time in milliseconds since the unix epoch, if any
+        #[serde(default)]
+        expires: Option<u64>,
+        /// MIME type of the value, see `Envelope`
+        #[serde(default)]
+        content_type: Option<String>,
+        /// The value is base64 encoded gzip
+        #[serde(default)]
+        compressed: bool,
+    },
+    Rm {
+        key: String,
+    },
+    /// Range tombstone, removes every key inside the range
+    RmRange {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+    /// Prefix tombstone, removes every key under `prefix` written up to `ts`
+    Purge {
+        prefix: String,
+        ts: u64,
+    },
+    /// Header of a batch, the next `len` records are applied all or none
+    Batch {
+        len: usize,
+    },
+}
+
+impl Op {
+    /// Envelope of the value of a set, plain for any other record
+    fn envelope(&self) -> Envelope {
+        match self {
+            Op::Set {
+                crc,
+                content_type,
+                compressed,
+                ..
+            } => Envelope {
+                content_type: content_type.clone(),
+                compressed: *compressed,
+                checksum: *crc,
+            },
+            _ => Envelope::default(),
+        }
+    }
+}
+
+/// A group of sets and removes, applied by `KvStore::apply_batch`
+///
+/// Writes apply in the order they are added, removing a missing key is not
+/// an error. After a crash either every write of the batch is visible, or
+/// none of them.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    // only sets and removes
+    changes: Vec<Change>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.changes.push(Change::Set { key, value });
+        self
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.changes.push(Change::Remove { key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Refuse the batch if it writes a key the store keeps for itself
+    pub(crate) fn check_keys(&self) -> Result<()> {
+        for key in self.changes.iter().filter_map(Change::key) {
+            check_user_key(key)?;
+        }
+        Ok(())
+    }
+}
+
+/// A log record, its offset and its length including its framing
+type Record = (Op, usize, usize);
+
+/// How the records of a log are encoded, told by its first byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordFormat {
+    /// One JSON object per line, followed by its CRC32 in hex since format
+    /// version 2. Only read, new logs are binary.
+    Json,
+    /// `RECORD_HEADER` then the bincode of the `Op`, after a `BINARY_LOG` byte
+    Binary,
+    /// Like `Binary` with the bincode compressed, after a `ZSTD_LOG` byte
+    Zstd,
+}
+
+impl RecordFormat {
+    /// Format of a log starting with `first`, an empty log reads as JSON
+    fn of(first: Option<u8>) -> Self {
+        match first {
+            Some(BINARY_LOG) => RecordFormat::Binary,
+            Some(ZSTD_LOG) => RecordFormat::Zstd,
+            _ => RecordFormat::Json,
+        }
+    }
+
+    /// Format of the logs written with `compression`
+    fn written(compression: Compression) -> Self {
+        match compression {
+            Compression::None => RecordFormat::Binary,
+            Compression::Zstd(_) => RecordFormat::Zstd,
+        }
+    }
+
+    /// Bytes ahead of the first record
+    fn header_len(self) -> usize {
+        match self {
+            RecordFormat::Json => 0,
+            RecordFormat::Binary | RecordFormat::Zstd => 1,
+        }
+    }
+}
+
+/// Format of the log read by `reader`, which is left at its start
+fn detect(reader: &mut (impl BufRead + Seek)) -> io::Result<RecordFormat> {
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(RecordFormat::of(reader.fill_buf()?.first().copied()))
+}
+
+/// Serialize `op` as one binary record, its payload compressed if asked
+fn encode(op: &Op, compression: Compression) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(op).map_err(|e| KvsError::StringError(e.to_string()))?;
+    let payload = match compression {
+        Compression::None => payload,
+        Compression::Zstd(level) => zstd::bulk::compress(&payload, level)?,
+    };
+    let len = u32::try_from(payload.len())
+        .map_err(|_| KvsError::StringError("record is over 4 GiB".to_owned()))?;
+    let mut record = Vec::with_capacity(RECORD_HEADER + payload.len());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&(!len).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Read the next record of a log into `raw`, return its length
+///
+/// At the end of the log, `raw` holds whatever is left of a torn record. A
+/// binary length which does not match its complement is not followed, so
+/// the record fails to decode instead of swallowing the log.
+fn read_raw(
+    reader: &mut impl BufRead,
+    format: RecordFormat,
+    raw: &mut Vec<u8>,
+) -> io::Result<usize> {
+    raw.clear();
+    match format {
+        RecordFormat::Json => reader.read_until(b'\n', raw),
+        RecordFormat::Binary | RecordFormat::Zstd => {
+            let mut n = reader
+                .by_ref()
+                .take(RECORD_HEADER as u64)
+                .read_to_end(raw)?;
+            if let Some(len) = payload_len(raw) {
+                n += reader.by_ref().take(len as u64).read_to_end(raw)?;
+            }
+            Ok(n)
+        }
+    }
+}
+
+/// Length of the payload of a binary record, `None` if its header is damaged
+fn payload_len(raw: &[u8]) -> Option<u32> {
+    let len = u32::from_le_bytes(raw.get(..4)?.try_into().unwrap());
+    let check = u32::from_le_bytes(raw.get(4..8)?.try_into().unwrap());
+    (len == !check).then_some(len)
+}
+
+/// Parse one record, `None` if it does not match its checksum
+///
+/// Lines written before format version 2 carry no checksum and are only
+/// parsed. They always end with `}`, so they never look like a checksum.
+fn decode_record(raw: &[u8], format: RecordFormat) -> Option<Op> {
+    match format {
+        RecordFormat::Json => {
+            let line = std::str::from_utf8(raw).ok()?;
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let json = match line.rsplit_once(' ') {
+                Some((json, crc))
+                    if crc.len() == 8 && crc.bytes().all(|b| b.is_ascii_hexdigit()) =>
+                {
+                    let crc = u32::from_str_radix(crc, 16).ok()?;
+                    if crc != crc32fast::hash(json.as_bytes()) {
+                        return None;
+                    }
+                    json
+                }
+                _ => line,
+            };
+            serde_json::from_str(json).ok()
+        }
+        RecordFormat::Binary | RecordFormat::Zstd => {
+            let len = payload_len(raw)? as usize;
+            let payload = raw.get(RECORD_HEADER..)?;
+            let crc = u32::from_le_bytes(raw[8..RECORD_HEADER].try_into().unwrap());
+            if payload.len() != len || crc != crc32fast::hash(payload) {
+                return None;
+            }
+            match format {
+                RecordFormat::Zstd => bincode::deserialize(&zstd::decode_all(payload).ok()?).ok(),
+                _ => bincode::deserialize(payload).ok(),
+            }
+        }
+    }
+}
+
+/// What the index needs of a log record, i.e. the record without its value
+///
+/// The hints of every record of a sealed log are kept next to it in
+/// `<version>.hint`, so opening the store reads those instead of the values.
+#[derive(Serialize, Deserialize, Debug)]
+enum Hint {
+    Set {
+        key: String,
+        offset: usize,
+        len: usize,
+        rec_len: usize,
+        ts: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        #[serde(default, skip_serializing_if = "Envelope::is_plain")]
+        envelope: Envelope,
+    },
+    Rm {
+        key: String,
+        rec_len: usize,
+    },
+    RmRange {
+        start: Bound<String>,
+        end: Bound<String>,
+        rec_len: usize,
+    },
+    Purge {
+        prefix: String,
+        rec_len: usize,
+    },
+    /// A record the index ignores, e.g. a batch header
+    Dead {
+        rec_len: usize,
+    },
+}
+
+impl Hint {
+    fn of(op: Op, offset: usize, rec_len: usize) -> Self {
+        let envelope = op.envelope();
+        match op {
+            Op::Set {
+                key,
+                value,
+                ts,
+                expires,
+                ..
+            } => Hint::Set {
+                key,
+                offset,
+                len: value.len(),
+                rec_len,
+                ts,
+                expires,
+                envelope,
+            },
+            Op::Rm { key } => Hint::Rm { key, rec_len },
+            Op::RmRange { start, end } => Hint::RmRange {
+                start,
+                end,
+                rec_len,
+            },
+            Op::Purge { prefix, .. } => Hint::Purge { prefix, rec_len },
+            Op::Batch { .. } => Hint::Dead { rec_len },
+        }
+    }
+}
+
+/// Apply the hints of log `version` to `index`, counting garbage on the way
+fn replay(index: &Index, garbage: &mut Garbage, version: usize, hints: Vec<Hint>, now: u64) {
+    for hint in hints {
+        match hint {
+            Hint::Set {
+                key,
+                expires: Some(expires),
+                rec_len,
+                ..
+            } if expires <= now => {
+                // expired while the store was closed
+                if let Some(old) = index.remove(&key) {
+                    garbage.kill(old.version, old.rec_len);
+                }
+                garbage.tombstone(version, rec_len);
+            }
+            Hint::Set {
+                key,
+                offset,
+                len,
+                rec_len,
+                ts,
+                expires,
+                envelope,
+            } => {
+                let entry = InMemIndex {
+                    version,
+                    start_pos: offset,
+                    len,
+                    rec_len,
+                    ts,
+                    expires,
+                    envelope,
+                };
+                if let Some(old) = index.insert(key, entry) {
+                    garbage.kill(old.version, old.rec_len);
+                }
+                if expires.is_some() {
+                    garbage.tombstone(version, 0);
+                }
+            }
+            Hint::Rm { key, rec_len } => {
+                // the set may have been skipped as corrupted
+                if let Some(old) = index.remove(&key) {
+                    garbage.kill(old.version, old.rec_len);
+                }
+                garbage.tombstone(version, rec_len);
+            }
+            Hint::RmRange {
+                start,
+                end,
+                rec_len,
+            } => {
+                let keys = index.removed_by(start, end);
+                for old in index.remove_all(&keys) {
+                    garbage.kill(old.version, old.rec_len);
+                }
+                garbage.tombstone(version, rec_len);
+            }
+            // the records before it are replayed, so it applies at once
+            Hint::Purge { prefix, rec_len } => {
+                let (start, end) = prefix_range(&prefix);
+                let keys = index.keys_in_range(start, end);
+                for old in index.remove_all(&keys) {
+                    garbage.kill(old.version, old.rec_len);
+                }
+                garbage.tombstone(version, rec_len);
+            }
+            Hint::Dead { rec_len } => garbage.kill(version, rec_len),
+        }
+    }
+}
+
+/// Write the hints of a log of `log_len` bytes to `path`
+/// The file shows up complete or not at all.
+fn write_hints(path: &Path, log_len: usize, hints: &[Hint]) -> Result<()> {
+    let tmp = path.with_extension("hint.tmp");
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    writeln!(out, "{}", log_len)?;
+    for hint in hints {
+        serde_json::to_writer(&mut out, hint)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    out.get_ref().sync_data()?;
+    fs::rename(tmp, path)?;
+    sync_parent(path)?;
+    Ok(())
+}
+
+/// Refuse `key` if it starts like those the store keeps for itself
+fn check_user_key(key: &str) -> Result<()> {
+    match is_reserved(key) {
+        true => Err(KvsError::ReservedKey(key.to_owned())),
+        false => Ok(()),
+    }
+}
+
+/// Key holding `version` of `key` once it is overwritten
+/// The version is zero padded, so the history of a key sorts by version.
+fn history_key(key: &str, version: u64) -> String {
+    format!(
+        "{}{}\u{0}{:0width$}",
+        HISTORY_PREFIX,
+        key,
+        version,
+        width = HISTORY_DIGITS
+    )
+}
+
+/// Delete log `version` and its hints, if they are still there
+fn remove_log(dir: &Path, layout: &LogLayout, version: usize) -> Result<()> {
+    for path in [layout.path(dir, version), layout.hint_path(dir, version)] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Hard link `from` to `to`, or copy it where the file system has no links
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Make the entries of the directory holding `path` durable, e.g. a rename
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if cfg!(unix) => File::open(dir)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+/// Begin a new log with the format byte, return its length
+fn start_log(log: &mut BufWriter<File>, compression: Compression) -> io::Result<usize> {
+    let first = match compression {
+        Compression::None => BINARY_LOG,
+        Compression::Zstd(_) => ZSTD_LOG,
+    };
+    log.write_all(&[first])?;
+    log.flush()?;
+    Ok(RecordFormat::written(compression).header_len())
+}
+
+/// Whether `path` is a hint file, or one being written
+fn is_hint(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.ends_with(".hint") || name.ends_with(".hint.tmp")
+}
+
+/// Read the hints at `path`, `None` if there are none for a log of `log_len`
+/// bytes, e.g. the log was written to after them
+fn read_hints(path: &Path, log_len: u64) -> Option<Vec<Hint>> {
+    let file = File::open(path).ok()?;
+    let mut lines = BufReader::new(file).lines();
+    let len: u64 = lines.next()?.ok()?.parse().ok()?;
+    if len != log_len {
+        warn!("Ignore stale hints {:?}", path);
+        return None;
+    }
+    let hints: Option<Vec<Hint>> = lines
+        .map(|line| serde_json::from_str(&line.ok()?).ok())
+        .collect();
+    if hints.is_none() {
+        warn!("Ignore unreadable hints {:?}", path);
+    }
+    hints
+}
+
+/// Records of a log, as read by `read_records`
+struct LogRecords {
+    records: Vec<Record>,
+    /// Bytes of the records dropped before the torn tail
+    dropped: usize,
+    /// Length of the intact part, when the log ends with a torn write
+    torn_at: Option<usize>,
+}
+
+/// Read every record of log `version` in order
+///
+/// The records of a batch are returned only if the whole batch made it to
+/// the log. A batch cut short by a crash is left out with its header, and so
+/// is a torn last line, both make up the torn tail. Any other record which
+/// does not match its checksum fails the read, unless `skipped` counts it,
+/// then it is dropped along with its batch.
+fn read_records(
+    file: &File,
+    version: usize,
+    mut skipped: Option<&mut usize>,
+) -> Result<LogRecords> {
+    let mut reader = BufReader::new(file.try_clone()?);
+    let format = detect(&mut reader)?;
+    let mut offset = format.header_len();
+    reader.consume(offset);
+    let mut records = Vec::new();
+    let mut dropped = 0;
+    let mut torn_at = None;
+    let mut raw = Vec::new();
+    // records of the open batch, header first, how many are missing, and
+    // whether all of them were intact
+    let mut batch: Option<(Vec<Record>, usize, bool)> = None;
+    loop {
+        let rec_len = read_raw(&mut reader, format, &mut raw)?;
+        if rec_len == 0 {
+            break;
+        }
+        let op = match decode_record(&raw, format) {
+            Some(op) => Some(op),
+            None if reader.fill_buf()?.is_empty() => {
+                torn_at = Some(offset);
+                break;
+            }
+            None => match skipped.as_deref_mut() {
+                Some(skipped) => {
+                    warn!("Skip a corrupted record at {} of log {}", offset, version);
+                    *skipped += 1;
+                    dropped += rec_len;
+                    None
+                }
+                None => {
+                    return Err(KvsError::CorruptRecord {
+                        segment: version,
+                        offset,
+                    });
+                }
+            },
+        };
+        let record = op.map(|op| (op, offset, rec_len));
+        offset += rec_len;
+        if let Some((ops, missing, intact)) = batch.as_mut() {
+            match record {
+                Some(record) => ops.push(record),
+                None => *intact = false,
+            }
+            *missing -= 1;
+            if *missing == 0 {
+                if *intact {
+                    records.append(ops);
+                } else {
+                    warn!("Drop a batch with a corrupted record in log {}", version);
+                    dropped += ops.iter().map(|(_, _, len)| len).sum::<usize>();
+                }
+                batch = None;
+            }
+            continue;
+        }
+        if let Some(record) = record {
+            match record.0 {
+                Op::Batch { len } if len > 0 => batch = Some((vec![record], len, true)),
+                _ => records.push(record),
+            }
+        }
+    }
+    if let Some((ops, _, _)) = batch {
+        torn_at = Some(ops[0].1);
+    }
+    Ok(LogRecords {
+        records,
+        dropped,
+        torn_at,
+    })
+}
+
+type Index = KeyDir<InMemIndex>;
+
+/// Time of a record written at `ts`, `None` for records of older versions
+/// written without timestamps
+fn modified_at(ts: u64) -> Option<SystemTime> {
+    (ts != 0).then(|| UNIX_EPOCH + Duration::from_millis(ts))
+}
+
+/// `len` and `ts` are kept here so metadata queries never touch the log
+/// `rec_len` is the length of the whole record, used to count dead bytes
+#[derive(Clone, PartialEq)]
+struct InMemIndex {
+    version: usize,
+    start_pos: usize,
+    len: usize,
+    rec_len: usize,
+    ts: u64,
+    expires: Option<u64>,
+    envelope: Envelope,
+}
+
+/// Log and offset of the record a key maps to, see `Txn`
+pub(crate) type KeyVersion = (usize, usize);
+
+impl InMemIndex {
+    fn key_version(&self) -> KeyVersion {
+        (self.version, self.start_pos)
+    }
+
+    /// An expired key stays in the index until it is overwritten or compacted
+    fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Whether the entry of `key` is neither expired nor purged
+    fn visible(&self, key: &str, now: u64, purges: &Purges) -> bool {
+        !self.expired(now) && !purges.covers(key, self)
+    }
+}
+
+/// Prefixes removed at once by `KvStore::remove_prefix`
+///
+/// A key under a purged prefix whose record is not newer than the purge is
+/// gone, even while its entry is still in the index. Reads treat it like an
+/// expired key and compaction leaves its record out. A purge is forgotten
+/// once no log written before it is left, the next open applies the ones
+/// still in the logs while it replays them.
+#[derive(Default)]
+struct Purges {
+    list: RwLock<Vec<Purge>>,
+}
+
+#[derive(Clone)]
+struct Purge {
+    prefix: String,
+    // records written up to this millisecond are covered
+    ts: u64,
+    // log of its tombstone, older logs may hold covered records
+    version: usize,
+}
+
+impl Purges {
+    fn covers(&self, key: &str, entry: &InMemIndex) -> bool {
+        self.covers_record(key, entry.ts)
+    }
+
+    /// Whether the record of `key` written at `ts` is purged
+    fn covers_record(&self, key: &str, ts: u64) -> bool {
+        let list = self.list.read().unwrap();
+        list.iter()
+            .any(|p| ts <= p.ts && key.starts_with(&p.prefix))
+    }
+
+    /// Keys of `index` in the range, the purged ones aside
+    /// The index answers alone while there is no purge.
+    fn count_range(&self, index: &Index, start: Bound<String>, end: Bound<String>) -> usize {
+        if self.is_empty() {
+            return index.count_range(start, end);
+        }
+        index.count_matching(start, end, |key, entry| !self.covers(key, entry))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.list.read().unwrap().is_empty()
+    }
+
+    /// Timestamp of the latest purge, 0 without any
+    fn latest(&self) -> u64 {
+        let list = self.list.read().unwrap();
+        list.iter().map(|p| p.ts).max().unwrap_or(0)
+    }
+
+    fn add(&self, purge: Purge) {
+        self.list.write().unwrap().push(purge);
+    }
+
+    /// Drop the purges older than every log, `oldest` is the oldest log
+    fn forget(&self, oldest: usize) {
+        self.list.write().unwrap().retain(|p| p.version >= oldest);
+    }
+
+    /// A copy which later purges leave untouched, for a snapshot
+    fn frozen(&self) -> Self {
+        Self {
+            list: RwLock::new(self.list.read().unwrap().clone()),
+        }
+    }
+}
+
+/// A write applied to the store, delivered to `KvStore::changefeed`
+/// Replaying the changes in order on a copy of the store reproduces it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Set {
+        key: String,
+        value: String,
+    },
+    /// A set which expires at `expires`, in milliseconds since the unix epoch
+    SetExpiring {
+        key: String,
+        value: String,
+        expires: u64,
+    },
+    /// A set whose value carries an envelope, e.g. a content type
+    SetEnveloped {
+        key: String,
+        value: String,
+        expires: Option<u64>,
+        envelope: Envelope,
+    },
+    Remove {
+        key: String,
+    },
+    RemoveRange {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+    /// Every key starting with `prefix`, see `KvStore::remove_prefix`
+    RemovePrefix {
+        prefix: String,
+    },
+}
+
+impl Change {
+    /// Key of a set or a removal of one key
+    fn key(&self) -> Option<&str> {
+        match self {
+            Change::Set { key, .. }
+            | Change::SetExpiring { key, .. }
+            | Change::SetEnveloped { key, .. }
+            | Change::Remove { key } => Some(key),
+            Change::RemoveRange { .. } | Change::RemovePrefix { .. } => None,
+        }
+    }
+
+    /// Whether it only writes keys the store keeps for itself, see `RESERVED`
+    pub(crate) fn is_reserved(&self) -> bool {
+        match self {
+            Change::RemoveRange {
+                start: Bound::Included(key) | Bound::Excluded(key),
+                ..
+            } => is_reserved(key),
+            Change::RemoveRange { .. } => false,
+            Change::RemovePrefix { prefix } => is_reserved(prefix),
+            change => change.key().is_some_and(is_reserved),
+        }
+    }
+
+    /// Value of a set
+    fn value(&self) -> Option<&str> {
+        match self {
+            Change::Set { value, .. }
+            | Change::SetExpiring { value, .. }
+            | Change::SetEnveloped { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// A change stamped with the time and the region it was written in
+/// Sent between the regions of an active/active deployment, see
+/// `KvStore::apply_remote`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RegionChange {
+    pub origin: String,
+    /// In milliseconds since the unix epoch
+    pub ts: u64,
+    pub change: Change,
+}
+
+/// A remote change dropped because the local write of its key is newer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// When it was dropped, in milliseconds since the unix epoch
+    pub at: u64,
+    /// Time of the local write which was kept
+    pub local_ts: u64,
+    pub change: RegionChange,
+}
+
+/// How far the replay of the logs is, while a store opens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    pub segments_done: usize,
+    pub segments_total: usize,
+    /// Keys in the index so far
+    pub keys_indexed: usize,
+    pub elapsed: Duration,
+    /// Estimated from the bytes replayed so far
+    pub eta: Option<Duration>,
+}
+
+/// Called after each log replayed by `KvStoreBuilder::open`
+pub type RecoveryCallback = Arc<dyn Fn(&RecoveryProgress) + Send + Sync>;
+
+/// Events about log files and the index, delivered to `KvStore::subscribe`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum EngineEvent {
+    /// The active log is full and becomes read-only
+    SegmentSealed { version: usize },
+    /// A compaction of `logs` sealed logs began in the background
+    CompactionStarted { logs: usize },
+    /// All old logs are merged, `reclaimed` bytes of disk are freed
+    CompactionFinished { reclaimed: u64 },
+    /// The index is replaced and now holds `keys` keys
+    IndexRebuilt { keys: usize },
+    /// `key` reached its TTL and is dropped, on the first read after
+    /// that or by the next compaction
+    Expired { key: String },
+}
+
+/// Whether writes are currently throttled because compaction falls behind
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStatus {
+    Normal,
+    /// Each write is delayed a little
+    Delayed,
+    /// Writes wait until compaction catches up
+    Stalled,
+}
+
+/// A snapshot of the store status
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoreStats {
+    /// Keys in the index, the expired ones not removed yet included
+    pub keys: usize,
+    /// Keys the store keeps for itself, e.g. previous versions, not in `keys`
+    pub reserved_keys: usize,
+    /// Number of sealed log files
+    pub segments: usize,
+    /// Bytes of all logs, the active one included
+    pub disk_bytes: usize,
+    /// Most bytes the logs may take, `None` without a quota
+    pub disk_quota: Option<u64>,
+    /// Bytes of records which are overwritten or removed
+    pub dead_bytes: usize,
+    /// When a compaction last finished, `None` if none did since the open
+    pub last_compaction: Option<SystemTime>,
+    pub write_status: WriteStatus,
+    /// The store is read-only as free disk space is below the reserve
+    pub disk_full: bool,
+    /// Estimated bytes of memory used, `None` without a memory budget
+    pub memory_usage: Option<usize>,
+    /// The stores sharing the memory budget went over it, caches are shrunk
+    pub memory_pressure: bool,
+    /// Bytes/sec budget of compaction I/O, `None` means unlimited
+    pub compaction_rate: Option<u64>,
+    /// Sealed logs with a smaller share of live bytes are compacted
+    pub compaction_threshold: f64,
+    /// Total time compaction has been throttled by the budget
+    pub compaction_throttled: Duration,
+    /// Number of reads which found a corrupted value
+    pub corrupted_reads: u64,
+    /// Reads answered by the value cache, 0 without one
+    pub cache_hits: u64,
+    /// Gets of missing keys answered by the index, without a read or a lock
+    pub index_misses: u64,
+    /// Gets of keys in the index which were gone once read, e.g. removed
+    /// meanwhile; the index is exact, so no other key gets through
+    pub false_positives: u64,
+    /// Gets which shared the read of a concurrent get, 0 without coalescing
+    pub coalesced_reads: u64,
+    /// Gets served by the sweep another get of the same log ran
+    pub merged_reads: u64,
+    /// Writes and reclaimed bytes lately, kept across restarts
+    pub growth: GrowthStats,
+    /// The previous run did not close the store, so the logs were verified
+    pub unclean_shutdown: bool,
+    /// Corrupted records dropped while verifying the logs
+    pub skipped_records: usize,
+    pub traffic: Traffic,
+    /// `None` unless the store is durable
+    pub sync: Option<SyncStats>,
+    pub writer: WriterStats,
+    #[cfg(feature = "metrics")]
+    pub latency: LatencyStats,
+}
+
+/// Bytes taken by the keys under a prefix, see `KvStore::estimate_size`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SizeEstimate {
+    pub keys: usize,
+    /// Index entries the estimate is made of, all of them when equal to `keys`
+    pub samples: usize,
+    /// Bytes of the latest record of each key, not of the records it replaced
+    pub bytes: u64,
+}
+
+/// Network traffic of the clients of a store, recorded by the server
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub requests: u64,
+    /// Bytes of requests, including the line feed
+    pub bytes_in: u64,
+    /// Bytes of responses, including the line feed
+    pub bytes_out: u64,
+}
+
+/// One page of a cursor scan
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ScanPage {
+    /// Pass it to the next call, `None` once the scan is complete
+    pub cursor: Option<String>,
+    pub entries: Vec<(String, String)>,
+}
+
+/// A range scan, see `KvsEngine::scan`
+///
+/// Keys are taken from the index `RANGE_SCAN_BATCH` at a time, each batch
+/// starting after the last key of the previous one, so compaction never
+/// makes it skip or repeat a key.
+pub struct RangeScan<E = KvStore> {
+    store: E,
+    index: Arc<Index>,
+    // after the last key taken from the index
+    start: Bound<String>,
+    end: Bound<String>,
+    keys: VecDeque<String>,
+}
+
+impl<E> RangeScan<E> {
+    fn next_key(&mut self) -> Option<String> {
+        if self.keys.is_empty() {
+            let keys = self.index.page(
+                self.start.clone(),
+                self.end.clone(),
+                RANGE_SCAN_BATCH,
+                |_| true,
+            );
+            self.start = Bound::Excluded(keys.last()?.clone());
+            self.keys = keys.into();
+        }
+        self.keys.pop_front()
+    }
+}
+
+impl<E: KvsEngine> Iterator for RangeScan<E> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.next_key()?;
+            match self.store.get(key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// A range scan with the time each pair was written, see
+/// `KvStore::scan_modified`
+///
+/// The time is looked up before the value, so it is never later than the
+/// write of the value returned. A tool copying the keys modified since its
+/// last run may copy a pair twice, but never misses one.
+pub struct ModifiedScan<E = KvStore>(RangeScan<E>);
+
+impl<E: KvsEngine> Iterator for ModifiedScan<E> {
+    type Item = Result<(String, String, Option<SystemTime>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.0.next_key()?;
+            let Some(ts) = self.0.index.get(&key).map(|index| index.ts) else {
+                continue;
+            };
+            match self.0.store.get(key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value, modified_at(ts)))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Open snapshots, and the logs compaction replaced while they were open
+///
+/// Compactions are numbered. A snapshot taken before compaction `n` finished
+/// may read its inputs, so they are only deleted once no such snapshot is
+/// left. Later snapshots never see them in their index.
+#[derive(Default)]
+struct Pins {
+    // compactions finished so far
+    epoch: u64,
+    // number of open snapshots by the epoch they were taken at
+    live: BTreeMap<u64, usize>,
+    // replaced logs not deleted yet, with the epoch of their compaction
+    replaced: Vec<(usize, u64)>,
+}
+
+impl Pins {
+    /// Take the replaced logs no open snapshot reads, oldest first
+    fn unpinned(&mut self) -> Vec<usize> {
+        let oldest = self.live.keys().next().copied();
+        let (mut free, pinned): (Vec<_>, Vec<_>) = self
+            .replaced
+            .iter()
+            .partition(|(_, epoch)| oldest.is_none_or(|oldest| oldest >= *epoch));
+        self.replaced = pinned;
+        free.sort_unstable();
+        free.into_iter().map(|(v, _)| v).collect()
+    }
+}
+
+/// Snapshots of the keys pinned by open cursors
+#[derive(Default)]
+struct ScanCursors {
+    next_id: u64,
+    pinned: HashMap<u64, (Snapshot<InMemIndex>, Instant)>,
+}
+
+/// Information about a key, answered purely from the in-memory index
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeyMetadata {
+    /// Size of the value in bytes
+    pub value_size: usize,
+    /// `None` if the record was written by an older version without timestamps
+    pub last_modified: Option<SystemTime>,
+    /// Version of the log file holding the value
+    pub version: usize,
+    /// Time left before the key expires, `None` if it never expires
+    pub ttl: Option<Duration>,
+    #[serde(default)]
+    pub envelope: Envelope,
+}
+
+/// How a value is encoded, kept with it in the log and in the index
+///
+/// The store never looks inside a value. The envelope lets the server hand
+/// it out in the representation a client asks for, and tools label it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// MIME type of the value, e.g. `application/json`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// The value is base64 encoded gzip
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    /// CRC32 of the value, set by the store if value checksums are enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u32>,
+}
+
+impl Envelope {
+    /// Nothing is known about the value, it is an opaque string
+    pub fn is_plain(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The value points to the chunks of a value, see `KvStore::set_from_reader`
+    pub(crate) fn is_chunked(&self) -> bool {
+        self.content_type.as_deref() == Some(chunk::CHUNKED)
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl KvsEngine for KvStore {
+    /// Map `key` to `value` in the kv store
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// let mut kvs = KvStore::new();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// ```
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_active()?;
+        trace!("in kvs: set");
+        check_user_key(&key)?;
+        self.metrics.time(Operation::Set, || {
+            self.write("set", |writer| {
+                self.set_kept(writer, Change::Set { key, value })
+            })
+        })
+    }
+
+    /// If `key` is in the kv store, return the `Some(value)`
+    /// Otherwise, return `None`
+    ///
+    /// The index holds every key in memory and is never spilled, so a miss is
+    /// answered without probing any log, and a hit reads exactly one record.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// let mut kvs = KvStore::new();
+    /// let k1 = String::from("jack");
+    /// let k2 = String::from("jone");
+    /// let v1 = String::from("2024");
+    /// kvs.set(k1, v1);
+    /// assert_eq!(kvs.get(String::from("jack")).unwrap(), Some(String::from("2024")));
+    /// assert_eq!(kvs.get(k2).unwrap(), None);
+    /// ```
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.check_active()?;
+        self.metrics.time(Operation::Get, || {
+            if let Some(f) = &self.merge {
+                return Ok(self.merged(&key, f)?.0);
+            }
+            match self.lookup(&key)? {
+                Some((value, index)) => Ok(Some(self.assemble(&key, value, &index)?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Values living in the same log are read in one pass, in offset order
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// let keys = ["jack".to_string(), "jone".to_string()];
+    /// assert_eq!(kvs.get_many(&keys).unwrap(), vec![Some("2024".to_string()), None]);
+    /// ```
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        self.check_active()?;
+        // the operands of each key are looked up anyway
+        if self.merge.is_some() {
+            return keys.iter().map(|key| self.get(key.clone())).collect();
+        }
+        let now = now_millis();
+        let mut lookups = Vec::with_capacity(keys.len());
+        // whether each key is looked up, in that case its record is next
+        let mut found = Vec::with_capacity(keys.len());
+        // whether its record points to the chunks of its value
+        let mut chunked = Vec::with_capacity(keys.len());
+        for key in keys {
+            let index = self
+                .entry_to_index
+                .get(key)
+                .filter(|i| i.visible(key, now, &self.purges));
+            found.push(index.is_some());
+            chunked.push(index.as_ref().is_some_and(|i| i.envelope.is_chunked()));
+            lookups.extend(index.map(|index| (key.as_str(), index)));
+        }
+        let mut records = self.kv_reader.get_many(&lookups)?.into_iter();
+
+        let mut values = Vec::with_capacity(keys.len());
+        for ((key, found), chunked) in keys.iter().zip(found).zip(chunked) {
+            let record = if found { records.next() } else { None };
+            let value = match record {
+                Some(Ok(_)) if chunked => self.get(key.clone())?,
+                Some(Ok(value)) => Some(value),
+                // a compaction may have moved it, `get` retries; it also
+                // reports an expired key
+                Some(Err(_)) | None if self.entry_to_index.get(key).is_some() => {
+                    self.get(key.clone())?
+                }
+                _ => {
+                    self.misses.count(found);
+                    None
+                }
+            };
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// If `key` is in the kv store, remove it
+    /// Otherwise, do nothing
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::KvStore;
+    /// let mut kvs = KvStore::new();
+    /// let k1 = String::from("jack");
+    /// let v1 = String::from("2024");
+    /// kvs.set(k1, v1);
+    /// assert_eq!(kvs.get(String::from("jack")), Some(String::from("2024")));
+    /// kvs.rm(String::from("jack"));
+    /// assert_eq!(kvs.get(String::from("jack")), None);
+    /// ```
+    fn remove(&self, key: String) -> Result<()> {
+        self.check_active()?;
+        trace!("in kvs remove");
+        check_user_key(&key)?;
+        self.metrics.time(Operation::Remove, || {
+            self.write("remove", |writer| writer.remove(key))
+        })
+    }
+
+    /// Map `key` to `value` only if `key` is not in the kv store
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// assert!(kvs.set_if_absent("jack".to_string(), "2024".to_string()).unwrap());
+    /// assert!(!kvs.set_if_absent("jack".to_string(), "2025".to_string()).unwrap());
+    /// ```
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.check_active()?;
+        trace!("in kvs: set if absent");
+        check_user_key(&key)?;
+        // the writer lock is held, so check and append are atomic
+        self.write("set if absent", |writer| {
+            if writer.live(&key) {
+                return Ok(false);
+            }
+            writer.set(key, value)?;
+            Ok(true)
+        })
+    }
+
+    /// Map `key` to `value` only if `key` is already in the kv store
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.check_active()?;
+        trace!("in kvs: set if present");
+        check_user_key(&key)?;
+        self.write("set if present", |writer| {
+            if !writer.live(&key) {
+                return Ok(false);
+            }
+            self.set_kept(writer, Change::Set { key, value })?;
+            Ok(true)
+        })
+    }
+
+    /// Remove `key` and return the value it used to hold
+    ///
+    /// The writer lock is held during the whole read-modify-write, so no
+    /// other mutation can slip in between.
+    fn take(&self, key: String) -> Result<Option<String>> {
+        self.check_active()?;
+        trace!("in kvs: take");
+        check_user_key(&key)?;
+        self.write("take", |writer| {
+            let old = self.get(key.clone())?;
+            if old.is_some() {
+                writer.remove(key)?;
+            }
+            Ok(old)
+        })
+    }
+
+    /// Map `key` to `value` and return the value it used to hold
+    fn insert(&self, key: String, value: String) -> Result<Option<String>> {
+        self.check_active()?;
+        trace!("in kvs: insert");
+        check_user_key(&key)?;
+        self.write("insert", |writer| {
+            let old = self.get(key.clone())?;
+            self.set_kept(writer, Change::Set { key, value })?;
+            Ok(old)
+        })
+    }
+
+    /// Remove all keys inside `range`, return how many are removed
+    ///
+    /// Only one tombstone record is appended, no matter how many keys match,
+    /// and the writer lock is taken once for all of them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set("a".to_string(), "1".to_string()).unwrap();
+    /// kvs.set("b".to_string(), "2".to_string()).unwrap();
+    /// assert_eq!(kvs.remove_range("a".to_string().."b".to_string()).unwrap(), 1);
+    /// ```
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<usize> {
+        self.check_active()?;
+        trace!("in kvs: remove range");
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        if let Bound::Included(key) | Bound::Excluded(key) = &start {
+            check_user_key(key)?;
+        }
+        self.write("remove range", |writer| writer.remove_range(start, end))
+    }
+
+    /// Remove every key starting with `prefix` with one prefix tombstone
+    /// The keys are dropped lazily, so no record is written per key. Return
+    /// the number of keys removed, expired ones too.
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        self.check_active()?;
+        trace!("in kvs: remove prefix");
+        check_user_key(prefix)?;
+        self.write("remove prefix", |writer| {
+            writer.purge_prefix(prefix.to_owned())
+        })
+    }
+
+    /// Count the keys starting with `prefix`, answered by the index only
+    /// Expired keys not reclaimed yet are counted too.
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        self.check_active()?;
+        let (start, end) = prefix_range(prefix);
+        Ok(self.purges.count_range(&self.entry_to_index, start, end))
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.check_active()?;
+        Ok(self.key_count())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.check_active()?;
+        let now = now_millis();
+        Ok(self
+            .entry_to_index
+            .get(key)
+            .is_some_and(|index| index.visible(key, now, &self.purges)))
+    }
+
+    /// Only the keys sharing the literal prefix of `pattern` are visited
+    fn keys(&self, pattern: &Pattern) -> Result<Vec<String>> {
+        self.check_active()?;
+        let (start, end) = prefix_range(pattern.prefix());
+        let now = now_millis();
+        Ok(self.entry_to_index.keys_matching(start, end, |k| {
+            pattern.matches(k)
+                && self
+                    .entry_to_index
+                    .get(k)
+                    .is_some_and(|index| index.visible(k, now, &self.purges))
+        }))
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<ScanIter> {
+        self.check_active()?;
+        Ok(Box::new(RangeScan {
+            store: self.clone(),
+            index: Arc::clone(&self.entry_to_index),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            keys: VecDeque::new(),
+        }))
+    }
+}
+
+impl KvStore {
+    /// Create a new KvStore using default directory
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use kvs::engine::kvs::KvStore;
+    /// let kvs = KvStore::new().unwrap();
+    /// ```
+    pub fn new() -> Result<Self> {
+        let cwd = env::current_dir()?;
+        Self::open(cwd)
+    }
+
+    /// Report the status of the store, e.g. whether writes are stalled
+    pub fn stats(&self) -> Result<StoreStats> {
+        let writer_stats = self.writer_stats();
+        let writer = self.lock_writer("stats");
+        writer.count_memory();
+        Ok(StoreStats {
+            keys: self.key_count(),
+            reserved_keys: self.entry_to_index.reserved(),
+            segments: writer.segments(),
+            disk_bytes: writer.log_bytes() as usize,
+            disk_quota: writer.disk_quota,
+            dead_bytes: writer.garbage.dead(),
+            last_compaction: writer.last_compaction,
+            write_status: writer.write_status,
+            disk_full: writer.disk_full,
+            memory_usage: writer.memory.as_ref().map(|m| m.usage.total()),
+            memory_pressure: writer.memory.as_ref().is_some_and(|m| m.budget.check()),
+            compaction_rate: writer.compaction_rate,
+            compaction_threshold: writer.compaction_threshold,
+            compaction_throttled: writer.compaction_throttled,
+            corrupted_reads: self.kv_reader.corruptions.load(Ordering::SeqCst),
+            cache_hits: self.kv_reader.cache.as_ref().map_or(0, |c| c.hits()),
+            index_misses: self.misses.index.load(Ordering::SeqCst),
+            false_positives: self.misses.false_positives.load(Ordering::SeqCst),
+            coalesced_reads: self.kv_reader.flights.as_ref().map_or(0, |f| f.joined()),
+            merged_reads: self.kv_reader.scheduler.merged(),
+            growth: writer
+                .growth
+                .stats(now_millis(), fs2::available_space(writer.dir.as_path())?),
+            unclean_shutdown: writer.unclean_shutdown,
+            skipped_records: writer.skipped_records,
+            traffic: self.traffic(),
+            sync: writer.syncer.as_ref().map(|s| s.stats()),
+            writer: writer_stats,
+            #[cfg(feature = "metrics")]
+            latency: self.metrics.latency(),
+        })
+    }
+
+    /// Group commits of the sync thread, `None` unless writes are synced
+    pub fn sync_stats(&self) -> Option<SyncStats> {
+        let syncer = self.lock_writer("sync stats").syncer.clone();
+        syncer.map(|s| s.stats())
+    }
+
+    /// Who holds the writer lock right now, and the longest hold so far
+    pub fn writer_stats(&self) -> WriterStats {
+        let leases = self.leases.lock().unwrap();
+        WriterStats {
+            holder: leases
+                .holder
+                .as_ref()
+                .map(|(op, thread, since)| WriterLease {
+                    op: (*op).to_owned(),
+                    thread: thread.clone(),
+                    held: since.elapsed(),
+                }),
+            longest: leases.longest.clone(),
+        }
+    }
+
+    /// Take the writer lock on behalf of `op`
+    fn lock_writer(&self, op: &'static str) -> WriterGuard<'_> {
+        self.lease_writer(self.kv_writer.lock().unwrap(), op)
+    }
+
+    /// Take the writer lock on behalf of `op`, unless it is held already
+    /// Also by this very thread, so it never deadlocks.
+    fn try_lock_writer(&self, op: &'static str) -> Option<WriterGuard<'_>> {
+        let writer = self.kv_writer.try_lock().ok()?;
+        Some(self.lease_writer(writer, op))
+    }
+
+    fn lease_writer<'a>(
+        &'a self,
+        writer: MutexGuard<'a, KvStoreWriter>,
+        op: &'static str,
+    ) -> WriterGuard<'a> {
+        let since = Instant::now();
+        self.leases.lock().unwrap().holder = Some((op, thread_label(), since));
+        WriterGuard {
+            writer,
+            leases: &self.leases,
+            op,
+            since,
+        }
+    }
+
+    /// Account a request of `bytes_in` bytes answered by `bytes_out` bytes
+    pub fn record_traffic(&self, bytes_in: u64, bytes_out: u64) {
+        let mut traffic = self.traffic.lock().unwrap();
+        traffic.requests += 1;
+        traffic.bytes_in += bytes_in;
+        traffic.bytes_out += bytes_out;
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        *self.traffic.lock().unwrap()
+    }
+
+    /// Register a long-running operation described by `op`, e.g. a scan
+    ///
+    /// It is listed by `operations` until the guard is dropped, and stops
+    /// cooperatively: `cancel_operation` only takes effect at its next
+    /// `OperationGuard::check`.
+    pub fn start_operation(&self, op: impl Into<String>) -> OperationGuard {
+        let id = NEXT_OPERATION.fetch_add(1, Ordering::SeqCst);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let op = op.into();
+        info!("Start operation {}: {}", id, op);
+        self.operations
+            .lock()
+            .unwrap()
+            .insert(id, (op, Instant::now(), Arc::clone(&cancelled)));
+        OperationGuard {
+            id,
+            cancelled,
+            operations: Arc::clone(&self.operations),
+        }
+    }
+
+    /// Ask operation `id` to stop, return whether it runs on this store
+    pub fn cancel_operation(&self, id: u64) -> bool {
+        match self.operations.lock().unwrap().get(&id) {
+            Some((op, _, cancelled)) => {
+                info!("Cancel operation {}: {}", id, op);
+                cancelled.store(true, Ordering::SeqCst);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Operations running on this store, oldest first
+    pub fn operations(&self) -> Vec<OperationInfo> {
+        self.operations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, (op, started, cancelled))| OperationInfo {
+                id,
+                op: op.clone(),
+                elapsed: started.elapsed(),
+                cancelled: cancelled.load(Ordering::SeqCst),
+            })
+            .collect()
+    }
+
+    /// Claim an idempotency token before running its request
+    ///
+    /// Return the response sent the first time if the token was seen
+    /// recently, `None` if the request has to run; then pass its response to
+    /// `settle_token`. Fails while the first run of the token is not over.
+    pub fn claim_token(&self, token: &str) -> Result<Option<Vec<u8>>> {
+        let mut tokens = self.tokens.lock().unwrap();
+        match tokens.responses.get(token) {
+            Some(Some(response)) => return Ok(Some(response.clone())),
+            Some(None) => return Err(KvsError::TokenInFlight(token.to_owned())),
+            None => {}
+        }
+        tokens.responses.insert(token.to_owned(), None);
+        tokens.order.push_back(token.to_owned());
+        if tokens.order.len() > TOKEN_WINDOW {
+            let oldest = tokens.order.pop_front().unwrap();
+            tokens.responses.remove(&oldest);
+        }
+        Ok(None)
+    }
+
+    /// Give up a claim of `claim_token` without a response
+    /// The request failed before it could answer, so a retry runs it again.
+    pub fn release_token(&self, token: &str) {
+        let mut tokens = self.tokens.lock().unwrap();
+        if let Some(None) = tokens.responses.get(token) {
+            tokens.responses.remove(token);
+            if let Some(i) = tokens.order.iter().rposition(|t| t == token) {
+                tokens.order.remove(i);
+            }
+        }
+    }
+
+    /// Remember the response of a request claimed by `claim_token`
+    pub fn settle_token(&self, token: &str, response: Vec<u8>) {
+        let mut tokens = self.tokens.lock().unwrap();
+        // unless it left the window meanwhile
+        if let Some(slot) = tokens.responses.get_mut(token) {
+            *slot = Some(response);
+        }
+    }
+
+    /// Receive every engine event from now on
+    ///
+    /// Drop the receiver to unsubscribe. Events are buffered, so a slow
+    /// receiver never blocks the writer.
+    pub fn subscribe(&self) -> Receiver<EngineEvent> {
+        let (tx, rx) = channel();
+        self.lock_writer("subscribe").subscribers.push(tx);
+        rx
+    }
+
+    /// Receive every write applied from now on, in order
+    ///
+    /// Drop the receiver to unsubscribe. A replica first copies the current
+    /// pairs, e.g. with `scan_page`, then applies the changes; applying a
+    /// change twice is harmless.
+    pub fn changefeed(&self) -> Receiver<Change> {
+        let (tx, rx) = channel();
+        self.lock_writer("changefeed").feed.push(tx);
+        rx
+    }
+
+    /// Receive the writes to the keys starting with `prefix` from now on, in
+    /// order, each once it is durable
+    ///
+    /// With a sync policy a write is sent after the sync covering it, without
+    /// one right after it is appended. Drop the receiver to stop watching.
+    pub fn watch(&self, prefix: impl Into<String>) -> Receiver<ChangeEvent> {
+        self.watch_with(Matcher::prefix(prefix.into()))
+    }
+
+    /// Like `watch`, for the writes `filter` lets through
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::{ChangeEvent, KvStore, WatchFilter, WriteKind}};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// let filter = WatchFilter {
+    ///     keys: vec!["jack".to_string()],
+    ///     only: Some(WriteKind::Set),
+    ///     value: Some("^20".to_string()),
+    ///     ..WatchFilter::default()
+    /// };
+    /// let events = kvs.watch_filtered(filter).unwrap();
+    /// kvs.set("jack".to_string(), "1999".to_string()).unwrap();
+    /// kvs.set("rose".to_string(), "2024".to_string()).unwrap();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// let set = ChangeEvent::Set { key: "jack".to_string(), value: "2024".to_string() };
+    /// assert_eq!(events.recv().unwrap(), set);
+    /// ```
+    pub fn watch_filtered(&self, filter: WatchFilter) -> Result<Receiver<ChangeEvent>> {
+        Ok(self.watch_with(Matcher::new(filter)?))
+    }
+
+    fn watch_with(&self, matcher: Matcher) -> Receiver<ChangeEvent> {
+        let mut writer = self.lock_writer("watch");
+        let syncer = writer.syncer.clone();
+        writer
+            .watchers
+            .get_or_insert_with(|| Watchers::start(syncer))
+            .watch(matcher)
+    }
+
+    /// Apply a change of the primary to this standby
+    ///
+    /// Removing a missing key is not an error, the change may have been
+    /// copied already.
+    pub fn apply(&self, change: Change) -> Result<()> {
+        self.write("apply", |writer| {
+            if !self.is_standby() {
+                return Err(KvsError::NotStandby);
+            }
+            writer.apply_change(change)
+        })
+    }
+
+    /// Receive every write applied from now on, with its time and region
+    /// The changes applied by `apply_remote` keep the region they came from.
+    pub fn region_feed(&self) -> Receiver<RegionChange> {
+        let (tx, rx) = channel();
+        self.lock_writer("region feed").region_feed.push(tx);
+        rx
+    }
+
+    /// Region of the store, see `KvStoreBuilder::region`
+    pub fn region(&self) -> String {
+        self.lock_writer("region").region.clone()
+    }
+
+    /// Apply a change written in another region, unless the local write of
+    /// its key is newer
+    ///
+    /// The last writer wins: the change is applied, keeping its time, if it
+    /// was written after the local set or removal of its key. Writes of the
+    /// same millisecond are settled by their values, so both regions agree.
+    /// A change which loses is appended to the conflict log, see `conflicts`.
+    /// Removals of ranges and prefixes are always applied. Return whether
+    /// the change is applied.
+    pub fn apply_remote(&self, remote: RegionChange) -> Result<bool> {
+        self.check_active()?;
+        self.write("apply remote", |writer| {
+            if let Some(key) = remote.change.key() {
+                let local_ts = match self.entry_to_index.get(key) {
+                    Some(index) => Some(index.ts),
+                    None => writer.removed.peek(key).copied(),
+                };
+                if let Some(local_ts) = local_ts {
+                    let newer = match remote.ts.cmp(&local_ts) {
+                        cmp::Ordering::Greater => true,
+                        cmp::Ordering::Less => false,
+                        cmp::Ordering::Equal => {
+                            let local = self.get(key.to_owned())?;
+                            if remote.change.value() == local.as_deref() {
+                                // the same write, e.g. sent back by the copy
+                                return Ok(false);
+                            }
+                            remote.change.value() > local.as_deref()
+                        }
+                    };
+                    if !newer {
+                        writer.log_conflict(&remote, local_ts)?;
+                        return Ok(false);
+                    }
+                }
+            }
+            writer.remote = Some((remote.origin, remote.ts));
+            let result = writer.apply_change(remote.change);
+            writer.remote = None;
+            result.map(|_| true)
+        })
+    }
+
+    /// Remote changes which lost to a newer local write, oldest first
+    pub fn conflicts(&self) -> Result<Vec<Conflict>> {
+        let content = match fs::read_to_string(self.dir.join(CONFLICT_LOG)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        content
+            .lines()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    /// Turn a standby into an active store which serves traffic
+    /// Every clone of the store is promoted at once, changes are refused after.
+    pub fn promote(&self) {
+        // under the writer lock, so no change is applied halfway
+        let _writer = self.lock_writer("promote");
+        self.standby.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+
+    /// Limits the store was opened with, also enforced by its server
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Run a write under the writer lock, then wait until it is durable
+    /// The wait happens without the lock, so concurrent writers share a sync.
+    fn write<T>(
+        &self,
+        op: &'static str,
+        f: impl FnOnce(&mut KvStoreWriter) -> Result<T>,
+    ) -> Result<T> {
+        let (result, written, syncer) = {
+            let mut writer = self.lock_writer(op);
+            let result = f(&mut writer)?;
+            (result, writer.written, writer.syncer.clone())
+        };
+        if let Some(syncer) = syncer {
+            syncer.sync(written)?;
+        }
+        Ok(result)
+    }
+
+    /// `get` without the timing, along with the index entry of the value
+    /// A missing key returns on the index, which never blocks readers, before
+    /// the reader, the cache or any lock is touched.
+    fn lookup(&self, key: &str) -> Result<Option<(String, InMemIndex)>> {
+        let mut index = self.entry_to_index.get(key);
+        let mut read = false;
+        while let Some(cur) = index {
+            // expired or purged lazily, the record is reclaimed by the next
+            // compaction
+            if !cur.visible(key, now_millis(), &self.purges) {
+                // a busy writer, maybe this thread's, leaves it to a later read
+                if let Some(mut writer) = self.try_lock_writer("expire") {
+                    writer.expire(key);
+                }
+                self.misses.count(false);
+                return Ok(None);
+            }
+            read = true;
+            match self.kv_reader.get(key, cur.clone()) {
+                Ok(s) => return Ok(Some((s, cur))),
+                Err(e) => {
+                    // No lock is held while reading, so a compaction may have
+                    // removed the log behind our snapshot. Retry with the
+                    // latest index, and only fail when it still points here.
+                    let latest = self.entry_to_index.get(key);
+                    if latest.as_ref() == Some(&cur) {
+                        return Err(e);
+                    }
+                    index = latest;
+                }
+            }
+        }
+        self.misses.count(read);
+        Ok(None)
+    }
+
+    /// The value found by `lookup`, with the chunks of a chunked one read in
+    fn assemble(&self, key: &str, value: String, index: &InMemIndex) -> Result<String> {
+        if !index.envelope.is_chunked() {
+            return Ok(value);
+        }
+        chunk::read_chunked(self.clone(), key, &value)
+    }
+
+    /// `get_versioned` with the operands of `key` folded into its value
+    /// The version is that of the value, merges do not change it.
+    fn merged(&self, key: &str, f: &MergeOperator) -> Result<(Option<String>, Option<KeyVersion>)> {
+        'retry: loop {
+            let (start, end) = merge::operand_range(key);
+            let operands = self.entry_to_index.reserved_keys(start, end);
+            let (value, version) = match self.lookup(key)? {
+                Some((value, index)) => (
+                    Some(self.assemble(key, value, &index)?),
+                    Some(index.key_version()),
+                ),
+                None => (None, None),
+            };
+            if operands.is_empty() {
+                return Ok((value, version));
+            }
+            let mut values = Vec::with_capacity(operands.len());
+            for operand in operands {
+                match self.lookup(&operand)? {
+                    Some((operand, _)) => values.push(operand),
+                    // folded or dropped along with a write of the value,
+                    // which may be read before it
+                    None => continue 'retry,
+                }
+            }
+            return Ok((Some(f(key, value.as_deref(), &values)), version));
+        }
+    }
+
+    /// Write the operands of `key` folded into its value, and drop them
+    fn fold(&self, writer: &mut KvStoreWriter, key: &str) -> Result<()> {
+        let Some(f) = &self.merge else {
+            return Ok(());
+        };
+        let mut batch = WriteBatch::new();
+        match self.merged(key, f)?.0 {
+            Some(value) => batch.set(key.to_owned(), value),
+            None => batch.remove(key.to_owned()),
+        };
+        writer.apply_batch(batch)
+    }
+
+    /// Keys in the index, the purged ones aside
+    fn key_count(&self) -> usize {
+        match self.purges.is_empty() {
+            true => self.entry_to_index.len(),
+            false => {
+                let index = &self.entry_to_index;
+                self.purges
+                    .count_range(index, Bound::Unbounded, Bound::Unbounded)
+            }
+        }
+    }
+
+    fn check_active(&self) -> Result<()> {
+        if self.is_standby() {
+            return Err(KvsError::Standby);
+        }
+        Ok(())
+    }
+
+    /// Store a checksum with every value written from now on
+    /// Values with a checksum are verified each time they are read.
+    pub fn set_value_checksum(&self, enabled: bool) {
+        self.lock_writer("set value checksum").value_checksum = enabled;
+    }
+
+    /// Limit compaction reads and writes to `bytes_per_sec`
+    /// So that compaction does not saturate the disk. `None` lifts the limit.
+    pub fn set_compaction_rate(&self, bytes_per_sec: Option<u64>) {
+        self.lock_writer("set compaction rate").compaction_rate = bytes_per_sec.filter(|&r| r > 0);
+    }
+
+    /// Compact a sealed log once less than `live_ratio` of it is live
+    /// 0 never compacts for garbage, 1 compacts any log with garbage.
+    pub fn set_compaction_threshold(&self, live_ratio: f64) {
+        self.lock_writer("set compaction threshold")
+            .compaction_threshold = live_ratio.clamp(0.0, 1.0);
+    }
+
+    /// Merge every sealed log and the active one now, however live
+    ///
+    /// Meant for off-peak hours, instead of waiting for a compaction to be
+    /// triggered under load. Writes wait until it is over, a compaction
+    /// already running is finished first.
+    pub fn compact(&self) -> Result<()> {
+        self.check_active()?;
+        let mut writer = self.lock_writer("compact");
+        // the operands are folded first, so they are compacted away
+        let merging: Vec<String> = writer.merging.keys().cloned().collect();
+        for key in merging {
+            self.fold(&mut writer, &key)?;
+        }
+        writer.compact()
+    }
+
+    /// Remove every key and rewrite the logs into a single empty one
+    ///
+    /// Meant to reset a test environment without deleting files by hand.
+    /// Return the number of keys removed, expired ones aside.
+    pub fn clear(&self) -> Result<usize> {
+        self.check_active()?;
+        self.write("clear", |writer| writer.clear())
+    }
+
+    /// Estimate the bytes the live records of the keys under `prefix` take
+    ///
+    /// Up to `SIZE_SAMPLES` index entries spread evenly over the prefix are
+    /// read, their mean record length is scaled by the number of keys. No log
+    /// is read, so it stays cheap for capacity planning on a large namespace.
+    pub fn estimate_size(&self, prefix: &str) -> Result<SizeEstimate> {
+        self.check_active()?;
+        let (start, end) = prefix_range(prefix);
+        let keys = self.entry_to_index.count_range(start.clone(), end.clone());
+        let stride = keys.div_ceil(SIZE_SAMPLES);
+        let samples = self.entry_to_index.sample_range(start, end, stride);
+        let sampled: usize = samples.iter().map(|entry| entry.rec_len).sum();
+        let bytes = match samples.len() {
+            0 => 0,
+            n => (sampled as f64 * keys as f64 / n as f64).round() as u64,
+        };
+        Ok(SizeEstimate {
+            keys,
+            samples: samples.len(),
+            bytes,
+        })
+    }
+
+    /// Set how many bytes of free disk space must be kept
+    /// Writes fail with `KvsError::DiskFull` below it
+    pub fn set_disk_reserve(&self, bytes: u64) {
+        self.lock_writer("set disk reserve").disk_reserve = bytes;
+    }
+
+    /// Set how many bytes the logs of the store may take, `None` for no quota
+    /// See `KvStoreBuilder::disk_quota`.
+    pub fn set_disk_quota(&self, bytes: Option<u64>) {
+        self.lock_writer("set disk quota").disk_quota = bytes;
+    }
+
+    /// Pin the current content of the store, see `KvSnapshot`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// let snapshot = kvs.snapshot().unwrap();
+    /// kvs.set("jack".to_string(), "2025".to_string()).unwrap();
+    /// assert_eq!(snapshot.get("jack".to_string()).unwrap(), Some("2024".to_string()));
+    /// ```
+    pub fn snapshot(&self) -> Result<KvSnapshot> {
+        self.check_active()?;
+        // under the lock, so no compaction finishes between the two
+        let (keys, epoch) = {
+            let mut pins = self.pins.lock().unwrap();
+            let keys = self.entry_to_index.pin();
+            let epoch = pins.epoch;
+            *pins.live.entry(epoch).or_default() += 1;
+            (keys, epoch)
+        };
+        // the reader keeps the compacted logs open, they are still ours
+        let mut reader = self.kv_reader.clone();
+        reader.min_version = Arc::new(AtomicU32::new(0));
+        Ok(KvSnapshot {
+            pin: Arc::new(SnapshotPin {
+                writer: Arc::clone(&self.kv_writer),
+                pins: Arc::clone(&self.pins),
+                index: Arc::new(Index::frozen(keys)),
+                purges: self.purges.frozen(),
+                epoch,
+                taken_at: now_millis(),
+            }),
+            reader,
+        })
+    }
+
+    /// Write a copy of the store as it is now into the directory `dir`, which
+    /// must be missing or empty
+    ///
+    /// The sealed logs are hard linked, so the copy costs little space and
+    /// time, or copied on a file system without links; the active log is
+    /// copied. The writer lock is held meanwhile, so the copy holds exactly
+    /// the writes done so far. Open it as any other store, e.g. to bootstrap
+    /// a replica or to keep it as a backup.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path().join("data")).unwrap();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// kvs.checkpoint(dir.path().join("backup")).unwrap();
+    /// kvs.set("jack".to_string(), "2025".to_string()).unwrap();
+    /// let backup = KvStore::open(dir.path().join("backup")).unwrap();
+    /// assert_eq!(backup.get("jack".to_string()).unwrap(), Some("2024".to_string()));
+    /// ```
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> Result<()> {
+        trace!("in kvs: checkpoint into {:?}", dir.as_ref());
+        self.lock_writer("checkpoint").checkpoint(dir.as_ref())
+    }
+
+    /// Return the next page of at most `count` pairs of a scan over all keys
+    ///
+    /// Start with `cursor` `None` and pass the returned cursor back until it
+    /// is `None`. The cursor holds the last returned key, not a log position,
+    /// so compaction never makes a scan skip or repeat a key. The keys which
+    /// existed when the scan started are pinned for `SCAN_CURSOR_TTL` after
+    /// each page, a key written later is not returned, a key removed later is
+    /// skipped. An expired cursor goes on with the keys present at that time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set("a".to_string(), "1".to_string()).unwrap();
+    /// kvs.set("b".to_string(), "2".to_string()).unwrap();
+    /// let page = kvs.scan_page(None, 1, None).unwrap();
+    /// assert_eq!(page.entries, vec![("a".to_string(), "1".to_string())]);
+    /// let page = kvs.scan_page(page.cursor, 1, None).unwrap();
+    /// assert_eq!(page.entries, vec![("b".to_string(), "2".to_string())]);
+    /// ```
+    pub fn scan_page(
+        &self,
+        cursor: Option<String>,
+        count: usize,
+        pattern: Option<&Pattern>,
+    ) -> Result<ScanPage> {
+        self.check_active()?;
+        let (id, after) = match &cursor {
+            None => (None, None),
+            Some(cursor) => {
+                let (id, key) = cursor.split_once(':').ok_or(KvsError::InvalidCursor)?;
+                let id: u64 = id.parse().map_err(|_| KvsError::InvalidCursor)?;
+                (Some(id), Some(key.to_owned()))
+            }
+        };
+
+        // only the keys with the literal prefix of the pattern are visited
+        let prefix = pattern.map_or("", |p| p.prefix());
+        let (prefix_start, end) = prefix_range(prefix);
+        let start = match after {
+            Some(key) if key.as_str() >= prefix => Bound::Excluded(key),
+            _ => prefix_start,
+        };
+        let count = count.max(1);
+        let filter = |k: &str| pattern.is_none_or(|p| p.matches(k));
+
+        let now = Instant::now();
+        let pinned = {
+            let mut scans = self.scans.lock().unwrap();
+            scans.pinned.retain(|_, (_, expires)| *expires > now);
+            match id {
+                Some(id) => scans.pinned.remove(&id).map(|(keys, _)| (id, keys)),
+                None => self.entry_to_index.snapshot().map(|keys| {
+                    scans.next_id += 1;
+                    (scans.next_id, keys)
+                }),
+            }
+        };
+        let keys = match &pinned {
+            Some((_, keys)) => keydir::page(keys, start, end, count, filter),
+            // expired, or the index can not be pinned
+            None => self.entry_to_index.page(start, end, count, filter),
+        };
+
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys.iter() {
+            if let Some(value) = self.get(key.clone())? {
+                entries.push((key.clone(), value));
+            }
+        }
+
+        let last = match keys.last() {
+            Some(last) if keys.len() == count => last,
+            _ => {
+                return Ok(ScanPage {
+                    cursor: None,
+                    entries,
+                });
+            }
+        };
+        // id 0 means the keys are not pinned
+        let id = match pinned {
+            Some((id, keys)) => {
+                let expires = Instant::now() + SCAN_CURSOR_TTL;
+                self.scans
+                    .lock()
+                    .unwrap()
+                    .pinned
+                    .insert(id, (keys, expires));
+                id
+            }
+            None => 0,
+        };
+        Ok(ScanPage {
+            cursor: Some(format!("{}:{}", id, last)),
+            entries,
+        })
+    }
+
+    /// Apply every write of `batch` atomically
+    ///
+    /// With the default ordered index readers see either none or all of the
+    /// writes, and so does the next open after a crash.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::{KvStore, WriteBatch}};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// let mut batch = WriteBatch::new();
+    /// batch
+    ///     .set("from".to_string(), "0".to_string())
+    ///     .set("to".to_string(), "100".to_string());
+    /// kvs.apply_batch(batch).unwrap();
+    /// assert_eq!(kvs.get("to".to_string()).unwrap(), Some("100".to_string()));
+    /// ```
+    pub fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.check_active()?;
+        trace!("in kvs: apply batch of {}", batch.len());
+        batch.check_keys()?;
+        self.write("apply batch", |writer| self.apply_kept(writer, batch))
+    }
+
+    /// Value of `key` at `version`, see `KvStoreBuilder::keep_versions`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::builder().keep_versions(8).open(dir.path()).unwrap();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// kvs.set("jack".to_string(), "2025".to_string()).unwrap();
+    /// assert_eq!(kvs.versions("jack").unwrap(), vec![1, 2]);
+    /// assert_eq!(kvs.get_at("jack".to_string(), 1).unwrap(), Some("2024".to_string()));
+    /// ```
+    pub fn get_at(&self, key: String, version: u64) -> Result<Option<String>> {
+        self.check_active()?;
+        let history = self.history(&key);
+        match history.last().map_or(1, |v| v + 1) == version {
+            true => self.get(key),
+            false => self.get(history_key(&key, version)),
+        }
+    }
+
+    /// Versions of `key` `get_at` reads, oldest first, the current one last
+    pub fn versions(&self, key: &str) -> Result<Vec<u64>> {
+        self.check_active()?;
+        let mut versions = self.history(key);
+        if self.contains_key(key)? {
+            versions.push(versions.last().map_or(1, |v| v + 1));
+        }
+        Ok(versions)
+    }
+
+    /// Previous versions of `key` still kept, oldest first
+    fn history(&self, key: &str) -> Vec<u64> {
+        let prefix = history_key(key, 0);
+        let prefix = &prefix[..prefix.len() - HISTORY_DIGITS];
+        let (start, end) = prefix_range(prefix);
+        self.entry_to_index
+            .reserved_keys(start, end)
+            .iter()
+            .map(|k| &k[prefix.len()..])
+            // the history of a key which goes on with a NUL is in the range too
+            .filter(|v| v.len() == HISTORY_DIGITS)
+            .filter_map(|v| v.parse().ok())
+            .collect()
+    }
+
+    /// Apply `batch`, keeping each value it overwrites as the latest
+    /// previous version of its key in the same batch, and dropping the
+    /// versions past `KvStoreBuilder::keep_versions`
+    fn apply_kept(&self, writer: &mut KvStoreWriter, batch: WriteBatch) -> Result<()> {
+        let keep = writer.keep_versions;
+        if keep == 0 {
+            return writer.apply_batch(batch);
+        }
+        let mut kept = WriteBatch::new();
+        let mut seen = HashSet::new();
+        for change in batch.changes.iter() {
+            let Some(key) = change.key().filter(|_| change.value().is_some()) else {
+                continue;
+            };
+            if !seen.insert(key) {
+                continue;
+            }
+            let Some(old) = self.get(key.to_owned())? else {
+                continue;
+            };
+            let history = self.history(key);
+            let version = history.last().map_or(1, |v| v + 1);
+            kept.set(history_key(key, version), old);
+            for v in history
+                .iter()
+                .take((history.len() + 1).saturating_sub(keep))
+            {
+                kept.remove(history_key(key, *v));
+            }
+        }
+        if kept.is_empty() {
+            return writer.apply_batch(batch);
+        }
+        kept.changes.extend(batch.changes);
+        writer.apply_batch(kept)
+    }
+
+    /// Write one set of `key`, through `apply_kept` if versions are kept
+    fn set_kept(&self, writer: &mut KvStoreWriter, change: Change) -> Result<()> {
+        if writer.keep_versions == 0 {
+            return writer.apply_change(change);
+        }
+        let mut batch = WriteBatch::new();
+        batch.changes.push(change);
+        self.apply_kept(writer, batch)
+    }
+
+    /// The keyspace `name` inside the store, see `Bucket`
+    ///
+    /// Buckets share the logs and the writer of the store, nothing is created
+    /// on disk, a bucket with no key is just empty.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// let users = kvs.bucket("users").unwrap();
+    /// users.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// assert_eq!(users.get("jack".to_string()).unwrap(), Some("2024".to_string()));
+    /// assert_eq!(kvs.bucket("teams").unwrap().get("jack".to_string()).unwrap(), None);
+    /// ```
+    pub fn bucket(&self, name: &str) -> Result<Bucket> {
+        Bucket::new(self.clone(), name)
+    }
+
+    /// Start an optimistic transaction, see `Txn`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// use kvs::error::KvsError;
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set("jack".to_string(), "10".to_string()).unwrap();
+    /// let mut txn = kvs.transaction();
+    /// let jack = txn.get("jack".to_string()).unwrap().unwrap();
+    /// txn.set("rose".to_string(), jack);
+    /// kvs.set("jack".to_string(), "20".to_string()).unwrap();
+    /// assert!(matches!(txn.commit(), Err(KvsError::Conflict(_))));
+    /// assert_eq!(kvs.get("rose".to_string()).unwrap(), None);
+    /// ```
+    pub fn transaction(&self) -> Txn {
+        Txn::new(self.clone())
+    }
+
+    /// `get` along with the version of `key`, `None` while it is missing
+    pub(crate) fn get_versioned(
+        &self,
+        key: String,
+    ) -> Result<(Option<String>, Option<KeyVersion>)> {
+        self.check_active()?;
+        if let Some(f) = &self.merge {
+            return self.merged(&key, f);
+        }
+        Ok(match self.lookup(&key)? {
+            Some((value, index)) => (
+                Some(self.assemble(&key, value, &index)?),
+                Some(index.key_version()),
+            ),
+            None => (None, None),
+        })
+    }
+
+    /// Apply `batch` if every key of `reads` still has the version read
+    pub(crate) fn commit(
+        &self,
+        reads: HashMap<String, Option<KeyVersion>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        self.check_active()?;
+        trace!(
+            "in kvs: commit {} reads, {} writes",
+            reads.len(),
+            batch.len()
+        );
+        batch.check_keys()?;
+        self.write("commit", |writer| {
+            let now = now_millis();
+            for (key, read) in reads {
+                let current = self
+                    .entry_to_index
+                    .get(&key)
+                    .filter(|index| index.visible(&key, now, &self.purges))
+                    .map(|index| index.key_version());
+                if current != read {
+                    return Err(KvsError::Conflict(key));
+                }
+            }
+            if batch.is_empty() {
+                return Ok(());
+            }
+            self.apply_kept(writer, batch)
+        })
+    }
+
+    /// Move the value of `key` to `new_key`, replacing its value if any
+    ///
+    /// The removal and the set are appended as one batch record, so after a
+    /// crash either both are recovered or neither. Like any batch write, the
+    /// value moves without its expiry and envelope.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// kvs.rename("jack".to_string(), "rose".to_string()).unwrap();
+    /// assert_eq!(kvs.get("jack".to_string()).unwrap(), None);
+    /// assert_eq!(kvs.get("rose".to_string()).unwrap(), Some("2024".to_string()));
+    /// ```
+    pub fn rename(&self, key: String, new_key: String) -> Result<()> {
+        self.check_active()?;
+        trace!("in kvs: rename");
+        check_user_key(&key)?;
+        check_user_key(&new_key)?;
+        self.write("rename", |writer| {
+            let value = self.get(key.clone())?.ok_or(KvsError::KeyNotFound)?;
+            if key == new_key {
+                return Ok(());
+            }
+            let mut batch = WriteBatch::new();
+            batch.remove(key).set(new_key, value);
+            self.apply_kept(writer, batch)
+        })
+    }
+
+    /// Map `key` to `value` only if its current value meets `condition`
+    /// Return whether the value is written.
+    ///
+    /// The writer lock is held from the check to the write, so no other
+    /// mutation can slip in between.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, condition::Condition, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// let below_10 = Condition::parse("value < 10").unwrap();
+    /// kvs.set("jack".to_string(), "7".to_string()).unwrap();
+    /// assert!(kvs.set_when("jack".to_string(), "12".to_string(), &below_10).unwrap());
+    /// assert!(!kvs.set_when("jack".to_string(), "5".to_string(), &below_10).unwrap());
+    /// ```
+    pub fn set_when(&self, key: String, value: String, condition: &Condition) -> Result<bool> {
+        self.check_active()?;
+        trace!("in kvs: set when");
+        check_user_key(&key)?;
+        self.write("set when", |writer| {
+            let old = self.get(key.clone())?;
+            if !condition.eval(old.as_deref()) {
+                return Ok(false);
+            }
+            self.set_kept(writer, Change::Set { key, value })?;
+            Ok(true)
+        })
+    }
+
+    /// Add `delta` to the integer value of `key`, return the new value
+    ///
+    /// A missing key counts as 0. The value is read and written under the
+    /// writer lock with one record, and the expiry of the key is kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::kvs::KvStore;
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// assert_eq!(kvs.incr("visits".to_string(), 2).unwrap(), 2);
+    /// assert_eq!(kvs.incr("visits".to_string(), -5).unwrap(), -3);
+    /// ```
+    pub fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.check_active()?;
+        trace!("in kvs: incr");
+        check_user_key(&key)?;
+        self.write("incr", |writer| {
+            let (old, expires) = match self.get(key.clone())? {
+                Some(old) => {
+                    let old = old
+                        .parse::<i64>()
+                        .map_err(|_| KvsError::NotAnInteger(key.clone()))?;
+                    let expires = self.entry_to_index.get(&key).and_then(|i| i.expires);
+                    (old, expires)
+                }
+                None => (0, None),
+            };
+            let new = old
+                .checked_add(delta)
+                .ok_or_else(|| KvsError::IncrOverflow(key.clone()))?;
+            let value = new.to_string();
+            let change = match expires {
+                Some(expires) => Change::SetExpiring {
+                    key,
+                    value,
+                    expires,
+                },
+                None => Change::Set { key, value },
+            };
+            self.set_kept(writer, change)?;
+            Ok(new)
+        })
+    }
+
+    /// Map `key` to `value` for `ttl`, after which the key reads as missing
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set_with_ttl("jack".to_string(), "2024".to_string(), Duration::from_secs(60))
+    ///     .unwrap();
+    /// assert_eq!(kvs.get("jack".to_string()).unwrap(), Some("2024".to_string()));
+    /// ```
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.check_active()?;
+        trace!("in kvs: set with ttl");
+        check_user_key(&key)?;
+        let expires = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.write("set with ttl", |writer| {
+            let change = Change::SetExpiring {
+                key,
+                value,
+                expires,
+            };
+            self.set_kept(writer, change)
+        })
+    }
+
+    /// Map `key` to `value`, described by `envelope`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::kvs::{Envelope, KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// let envelope = Envelope {
+    ///     content_type: Some("application/json".to_string()),
+    ///     ..Envelope::default()
+    /// };
+    /// kvs.set_with_envelope("jack".to_string(), "{}".to_string(), envelope.clone())
+    ///     .unwrap();
+    /// let (_, stored) = kvs.get_with_envelope("jack".to_string()).unwrap().unwrap();
+    /// assert_eq!(stored, envelope);
+    /// ```
+    pub fn set_with_envelope(&self, key: String, value: String, envelope: Envelope) -> Result<()> {
+        self.check_active()?;
+        trace!("in kvs: set with envelope");
+        check_user_key(&key)?;
+        self.metrics.time(Operation::Set, || {
+            self.write("set with envelope", |writer| {
+                let change = Change::SetEnveloped {
+                    key,
+                    value,
+                    expires: None,
+                    envelope,
+                };
+                self.set_kept(writer, change)
+            })
+        })
+    }
+
+    /// Return the value of `key` with its envelope
+    pub fn get_with_envelope(&self, key: String) -> Result<Option<(String, Envelope)>> {
+        self.check_active()?;
+        self.metrics
+            .time(Operation::Get, || match self.lookup(&key)? {
+                Some((value, index)) if index.envelope.is_chunked() => Ok(Some((
+                    self.assemble(&key, value, &index)?,
+                    Envelope::default(),
+                ))),
+                found => Ok(found.map(|(value, index)| (value, index.envelope))),
+            })
+    }
+
+    /// Set `key` to the value read from `input`, without holding all of it
+    /// in memory
+    ///
+    /// A value longer than `KvStoreBuilder::chunk_size` is stored in chunks,
+    /// each one record, and `key` points to them once all are written. `get`
+    /// puts them together again, `get_reader` reads them one at a time.
+    /// Each chunk passes the limits and the quota like a value would, the
+    /// whole value is not limited. The input must be UTF-8. A chunked value
+    /// keeps no previous version, see `KvStoreBuilder::keep_versions`.
+    /// Return the length of the value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// use std::io::Read;
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::builder().chunk_size(4).open(dir.path()).unwrap();
+    /// kvs.set_from_reader("jack".to_string(), "2024-2025".as_bytes()).unwrap();
+    /// let mut value = String::new();
+    /// let mut reader = kvs.get_reader("jack".to_string()).unwrap().unwrap();
+    /// reader.read_to_string(&mut value).unwrap();
+    /// assert_eq!(value, "2024-2025");
+    /// ```
+    pub fn set_from_reader(&self, key: String, input: impl Read) -> Result<u64> {
+        self.check_active()?;
+        trace!("in kvs: set from reader");
+        check_user_key(&key)?;
+        let (chunk_size, id) = {
+            let mut writer = self.lock_writer("chunk id");
+            (writer.chunk_size, writer.next_id())
+        };
+        let mut chunker = Chunker::new(input, chunk_size);
+        let first = chunker.next_chunk()?.unwrap_or_default();
+        let Some(second) = chunker.next_chunk()? else {
+            let len = first.len() as u64;
+            self.write("set", |writer| {
+                self.set_kept(writer, Change::Set { key, value: first })
+            })?;
+            return Ok(len);
+        };
+        let mut chunks = Chunks {
+            id,
+            count: 0,
+            len: 0,
+        };
+        let rest = iter::from_fn(|| chunker.next_chunk().transpose());
+        for chunk in [Ok(first), Ok(second)].into_iter().chain(rest) {
+            let chunk = chunk?;
+            chunks.len += chunk.len() as u64;
+            let chunk_key = chunk::chunk_key(&key, id, chunks.count);
+            self.write("set chunk", |writer| writer.set(chunk_key, chunk))?;
+            chunks.count += 1;
+        }
+        self.write("set chunked", |writer| writer.finish_chunked(key, chunks))?;
+        Ok(chunks.len)
+    }
+
+    /// Append `operand` to `key`, folded into its value by the merge operator
+    ///
+    /// The value is not read, so e.g. a counter is bumped without a
+    /// read-modify-write. Reads fold the operands written so far, the
+    /// `MAX_OPERANDS`th merge of a key and `compact` write the folded value
+    /// back. A set or a remove of the key drops its operands. Fails unless
+    /// the store was opened with `KvStoreBuilder::merge_operator`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::builder()
+    ///     .merge_operator(|_, value, operands| {
+    ///         let base: u64 = value.map_or(0, |v| v.parse().unwrap());
+    ///         let sum: u64 = operands.iter().map(|o| o.parse::<u64>().unwrap()).sum();
+    ///         (base + sum).to_string()
+    ///     })
+    ///     .open(dir.path())
+    ///     .unwrap();
+    /// kvs.set("visits".to_string(), "10".to_string()).unwrap();
+    /// kvs.merge("visits".to_string(), "1".to_string()).unwrap();
+    /// kvs.merge("visits".to_string(), "2".to_string()).unwrap();
+    /// assert_eq!(kvs.get("visits".to_string()).unwrap(), Some("13".to_string()));
+    /// ```
+    pub fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.check_active()?;
+        if self.merge.is_none() {
+            return Err(KvsError::StringError(
+                "the store has no merge operator".to_owned(),
+            ));
+        }
+        trace!("in kvs: merge");
+        check_user_key(&key)?;
+        self.metrics.time(Operation::Set, || {
+            self.write("merge", |writer| {
+                if writer.merge(key.clone(), operand)? >= MAX_OPERANDS {
+                    self.fold(writer, &key)?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    /// Read the value of `key` one chunk at a time, see `set_from_reader`
+    /// A value stored whole is read whole first.
+    pub fn get_reader(&self, key: String) -> Result<Option<ValueReader>> {
+        self.check_active()?;
+        Ok(match self.lookup(&key)? {
+            Some((value, index)) if index.envelope.is_chunked() => {
+                let chunks = serde_json::from_str(&value)?;
+                Some(ValueReader::chunked(self.clone(), key, chunks))
+            }
+            Some((value, _)) => Some(ValueReader::plain(self.clone(), key, value)),
+            None => None,
+        })
+    }
+
+    /// Return the metadata of `key` without reading its value from disk
     ///
     /// # Examples
     ///
     /// ```
-    /// use kvs::KvStore;
-    /// let mut kvs = KvStore::new();
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
     /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// assert_eq!(kvs.metadata("jack".to_string()).unwrap().unwrap().value_size, 4);
     /// ```
-    fn set(&self, key: String, value: String) -> Result<()> {
-        trace!("in kvs: set");
-        self.kv_writer.lock().unwrap().set(key, value)
+    pub fn metadata(&self, key: String) -> Result<Option<KeyMetadata>> {
+        self.check_active()?;
+        let now = now_millis();
+        let meta = self
+            .entry_to_index
+            .get(&key)
+            .filter(|index| index.visible(&key, now, &self.purges))
+            .map(|index| KeyMetadata {
+                value_size: index.len,
+                last_modified: modified_at(index.ts),
+                version: index.version,
+                ttl: index
+                    .expires
+                    .map(|expires| Duration::from_millis(expires - now)),
+                // the chunks are read put together, see `get_with_envelope`
+                envelope: match index.envelope.is_chunked() {
+                    true => Envelope::default(),
+                    false => index.envelope,
+                },
+            });
+        Ok(meta)
     }
 
-    /// If `key` is in the kv store, return the `Some(value)`
-    /// Otherwise, return `None`
-    ///
-    /// # Examples
+    /// Return when `key` was last written, `None` if it is missing
     ///
-    /// ```
-    /// use kvs::KvStore;
-    /// let mut kvs = KvStore::new();
-    /// let k1 = String::from("jack");
-    /// let k2 = String::from("jone");
-    /// let v1 = String::from("2024");
-    /// kvs.set(k1, v1);
-    /// assert_eq!(kvs.get(String::from("jack")).unwrap(), Some(String::from("2024")));
-    /// assert_eq!(kvs.get(k2).unwrap(), None);
-    /// ```
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let reader = self
-            .entry_to_index
-            .read()
-            .expect("Fail to get read lock of entry to index");
-        if reader.contains_key(&key) {
-            let s = self
-                .kv_reader
-                .get(reader.get(&key).unwrap().read().unwrap().clone())?;
-            Ok(Some(s))
-        } else {
-            Ok(None)
-        }
+    /// A key written by an older version without timestamps reads as
+    /// written at the unix epoch.
+    pub fn last_modified(&self, key: String) -> Result<Option<SystemTime>> {
+        Ok(self
+            .metadata(key)?
+            .map(|meta| meta.last_modified.unwrap_or(UNIX_EPOCH)))
     }
 
-    /// If `key` is in the kv store, remove it
-    /// Otherwise, do nothing
+    /// Scan the pairs of `range` in key order, each with the time it was
+    /// last written
     ///
     /// # Examples
     ///
     /// ```
-    /// use kvs::KvStore;
-    /// let mut kvs = KvStore::new();
-    /// let k1 = String::from("jack");
-    /// let v1 = String::from("2024");
-    /// kvs.set(k1, v1);
-    /// assert_eq!(kvs.get(String::from("jack")), Some(String::from("2024")));
-    /// kvs.rm(String::from("jack"));
-    /// assert_eq!(kvs.get(String::from("jack")), None);
+    /// use kvs::engine::{KvsEngine, kvs::KvStore};
+    /// use std::time::SystemTime;
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
+    /// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+    /// let (key, _, modified) = kvs.scan_modified(..).unwrap().next().unwrap().unwrap();
+    /// assert_eq!(key, "jack");
+    /// assert!(modified.unwrap() <= SystemTime::now());
     /// ```
-    fn remove(&self, key: String) -> Result<()> {
-        trace!("in kvs remove");
-        self.kv_writer.lock().unwrap().remove(key)
+    pub fn scan_modified(&self, range: impl RangeBounds<String>) -> Result<ModifiedScan> {
+        self.check_active()?;
+        Ok(ModifiedScan(RangeScan {
+            store: self.clone(),
+            index: Arc::clone(&self.entry_to_index),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            keys: VecDeque::new(),
+        }))
     }
-}
 
-impl KvStore {
-    /// Create a new KvStore using default directory
+    /// Create a new KvStorage with given directory
     ///
     /// # Examples
     ///
     /// ```
     /// use kvs::engine::kvs::KvStore;
-    /// let kvs = KvStore::new().unwrap();    
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::open(dir.path()).unwrap();
     /// ```
-    pub fn new() -> Result<Self> {
-        let cwd = env::current_dir()?;
-        Self::open(cwd)
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::builder().open(path)
     }
 
-    /// Create a new KvStorage with given directory
+    /// Configure the store before opening it
     ///
     /// # Examples
     ///
     /// ```
-    /// use kvs::engine::kvs::KvStore;
-    /// use std::env;
-    /// let kvs = KvStore::open(env::current_dir().unwrap()).unwrap();
+    /// use kvs::engine::kvs::{IndexKind, KvStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let kvs = KvStore::builder()
+    ///     .index(IndexKind::Concurrent)
+    ///     .open(dir.path())
+    ///     .unwrap();
     /// ```
-    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+}
+
+/// When the writes appended to the active log reach the disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Left to the OS, a crash of the machine may lose the latest writes
+    #[default]
+    Never,
+    /// Every write is synced before it returns, syncs run back to back
+    Always,
+    /// Every write is synced before it returns, by a sync shared with the
+    /// writes waiting at that point, at most once per interval
+    Every(Duration),
+    /// Writes return unsynced, a thread syncs them at most once per
+    /// interval, and on close; a crash of the machine may lose the writes
+    /// of the last interval
+    Periodic(Duration),
+}
+
+/// How thoroughly opening a store checks its logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verify {
+    /// Every checksum is checked only after an unclean shutdown, corrupted
+    /// records are skipped
+    #[default]
+    AfterCrash,
+    /// Every checksum is checked, a corrupted record fails the open
+    Check,
+    /// Every checksum is checked, corrupted records are skipped and the logs
+    /// rewritten without them
+    Repair,
+}
+
+/// How the records of the logs written by compaction are compressed
+///
+/// Each record is compressed on its own, so a value is still read with one
+/// seek. The codec is the first byte of a log, logs of any codec are read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// zstd at the given level, 0 for its default
+    Zstd(i32),
+}
+
+/// Options of a `KvStore`, fixed once it is opened
+#[derive(Clone, Default)]
+pub struct KvStoreBuilder {
+    index_kind: IndexKind,
+    standby: bool,
+    sync: SyncPolicy,
+    sync_batch: Option<u64>,
+    verify: Verify,
+    layout: LogLayout,
+    limits: Limits,
+    active_log_size: Option<usize>,
+    compaction_threshold: Option<f64>,
+    compression: Compression,
+    keep_versions: usize,
+    read_cache: Option<usize>,
+    value_cache: Option<usize>,
+    coalesce_reads: bool,
+    region: Option<String>,
+    memory_budget: Option<MemoryBudget>,
+    on_recovery: Option<RecoveryCallback>,
+    create_if_missing: Option<bool>,
+    error_if_exists: bool,
+    disk_quota: Option<u64>,
+    compact_over_quota: bool,
+    chunk_size: Option<usize>,
+    merge: Option<MergeOperator>,
+}
+
+impl fmt::Debug for KvStoreBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStoreBuilder")
+            .field("index_kind", &self.index_kind)
+            .field("standby", &self.standby)
+            .field("sync", &self.sync)
+            .field("sync_batch", &self.sync_batch)
+            .field("verify", &self.verify)
+            .field("layout", &self.layout)
+            .field("limits", &self.limits)
+            .field("active_log_size", &self.active_log_size)
+            .field("compaction_threshold", &self.compaction_threshold)
+            .field("compression", &self.compression)
+            .field("keep_versions", &self.keep_versions)
+            .field("read_cache", &self.read_cache)
+            .field("value_cache", &self.value_cache)
+            .field("coalesce_reads", &self.coalesce_reads)
+            .field("region", &self.region)
+            .field(
+                "memory_budget",
+                &self.memory_budget.as_ref().map(MemoryBudget::limit),
+            )
+            .field("on_recovery", &self.on_recovery.is_some())
+            .field("create_if_missing", &self.create_if_missing)
+            .field("error_if_exists", &self.error_if_exists)
+            .field("disk_quota", &self.disk_quota)
+            .field("compact_over_quota", &self.compact_over_quota)
+            .field("chunk_size", &self.chunk_size)
+            .field("merge", &self.merge.is_some())
+            .finish()
+    }
+}
+
+impl KvStoreBuilder {
+    /// Choose the structure backing the in-memory index
+    /// The index is rebuilt on every open, so this may change between runs.
+    pub fn index(mut self, kind: IndexKind) -> Self {
+        self.index_kind = kind;
+        self
+    }
+
+    /// Open the store as a warm standby
+    /// It refuses all traffic and only applies changes until `KvStore::promote`.
+    pub fn standby(mut self, standby: bool) -> Self {
+        self.standby = standby;
+        self
+    }
+
+    /// Make every write durable before it returns
+    ///
+    /// A sync thread calls `fdatasync` on the active log at most once per
+    /// `interval`, each call covers all writes waiting at that point.
+    pub fn durable(self, interval: Duration) -> Self {
+        self.sync(SyncPolicy::Every(interval))
+    }
+
+    /// Choose when writes are synced to the disk, `SyncPolicy::Never` by default
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Sync as soon as `writes` writes wait, without waiting out the interval
+    /// of `SyncPolicy::Every`. Bounds the latency a batch trades for throughput.
+    pub fn sync_batch(mut self, writes: u64) -> Self {
+        self.sync_batch = Some(writes);
+        self
+    }
+
+    /// Check the logs on open, beside after a crash, see `Verify`
+    pub fn verify(mut self, verify: Verify) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Rewrite the logs without their corrupted records on open, the same
+    /// as `verify(Verify::Repair)`
+    pub fn repair(mut self, repair: bool) -> Self {
+        if repair {
+            self.verify = Verify::Repair;
+        } else if self.verify == Verify::Repair {
+            self.verify = Verify::default();
+        }
+        self
+    }
+
+    /// Initialize a new store when the directory holds none, the default
+    /// Without it opening fails with `KvsError::StoreNotFound`, so a wrong
+    /// path is not mistaken for an empty store.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = Some(create);
+        self
+    }
+
+    /// Fail with `KvsError::StoreExists` when the directory holds a store
+    /// already, to initialize a new one and nothing else
+    pub fn error_if_exists(mut self, error: bool) -> Self {
+        self.error_if_exists = error;
+        self
+    }
+
+    /// Let the logs take at most `bytes` on disk, garbage included
+    /// A set which would pass it fails with `KvsError::QuotaExceeded`, removals
+    /// always go through. Keep room for a compaction, which writes its output
+    /// before deleting its inputs.
+    pub fn disk_quota(mut self, bytes: u64) -> Self {
+        self.disk_quota = Some(bytes);
+        self
+    }
+
+    /// Compact every log before failing a set over the quota
+    /// The set blocks meanwhile, and still fails if the live keys fill the quota.
+    pub fn compact_over_quota(mut self, compact: bool) -> Self {
+        self.compact_over_quota = compact;
+        self
+    }
+
+    /// Cut the values of `KvStore::set_from_reader` into chunks of at most
+    /// `bytes`, 1 MiB by default
+    /// Keep it within `Limits::max_value`, every chunk is checked against it.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = Some(bytes);
+        self
+    }
+
+    /// Fold the operands of `KvStore::merge` with `f`, see `MergeOperator`
+    /// Pass it on every open, operands written before are folded by it.
+    pub fn merge_operator(
+        mut self,
+        f: impl Fn(&str, Option<&str>, &[String]) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.merge = Some(Arc::new(f));
+        self
+    }
+
+    /// Seal the active log and start a new one once it is `bytes` long
+    pub fn active_log_size(mut self, bytes: usize) -> Self {
+        self.active_log_size = Some(bytes.max(1));
+        self
+    }
+
+    /// Compact sealed logs with less than `live_ratio` of their bytes live
+    /// See `KvStore::set_compaction_threshold`, which changes it later.
+    pub fn compaction_threshold(mut self, live_ratio: f64) -> Self {
+        self.compaction_threshold = Some(live_ratio.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Let `set` keep up to `n` previous values of each key, see `get_at`
+    ///
+    /// An overwrite saves the old value under a reserved key in the same
+    /// batch, so the versions are compacted like any key and survive a
+    /// crash together with the write. The key reads its own value first,
+    /// and only `set` keeps versions, the other writes replace the value.
+    /// A removed value is not kept, its history is.
+    pub fn keep_versions(mut self, n: usize) -> Self {
+        self.keep_versions = n;
+        self
+    }
+
+    /// Compress the records of the logs compaction writes
+    /// Writes to the active log stay uncompressed, they are sealed as is.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Keep the logs in the subdirectory `name` instead of `log`
+    /// Like `layout`, only used when the directory has no logs yet.
+    pub fn log_dir(mut self, name: impl Into<String>) -> Self {
+        self.layout.dir_name = Some(name.into());
+        self
+    }
+
+    /// Keep up to `logs` log files open, with their read buffers
+    /// Overrides `Limits::max_open_segments`.
+    pub fn read_cache(mut self, logs: usize) -> Self {
+        self.read_cache = Some(logs.max(1));
+        self
+    }
+
+    /// Keep the values read lately in memory, up to `bytes` of keys and values
+    /// The least recently used go first, a write makes the cached value stale.
+    pub fn value_cache(mut self, bytes: usize) -> Self {
+        self.value_cache = Some(bytes);
+        self
+    }
+
+    /// Let concurrent gets of the same record share one read of the log
+    /// Smooths a burst of gets of a hot key, e.g. once its cached value is stale.
+    pub fn coalesce_reads(mut self, coalesce: bool) -> Self {
+        self.coalesce_reads = coalesce;
+        self
+    }
+
+    /// Name the region of the store, stamped on the changes of its writes
+    /// Every region of an active/active deployment needs its own name.
+    pub fn region(mut self, name: impl Into<String>) -> Self {
+        self.region = Some(name.into());
+        self
+    }
+
+    /// Count the memory of the store against `budget`, shared with other stores
+    /// Past it the store shrinks its caches rather than failing.
+    pub fn memory_budget(mut self, budget: &MemoryBudget) -> Self {
+        self.memory_budget = Some(budget.clone());
+        self
+    }
+
+    /// Lay the logs out in subdirectories, or name them differently
+    /// Only used when the directory has no logs yet, later the manifest decides.
+    pub fn layout(mut self, layout: LogLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Cap the size of keys, values and batches, and the logs kept open
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Call `f` after each log replayed while opening, e.g. to show progress
+    /// The replay is also logged, at most every few seconds.
+    pub fn on_recovery(mut self, f: impl Fn(&RecoveryProgress) + Send + Sync + 'static) -> Self {
+        self.on_recovery = Some(Arc::new(f));
+        self
+    }
+
+    /// Open the store in the given directory
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        let path: PathBuf = path.into();
+        // a store has a manifest, or logs from before manifests
+        let exists = Manifest::load(&path)?.is_some() || LogLayout::default().root(&path).exists();
+        if !exists && !self.create_if_missing.unwrap_or(true) {
+            return Err(KvsError::StoreNotFound(path.display().to_string()));
+        }
+        if exists && self.error_if_exists {
+            return Err(KvsError::StoreExists(path.display().to_string()));
+        }
         let mut ver_to_file: HashMap<usize, BufReader<File>> = HashMap::new();
-        let kv_writer = KvStoreWriter::new(path, &mut ver_to_file)?;
-        let kv_reader = KvStoreReader::new(
+        let mut kv_writer = KvStoreWriter::new(
+            path,
+            &mut ver_to_file,
+            self.index_kind,
+            self.layout,
+            self.verify,
+            self.on_recovery.as_ref(),
+        )?;
+        let mut limits = self.limits;
+        if let Some(logs) = self.read_cache {
+            limits.max_open_segments = logs;
+        }
+        kv_writer.limits = limits;
+        if let Some(bytes) = self.active_log_size {
+            kv_writer.active_log_size = bytes;
+        }
+        if let Some(live_ratio) = self.compaction_threshold {
+            kv_writer.compaction_threshold = live_ratio;
+        }
+        kv_writer.compression = self.compression;
+        kv_writer.keep_versions = self.keep_versions;
+        kv_writer.disk_quota = self.disk_quota;
+        kv_writer.compact_over_quota = self.compact_over_quota;
+        if let Some(bytes) = self.chunk_size {
+            kv_writer.chunk_size = bytes;
+        }
+        let (start, end) = merge::all_operands();
+        for operand in kv_writer.entry_to_index.reserved_keys(start, end) {
+            if let Some(key) = merge::owner(&operand) {
+                *kv_writer.merging.entry(key.to_owned()).or_default() += 1;
+            }
+        }
+        if let Some(region) = self.region {
+            kv_writer.region = region;
+        }
+        let interval = match self.sync {
+            SyncPolicy::Never => None,
+            SyncPolicy::Always => Some(Duration::ZERO),
+            SyncPolicy::Every(interval) | SyncPolicy::Periodic(interval) => Some(interval),
+        };
+        if let Some(interval) = interval {
+            let active = kv_writer.writer.get_ref().try_clone()?;
+            let max_batch = self.sync_batch.unwrap_or(u64::MAX);
+            let background = matches!(self.sync, SyncPolicy::Periodic(_));
+            kv_writer.syncer = Some(Syncer::start(active, interval, max_batch, background));
+        }
+        kv_writer.memory = self.memory_budget.as_ref().map(MemoryBudget::share);
+        kv_writer.count_memory();
+        let mut kv_reader = KvStoreReader::new(
             Arc::clone(&kv_writer.dir),
+            kv_writer.manifest.layout.clone(),
             Arc::clone(&kv_writer.min_version),
+            Arc::clone(&kv_writer.generations),
             ver_to_file,
+            limits.max_open_segments,
+            kv_writer.memory.clone(),
         )?;
+        kv_reader.cache = self
+            .value_cache
+            .map(|bytes| Arc::new(ValueCache::new(bytes)));
+        kv_reader.flights = self.coalesce_reads.then(|| Arc::new(Flights::new()));
 
-        Ok(Self {
+        let repair = self.verify == Verify::Repair && kv_writer.skipped_records > 0;
+        let store = KvStore {
             dir: Arc::clone(&kv_writer.dir),
             entry_to_index: Arc::clone(&kv_writer.entry_to_index),
+            metrics: Arc::clone(&kv_writer.metrics),
+            pins: Arc::clone(&kv_writer.pins),
+            purges: Arc::clone(&kv_writer.purges),
             kv_writer: Arc::new(Mutex::new(kv_writer)),
             kv_reader,
-        })
+            scans: Arc::new(Mutex::new(ScanCursors::default())),
+            standby: Arc::new(AtomicBool::new(self.standby)),
+            traffic: Arc::new(Mutex::new(Traffic::default())),
+            tokens: Arc::new(Mutex::new(Tokens::default())),
+            operations: Arc::default(),
+            misses: Arc::default(),
+            leases: Arc::new(Mutex::new(Leases::default())),
+            limits,
+            merge: self.merge,
+        };
+        // only the records in the index are copied, the corrupted ones are left
+        if repair {
+            let mut writer = store.lock_writer("repair");
+            warn!(
+                "Rewrite the logs without {} corrupted records",
+                writer.skipped_records
+            );
+            writer.compact()?;
+        }
+        Ok(store)
+    }
+}
+
+impl KvStoreBuilder {
+    /// Open a read-only replica of the store another process writes in `path`
+    /// Its index catches up with the primary every `refresh`, see `KvReplica`.
+    pub fn open_replica(self, path: impl Into<PathBuf>, refresh: Duration) -> Result<KvReplica> {
+        let dir = Arc::new(path.into());
+        let manifest = Manifest::load(&dir)?
+            .ok_or_else(|| KvsError::IncompatibleStore(format!("no store in {:?}", dir)))?;
+        manifest.check(ENGINE_NAME)?;
+        let min_version = Arc::new(AtomicU32::new(0));
+        let shared = Arc::new(ReplicaShared {
+            dir: Arc::clone(&dir),
+            layout: manifest.layout.clone(),
+            index_kind: self.index_kind,
+            entry_to_index: Arc::new(Index::new(self.index_kind)),
+            min_version: Arc::clone(&min_version),
+            sealed: Mutex::new(Vec::new()),
+        });
+        shared.refresh()?;
+        let reader = KvStoreReader::new(
+            dir,
+            manifest.layout,
+            min_version,
+            // sealed logs never change, their buffers stay valid
+            Arc::new(Generations::default()),
+            HashMap::new(),
+            self.read_cache.unwrap_or(self.limits.max_open_segments),
+            self.memory_budget.as_ref().map(MemoryBudget::share),
+        )?;
+
+        // stops once the last clone of the replica is dropped
+        let weak = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name("kvs-replica".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(refresh);
+                    let shared = match weak.upgrade() {
+                        Some(shared) => shared,
+                        None => break,
+                    };
+                    if let Err(e) = shared.refresh() {
+                        warn!("Fail to refresh the replica of {:?}: {}", shared.dir, e);
+                    }
+                }
+            })?;
+        Ok(KvReplica { shared, reader })
+    }
+}
+
+/// A read-only replica of a store written by another process
+///
+/// Meant for read scaling over shared storage, e.g. NFS. The replica never
+/// writes to the data directory, it watches the manifest and replays the
+/// logs sealed since the last refresh, so a write of the primary shows up
+/// once its log is sealed. Writes fail with `KvsError::ReadOnly`.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use kvs::engine::{KvsEngine, kvs::{KvReplica, KvStore}};
+/// let dir = tempfile::tempdir().unwrap();
+/// let kvs = KvStore::open(dir.path()).unwrap();
+/// kvs.set("jack".to_string(), "2024".to_string()).unwrap();
+/// drop(kvs);
+/// let replica = KvReplica::open(dir.path(), Duration::from_secs(1)).unwrap();
+/// assert_eq!(replica.get("jack".to_string()).unwrap(), Some("2024".to_string()));
+/// ```
+#[derive(Clone)]
+pub struct KvReplica {
+    shared: Arc<ReplicaShared>,
+    // every clone has its own reader
+    reader: KvStoreReader,
+}
+
+/// Index of a replica, shared by its clones and its refresh thread
+struct ReplicaShared {
+    dir: Arc<PathBuf>,
+    layout: LogLayout,
+    index_kind: IndexKind,
+    entry_to_index: Arc<Index>,
+    min_version: Arc<AtomicU32>,
+    // sealed logs of the manifest the index was built from
+    sealed: Mutex<Vec<usize>>,
+}
+
+impl ReplicaShared {
+    /// Catch up with the logs sealed since the last refresh
+    ///
+    /// Logs sealed since are replayed on top of the index. A compaction
+    /// replaces logs in the middle instead, then the index is rebuilt aside
+    /// from every log and swapped in. Nothing changes if a log can not be
+    /// read, e.g. it was compacted away meanwhile, the next refresh retries.
+    fn refresh(&self) -> Result<bool> {
+        let manifest = Manifest::load(&self.dir)?
+            .ok_or_else(|| KvsError::IncompatibleStore(format!("no store in {:?}", self.dir)))?;
+        let mut sealed = self.sealed.lock().unwrap();
+        if manifest.sealed == *sealed {
+            return Ok(false);
+        }
+        let now = now_millis();
+        // garbage only matters to the primary
+        let mut garbage = Garbage::default();
+        if manifest.sealed.starts_with(&sealed) {
+            let logs = manifest.sealed[sealed.len()..]
+                .iter()
+                .map(|&v| Ok((v, self.hints(v)?)))
+                .collect::<Result<Vec<_>>>()?;
+            for (v, hints) in logs {
+                replay(&self.entry_to_index, &mut garbage, v, hints, now);
+            }
+        } else {
+            let index = Index::new(self.index_kind);
+            for &v in manifest.sealed.iter() {
+                replay(&index, &mut garbage, v, self.hints(v)?, now);
+            }
+            let mut updates: Vec<(String, Option<InMemIndex>)> = self
+                .entry_to_index
+                .all_keys()
+                .into_iter()
+                .filter(|key| index.get(key).is_none())
+                .map(|key| (key, None))
+                .collect();
+            for key in index.all_keys() {
+                let entry = index.get(&key);
+                updates.push((key, entry));
+            }
+            self.entry_to_index.apply(updates);
+            info!(
+                "Rebuilt the replica index of {:?} after a compaction",
+                self.dir
+            );
+        }
+        let oldest = manifest.sealed.first().copied().unwrap_or(0);
+        self.min_version.store(oldest as u32, Ordering::SeqCst);
+        *sealed = manifest.sealed;
+        Ok(true)
+    }
+
+    /// Hints of the sealed log `version`, read from the log without a hint file
+    fn hints(&self, version: usize) -> Result<Vec<Hint>> {
+        let file = File::open(self.layout.path(&self.dir, version))?;
+        let log_len = file.metadata()?.len();
+        if let Some(hints) = read_hints(&self.layout.hint_path(&self.dir, version), log_len) {
+            return Ok(hints);
+        }
+        let records = read_records(&file, version, None)?.records;
+        Ok(records
+            .into_iter()
+            .map(|(op, offset, rec_len)| Hint::of(op, offset, rec_len))
+            .collect())
+    }
+}
+
+impl KvReplica {
+    /// Open a replica of the store in `path`, refreshed every `refresh`
+    pub fn open(path: impl Into<PathBuf>, refresh: Duration) -> Result<Self> {
+        KvStore::builder().open_replica(path, refresh)
+    }
+
+    /// Catch up with the primary now instead of at the next refresh
+    /// Return whether a log was sealed or compacted since the last one.
+    pub fn refresh(&self) -> Result<bool> {
+        self.shared.refresh()
+    }
+}
+
+impl KvsEngine for KvReplica {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let index = &self.shared.entry_to_index;
+        let mut entry = index.get(&key);
+        let mut refreshed = false;
+        while let Some(cur) = entry {
+            // the primary reclaims it
+            if cur.expired(now_millis()) {
+                return Ok(None);
+            }
+            match self.reader.get(&key, cur.clone()) {
+                Ok(s) if cur.envelope.is_chunked() => {
+                    return chunk::read_chunked(self.clone(), &key, &s).map(Some);
+                }
+                Ok(s) => return Ok(Some(s)),
+                Err(e) => {
+                    // the primary may have compacted the log away, catch up
+                    // once, and only fail when the index still points here
+                    if !refreshed {
+                        refreshed = true;
+                        self.shared.refresh()?;
+                    }
+                    let latest = index.get(&key);
+                    if latest.as_ref() == Some(&cur) {
+                        return Err(e);
+                    }
+                    entry = latest;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn set_if_absent(&self, _key: String, _value: String) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn set_if_present(&self, _key: String, _value: String) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn take(&self, _key: String) -> Result<Option<String>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn insert(&self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn remove_range(&self, _range: impl RangeBounds<String>) -> Result<usize> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        let (start, end) = prefix_range(prefix);
+        Ok(self.shared.entry_to_index.count_range(start, end))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.shared.entry_to_index.len())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let now = now_millis();
+        Ok(self
+            .shared
+            .entry_to_index
+            .get(key)
+            .is_some_and(|entry| !entry.expired(now)))
+    }
+
+    fn keys(&self, pattern: &Pattern) -> Result<Vec<String>> {
+        let index = &self.shared.entry_to_index;
+        let (start, end) = prefix_range(pattern.prefix());
+        let now = now_millis();
+        Ok(index.keys_matching(start, end, |k| {
+            pattern.matches(k) && index.get(k).is_some_and(|entry| !entry.expired(now))
+        }))
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<ScanIter> {
+        Ok(Box::new(RangeScan {
+            store: self.clone(),
+            index: Arc::clone(&self.shared.entry_to_index),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            keys: VecDeque::new(),
+        }))
+    }
+}
+
+/// A consistent view of a `KvStore` at the time it was taken
+///
+/// Gets and scans see the keys and values of that time, whatever is written
+/// or compacted since. Compaction keeps the logs it replaces until the last
+/// clone of the snapshot is dropped. With `IndexKind::Concurrent` or
+/// `IndexKind::Sharded` the index is copied, so a write racing with
+/// `KvStore::snapshot` may be seen or not.
+/// Writes fail with `KvsError::ReadOnly`.
+#[derive(Clone)]
+pub struct KvSnapshot {
+    pin: Arc<SnapshotPin>,
+    // every clone has its own reader
+    reader: KvStoreReader,
+}
+
+/// Shared by the clones of a snapshot, releases its logs when dropped
+struct SnapshotPin {
+    writer: Arc<Mutex<KvStoreWriter>>,
+    pins: Arc<Mutex<Pins>>,
+    index: Arc<Index>,
+    // the purges done when it was taken
+    purges: Purges,
+    // compactions finished when it was taken
+    epoch: u64,
+    // keys expire as of this time, in milliseconds since the unix epoch
+    taken_at: u64,
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(count) = pins.live.get_mut(&self.epoch) {
+            *count -= 1;
+            if *count == 0 {
+                pins.live.remove(&self.epoch);
+            }
+        }
+        let replaced = !pins.replaced.is_empty();
+        drop(pins);
+        if replaced && let Err(e) = self.writer.lock().unwrap().remove_unpinned() {
+            warn!("Fail to remove the logs released by a snapshot: {}", e);
+        }
+    }
+}
+
+impl KvsEngine for KvSnapshot {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.pin.index.get(&key) {
+            Some(entry) if entry.visible(&key, self.pin.taken_at, &self.pin.purges) => {
+                let value = self.reader.get(&key, entry.clone())?;
+                if entry.envelope.is_chunked() {
+                    return chunk::read_chunked(self.clone(), &key, &value).map(Some);
+                }
+                Ok(Some(value))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn set_if_absent(&self, _key: String, _value: String) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn set_if_present(&self, _key: String, _value: String) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn take(&self, _key: String) -> Result<Option<String>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn insert(&self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn remove_range(&self, _range: impl RangeBounds<String>) -> Result<usize> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        let (start, end) = prefix_range(prefix);
+        Ok(self.pin.index.count_range(start, end))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.pin.index.len())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self
+            .pin
+            .index
+            .get(key)
+            .is_some_and(|entry| entry.visible(key, self.pin.taken_at, &self.pin.purges)))
+    }
+
+    fn keys(&self, pattern: &Pattern) -> Result<Vec<String>> {
+        let index = &self.pin.index;
+        let (start, end) = prefix_range(pattern.prefix());
+        Ok(index.keys_matching(start, end, |k| {
+            pattern.matches(k)
+                && index
+                    .get(k)
+                    .is_some_and(|entry| entry.visible(k, self.pin.taken_at, &self.pin.purges))
+        }))
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<ScanIter> {
+        Ok(Box::new(RangeScan {
+            store: self.clone(),
+            index: Arc::clone(&self.pin.index),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            keys: VecDeque::new(),
+        }))
     }
 }
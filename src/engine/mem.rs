@@ -0,0 +1,363 @@
+//! An in-memory engine, persisted by periodic snapshots
+//!
+//! Every pair lives in memory, so reads and writes never touch the disk.
+//! A background thread dumps the whole map into `dump.json` every
+//! `snapshot_interval`, like a Redis RDB file. With the append log enabled
+//! each write is also appended to `append.log`, like a Redis AOF, which is
+//! replayed on top of the snapshot when the store is opened, and emptied by
+//! every snapshot.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use log::{trace, warn};
+
+use super::kvs::Change;
+use super::pattern::Pattern;
+use super::{KvsEngine, ScanIter, is_empty_range, prefix_range};
+use crate::error::{KvsError, Result};
+use crate::manifest::Manifest;
+
+const ENGINE_NAME: &str = "mem";
+const SNAPSHOT_FILE: &str = "dump.json";
+const APPEND_LOG_FILE: &str = "append.log";
+/// Default time between two snapshots
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct MemStore {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    dir: PathBuf,
+    map: RwLock<BTreeMap<String, String>>,
+    // `None` when only snapshots persist the data
+    append_log: Option<Mutex<File>>,
+    // writes since the last snapshot
+    dirty: AtomicU64,
+}
+
+impl Shared {
+    /// Dump the map into the snapshot file, then empty the append log
+    ///
+    /// With the append log, writers wait for the whole snapshot, so the log
+    /// never loses a write the snapshot does not hold.
+    fn snapshot(&self) -> Result<()> {
+        let log = self.append_log.as_ref().map(|log| log.lock().unwrap());
+        let dirty = self.dirty.load(Ordering::SeqCst);
+        let map = self.map.read().unwrap().clone();
+
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(serde_json::to_string(&map)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, self.dir.join(SNAPSHOT_FILE))?;
+        match log {
+            Some(log) => log.set_len(0)?,
+            // left behind by a run with the append log
+            None => match fs::remove_file(self.dir.join(APPEND_LOG_FILE)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+        self.dirty.fetch_sub(dirty, Ordering::SeqCst);
+        trace!("snapshot of {} pairs taken", map.len());
+        Ok(())
+    }
+
+    /// Apply `f` to the map, then log the change it returns, if any
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, String>) -> (T, Option<Change>),
+    ) -> Result<T> {
+        let log = self.append_log.as_ref().map(|log| log.lock().unwrap());
+        let mut map = self.map.write().unwrap();
+        let (result, change) = f(&mut map);
+        if let Some(change) = change {
+            if let Some(mut log) = log {
+                let mut line = serde_json::to_string(&change)?;
+                line.push('\n');
+                log.write_all(line.as_bytes())?;
+            }
+            self.dirty.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(result)
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if self.dirty.load(Ordering::SeqCst) > 0
+            && let Err(e) = self.snapshot()
+        {
+            warn!("Fail to take the last snapshot on close: {}", e);
+        }
+    }
+}
+
+/// Options of a `MemStore`
+#[derive(Debug, Clone)]
+pub struct MemStoreBuilder {
+    snapshot_interval: Duration,
+    append_log: bool,
+}
+
+impl Default for MemStoreBuilder {
+    fn default() -> Self {
+        Self {
+            snapshot_interval: SNAPSHOT_INTERVAL,
+            append_log: false,
+        }
+    }
+}
+
+impl MemStoreBuilder {
+    /// Time between two snapshots, taken only if something was written
+    pub fn snapshot_interval(mut self, interval: Duration) -> Self {
+        self.snapshot_interval = interval;
+        self
+    }
+
+    /// Also append every write to a log, so a crash loses nothing
+    /// Without it the writes since the last snapshot are lost.
+    pub fn append_log(mut self, enabled: bool) -> Self {
+        self.append_log = enabled;
+        self
+    }
+
+    /// Open the store in the given directory, loading what was persisted
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<MemStore> {
+        let dir: PathBuf = path.into();
+        fs::create_dir_all(&dir)?;
+        match Manifest::load(&dir)? {
+            Some(manifest) => manifest.check(ENGINE_NAME)?,
+            None => Manifest::new(ENGINE_NAME).store(&dir)?,
+        }
+
+        let mut map = match fs::read_to_string(dir.join(SNAPSHOT_FILE)) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let replayed = replay(&dir.join(APPEND_LOG_FILE), &mut map)?;
+
+        let append_log = match self.append_log {
+            true => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(dir.join(APPEND_LOG_FILE))?,
+            )),
+            false => None,
+        };
+        let shared = Arc::new(Shared {
+            dir,
+            map: RwLock::new(map),
+            append_log,
+            dirty: AtomicU64::new(replayed),
+        });
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || snapshot_periodically(weak, self.snapshot_interval));
+        Ok(MemStore { shared })
+    }
+}
+
+/// Apply the append log at `path` to `map`, return the number of changes
+/// A torn last line, left by a crash, is dropped.
+fn replay(path: &PathBuf, map: &mut BTreeMap<String, String>) -> Result<u64> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut replayed = 0;
+    for line in BufReader::new(file).lines() {
+        let change: Change = match serde_json::from_str(&line?) {
+            Ok(change) => change,
+            Err(e) => {
+                warn!("Stop replaying the append log at a torn record: {}", e);
+                break;
+            }
+        };
+        match change {
+            // expiry is not supported, the store never logs it
+            Change::Set { key, value }
+            | Change::SetExpiring { key, value, .. }
+            | Change::SetEnveloped { key, value, .. } => {
+                map.insert(key, value);
+            }
+            Change::Remove { key } => {
+                map.remove(&key);
+            }
+            Change::RemovePrefix { prefix } => {
+                let keys: Vec<String> = map
+                    .range(prefix_range(&prefix))
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                for key in keys {
+                    map.remove(&key);
+                }
+            }
+            Change::RemoveRange { start, end } => {
+                if !is_empty_range(&start, &end) {
+                    let keys: Vec<String> =
+                        map.range((start, end)).map(|(k, _)| k.clone()).collect();
+                    for key in keys {
+                        map.remove(&key);
+                    }
+                }
+            }
+        }
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+/// Return once every clone of the store is dropped
+fn snapshot_periodically(shared: Weak<Shared>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if shared.dirty.load(Ordering::SeqCst) == 0 {
+            continue;
+        }
+        if let Err(e) = shared.snapshot() {
+            warn!("Fail to take a snapshot: {}", e);
+        }
+    }
+}
+
+impl MemStore {
+    pub fn builder() -> MemStoreBuilder {
+        MemStoreBuilder::default()
+    }
+
+    /// Open with snapshots every `SNAPSHOT_INTERVAL` and no append log
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::builder().open(path)
+    }
+
+    /// Take a snapshot right away, like Redis `SAVE`
+    pub fn save(&self) -> Result<()> {
+        self.shared.snapshot()
+    }
+
+    fn set_if(&self, key: String, value: String, exists: bool) -> Result<bool> {
+        self.shared.write(|map| {
+            if map.contains_key(&key) != exists {
+                return (false, None);
+            }
+            map.insert(key.clone(), value.clone());
+            (true, Some(Change::Set { key, value }))
+        })
+    }
+}
+
+impl KvsEngine for MemStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shared.write(|map| {
+            map.insert(key.clone(), value.clone());
+            ((), Some(Change::Set { key, value }))
+        })
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.shared.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shared.write(|map| match map.remove(&key) {
+            Some(_) => (Ok(()), Some(Change::Remove { key })),
+            None => (Err(KvsError::KeyNotFound), None),
+        })?
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, false)
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, true)
+    }
+
+    fn take(&self, key: String) -> Result<Option<String>> {
+        self.shared.write(|map| match map.remove(&key) {
+            Some(old) => (Some(old), Some(Change::Remove { key })),
+            None => (None, None),
+        })
+    }
+
+    fn insert(&self, key: String, value: String) -> Result<Option<String>> {
+        self.shared.write(|map| {
+            let old = map.insert(key.clone(), value.clone());
+            (old, Some(Change::Set { key, value }))
+        })
+    }
+
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<usize> {
+        let (start, end): (Bound<String>, Bound<String>) =
+            (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&start, &end) {
+            return Ok(0);
+        }
+        self.shared.write(|map| {
+            let keys: Vec<String> = map
+                .range((start.clone(), end.clone()))
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in keys.iter() {
+                map.remove(key);
+            }
+            match keys.len() {
+                0 => (0, None),
+                n => (n, Some(Change::RemoveRange { start, end })),
+            }
+        })
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        let map = self.shared.map.read().unwrap();
+        Ok(map.range(prefix_range(prefix)).count())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.shared.map.read().unwrap().len())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.shared.map.read().unwrap().contains_key(key))
+    }
+
+    fn keys(&self, pattern: &Pattern) -> Result<Vec<String>> {
+        let map = self.shared.map.read().unwrap();
+        Ok(map
+            .range(prefix_range(pattern.prefix()))
+            .filter(|(k, _)| pattern.matches(k))
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    /// The pairs are copied when the scan starts
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<ScanIter> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&start, &end) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let map = self.shared.map.read().unwrap();
+        let pairs: Vec<(String, String)> = map
+            .range((start, end))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Ok(Box::new(pairs.into_iter().map(Ok)))
+    }
+}
@@ -0,0 +1,110 @@
+//! A soft limit on the memory used by stores, shared by all of them
+//!
+//! Usage is estimated rather than measured: the index entries, the buffers of
+//! the open log readers and the hints of the active log. Once the stores
+//! sharing a budget go over it, they close cached log readers and seal their
+//! active log instead of growing until the OOM killer picks the process.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Bytes of an index entry beside its key, e.g. the tree node around it
+pub(crate) const ENTRY_OVERHEAD: usize = 96;
+/// Bytes buffered by an open log reader
+const READER_BUFFER: usize = 8 * 1024;
+
+/// A soft memory limit, in bytes, for the stores opened with it
+///
+/// A server shares one budget among all its databases, which makes the limit
+/// process-wide. Going over it is never an error, the stores shrink what they
+/// cache and report `memory_pressure` in their stats.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    shared: Arc<Budget>,
+}
+
+struct Budget {
+    limit: usize,
+    stores: Mutex<Vec<Weak<Usage>>>,
+    // result of the last check
+    pressure: AtomicBool,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            shared: Arc::new(Budget {
+                limit,
+                stores: Mutex::new(Vec::new()),
+                pressure: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.shared.limit
+    }
+
+    /// Estimated bytes used by the open stores sharing the budget
+    pub fn usage(&self) -> usize {
+        let mut stores = self.shared.stores.lock().unwrap();
+        stores.retain(|s| s.strong_count() > 0);
+        stores
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|s| s.total())
+            .sum()
+    }
+
+    /// Whether the usage was over the limit when last checked
+    pub fn under_pressure(&self) -> bool {
+        self.shared.pressure.load(Ordering::SeqCst)
+    }
+
+    /// Sum the usage up again, return whether it is over the limit
+    pub(crate) fn check(&self) -> bool {
+        let pressure = self.usage() > self.shared.limit;
+        self.shared.pressure.store(pressure, Ordering::SeqCst);
+        pressure
+    }
+
+    /// Account for the memory of one more store
+    pub(crate) fn share(&self) -> MemoryShare {
+        let usage = Arc::new(Usage::default());
+        self.shared
+            .stores
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&usage));
+        MemoryShare {
+            budget: self.clone(),
+            usage,
+        }
+    }
+}
+
+/// The part of a budget used by one store, shared by its writer and readers
+#[derive(Clone)]
+pub(crate) struct MemoryShare {
+    pub budget: MemoryBudget,
+    pub usage: Arc<Usage>,
+}
+
+/// Estimated memory of one store
+#[derive(Default)]
+pub(crate) struct Usage {
+    /// Bytes of the index
+    pub index: AtomicUsize,
+    /// Log readers open in all clones of the store
+    pub readers: AtomicUsize,
+    /// Bytes buffered by the writer, e.g. the hints of the active log
+    pub buffers: AtomicUsize,
+}
+
+impl Usage {
+    pub fn total(&self) -> usize {
+        self.index.load(Ordering::SeqCst)
+            + self.readers.load(Ordering::SeqCst) * READER_BUFFER
+            + self.buffers.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,55 @@
+//! Merge operands, see `KvStoreBuilder::merge_operator`
+//!
+//! `KvStore::merge` appends its operand as a record of its own, under a
+//! reserved key made of the key and a sequence number, without reading the
+//! value. A read folds the operands into the value with the merge operator,
+//! in the order they were written. Once a key has `MAX_OPERANDS` of them, or
+//! on `KvStore::compact`, the folded value is written back and the operands
+//! are removed in the same batch. A set, a remove or a batch writing the key
+//! drops its operands the same way, so they never outlive the value they
+//! were merged into.
+
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::prefix_range;
+
+/// Folds the operands of a key, oldest first, into its value
+/// The value is `None` while the key has none, e.g. before the first merge.
+pub type MergeOperator = Arc<dyn Fn(&str, Option<&str>, &[String]) -> String + Send + Sync>;
+
+/// Operands of one key folded by the write which reaches this many
+pub(crate) const MAX_OPERANDS: usize = 64;
+
+/// Starts the keys holding operands, among the `RESERVED` ones
+const OPERAND_PREFIX: &str = "\u{10FFFF}\u{2}";
+/// Digits of the sequence number at the end of an operand key
+const OPERAND_DIGITS: usize = 20;
+
+/// Key of operand `seq` of `key`
+/// The number is zero padded, so the operands of a key sort in order.
+pub(crate) fn operand_key(key: &str, seq: u64) -> String {
+    format!(
+        "{}{}\u{0}{:0width$}",
+        OPERAND_PREFIX,
+        key,
+        seq,
+        width = OPERAND_DIGITS
+    )
+}
+
+/// Range of the operands of `key`
+pub(crate) fn operand_range(key: &str) -> (Bound<String>, Bound<String>) {
+    prefix_range(&format!("{}{}\u{0}", OPERAND_PREFIX, key))
+}
+
+/// Range of the operands of every key
+pub(crate) fn all_operands() -> (Bound<String>, Bound<String>) {
+    prefix_range(OPERAND_PREFIX)
+}
+
+/// The key an operand key belongs to
+pub(crate) fn owner(operand_key: &str) -> Option<&str> {
+    let rest = operand_key.strip_prefix(OPERAND_PREFIX)?;
+    rest.get(..rest.len().checked_sub(OPERAND_DIGITS + 1)?)
+}
@@ -0,0 +1,154 @@
+//! Latency of the operations of a store, recorded with the `metrics` feature
+//!
+//! Without the feature `Metrics` records nothing and costs nothing, so the
+//! store times its operations unconditionally.
+
+use std::time::Duration;
+#[cfg(feature = "metrics")]
+use std::{sync::Mutex, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// log2 of the buckets each power of two is split into
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// A histogram of durations with a bounded relative error
+///
+/// Like an HDR histogram, every power of two is split into 16 buckets, so a
+/// percentile is off by at most 1/16 while the histogram stays a few hundred
+/// counters. Durations are recorded in microseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    // records per bucket, grown on demand
+    buckets: Vec<u64>,
+    count: u64,
+    // in microseconds
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = bucket_of(micros);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(micros);
+        self.max = self.max.max(micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_micros(self.sum / count),
+        }
+    }
+
+    /// The duration `q` of the records took at most, e.g. 0.99 for p99
+    /// Rounded up to the end of its bucket.
+    pub fn percentile(&self, q: f64) -> Duration {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(last_of(bucket).min(self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+/// Values below `SUB_BUCKETS` have a bucket each, then 16 per power of two
+fn bucket_of(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let exp = 63 - value.leading_zeros();
+    let shift = exp - SUB_BUCKET_BITS;
+    let sub = (value >> shift) & (SUB_BUCKETS - 1);
+    ((shift as u64 + 1) * SUB_BUCKETS + sub) as usize
+}
+
+/// Largest value counted in `bucket`
+fn last_of(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    let sub = bucket % SUB_BUCKETS;
+    ((SUB_BUCKETS + sub + 1) << shift).saturating_sub(1)
+}
+
+/// Latency of each operation of a store, since it was opened
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub get: Histogram,
+    pub set: Histogram,
+    pub remove: Histogram,
+    /// Merging the logs, the swap at the end is not included
+    pub compaction: Histogram,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Operation {
+    Get,
+    Set,
+    Remove,
+    Compaction,
+}
+
+/// Histograms shared by a store and its writer
+#[derive(Default)]
+pub(crate) struct Metrics {
+    #[cfg(feature = "metrics")]
+    latency: Mutex<LatencyStats>,
+}
+
+impl Metrics {
+    /// Run `f`, timing it as one `op`
+    pub(crate) fn time<T>(&self, op: Operation, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "metrics")]
+        {
+            let start = Instant::now();
+            let result = f();
+            self.record(op, start.elapsed());
+            result
+        }
+        #[cfg(not(feature = "metrics"))]
+        {
+            let _ = op;
+            f()
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record(&self, op: Operation, elapsed: Duration) {
+        let mut latency = self.latency.lock().unwrap();
+        let histogram = match op {
+            Operation::Get => &mut latency.get,
+            Operation::Set => &mut latency.set,
+            Operation::Remove => &mut latency.remove,
+            Operation::Compaction => &mut latency.compaction,
+        };
+        histogram.record(elapsed);
+    }
+
+    #[cfg(feature = "metrics")]
+    pub(crate) fn latency(&self) -> LatencyStats {
+        self.latency.lock().unwrap().clone()
+    }
+}
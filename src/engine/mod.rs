@@ -1,4 +1,11 @@
+use std::io::{Read, Write};
+use std::ops::{Bound, RangeBounds};
+
 use super::error::Result;
+use pattern::Pattern;
+
+/// Pairs of a scan in key order, values are read as it advances
+pub type ScanIter = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn set(&self, key: String, value: String) -> Result<()>;
@@ -6,7 +13,136 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn get(&self, key: String) -> Result<Option<String>>;
 
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Get several keys at once, the values come back in the order of `keys`.
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        keys.iter().map(|key| self.get(key.clone())).collect()
+    }
+
+    /// Set `key` only if it does not exist yet (NX).
+    /// Return whether the value is written.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool>;
+
+    /// Set `key` only if it already exists (XX).
+    /// Return whether the value is written.
+    fn set_if_present(&self, key: String, value: String) -> Result<bool>;
+
+    /// Remove `key` and return its old value atomically.
+    /// Removing a missing key is not an error here, `None` is returned.
+    fn take(&self, key: String) -> Result<Option<String>>;
+
+    /// Map `key` to `value` and return its old value atomically.
+    fn insert(&self, key: String, value: String) -> Result<Option<String>>;
+
+    /// Remove every key inside `range` at once.
+    /// Return the number of removed keys.
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<usize>;
+
+    /// Remove every key starting with `prefix` at once.
+    /// Return the number of removed keys.
+    fn remove_prefix(&self, prefix: &str) -> Result<usize> {
+        self.remove_range(prefix_range(prefix))
+    }
+
+    /// Count the keys starting with `prefix` without reading any value.
+    fn count_prefix(&self, prefix: &str) -> Result<usize>;
+
+    /// Count all keys without reading any value.
+    /// Keys which expired but are not removed yet may be counted.
+    fn len(&self) -> Result<usize>;
+
+    /// Whether the store holds no key at all.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Whether `key` exists, without reading its value.
+    fn contains_key(&self, key: &str) -> Result<bool>;
+
+    /// List the keys matching `pattern` in order, without reading any value.
+    fn keys(&self, pattern: &Pattern) -> Result<Vec<String>>;
+
+    /// Iterate the pairs inside `range` in key order.
+    /// Values are read lazily, a key removed before it is reached is skipped.
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<ScanIter>;
+
+    /// Every pair whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.scan(prefix_range(prefix))?.collect()
+    }
+
+    /// Write every pair to `out` in the format of `dump`.
+    /// Return the number of pairs.
+    fn export(&self, out: impl Write) -> Result<usize> {
+        dump::export(self, out)
+    }
+
+    /// Set every pair of a dump written by `export`, of any engine.
+    /// Return the number of pairs.
+    fn import(&self, input: impl Read) -> Result<usize> {
+        dump::import(self, input)
+    }
+}
+
+/// Turn a prefix into the range of keys starting with it
+///
+/// The upper bound is the smallest string greater than every key with the
+/// prefix, i.e. the prefix with its last char bumped by one. Trailing
+/// `char::MAX` can not be bumped, so they are dropped first.
+pub fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let start = Bound::Included(prefix.to_owned());
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(c) = chars.pop() {
+        let next = match c {
+            char::MAX => continue,
+            '\u{D7FF}' => '\u{E000}',
+            c => char::from_u32(c as u32 + 1).unwrap(),
+        };
+        chars.push(next);
+        return (start, Bound::Excluded(chars.into_iter().collect()));
+    }
+    (start, Bound::Unbounded)
+}
+
+/// `BTreeMap::range` panics on an inverted range, check it beforehand
+pub fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
+    }
+}
+
+/// Starts the keys a `KvStore` keeps for itself: the previous versions of
+/// `KvStoreBuilder::keep_versions`, the chunks of large values and the
+/// operands of merges
+///
+/// No user key may start with it. It sorts after every other key, so the
+/// index cuts it off the end of every listing and count.
+pub const RESERVED: &str = "\u{10FFFF}";
+
+/// Whether `key` is one the store keeps for itself, see `RESERVED`
+pub fn is_reserved(key: &str) -> bool {
+    key.starts_with(RESERVED)
 }
 
+mod bucket;
+mod cache;
+mod chunk;
+pub mod condition;
+pub mod dump;
+mod flight;
+mod growth;
+mod keydir;
 pub mod kvs;
+pub mod mem;
+mod memory;
+mod merge;
+mod metrics;
+pub mod pattern;
 pub mod sled;
+mod syncer;
+mod txn;
+mod watch;
@@ -0,0 +1,90 @@
+/// A compiled glob pattern over keys
+///
+/// `*` matches any run of chars, `?` matches exactly one char and `\`
+/// escapes the next char. Everything else matches itself, e.g.
+/// `user:*:profile`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+    // literal chars before the first wildcard
+    prefix: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    Any,
+    Star,
+}
+
+impl Pattern {
+    pub fn new(glob: &str) -> Self {
+        let mut tokens = Vec::new();
+        let mut chars = glob.chars();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '*' => Token::Star,
+                '?' => Token::Any,
+                // a trailing backslash matches itself
+                '\\' => Token::Literal(chars.next().unwrap_or('\\')),
+                c => Token::Literal(c),
+            };
+            // consecutive stars match the same as one
+            if token == Token::Star && tokens.last() == Some(&Token::Star) {
+                continue;
+            }
+            tokens.push(token);
+        }
+        let prefix = tokens
+            .iter()
+            .map_while(|t| match t {
+                Token::Literal(c) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        Self { tokens, prefix }
+    }
+
+    /// Every matching key starts with this prefix
+    /// So only the keys in its `prefix_range` need to be checked.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut t, mut k) = (0, 0);
+        // position of the last star, and the key position it resumes from
+        let mut star = None;
+        while k < key.len() {
+            match self.tokens.get(t) {
+                Some(Token::Star) => {
+                    star = Some((t, k));
+                    t += 1;
+                    continue;
+                }
+                Some(Token::Any) => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                Some(Token::Literal(c)) if *c == key[k] => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            // mismatch, let the last star swallow one more char
+            match star {
+                Some((st, sk)) => {
+                    t = st + 1;
+                    k = sk + 1;
+                    star = Some((st, sk + 1));
+                }
+                None => return false,
+            }
+        }
+        self.tokens[t..].iter().all(|t| *t == Token::Star)
+    }
+}
@@ -1,16 +1,19 @@
 use std::env;
+use std::ops::RangeBounds;
 
-use super::KvsEngine;
+use super::pattern::Pattern;
+use super::{KvsEngine, ScanIter, is_empty_range};
 use crate::error::{KvsError, Result};
 use log::debug;
-use sled::Db;
+use sled::{Batch, Db};
 
+#[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get(&self, key: String) -> Result<Option<String>> {
         let ans = self.db.get(key)?;
         match ans {
             None => {
@@ -25,7 +28,7 @@ impl KvsEngine for SledKvsEngine {
         }
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&self, key: String) -> Result<()> {
         let q = self.db.remove(key)?;
         if q.is_none() {
             return Err(KvsError::KeyNotFound);
@@ -34,11 +37,119 @@ impl KvsEngine for SledKvsEngine {
         Ok(())
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&self, key: String, value: String) -> Result<()> {
         self.db.insert(key, value)?;
         self.db.flush()?;
         Ok(())
     }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let written = self
+            .db
+            .compare_and_swap(key, None as Option<&[u8]>, Some(value.as_bytes()))?
+            .is_ok();
+        if written {
+            self.db.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        // retry until no one else modifies the key between `get` and `cas`
+        loop {
+            let old = match self.db.get(&key)? {
+                None => return Ok(false),
+                Some(old) => old,
+            };
+            let swapped = self
+                .db
+                .compare_and_swap(&key, Some(old), Some(value.as_bytes()))?
+                .is_ok();
+            if swapped {
+                self.db.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn take(&self, key: String) -> Result<Option<String>> {
+        let old = self.db.remove(key)?;
+        self.db.flush()?;
+        match old {
+            None => Ok(None),
+            Some(arr) => Ok(Some(String::from_utf8(arr.to_vec())?)),
+        }
+    }
+
+    fn insert(&self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.db.insert(key, value)?;
+        self.db.flush()?;
+        match old {
+            None => Ok(None),
+            Some(arr) => Ok(Some(String::from_utf8(arr.to_vec())?)),
+        }
+    }
+
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<usize> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&range.0, &range.1) {
+            return Ok(0);
+        }
+        let mut batch = Batch::default();
+        let mut cnt = 0;
+        for item in self.db.range::<String, _>(range) {
+            let (key, _) = item?;
+            batch.remove(key);
+            cnt += 1;
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(cnt)
+    }
+
+    fn count_prefix(&self, prefix: &str) -> Result<usize> {
+        let mut cnt = 0;
+        for item in self.db.scan_prefix(prefix) {
+            item?;
+            cnt += 1;
+        }
+        Ok(cnt)
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.count_prefix("")
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.db.get(key)?.is_some())
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<ScanIter> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        if is_empty_range(&range.0, &range.1) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let iter = self.db.range::<String, _>(range).map(|item| -> Result<_> {
+            let (key, value) = item?;
+            Ok((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ))
+        });
+        Ok(Box::new(iter))
+    }
+
+    fn keys(&self, pattern: &Pattern) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for item in self.db.scan_prefix(pattern.prefix()) {
+            let (key, _) = item?;
+            let key = String::from_utf8(key.to_vec())?;
+            if pattern.matches(&key) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
 }
 
 impl SledKvsEngine {
@@ -0,0 +1,213 @@
+use std::fs::File;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{KvsError, Result};
+
+/// Buckets of `SyncStats::batch_sizes`
+pub const BATCH_BUCKETS: usize = 12;
+
+/// Latency and queue depth of the sync thread
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// Number of `fdatasync` calls
+    pub syncs: u64,
+    /// Writes made durable by those calls, each call covers a whole batch
+    pub synced_writes: u64,
+    /// Syncs by the writes they covered, bucket `i` counts batches of
+    /// `2^i` up to `2^(i+1) - 1` writes, the last one all larger batches
+    pub batch_sizes: [u64; BATCH_BUCKETS],
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    /// Writers currently waiting for their write to be synced
+    pub queue_depth: usize,
+}
+
+struct State {
+    // active log, synced by the thread
+    file: File,
+    // highest write a writer waits for
+    requested: u64,
+    // every write up to here is on disk
+    synced: u64,
+    // a failed sync poisons the syncer, the page cache state is unknown
+    failed: Option<String>,
+    closed: bool,
+    stats: SyncStats,
+}
+
+/// A dedicated thread syncing the active log on behalf of the writers
+///
+/// Writers append under the writer lock, then wait here with the lock
+/// released. All writes appended while a sync runs are covered by the next
+/// one, and syncs are at least `interval` apart, so the cost of durability
+/// is shared by every writer instead of paid by each. A batch of `max_batch`
+/// waiting writes is synced without waiting out the interval.
+///
+/// In the background, writers only ask for their write to be synced and
+/// go on, the thread syncs once per `interval` while writes come in.
+pub(crate) struct Syncer {
+    state: Mutex<State>,
+    changed: Condvar,
+    max_batch: u64,
+    background: bool,
+}
+
+impl Syncer {
+    pub(crate) fn start(
+        file: File,
+        interval: Duration,
+        max_batch: u64,
+        background: bool,
+    ) -> Arc<Self> {
+        let syncer = Arc::new(Self {
+            state: Mutex::new(State {
+                file,
+                requested: 0,
+                synced: 0,
+                failed: None,
+                closed: false,
+                stats: SyncStats::default(),
+            }),
+            changed: Condvar::new(),
+            max_batch: max_batch.max(1),
+            background,
+        });
+        let worker = Arc::clone(&syncer);
+        thread::spawn(move || worker.run(interval));
+        syncer
+    }
+
+    /// Have write number `written` synced: wait for it, or only ask for it
+    /// in the background
+    /// A failed sync in the background fails the writes after it.
+    pub(crate) fn sync(&self, written: u64) -> Result<()> {
+        if !self.background {
+            return self.wait(written);
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(e) = &state.failed {
+            return Err(KvsError::IoError(io::Error::other(e.clone())));
+        }
+        state.requested = state.requested.max(written);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Block until write number `written` is on disk
+    pub(crate) fn wait(&self, written: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.synced >= written {
+            return Ok(());
+        }
+        state.requested = state.requested.max(written);
+        state.stats.queue_depth += 1;
+        self.changed.notify_all();
+        while state.synced < written && state.failed.is_none() && !state.closed {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.stats.queue_depth -= 1;
+        match &state.failed {
+            Some(e) => Err(KvsError::IoError(io::Error::other(e.clone()))),
+            None if state.synced < written => Err(KvsError::StringError(
+                "store closed before the write was synced".to_owned(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The writer switched to a new active log
+    /// The old one is synced already, so are all writes up to `written`.
+    pub(crate) fn rotate(&self, file: File, written: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.file = file;
+        state.synced = state.synced.max(written);
+        self.changed.notify_all();
+    }
+
+    pub(crate) fn stats(&self) -> SyncStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Wake the thread up, so it exits
+    /// In the background, the writes not synced yet are synced first.
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if self.background
+            && state.requested > state.synced
+            && state.failed.is_none()
+            && let Err(e) = state.file.sync_data()
+        {
+            warn!("Fail to sync the active log on close: {}", e);
+        }
+        self.changed.notify_all();
+    }
+
+    fn run(&self, interval: Duration) {
+        let mut last_sync: Option<Instant> = None;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            while state.requested <= state.synced && !state.closed {
+                state = self.changed.wait(state).unwrap();
+            }
+            if state.closed {
+                return;
+            }
+            if let Some(deadline) = last_sync.map(|t| t + interval) {
+                // bounded rate, more writers join the batch meanwhile
+                while !state.closed && state.requested.saturating_sub(state.synced) < self.max_batch
+                {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+                }
+            }
+            let target = state.requested;
+            let file = match state.file.try_clone() {
+                Ok(file) => file,
+                Err(e) => {
+                    self.fail(state, e);
+                    return;
+                }
+            };
+            drop(state);
+
+            let start = Instant::now();
+            let result = file.sync_data();
+            let latency = start.elapsed();
+            last_sync = Some(Instant::now());
+
+            let mut state = self.state.lock().unwrap();
+            if let Err(e) = result {
+                self.fail(state, e);
+                return;
+            }
+            trace!("synced up to write {} in {:?}", target, latency);
+            let batch = target.saturating_sub(state.synced);
+            state.stats.syncs += 1;
+            state.stats.synced_writes += batch;
+            if batch > 0 {
+                let bucket = (batch.ilog2() as usize).min(BATCH_BUCKETS - 1);
+                state.stats.batch_sizes[bucket] += 1;
+            }
+            state.stats.total_latency += latency;
+            state.stats.max_latency = state.stats.max_latency.max(latency);
+            state.synced = state.synced.max(target);
+            self.changed.notify_all();
+        }
+    }
+
+    fn fail(&self, mut state: std::sync::MutexGuard<State>, e: io::Error) {
+        warn!("Fail to sync the active log: {}", e);
+        state.failed = Some(e.to_string());
+        self.changed.notify_all();
+    }
+}
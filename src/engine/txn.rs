@@ -0,0 +1,69 @@
+//! Optimistic transactions, see `KvStore::transaction`
+//!
+//! A transaction reads from the store as it goes and keeps its writes in
+//! memory. Each read remembers the version of its key, the record the index
+//! points to, or none for a missing key. `commit` takes the writer lock,
+//! checks that every key read still has that version, and appends the writes
+//! as one batch. If a key was written meanwhile nothing is appended and the
+//! commit fails with `KvsError::Conflict`; run the transaction again.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::kvs::{KeyVersion, KvStore, WriteBatch};
+use crate::error::Result;
+
+/// Reads and buffered writes of one transaction
+///
+/// Dropping it without `commit` discards the writes.
+pub struct Txn {
+    store: KvStore,
+    // the version each key had at its first read
+    reads: HashMap<String, Option<KeyVersion>>,
+    // `None` removes the key
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Txn {
+    pub(crate) fn new(store: KvStore) -> Self {
+        Self {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Value of `key`, the one written by this transaction if any
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.store.get_versioned(key.clone())?;
+        // a later read of another version fails the commit anyway
+        self.reads.entry(key).or_insert(version);
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removing a missing key is not an error, like in a `WriteBatch`
+    pub fn remove(&mut self, key: String) {
+        self.writes.insert(key, None);
+    }
+
+    /// Append the writes in one batch, if no key read was written meanwhile
+    ///
+    /// A compaction moves the records, so it may fail a commit whose keys did
+    /// not change. A transaction without writes only checks its reads.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.store.commit(self.reads, batch)
+    }
+}
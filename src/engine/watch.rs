@@ -0,0 +1,247 @@
+//! Changes of the keys under a prefix, see `KvStore::watch`, or of the keys
+//! and prefixes of a `WatchFilter`
+//!
+//! The writer hands each applied write, along with its number, to one thread
+//! per store. When the store syncs, that thread first waits for the syncer
+//! to cover the write, so a watcher never sees a write which a crash could
+//! still undo. The writes go out in the order they were applied, and a slow
+//! watcher never blocks the writer.
+
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread;
+
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::kvs::Change;
+use super::syncer::Syncer;
+use super::{is_empty_range, prefix_range};
+use crate::error::{KvsError, Result};
+
+/// A durable write to the keys a watcher asked for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Removal of the keys between the bounds, some may be out of the
+    /// watched prefix
+    RmRange {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+    /// Removal of every key starting with `prefix`, which may be shorter or
+    /// longer than the watched one
+    RmPrefix {
+        prefix: String,
+    },
+}
+
+impl ChangeEvent {
+    fn is_set(&self) -> bool {
+        matches!(self, ChangeEvent::Set { .. })
+    }
+
+    /// Whether the write touches `key`
+    fn touches_key(&self, key: &String) -> bool {
+        match self {
+            ChangeEvent::Set { key: written, .. } | ChangeEvent::Rm { key: written } => {
+                written == key
+            }
+            ChangeEvent::RmRange { start, end } => (start.as_ref(), end.as_ref()).contains(key),
+            ChangeEvent::RmPrefix { prefix } => key.starts_with(prefix.as_str()),
+        }
+    }
+
+    /// Whether the write may touch a key starting with `prefix`
+    fn touches(&self, prefix: &str) -> bool {
+        match self {
+            ChangeEvent::Set { key, .. } | ChangeEvent::Rm { key } => key.starts_with(prefix),
+            ChangeEvent::RmRange { start, end } => {
+                let (first, after) = prefix_range(prefix);
+                !is_empty_range(start, &after) && !is_empty_range(&first, end)
+            }
+            ChangeEvent::RmPrefix { prefix: removed } => {
+                removed.starts_with(prefix) || prefix.starts_with(removed.as_str())
+            }
+        }
+    }
+}
+
+impl From<Change> for ChangeEvent {
+    fn from(change: Change) -> Self {
+        match change {
+            Change::Set { key, value }
+            | Change::SetExpiring { key, value, .. }
+            | Change::SetEnveloped { key, value, .. } => ChangeEvent::Set { key, value },
+            Change::Remove { key } => ChangeEvent::Rm { key },
+            Change::RemoveRange { start, end } => ChangeEvent::RmRange { start, end },
+            Change::RemovePrefix { prefix } => ChangeEvent::RmPrefix { prefix },
+        }
+    }
+}
+
+/// Which writes a watcher gets, see `KvStore::watch_filtered`
+///
+/// A write goes out if it touches one of `keys` or of `prefixes`, or any key
+/// while both are empty, and it passes `only` and `value`. The filter runs
+/// on the server, so the writes left out never reach the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WatchFilter {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefixes: Vec<String>,
+    /// Only the sets, or only the removals
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub only: Option<WriteKind>,
+    /// Regex the value of a set must match, removals carry no value and
+    /// are not held back by it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+/// Kind of the writes a `WatchFilter` lets through
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteKind {
+    Set,
+    /// Removals of a key, of a range or of a prefix
+    Remove,
+}
+
+impl WatchFilter {
+    /// Every write to the keys starting with `prefix`
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self {
+            prefixes: vec![prefix.into()],
+            ..Self::default()
+        }
+    }
+
+    /// Whether it lets every write through
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A `WatchFilter` with its regex compiled
+pub(crate) struct Matcher {
+    filter: WatchFilter,
+    value: Option<Regex>,
+}
+
+impl Matcher {
+    /// Fails with `KvsError::InvalidWatch` on a bad regex
+    pub fn new(filter: WatchFilter) -> Result<Self> {
+        let value = filter
+            .value
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| KvsError::InvalidWatch(e.to_string()))?;
+        Ok(Self { filter, value })
+    }
+
+    /// Every write to the keys starting with `prefix`
+    pub fn prefix(prefix: String) -> Self {
+        Self {
+            filter: WatchFilter::prefix(prefix),
+            value: None,
+        }
+    }
+
+    fn wants(&self, event: &ChangeEvent) -> bool {
+        let filter = &self.filter;
+        let kind = match filter.only {
+            Some(WriteKind::Set) => event.is_set(),
+            Some(WriteKind::Remove) => !event.is_set(),
+            None => true,
+        };
+        let value = match (&self.value, event) {
+            (Some(regex), ChangeEvent::Set { value, .. }) => regex.is_match(value),
+            _ => true,
+        };
+        let keys = (filter.keys.is_empty() && filter.prefixes.is_empty())
+            || filter.keys.iter().any(|key| event.touches_key(key))
+            || filter.prefixes.iter().any(|prefix| event.touches(prefix));
+        kind && value && keys
+    }
+}
+
+enum Message {
+    Watch(Matcher, Sender<ChangeEvent>),
+    // a write and the number of its record
+    Write(u64, ChangeEvent),
+}
+
+/// The watchers of one store, served by their own thread
+pub(crate) struct Watchers {
+    tx: Sender<Message>,
+    // receivers still listening, so the writer skips the work without any
+    active: Arc<AtomicUsize>,
+}
+
+impl Watchers {
+    /// The thread exits once the writer drops the watchers
+    pub fn start(syncer: Option<Arc<Syncer>>) -> Self {
+        let (tx, rx) = channel();
+        let active = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&active);
+        thread::spawn(move || run(rx, syncer, count));
+        Self { tx, active }
+    }
+
+    pub fn watch(&self, matcher: Matcher) -> Receiver<ChangeEvent> {
+        let (tx, rx) = channel();
+        self.active.fetch_add(1, Ordering::SeqCst);
+        // the thread lives as long as `self`
+        let _ = self.tx.send(Message::Watch(matcher, tx));
+        rx
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.load(Ordering::SeqCst) == 0
+    }
+
+    /// Deliver `change` once record number `written` is durable
+    pub fn send(&self, written: u64, change: Change) {
+        // the keys the store keeps for itself are never watched
+        if change.is_reserved() {
+            return;
+        }
+        let _ = self.tx.send(Message::Write(written, change.into()));
+    }
+}
+
+fn run(rx: Receiver<Message>, syncer: Option<Arc<Syncer>>, active: Arc<AtomicUsize>) {
+    let mut watchers: Vec<(Matcher, Sender<ChangeEvent>)> = Vec::new();
+    for message in rx {
+        let before = watchers.len();
+        match message {
+            Message::Watch(matcher, tx) => watchers.push((matcher, tx)),
+            Message::Write(written, event) => {
+                if let Some(syncer) = &syncer
+                    && let Err(e) = syncer.wait(written)
+                {
+                    // the write may be lost, so are the watchers
+                    warn!("stop the watchers, a write is not durable: {}", e);
+                    watchers.clear();
+                } else {
+                    watchers.retain(|(matcher, tx)| {
+                        !matcher.wants(&event) || tx.send(event.clone()).is_ok()
+                    });
+                }
+            }
+        }
+        // a new watcher is counted by `Watchers::watch`, here only those gone
+        active.fetch_sub(before.saturating_sub(watchers.len()), Ordering::SeqCst);
+    }
+}
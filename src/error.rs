@@ -1,7 +1,11 @@
 use failure::Fail;
 use std::{io, num::ParseIntError, string::FromUtf8Error};
 
-use crate::protocol::{GetResponse, RmResponse, SetResponse};
+use crate::engine::kvs::{KeyMetadata, ScanPage, SizeEstimate, StoreStats};
+use crate::protocol::{
+    CountResponse, GetResponse, IncrResponse, KeysResponse, PairsResponse, RmResponse,
+    ScanResponse, SetIfResponse, SetResponse, StatResponse, StatsResponse,
+};
 
 /// Self defined Error enum
 ///
@@ -33,6 +37,105 @@ pub enum KvsError {
     Utf8Error(FromUtf8Error),
     #[fail(display = "parse int error: {}", _0)]
     ParseIntError(ParseIntError),
+    /// Free disk space is below the reserve, the store only serves reads
+    #[fail(display = "disk space is below the reserve, store is read-only")]
+    DiskFull,
+    /// A set would make the logs of the store larger than its quota
+    #[fail(
+        display = "logs take {} bytes, a set would pass the quota of {}",
+        used, quota
+    )]
+    QuotaExceeded { used: u64, quota: u64 },
+    /// The record read back does not match the key or its checksum
+    #[fail(display = "value of key {} in log {} is corrupted", key, segment)]
+    Corruption { key: String, segment: usize },
+    /// A log record does not match its checksum
+    #[fail(
+        display = "record at offset {} of log {} is corrupted",
+        offset, segment
+    )]
+    CorruptRecord { segment: usize, offset: usize },
+    /// The data directory belongs to another engine or a newer format
+    #[fail(display = "incompatible data directory: {}", _0)]
+    IncompatibleStore(String),
+    /// `Select` of a database the server does not host
+    #[fail(display = "invalid database {}", _0)]
+    InvalidDatabase(usize),
+    /// A read modifier can not be applied to the value
+    #[fail(display = "fail to transform value: {}", _0)]
+    Transform(String),
+    /// The scan cursor was not returned by the store
+    #[fail(display = "invalid scan cursor")]
+    InvalidCursor,
+    /// A standby serves no traffic until it is promoted
+    #[fail(display = "store is a standby, promote it first")]
+    Standby,
+    /// A replica or a snapshot only serves reads
+    #[fail(display = "store is read-only, a replica or a snapshot")]
+    ReadOnly,
+    /// Only a standby applies the changes of a primary
+    #[fail(display = "store is not a standby")]
+    NotStandby,
+    /// A retry arrived while the first run of its request is not over
+    #[fail(display = "request with token {} is still running, retry later", _0)]
+    TokenInFlight(String),
+    /// The condition of a conditional update does not parse
+    #[fail(display = "invalid condition: {}", _0)]
+    InvalidCondition(String),
+    #[fail(display = "key of {} bytes is over the limit of {}", len, max)]
+    KeyTooLarge { len: usize, max: usize },
+    #[fail(display = "value of {} bytes is over the limit of {}", len, max)]
+    ValueTooLarge { len: usize, max: usize },
+    #[fail(display = "batch of {} writes is over the limit of {}", len, max)]
+    BatchTooLarge { len: usize, max: usize },
+    /// A request line longer than the server reads
+    #[fail(display = "request is over the limit of {} bytes", _0)]
+    RequestTooLarge(usize),
+    /// The server limits do not allow `FlushAll`
+    #[fail(display = "flush all is disabled on this server")]
+    FlushAllDisabled,
+    #[fail(display = "server is at its limit of {} connections", _0)]
+    TooManyConnections(usize),
+    /// The server has an authenticator and the connection sent no valid `Auth`
+    #[fail(display = "authentication required")]
+    Unauthenticated,
+    #[fail(display = "invalid credentials")]
+    InvalidCredentials,
+    /// The server is draining its connections, e.g. before maintenance
+    #[fail(display = "server is draining, retry in {} ms", _0)]
+    Draining(u64),
+    /// `Cancel` of an operation which is not running, maybe already over
+    #[fail(display = "no operation {} is running", _0)]
+    UnknownOperation(u64),
+    /// The operation was stopped by a `Cancel`
+    #[fail(display = "operation {} is cancelled", _0)]
+    Cancelled(u64),
+    /// `incr` of a key whose value does not parse as an i64
+    #[fail(display = "value of key {} is not an integer", _0)]
+    NotAnInteger(String),
+    #[fail(display = "increment of key {} overflows", _0)]
+    IncrOverflow(String),
+    /// The value breaks a rule of the validators of the server
+    #[fail(display = "invalid value of key {}: {}", key, reason)]
+    InvalidValue { key: String, reason: String },
+    /// A bucket name is empty or holds a NUL char
+    #[fail(display = "invalid bucket name {:?}", _0)]
+    InvalidBucket(String),
+    /// A key read by a transaction was written before it committed
+    #[fail(display = "transaction conflicts on key {}, retry it", _0)]
+    Conflict(String),
+    /// The directory holds no store, and the open may not create one
+    #[fail(display = "no store in {}", _0)]
+    StoreNotFound(String),
+    /// The directory holds a store, and the open must create a new one
+    #[fail(display = "a store exists in {} already", _0)]
+    StoreExists(String),
+    /// The key starts like those the store keeps for itself
+    #[fail(display = "key {:?} is reserved", _0)]
+    ReservedKey(String),
+    /// The value regex of a `WatchFilter` does not compile
+    #[fail(display = "invalid watch filter: {}", _0)]
+    InvalidWatch(String),
 }
 
 impl From<io::Error> for KvsError {
@@ -96,3 +199,78 @@ impl From<Result<()>> for RmResponse {
         }
     }
 }
+
+impl From<Result<bool>> for SetIfResponse {
+    fn from(value: Result<bool>) -> Self {
+        match value {
+            Ok(b) => Self::Ok(b),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<Option<KeyMetadata>>> for StatResponse {
+    fn from(value: Result<Option<KeyMetadata>>) -> Self {
+        match value {
+            Ok(m) => Self::Ok(m),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<(StoreStats, Option<SizeEstimate>)>> for StatsResponse {
+    fn from(value: Result<(StoreStats, Option<SizeEstimate>)>) -> Self {
+        match value {
+            Ok((stats, prefix)) => Self::Ok {
+                stats: Box::new(stats),
+                prefix,
+            },
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<i64>> for IncrResponse {
+    fn from(value: Result<i64>) -> Self {
+        match value {
+            Ok(n) => Self::Ok(n),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<usize>> for CountResponse {
+    fn from(value: Result<usize>) -> Self {
+        match value {
+            Ok(n) => Self::Ok(n),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<Vec<String>>> for KeysResponse {
+    fn from(value: Result<Vec<String>>) -> Self {
+        match value {
+            Ok(keys) => Self::Ok(keys),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<Vec<(String, String)>>> for PairsResponse {
+    fn from(value: Result<Vec<(String, String)>>) -> Self {
+        match value {
+            Ok(pairs) => Self::Ok(pairs),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl From<Result<ScanPage>> for ScanResponse {
+    fn from(value: Result<ScanPage>) -> Self {
+        match value {
+            Ok(page) => Self::Ok(page),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
@@ -1,6 +1,13 @@
+pub mod auth;
 pub mod client;
 pub mod engine;
 pub mod error;
+pub mod limits;
+pub mod logging;
+pub mod manifest;
 pub mod protocol;
 pub mod server;
+pub mod testing;
 pub mod thread_pool;
+pub mod transport;
+pub mod validate;
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{KvsError, Result};
+
+/// Bytes of a request line beside its key and value, e.g. the JSON around them
+const REQUEST_OVERHEAD: usize = 4096;
+
+/// Caps on what clients may ask of a server and its stores
+///
+/// The server refuses oversized requests and extra connections before any
+/// work is done, the store checks every write again, so embedding the store
+/// gives the same guarantees. Sizes are in bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_key: usize,
+    pub max_value: usize,
+    /// Writes in one `WriteBatch`
+    pub max_batch: usize,
+    /// Log files each reader keeps open, the least recent ones are closed
+    pub max_open_segments: usize,
+    /// Connections served at once by a server
+    pub max_connections: usize,
+    /// Whether a server accepts `FlushAll`, which empties a database
+    pub flush_all: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key: 64 * 1024,
+            max_value: 16 * 1024 * 1024,
+            max_batch: 10_000,
+            max_open_segments: 64,
+            max_connections: 1024,
+            flush_all: false,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_key(&self, key: &str) -> Result<()> {
+        if key.len() > self.max_key {
+            return Err(KvsError::KeyTooLarge {
+                len: key.len(),
+                max: self.max_key,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_value(&self, value: &str) -> Result<()> {
+        if value.len() > self.max_value {
+            return Err(KvsError::ValueTooLarge {
+                len: value.len(),
+                max: self.max_value,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_batch(&self, len: usize) -> Result<()> {
+        if len > self.max_batch {
+            return Err(KvsError::BatchTooLarge {
+                len,
+                max: self.max_batch,
+            });
+        }
+        Ok(())
+    }
+
+    /// Longest request line a server reads
+    /// Room for the largest key and value, even if JSON escapes every other byte.
+    pub fn max_request(&self) -> usize {
+        (self.max_key + self.max_value)
+            .saturating_mul(2)
+            .saturating_add(REQUEST_OVERHEAD)
+    }
+}
@@ -0,0 +1,105 @@
+//! A logger whose filter can be changed while the process runs
+//!
+//! `init` installs it instead of `env_logger::init`, starting from `RUST_LOG`.
+//! `set_filter` takes the same directives, e.g. `kvs=trace,info`, so an
+//! incident can be diagnosed without a restart losing its state.
+
+use std::env;
+use std::sync::{OnceLock, RwLock};
+
+use env_logger::{Builder, Env, Logger};
+use log::{Log, Metadata, Record};
+
+use crate::error::{KvsError, Result};
+
+/// Filter `toggle_verbose` switches to
+pub const VERBOSE: &str = "trace";
+
+static LOGGER: OnceLock<Reloadable> = OnceLock::new();
+
+/// Configures every logger built, e.g. its output format
+type Configure = Box<dyn Fn(&mut Builder) + Send + Sync>;
+
+struct Reloadable {
+    configure: Configure,
+    // the filter given at start
+    initial: String,
+    current: RwLock<(String, Logger)>,
+}
+
+impl Reloadable {
+    fn build(&self, filter: &str) -> Logger {
+        let mut builder = Builder::from_env(Env::new().write_style("RUST_LOG_STYLE"));
+        (self.configure)(&mut builder);
+        builder.parse_filters(filter);
+        builder.build()
+    }
+
+    fn set(&self, filter: &str) {
+        let logger = self.build(filter);
+        log::set_max_level(logger.filter());
+        *self.current.write().unwrap() = (filter.to_owned(), logger);
+    }
+}
+
+impl Log for Reloadable {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.current.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.current.read().unwrap().1.log(record)
+    }
+
+    fn flush(&self) {
+        self.current.read().unwrap().1.flush()
+    }
+}
+
+/// Install the logger, filtered by `RUST_LOG`
+/// `configure` is applied again each time the filter changes.
+pub fn init(configure: impl Fn(&mut Builder) + Send + Sync + 'static) -> Result<()> {
+    let initial = env::var("RUST_LOG").unwrap_or_default();
+    let reloadable = Reloadable {
+        configure: Box::new(configure),
+        initial: initial.clone(),
+        current: RwLock::new((String::new(), Builder::new().build())),
+    };
+    reloadable.set(&initial);
+    if LOGGER.set(reloadable).is_err() {
+        return Err(KvsError::StringError(
+            "logger is already installed".to_owned(),
+        ));
+    }
+    log::set_logger(LOGGER.get().unwrap())
+        .map_err(|e| KvsError::StringError(format!("fail to install the logger: {}", e)))
+}
+
+/// Replace the filter, written like `RUST_LOG`
+pub fn set_filter(filter: &str) -> Result<()> {
+    installed()?.set(filter);
+    Ok(())
+}
+
+/// The filter in use
+pub fn filter() -> Result<String> {
+    Ok(installed()?.current.read().unwrap().0.clone())
+}
+
+/// Switch to `VERBOSE`, or back to the filter given at start
+/// Return the new filter.
+pub fn toggle_verbose() -> Result<String> {
+    let logger = installed()?;
+    let filter = match filter()? == VERBOSE {
+        true => logger.initial.clone(),
+        false => VERBOSE.to_owned(),
+    };
+    logger.set(&filter);
+    Ok(filter)
+}
+
+fn installed() -> Result<&'static Reloadable> {
+    LOGGER
+        .get()
+        .ok_or_else(|| KvsError::StringError("logger can not be reconfigured".to_owned()))
+}
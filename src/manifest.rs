@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::kvs::now_millis;
+use crate::error::{KvsError, Result};
+
+/// Name of the manifest inside the data directory
+pub const MANIFEST_FILE: &str = "meta";
+
+/// Version of the on-disk format written by this build
+/// Version 2 ends every log record with the CRC32 of the record.
+/// Version 3 writes new logs as binary records, older logs stay JSON lines
+/// until a compaction rewrites them.
+/// Version 4 may compress the records of compacted logs, see `Compression`.
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Default subdirectory of the data directory holding the logs
+pub const LOG_DIR: &str = "log";
+
+/// Where the logs of a store live inside `log/`
+///
+/// With more than one shard, log `v` goes to the subdirectory `v % shards`,
+/// so no directory holds thousands of logs. `name_width` zero pads the version
+/// in the file name, so the logs also sort by name. `dir_name` replaces
+/// `log`. All are fixed when the directory is created.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct LogLayout {
+    pub shards: usize,
+    pub name_width: usize,
+    pub dir_name: Option<String>,
+}
+
+impl LogLayout {
+    /// Directory of all logs inside the data directory `dir`
+    pub fn root(&self, dir: &Path) -> PathBuf {
+        dir.join(self.dir_name.as_deref().unwrap_or(LOG_DIR))
+    }
+
+    /// Path of log `version` in the data directory `dir`
+    pub fn path(&self, dir: &Path, version: usize) -> PathBuf {
+        let name = format!("{:0width$}.log", version, width = self.name_width);
+        match self.shards {
+            0 | 1 => self.root(dir).join(name),
+            n => self
+                .root(dir)
+                .join(format!("{:03}", version % n))
+                .join(name),
+        }
+    }
+
+    /// Path of the hints of log `version`, next to it
+    pub fn hint_path(&self, dir: &Path, version: usize) -> PathBuf {
+        self.path(dir, version).with_extension("hint")
+    }
+
+    /// Every directory which may hold a log
+    pub fn dirs(&self, dir: &Path) -> Vec<PathBuf> {
+        match self.shards {
+            0 | 1 => vec![self.root(dir)],
+            n => (0..n)
+                .map(|shard| self.root(dir).join(format!("{:03}", shard)))
+                .collect(),
+        }
+    }
+
+    /// Version of the log file named `name`, `None` for a foreign file
+    /// e.g. an editor backup like `3.log~`
+    pub fn parse(name: &str) -> Option<usize> {
+        let version = name.strip_suffix(".log")?;
+        if version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        version.parse().ok()
+    }
+}
+
+/// Description of a data directory, kept in the `meta` file
+///
+/// Older servers only wrote the engine name into `meta`, such a file is read
+/// as format version 0 which was closed cleanly.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Engine owning the directory, `kvs` or `sled`
+    pub engine: String,
+    pub format_version: u32,
+    /// Milliseconds since the unix epoch, 0 if unknown
+    pub created: u64,
+    /// Cleared while a store is open, set again when it is closed
+    pub clean_shutdown: bool,
+    #[serde(default)]
+    pub layout: LogLayout,
+    /// Logs no longer written to, in version order, followed by replicas
+    /// Updated when a log is sealed and when a compaction swaps logs.
+    #[serde(default)]
+    pub sealed: Vec<usize>,
+    /// Logs merged by a compaction whose output is durable, deleted on the
+    /// next open if a crash left them behind
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub obsolete: Vec<usize>,
+    /// Log appended to while the store is open, named here before it is
+    /// created, so the logs of the store are `sealed` and this one
+    ///
+    /// An open then finds the logs without listing the directory. `None` in
+    /// a manifest written before it was kept, then the directory is listed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<usize>,
+    /// Length of each sealed log, a log of another length is verified on open
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sizes: BTreeMap<usize, u64>,
+    /// Output of the running compaction, deleted on the next open unless it
+    /// was swapped in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compacting: Option<usize>,
+}
+
+impl Manifest {
+    /// Manifest of a fresh directory
+    pub fn new(engine: &str) -> Self {
+        Self {
+            engine: engine.to_owned(),
+            format_version: FORMAT_VERSION,
+            created: now_millis(),
+            clean_shutdown: true,
+            layout: LogLayout::default(),
+            sealed: Vec::new(),
+            obsolete: Vec::new(),
+            active: None,
+            sizes: BTreeMap::new(),
+            compacting: None,
+        }
+    }
+
+    /// Read the manifest of `dir`, `None` if there is none yet
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let content = match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let content = content.trim();
+        if content.is_empty() {
+            return Ok(None);
+        }
+        if !content.starts_with('{') {
+            return Ok(Some(Self {
+                engine: content.to_owned(),
+                format_version: 0,
+                created: 0,
+                clean_shutdown: true,
+                layout: LogLayout::default(),
+                sealed: Vec::new(),
+                obsolete: Vec::new(),
+                active: None,
+                sizes: BTreeMap::new(),
+                compacting: None,
+            }));
+        }
+        Ok(Some(serde_json::from_str(content)?))
+    }
+
+    /// Write the manifest into `dir`
+    ///
+    /// It goes to a temporary file first and is renamed over the old one,
+    /// so a crash never leaves a half written manifest behind.
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(serde_json::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+
+    /// Check that a store of `engine` may open this directory
+    pub fn check(&self, engine: &str) -> Result<()> {
+        if self.engine != engine {
+            return Err(KvsError::IncompatibleStore(format!(
+                "previous engine is {}, current is {}",
+                self.engine, engine
+            )));
+        }
+        if self.format_version > FORMAT_VERSION {
+            return Err(KvsError::IncompatibleStore(format!(
+                "format version {} is newer than {}",
+                self.format_version, FORMAT_VERSION
+            )));
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,14 @@
+use std::ops::Bound;
+
 use serde::{Deserialize, Serialize};
 
+use crate::engine::kvs::{
+    Envelope, KeyMetadata, OperationInfo, ScanPage, SizeEstimate, StoreStats, SyncStats, Traffic,
+    WatchFilter,
+};
+use crate::error::Result;
+use crate::limits::Limits;
+
 /// A common request format for both server and client
 ///
 /// Server deserializes the request and serialize the response.
@@ -7,13 +16,248 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+        #[serde(default, skip_serializing_if = "ReadModifiers::is_empty")]
+        modifiers: ReadModifiers,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    /// Set `key` with the content type and encoding of its value
+    SetWithEnvelope {
+        key: String,
+        value: String,
+        envelope: Envelope,
+    },
+    Rm {
+        key: String,
+    },
+    SetIfAbsent {
+        key: String,
+        value: String,
+    },
+    SetIfPresent {
+        key: String,
+        value: String,
+    },
+    GetDel {
+        key: String,
+    },
+    GetSet {
+        key: String,
+        value: String,
+    },
+    Stat {
+        key: String,
+    },
+    RmRange {
+        start: Bound<String>,
+        end: Bound<String>,
+    },
+    RmPrefix {
+        prefix: String,
+    },
+    CountPrefix {
+        prefix: String,
+    },
+    Select {
+        db: usize,
+    },
+    Keys {
+        pattern: String,
+    },
+    Scan {
+        cursor: Option<String>,
+        count: usize,
+        pattern: Option<String>,
+    },
+    Replicate,
+    /// Like `Replicate` for a peer region, every change is a `RegionChange`
+    /// The changes which came from `region` are left out, it has them already.
+    Peer {
+        region: String,
+    },
+    /// The durable writes to the keys starting with `prefix`, see
+    /// `KvStore::watch`
+    /// A non-empty `prefix` is one more prefix of `filter`, see
+    /// `KvStore::watch_filtered`.
+    Watch {
+        prefix: String,
+        #[serde(default, skip_serializing_if = "WatchFilter::is_empty")]
+        filter: WatchFilter,
+    },
+    Promote,
+    /// Close every connection at its next request, with a hint to retry
+    /// after `retry_after_ms`, until `Resume`
+    Drain {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    /// Serve requests again after a `Drain`
+    Resume,
+    /// Stop the long-running operation `id` of any database, listed by `Info`
+    /// It fails with `KvsError::Cancelled` at its next step.
+    Cancel {
+        id: u64,
+    },
+    /// Health probe, fails on a standby
+    Ping,
+    /// Merge the logs of the database now, see `KvStore::compact`
+    Compact,
+    /// Remove every key of the database, see `KvStore::clear`
+    /// Refused unless the server limits allow it.
+    FlushAll,
+    /// Status of the database, see `KvStore::stats`, along with the size of
+    /// the keys under `prefix`, see `KvStore::estimate_size`
+    Stats {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+    },
+    /// Traffic, group commits and running operations of every database
+    Info,
+    /// Replace the log filter of the server, written like `RUST_LOG`
+    SetLogFilter {
+        filter: String,
+    },
+    /// Credentials for the authenticator of the server, accepted as is by a
+    /// server without one, see `auth::Authenticator`
+    Auth {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+        secret: String,
+    },
+    ScanPrefix {
+        prefix: String,
+    },
+    /// Run `request` at most once per `token`
+    ///
+    /// A retry with the same token gets the response of the first run, as
+    /// long as the server still remembers the token. Only the requests of a
+    /// database may be wrapped, not `Select`, `Promote`, `Drain`, `Resume`,
+    /// `Cancel`, `Info`, `SetLogFilter`, `Auth`, `Replicate`, `Peer` or `Watch`.
+    Idempotent {
+        token: String,
+        request: Box<Request>,
+    },
+    /// Set `key` only if its current value satisfies `condition`,
+    /// e.g. `value < 10`, see `Condition`
+    SetWhen {
+        key: String,
+        value: String,
+        condition: String,
+    },
+    /// Add `delta` to the integer value of `key`, a missing key counts as 0
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    /// Run `request` on the keys of the bucket `namespace`, see `Bucket`
+    /// Only the requests a `Bucket` has are served: `Get`, `Set`, `Rm`,
+    /// `SetIfAbsent`, `SetIfPresent`, `GetDel`, `GetSet`, `RmRange`,
+    /// `RmPrefix`, `CountPrefix`, `Keys` and `ScanPrefix`.
+    Namespaced {
+        namespace: String,
+        request: Box<Request>,
+    },
+}
+
+impl Request {
+    /// Wrap the request so a retry with the same `token` is not applied twice
+    pub fn with_token(self, token: String) -> Request {
+        Request::Idempotent {
+            token,
+            request: Box::new(self),
+        }
+    }
+
+    /// Wrap the request so it runs on the bucket `namespace`
+    pub fn in_namespace(self, namespace: String) -> Request {
+        Request::Namespaced {
+            namespace,
+            request: Box::new(self),
+        }
+    }
+
+    /// The request itself, without its idempotency token and namespace
+    pub fn inner(&self) -> &Request {
+        match self {
+            Request::Idempotent { request, .. } | Request::Namespaced { request, .. } => {
+                request.inner()
+            }
+            request => request,
+        }
+    }
+
+    /// Check the key and the value carried by the request against `limits`
+    pub fn check(&self, limits: &Limits) -> Result<()> {
+        match self.inner() {
+            Request::Get { key, .. }
+            | Request::Rm { key }
+            | Request::GetDel { key }
+            | Request::Stat { key }
+            | Request::Incr { key, .. } => limits.check_key(key),
+            Request::Set { key, value }
+            | Request::SetWithEnvelope { key, value, .. }
+            | Request::SetIfAbsent { key, value }
+            | Request::SetIfPresent { key, value }
+            | Request::GetSet { key, value }
+            | Request::SetWhen { key, value, .. } => {
+                limits.check_key(key)?;
+                limits.check_value(value)
+            }
+            // a prefix longer than any key matches none, it is a bad request
+            Request::RmPrefix { prefix }
+            | Request::CountPrefix { prefix }
+            | Request::ScanPrefix { prefix } => limits.check_key(prefix),
+            Request::Watch { prefix, filter } => {
+                limits.check_key(prefix)?;
+                for key in filter.keys.iter().chain(filter.prefixes.iter()) {
+                    limits.check_key(key)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Transformations applied by the server to a value before sending it back
+///
+/// `decompress` comes first, then `json_pointer` picks one field out of the
+/// result. A pointer to a missing field reads as a missing key. A value whose
+/// envelope says it is compressed is always decompressed, unless `raw`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadModifiers {
+    /// The value is base64 encoded gzip, send back the plain text
+    #[serde(default)]
+    pub decompress: bool,
+    /// Send a compressed value as stored
+    #[serde(default)]
+    pub raw: bool,
+    /// RFC 6901 pointer into a JSON value, e.g. `/user/name`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json_pointer: Option<String>,
+}
+
+impl ReadModifiers {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 /// Err will hold string
 /// Server will serialize the KvsError as configured in the Fail
+///
+/// `GetDel` and `GetSet` also answer with a `GetResponse` holding the old value
+/// `SetWhen` answers with a `SetIfResponse`, like the other conditional sets
+/// `Incr` answers with an `IncrResponse` holding the new value
+/// `SetWithEnvelope`, `Select`, `Promote`, `Drain`, `Resume`, `Cancel`,
+/// `SetLogFilter`, `Auth`, `Compact` and `Ping` answer with a `SetResponse`
+/// `Replicate` is answered by a `Change` per line until the replica hangs up
+/// `Peer` is answered the same way, by a `RegionChange` per line
+/// `Watch` is answered by a `ChangeEvent` per line until the client hangs up
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GetResponse {
@@ -32,3 +276,82 @@ pub enum RmResponse {
     Ok,
     Err(String),
 }
+
+/// `Ok(true)` means the conditional set is applied
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SetIfResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum IncrResponse {
+    Ok(i64),
+    Err(String),
+}
+
+/// `Ok(None)` means the key does not exist
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StatResponse {
+    Ok(Option<KeyMetadata>),
+    Err(String),
+}
+
+/// Keys matching a pattern, in order
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum KeysResponse {
+    Ok(Vec<String>),
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ScanResponse {
+    Ok(ScanPage),
+    Err(String),
+}
+
+/// Pairs in key order
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PairsResponse {
+    Ok(Vec<(String, String)>),
+    Err(String),
+}
+
+/// Status of the selected database, and the size of a prefix if asked
+#[derive(Serialize, Deserialize, Debug)]
+pub enum StatsResponse {
+    Ok {
+        stats: Box<StoreStats>,
+        prefix: Option<SizeEstimate>,
+    },
+    Err(String),
+}
+
+/// What the server reports about one database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatabaseInfo {
+    pub traffic: Traffic,
+    /// `None` unless the server syncs writes
+    pub sync: Option<SyncStats>,
+    /// Scans and replications running, see `Request::Cancel`
+    #[serde(default)]
+    pub operations: Vec<OperationInfo>,
+}
+
+/// Info of each database, indexed by its number
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InfoResponse {
+    Ok(Vec<DatabaseInfo>),
+    Err(String),
+}
+
+/// Number of keys affected by a bulk request
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CountResponse {
+    Ok(usize),
+    Err(String),
+}
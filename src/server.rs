@@ -1,93 +1,843 @@
 use std::{
-    io::{BufRead, BufReader, BufWriter, Write},
-    net::TcpStream,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, UNIX_EPOCH},
 };
 
-use log::trace;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use flate2::read::GzDecoder;
+use log::{debug, info, trace, warn};
+use serde::Serialize;
 
-use crate::engine::{KvsEngine, kvs::KvStore};
+use crate::engine::{
+    KvsEngine,
+    condition::Condition,
+    kvs::{
+        Bucket, Change, EngineEvent, Envelope, KvStore, OperationGuard, RegionChange, now_millis,
+    },
+    pattern::Pattern,
+    prefix_range,
+};
 use crate::{
-    error::KvsError,
-    protocol::{GetResponse, Request, RmResponse, SetResponse},
+    auth::Authenticator,
+    error::{KvsError, Result},
+    logging,
+    protocol::{
+        CountResponse, DatabaseInfo, GetResponse, IncrResponse, InfoResponse, KeysResponse,
+        PairsResponse, ReadModifiers, Request, RmResponse, ScanResponse, SetIfResponse,
+        SetResponse, StatResponse, StatsResponse,
+    },
+    transport::{Listener, Stream},
+    validate::Validators,
 };
 
-pub fn handle_stream(stream: TcpStream, engine: KvStore) {
-    let mut buffer = Vec::new();
-    trace!("start to retrieve info from the stream");
-    let mut reader = BufReader::new(&stream);
-    if let Err(e) = reader.read_until(b'\n', &mut buffer) {
-        handle_error(e.into(), stream);
-        return;
-    }
-    buffer.pop();
-    let request = serde_json::from_slice::<Request>(&buffer);
-    let request = match request {
-        Ok(r) => r,
+/// Pairs per page when copying a store to a replica
+const REPLICATE_PAGE: usize = 256;
+/// How often an idle replication stream checks whether the replica is gone
+const REPLICATE_PROBE: Duration = Duration::from_secs(1);
+/// Retry hint of a `Drain` which gives none
+pub const DRAIN_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Serve every connection of `listener` on its own thread
+/// Return once the listener fails.
+pub fn serve<L: Listener>(listener: L, databases: Vec<KvStore>) -> Result<()> {
+    let connections = ConnectionLimit::new(databases[0].limits().max_connections);
+    let drain = Drain::default();
+    loop {
+        let stream = listener.accept()?;
+        match connections.acquire() {
+            Some(slot) => {
+                let databases = databases.clone();
+                let drain = drain.clone();
+                thread::spawn(move || {
+                    handle_stream(stream, databases, None, &Validators::default(), &drain);
+                    drop(slot);
+                });
+            }
+            None => refuse(stream, &connections),
+        }
+    }
+}
+
+/// Number of connections being served, out of at most `max`
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Room for one connection, given back when dropped
+pub struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// `None` once `max` connections are served
+    pub fn acquire(&self) -> Option<ConnectionSlot> {
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(ConnectionSlot {
+            open: Arc::clone(&self.open),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether the server drains its connections, shared by all of them
+///
+/// While draining, a connection finishes the request it runs, then answers
+/// its next one with `KvsError::Draining` and is closed, so clients move to
+/// another server or come back after the hint. Admin requests still go
+/// through, so the connection which started the drain can `Resume`.
+#[derive(Clone, Default)]
+pub struct Drain {
+    // hint given to clients, `None` while serving
+    retry_after: Arc<Mutex<Option<Duration>>>,
+}
+
+impl Drain {
+    pub fn start(&self, retry_after: Duration) {
+        *self
+            .retry_after
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(retry_after);
+    }
+
+    pub fn stop(&self) {
+        *self
+            .retry_after
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+    }
+
+    /// `None` unless draining
+    pub fn retry_after(&self) -> Option<Duration> {
+        *self
+            .retry_after
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Tell a client over the limit why its connection is closed
+pub fn refuse(mut stream: impl Write, connections: &ConnectionLimit) {
+    warn!("Refuse a connection, {} are served", connections.max);
+    let err = KvsError::TooManyConnections(connections.max).to_string();
+    if let Err(e) = stream.write_all(err.as_bytes()) {
+        debug!("fail to refuse a connection: {}", e);
+    }
+}
+
+/// Serve every request of a connection, until the client hangs up
+///
+/// A connection starts on database 0, `Select` switches it to another one.
+/// Requests are checked against the limits of database 0. With `auth` only
+/// `Auth` and `Ping` are served until the credentials are accepted. A set
+/// whose value breaks a rule of `validators` is refused.
+pub fn handle_stream<S: Stream>(
+    mut stream: S,
+    databases: Vec<KvStore>,
+    auth: Option<&dyn Authenticator>,
+    validators: &Validators,
+    drain: &Drain,
+) {
+    let limits = databases[0].limits();
+    let mut reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
         Err(e) => {
-            handle_error(e.into(), stream);
+            warn!("fail to clone the stream: {}", e);
             return;
         }
     };
+    let mut db = 0;
+    let mut authenticated = auth.is_none();
+    loop {
+        let mut buffer = Vec::new();
+        trace!("start to retrieve info from the stream");
+        let max = limits.max_request();
+        match reader
+            .by_ref()
+            .take(max as u64)
+            .read_until(b'\n', &mut buffer)
+        {
+            Ok(0) => return,
+            Ok(n) if n == max && buffer.last() != Some(&b'\n') => {
+                let _ = handle_error(KvsError::RequestTooLarge(max), &mut stream);
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                let _ = handle_error(e.into(), &mut stream);
+                return;
+            }
+        }
+        let bytes_in = buffer.len() as u64;
+        buffer.pop();
+        let request = serde_json::from_slice::<Request>(&buffer);
+        let request = match request {
+            Ok(r) => r,
+            Err(e) => {
+                let _ = handle_error(e.into(), &mut stream);
+                return;
+            }
+        };
+        if let Err(e) = request.check(&limits) {
+            let _ = handle_error(e, &mut stream);
+            return;
+        }
+        let admin = matches!(
+            request,
+            Request::Drain { .. }
+                | Request::Resume
+                | Request::Cancel { .. }
+                | Request::Select { .. }
+                | Request::Promote
+                | Request::Info
+                | Request::SetLogFilter { .. }
+                | Request::Auth { .. }
+        );
+        if let Some(retry_after) = drain.retry_after().filter(|_| !admin) {
+            let err = KvsError::Draining(retry_after.as_millis() as u64);
+            let result: SetResponse = Err(err).into();
+            let _ = reply(&result, &mut stream, "draining");
+            return;
+        }
 
-    match request {
-        Request::Get { key } => {
-            let result = engine.get(key);
-            let result: GetResponse = result.into();
-            match serde_json::to_string(&result) {
-                Ok(s) => {
-                    respond(s, &stream);
-                    trace!("get success");
+        let valid = validators.check_request(&request);
+
+        // the request is accounted to the database it runs on
+        let tenant = db;
+        let bytes_out = match request {
+            Request::Auth { user, secret } => {
+                let result = match auth {
+                    Some(auth) => authenticate(auth, user.as_deref(), &secret),
+                    None => Ok(()),
+                };
+                authenticated |= result.is_ok();
+                let result: SetResponse = result.into();
+                reply(&result, &mut stream, "auth")
+            }
+            Request::Ping => handle_request(request, &databases[db], &mut stream),
+            _ if !authenticated => {
+                let result: SetResponse = Err(KvsError::Unauthenticated).into();
+                reply(&result, &mut stream, "unauthenticated")
+            }
+            _ if valid.is_err() => {
+                let result: SetResponse = valid.into();
+                reply(&result, &mut stream, "invalid value")
+            }
+            Request::Select { db: n } => {
+                let result: SetResponse = if n < databases.len() {
+                    db = n;
+                    Ok(())
+                } else {
+                    Err(KvsError::InvalidDatabase(n))
                 }
-                Err(e) => {
-                    handle_error(e.into(), stream);
+                .into();
+                reply(&result, &mut stream, "select")
+            }
+            Request::Promote => {
+                for engine in databases.iter() {
+                    engine.promote();
                 }
-            };
+                let result: SetResponse = Ok(()).into();
+                reply(&result, &mut stream, "promote")
+            }
+            Request::Drain { retry_after_ms } => {
+                let retry_after = retry_after_ms.map_or(DRAIN_RETRY_AFTER, Duration::from_millis);
+                info!("Drain the connections, clients retry in {:?}", retry_after);
+                drain.start(retry_after);
+                let result: SetResponse = Ok(()).into();
+                reply(&result, &mut stream, "drain")
+            }
+            Request::Resume => {
+                info!("Serve requests again after a drain");
+                drain.stop();
+                let result: SetResponse = Ok(()).into();
+                reply(&result, &mut stream, "resume")
+            }
+            Request::Cancel { id } => {
+                let result: SetResponse = match databases.iter().any(|e| e.cancel_operation(id)) {
+                    true => Ok(()),
+                    false => Err(KvsError::UnknownOperation(id)),
+                }
+                .into();
+                reply(&result, &mut stream, "cancel")
+            }
+            Request::Info => {
+                let info = databases
+                    .iter()
+                    .map(|engine| DatabaseInfo {
+                        traffic: engine.traffic(),
+                        sync: engine.sync_stats(),
+                        operations: engine.operations(),
+                    })
+                    .collect();
+                reply(&InfoResponse::Ok(info), &mut stream, "info")
+            }
+            Request::SetLogFilter { filter } => {
+                let result = logging::set_filter(&filter);
+                if result.is_ok() {
+                    info!("log filter is now {:?}", filter);
+                }
+                let result: SetResponse = result.into();
+                reply(&result, &mut stream, "set log filter")
+            }
+            Request::Replicate => {
+                let engine = &databases[db];
+                let feed = engine.changefeed();
+                replicate(engine, feed, |change, _| change, |_| true, &mut stream)
+            }
+            Request::Peer { region } => {
+                let engine = &databases[db];
+                let origin = engine.region();
+                let feed = engine.region_feed();
+                // the pairs go as written in this region, a write of the
+                // peer comes back with its own time and is skipped there
+                let copy = |change, ts| RegionChange {
+                    origin: origin.clone(),
+                    ts,
+                    change,
+                };
+                let wanted = |c: &RegionChange| c.origin != region;
+                replicate(engine, feed, copy, wanted, &mut stream)
+            }
+            Request::Watch { prefix, mut filter } => {
+                let engine = &databases[db];
+                if !prefix.is_empty() {
+                    filter.prefixes.push(prefix);
+                }
+                match engine.watch_filtered(filter) {
+                    Ok(feed) => {
+                        let op = engine.start_operation("watch");
+                        forward(&op, feed, |_| true, &mut stream, 0)
+                    }
+                    Err(e) => reply_error(e, &mut stream),
+                }
+            }
+            Request::Idempotent { token, request } => {
+                handle_idempotent(&token, *request, &databases[db], &mut stream)
+            }
+            request => handle_request(request, &databases[db], &mut stream),
+        };
+        // the client is gone, nothing more can be answered
+        let bytes_out = match bytes_out {
+            Ok(n) => n,
+            Err(e) => {
+                debug!("fail to answer, close the connection: {}", e);
+                return;
+            }
+        };
+        databases[tenant].record_traffic(bytes_in, bytes_out);
+    }
+}
+
+/// Return the number of bytes sent back
+fn handle_request(request: Request, engine: &KvStore, out: &mut dyn Write) -> Result<u64> {
+    match request {
+        Request::Get { key, modifiers } => {
+            let result: GetResponse = engine
+                .get_with_envelope(key)
+                .and_then(|v| {
+                    v.map_or(Ok(None), |(v, envelope)| {
+                        transform(v, &envelope, &modifiers)
+                    })
+                })
+                .into();
+            reply(&result, out, "get")
         }
         Request::Set { key, value } => {
             let result = engine.set(key, value);
             trace!("engine done with result");
             let result: SetResponse = result.into();
-            match serde_json::to_string(&result) {
-                Ok(s) => {
-                    respond(s, &stream);
-                    trace!("set success");
-                }
-                Err(e) => {
-                    handle_error(e.into(), stream);
-                }
+            reply(&result, out, "set")
+        }
+        Request::SetWithEnvelope {
+            key,
+            value,
+            envelope,
+        } => {
+            let result: SetResponse = engine.set_with_envelope(key, value, envelope).into();
+            reply(&result, out, "set with envelope")
+        }
+        Request::Rm { key } => {
+            let result: RmResponse = engine.remove(key).into();
+            reply(&result, out, "remove")
+        }
+        Request::SetIfAbsent { key, value } => {
+            let result: SetIfResponse = engine.set_if_absent(key, value).into();
+            reply(&result, out, "set if absent")
+        }
+        Request::SetIfPresent { key, value } => {
+            let result: SetIfResponse = engine.set_if_present(key, value).into();
+            reply(&result, out, "set if present")
+        }
+        Request::SetWhen {
+            key,
+            value,
+            condition,
+        } => {
+            let result: SetIfResponse = Condition::parse(&condition)
+                .and_then(|condition| engine.set_when(key, value, &condition))
+                .into();
+            reply(&result, out, "set when")
+        }
+        Request::Incr { key, delta } => {
+            let result: IncrResponse = engine.incr(key, delta).into();
+            reply(&result, out, "incr")
+        }
+        Request::GetDel { key } => {
+            let result: GetResponse = engine.take(key).into();
+            reply(&result, out, "getdel")
+        }
+        Request::GetSet { key, value } => {
+            let result: GetResponse = engine.insert(key, value).into();
+            reply(&result, out, "getset")
+        }
+        Request::Stat { key } => {
+            let result: StatResponse = engine.metadata(key).into();
+            reply(&result, out, "stat")
+        }
+        Request::RmRange { start, end } => {
+            let result: CountResponse = engine.remove_range((start, end)).into();
+            reply(&result, out, "remove range")
+        }
+        Request::FlushAll => {
+            let result: CountResponse = match engine.limits().flush_all {
+                true => engine.clear(),
+                false => Err(KvsError::FlushAllDisabled),
+            }
+            .into();
+            reply(&result, out, "flush all")
+        }
+        Request::RmPrefix { prefix } => {
+            let result: CountResponse = engine.remove_prefix(&prefix).into();
+            reply(&result, out, "remove prefix")
+        }
+        Request::CountPrefix { prefix } => {
+            let result: CountResponse = engine.count_prefix(&prefix).into();
+            reply(&result, out, "count prefix")
+        }
+        Request::Keys { pattern } => {
+            // compiled once, then checked against every key of its prefix
+            let pattern = Pattern::new(&pattern);
+            let result: KeysResponse = engine.keys(&pattern).into();
+            reply(&result, out, "keys")
+        }
+        Request::Scan {
+            cursor,
+            count,
+            pattern,
+        } => {
+            let pattern = pattern.as_deref().map(Pattern::new);
+            let result: ScanResponse = engine.scan_page(cursor, count, pattern.as_ref()).into();
+            reply(&result, out, "scan")
+        }
+        Request::ScanPrefix { prefix } => {
+            // a full scan may take long, it stops at the pair after a `Cancel`
+            let op = engine.start_operation(format!("scan prefix {:?}", prefix));
+            let result: PairsResponse = engine
+                .scan(prefix_range(&prefix))
+                .and_then(|pairs| {
+                    pairs
+                        .map(|pair| {
+                            op.check()?;
+                            pair
+                        })
+                        .collect()
+                })
+                .into();
+            reply(&result, out, "scan prefix")
+        }
+        Request::Compact => {
+            let result: SetResponse = engine.compact().into();
+            reply(&result, out, "compact")
+        }
+        Request::Stats { prefix } => {
+            let result: StatsResponse = engine
+                .stats()
+                .and_then(|stats| {
+                    let size = prefix.map(|p| engine.estimate_size(&p)).transpose()?;
+                    Ok((stats, size))
+                })
+                .into();
+            reply(&result, out, "stats")
+        }
+        Request::Namespaced { namespace, request } => match engine.bucket(&namespace) {
+            Ok(bucket) => handle_bucket_request(*request, &bucket, out),
+            Err(e) => {
+                let result: SetResponse = Err(e).into();
+                reply(&result, out, "namespace")
+            }
+        },
+        Request::Ping => {
+            let result: SetResponse = match engine.is_standby() {
+                true => Err(KvsError::Standby).into(),
+                false => Ok(()).into(),
             };
+            reply(&result, out, "ping")
+        }
+        Request::Select { .. }
+        | Request::Promote
+        | Request::Drain { .. }
+        | Request::Resume
+        | Request::Cancel { .. }
+        | Request::Info
+        | Request::SetLogFilter { .. }
+        | Request::Auth { .. }
+        | Request::Replicate
+        | Request::Peer { .. }
+        | Request::Watch { .. }
+        | Request::Idempotent { .. } => {
+            unreachable!("handled for the whole connection")
+        }
+    }
+}
+
+/// Serve `request` on the keys of `bucket`, for the requests a bucket has
+fn handle_bucket_request(request: Request, bucket: &Bucket, out: &mut dyn Write) -> Result<u64> {
+    match request {
+        Request::Get { key, modifiers } => {
+            // a bucket keeps no envelope, the value is taken as plain
+            let result: GetResponse = bucket
+                .get(key)
+                .and_then(|v| {
+                    v.map_or(Ok(None), |v| transform(v, &Envelope::default(), &modifiers))
+                })
+                .into();
+            reply(&result, out, "bucket get")
+        }
+        Request::Set { key, value } => {
+            let result: SetResponse = bucket.set(key, value).into();
+            reply(&result, out, "bucket set")
         }
         Request::Rm { key } => {
-            let result = engine.remove(key);
-            let result: RmResponse = result.into();
-            match serde_json::to_string(&result) {
-                Ok(s) => {
-                    respond(s, &stream);
-                    trace!("remove success");
-                }
-                Err(e) => {
-                    handle_error(e.into(), stream);
+            let result: RmResponse = bucket.remove(key).into();
+            reply(&result, out, "bucket remove")
+        }
+        Request::SetIfAbsent { key, value } => {
+            let result: SetIfResponse = bucket.set_if_absent(key, value).into();
+            reply(&result, out, "bucket set if absent")
+        }
+        Request::SetIfPresent { key, value } => {
+            let result: SetIfResponse = bucket.set_if_present(key, value).into();
+            reply(&result, out, "bucket set if present")
+        }
+        Request::GetDel { key } => {
+            let result: GetResponse = bucket.take(key).into();
+            reply(&result, out, "bucket getdel")
+        }
+        Request::GetSet { key, value } => {
+            let result: GetResponse = bucket.insert(key, value).into();
+            reply(&result, out, "bucket getset")
+        }
+        Request::RmRange { start, end } => {
+            let result: CountResponse = bucket.remove_range((start, end)).into();
+            reply(&result, out, "bucket remove range")
+        }
+        Request::RmPrefix { prefix } => {
+            let result: CountResponse = bucket.remove_prefix(&prefix).into();
+            reply(&result, out, "bucket remove prefix")
+        }
+        Request::CountPrefix { prefix } => {
+            let result: CountResponse = bucket.count_prefix(&prefix).into();
+            reply(&result, out, "bucket count prefix")
+        }
+        Request::Keys { pattern } => {
+            let result: KeysResponse = bucket.keys(&Pattern::new(&pattern)).into();
+            reply(&result, out, "bucket keys")
+        }
+        Request::ScanPrefix { prefix } => {
+            let result: PairsResponse = bucket.scan_prefix(&prefix).into();
+            reply(&result, out, "bucket scan prefix")
+        }
+        _ => {
+            let result: SetResponse = Err(KvsError::UnexpectedType).into();
+            reply(&result, out, "bucket")
+        }
+    }
+}
+
+/// Check credentials, a backend failure rejects them too
+fn authenticate(auth: &dyn Authenticator, user: Option<&str>, secret: &str) -> Result<()> {
+    match auth.authenticate(user, secret) {
+        Ok(true) => {
+            debug!("accept the credentials of {:?}", user);
+            Ok(())
+        }
+        Ok(false) => {
+            warn!("Reject the credentials of {:?}", user);
+            Err(KvsError::InvalidCredentials)
+        }
+        Err(e) => {
+            warn!("Fail to check the credentials of {:?}: {}", user, e);
+            Err(KvsError::InvalidCredentials)
+        }
+    }
+}
+
+/// A claimed token, released unless its response is settled
+/// So a request which fails or panics on the way may be retried.
+struct TokenClaim<'a> {
+    engine: &'a KvStore,
+    token: &'a str,
+    settled: bool,
+}
+
+impl Drop for TokenClaim<'_> {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.engine.release_token(self.token);
+        }
+    }
+}
+
+/// Run `request` unless `token` was seen recently, then replay its response
+fn handle_idempotent(
+    token: &str,
+    request: Request,
+    engine: &KvStore,
+    out: &mut dyn Write,
+) -> Result<u64> {
+    if let Request::Select { .. }
+    | Request::Promote
+    | Request::Drain { .. }
+    | Request::Resume
+    | Request::Cancel { .. }
+    | Request::Info
+    | Request::SetLogFilter { .. }
+    | Request::Auth { .. }
+    | Request::Replicate
+    | Request::Peer { .. }
+    | Request::Watch { .. }
+    | Request::Idempotent { .. } = request
+    {
+        return reply_error(KvsError::UnexpectedType, out);
+    }
+    let response = match engine.claim_token(token) {
+        Ok(Some(response)) => {
+            trace!("replay the response of token {}", token);
+            response
+        }
+        Ok(None) => {
+            let mut claim = TokenClaim {
+                engine,
+                token,
+                settled: false,
+            };
+            let mut response = Vec::new();
+            handle_request(request, engine, &mut response)?;
+            engine.settle_token(token, response.clone());
+            claim.settled = true;
+            response
+        }
+        Err(e) => return reply_error(e, out),
+    };
+    out.write_all(&response)?;
+    Ok(response.len() as u64)
+}
+
+/// Stream every pair of `engine`, then every `wanted` change of `feed`, until
+/// the replica hangs up
+///
+/// `feed` is subscribed by the caller before the copy starts, so no change
+/// in between is lost. `copy` turns a pair and its time into a change.
+/// Return the number of bytes sent.
+fn replicate<S: Stream, T: Serialize>(
+    engine: &KvStore,
+    feed: Receiver<T>,
+    copy: impl Fn(Change, u64) -> T,
+    wanted: impl Fn(&T) -> bool,
+    stream: &mut S,
+) -> Result<u64> {
+    let mut sent = 0;
+    let op = engine.start_operation("replicate");
+    let mut cursor = None;
+    loop {
+        let page = match op
+            .check()
+            .and_then(|_| engine.scan_page(cursor, REPLICATE_PAGE, None))
+        {
+            Ok(page) => page,
+            Err(e) => return handle_error(e, stream).map(|n| sent + n),
+        };
+        for (key, value) in page.entries {
+            // the replica expires the key at the same time, and keeps its
+            // content type
+            let (ttl, envelope, written) = match engine.metadata(key.clone()).ok().flatten() {
+                Some(meta) => (meta.ttl, meta.envelope, meta.last_modified),
+                None => (None, Envelope::default(), None),
+            };
+            let ts = written
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |t| t.as_millis() as u64);
+            let expires = ttl.map(|ttl| now_millis() + ttl.as_millis() as u64);
+            let change = match (expires, envelope) {
+                (expires, envelope) if envelope.content_type.is_some() || envelope.compressed => {
+                    Change::SetEnveloped {
+                        key,
+                        value,
+                        expires,
+                        envelope,
+                    }
                 }
+                (Some(expires), _) => Change::SetExpiring {
+                    key,
+                    value,
+                    expires,
+                },
+                (None, _) => Change::Set { key, value },
             };
+            sent += send_change(&copy(change, ts), stream)?;
+        }
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
         }
     }
+    trace!("replica is up to date, follow the changefeed");
+    forward(&op, feed, wanted, stream, sent)
+}
+
+/// Stream every `wanted` item of `feed` until the client hangs up or `op` is
+/// cancelled
+/// `sent` bytes went out before, return the total.
+fn forward<S: Stream, T: Serialize>(
+    op: &OperationGuard,
+    feed: Receiver<T>,
+    wanted: impl Fn(&T) -> bool,
+    stream: &mut S,
+    mut sent: u64,
+) -> Result<u64> {
+    loop {
+        if let Err(e) = op.check() {
+            return handle_error(e, stream).map(|n| sent + n);
+        }
+        match feed.recv_timeout(REPLICATE_PROBE) {
+            Ok(change) if wanted(&change) => sent += send_change(&change, stream)?,
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => {
+                if stream.peer_closed() {
+                    return Ok(sent);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(sent),
+        }
+    }
+}
+
+fn send_change<T: Serialize>(change: &T, stream: &mut dyn Write) -> Result<u64> {
+    let mut line = serde_json::to_string(change)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(line.len() as u64)
 }
 
-fn handle_error(error: KvsError, mut stream: TcpStream) {
+/// Apply the read modifiers of a `Get`, so only what the client asked for
+/// is sent over the wire
+fn transform(
+    value: String,
+    envelope: &Envelope,
+    modifiers: &ReadModifiers,
+) -> Result<Option<String>> {
+    let value = if modifiers.decompress || (envelope.compressed && !modifiers.raw) {
+        let compressed = STANDARD
+            .decode(value)
+            .map_err(|e| KvsError::Transform(e.to_string()))?;
+        let mut plain = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut plain)
+            .map_err(|e| KvsError::Transform(e.to_string()))?;
+        plain
+    } else {
+        value
+    };
+    match &modifiers.json_pointer {
+        Some(pointer) => {
+            let json: serde_json::Value =
+                serde_json::from_str(&value).map_err(|e| KvsError::Transform(e.to_string()))?;
+            Ok(json.pointer(pointer).map(|field| field.to_string()))
+        }
+        None => Ok(Some(value)),
+    }
+}
+
+/// Forward engine events into the server log
+/// Return once the engine is dropped.
+pub fn log_events(events: Receiver<EngineEvent>) {
+    let mut compactions = 0;
+    let mut reclaimed = 0;
+    for event in events {
+        match event {
+            EngineEvent::CompactionFinished { reclaimed: r } => {
+                compactions += 1;
+                reclaimed += r;
+                info!(
+                    "compaction {} reclaimed {} bytes, {} bytes in total",
+                    compactions, r, reclaimed
+                );
+            }
+            e => debug!("engine event: {:?}", e),
+        }
+    }
+}
+
+/// Serialize the response and send it back
+/// Return the number of bytes sent, an io error once the client is gone.
+fn reply<T: Serialize>(result: &T, out: &mut dyn Write, op: &str) -> Result<u64> {
+    match serde_json::to_string(result) {
+        Ok(s) => {
+            let sent = respond(s, out)?;
+            trace!("{} success", op);
+            Ok(sent)
+        }
+        Err(e) => handle_error(e.into(), out),
+    }
+}
+
+/// Send `error` as a response line, which every response type reads as its
+/// `Err`
+fn reply_error(error: KvsError, out: &mut dyn Write) -> Result<u64> {
+    trace!("an error happens: {}", error);
+    let err = serde_json::to_string(&SetResponse::Err(error.to_string()))?;
+    respond(err, out)
+}
+
+fn handle_error(error: KvsError, out: &mut dyn Write) -> Result<u64> {
     let err: String = error.to_string();
     trace!("an error happens: {}", err);
-    stream
-        .write_all(err.as_bytes())
-        .expect("Error message should be sent to client successfully");
-}
-
-fn respond(resp: String, stream: &TcpStream) {
-    let mut writer = BufWriter::new(stream);
-    writer
-        .write_all(resp.as_bytes())
-        .expect("Fail to send back error message");
-    writer
-        .write_all(b"\n")
-        .expect("Fail to send back stop sign");
-    writer.flush().expect("Fail to flush the buffer writer");
+    out.write_all(err.as_bytes())?;
+    Ok(err.len() as u64)
+}
+
+fn respond(resp: String, out: &mut dyn Write) -> Result<u64> {
+    let mut writer = BufWriter::new(out);
+    writer.write_all(resp.as_bytes())?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(resp.len() as u64 + 1)
 }
@@ -0,0 +1,116 @@
+//! End-to-end testing against a real server, without any bootstrap code
+//!
+//! ```
+//! use kvs::client;
+//! use kvs::protocol::Request;
+//! use kvs::testing::TestServer;
+//! let server = TestServer::start().unwrap();
+//! let request = Request::Set {
+//!     key: "jack".to_owned(),
+//!     value: "2024".to_owned(),
+//! };
+//! client::send_and_recv(request, server.connect().unwrap()).unwrap();
+//! let request = Request::Get {
+//!     key: "jack".to_owned(),
+//!     modifiers: Default::default(),
+//! };
+//! let value = client::send_and_recv(request, server.connect().unwrap()).unwrap();
+//! assert_eq!(value, Some("2024".to_owned()));
+//! ```
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use log::warn;
+use tempfile::TempDir;
+
+use crate::client::{self, KvsClient};
+use crate::engine::kvs::{KvStore, KvStoreBuilder};
+use crate::error::Result;
+use crate::server::{self, Drain};
+use crate::validate::Validators;
+
+/// A server with one database in a temporary directory, on a free port
+///
+/// Every connection is served by its own thread. Dropping the server stops
+/// accepting connections and removes the directory.
+pub struct TestServer {
+    addr: SocketAddr,
+    store: KvStore,
+    stop: Arc<AtomicBool>,
+    _dir: TempDir,
+}
+
+impl TestServer {
+    pub fn start() -> Result<Self> {
+        Self::start_with(KvStore::builder())
+    }
+
+    /// Open the database with `builder`, e.g. to make it durable
+    pub fn start_with(builder: KvStoreBuilder) -> Result<Self> {
+        let dir = TempDir::new()?;
+        let store = builder.open(dir.path())?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let databases = vec![store.clone()];
+        let stopped = Arc::clone(&stop);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                match stream {
+                    Ok(s) => {
+                        let databases = databases.clone();
+                        thread::spawn(move || {
+                            server::handle_stream(
+                                s,
+                                databases,
+                                None,
+                                &Validators::default(),
+                                &Drain::default(),
+                            )
+                        });
+                    }
+                    Err(e) => warn!("Test server fails to accept: {}", e),
+                }
+            }
+        });
+        Ok(Self {
+            addr,
+            store,
+            stop,
+            _dir: dir,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The database behind the server, to check or prepare its content
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    /// A new connection, which already answered a health probe
+    pub fn connect(&self) -> Result<TcpStream> {
+        client::probe(&self.addr.to_string())
+    }
+
+    pub fn client(&self) -> KvsClient {
+        KvsClient::new(vec![self.addr.to_string()])
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wake the accepting thread up, so it sees the flag
+        let _ = TcpStream::connect(self.addr);
+    }
+}
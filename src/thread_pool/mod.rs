@@ -4,7 +4,7 @@ use std::sync::{
 };
 use std::thread;
 
-use log::trace;
+use log::{trace, warn};
 
 type Message = Box<dyn FnOnce() + Send + 'static>;
 pub struct ThreadPool {
@@ -61,11 +61,11 @@ impl Drop for ThreadPool {
         drop(self.sender.take());
 
         for worker in self.worker.drain(..) {
-            trace!("Error in joining thread {}", worker.id);
-            worker
-                .handle
-                .join()
-                .expect("Error happens in joining thread");
+            trace!("join thread {}", worker.id);
+            // a panicked task already took down its worker, not the pool
+            if worker.handle.join().is_err() {
+                warn!("thread {} panicked", worker.id);
+            }
         }
     }
 }
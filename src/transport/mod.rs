@@ -0,0 +1,125 @@
+//! The network under the server and the client
+//!
+//! `Tcp` uses real sockets, a server may also listen on unix sockets.
+//! `sim::SimNetwork` connects nodes in memory, with
+//! latency, dropped connections and partitions decided by a seeded random
+//! generator, so failure handling can be tested without sockets.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+pub mod sim;
+
+/// Time allowed to open a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A connection, both ends of it read and write
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    /// Another handle on the same connection
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Whether the peer hung up or sent something, without consuming it
+    fn peer_closed(&self) -> bool;
+
+    /// Fail reads which wait longer than `timeout`, `None` waits forever
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+pub trait Listener: Send + 'static {
+    type Stream: Stream;
+
+    /// Wait for the next connection
+    fn accept(&self) -> io::Result<Self::Stream>;
+}
+
+/// Opens connections to, and listens on, addresses
+pub trait Transport: Clone + Send + 'static {
+    type Stream: Stream;
+    type Listener: Listener<Stream = Self::Stream>;
+
+    fn bind(&self, addr: &str) -> io::Result<Self::Listener>;
+
+    fn connect(&self, addr: &str) -> io::Result<Self::Stream>;
+}
+
+/// Real TCP sockets
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tcp;
+
+impl Transport for Tcp {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    fn bind(&self, addr: &str) -> io::Result<TcpListener> {
+        TcpListener::bind(addr)
+    }
+
+    fn connect(&self, addr: &str) -> io::Result<TcpStream> {
+        let sock_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, format!("invalid address {}", addr))
+        })?;
+        TcpStream::connect_timeout(&sock_addr, CONNECT_TIMEOUT)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    /// A readable stream means the peer hung up, when it never sends anything
+    fn peer_closed(&self) -> bool {
+        if self.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = !matches!(
+            self.peek(&mut [0; 1]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock
+        );
+        self.set_nonblocking(false).is_err() || closed
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        UnixListener::accept(self).map(|(stream, _)| stream)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    /// Like for TCP, but without peeking the byte read is consumed
+    fn peer_closed(&self) -> bool {
+        if self.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let closed = !matches!(
+            (&*self).read(&mut [0; 1]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock
+        );
+        self.set_nonblocking(false).is_err() || closed
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
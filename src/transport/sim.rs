@@ -0,0 +1,382 @@
+//! An in-memory network to test failure handling deterministically
+//!
+//! Nodes are named, and a node may listen on any address string. Every
+//! fault is drawn from a random generator seeded by the test, so a test
+//! driving the network from one thread sees the same faults on every run.
+//!
+//! ```
+//! use std::io::{Read, Write};
+//! use kvs::transport::{Listener, Transport, sim::SimNetwork};
+//! let net = SimNetwork::new(7);
+//! let listener = net.node("server").bind("server:4000").unwrap();
+//! let mut client = net.node("client").connect("server:4000").unwrap();
+//! client.write_all(b"ping").unwrap();
+//! let mut buf = [0; 4];
+//! listener.accept().unwrap().read_exact(&mut buf).unwrap();
+//! assert_eq!(&buf, b"ping");
+//!
+//! net.partition("client", "server");
+//! assert!(net.node("client").connect("server:4000").is_err());
+//! ```
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use super::{Listener, Stream, Transport};
+
+/// A simulated network shared by every node of a test
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // xorshift state, never 0
+    rng: u64,
+    latency: Duration,
+    drop_rate: f64,
+    // unordered pairs of nodes which can not reach each other
+    partitions: HashSet<(String, String)>,
+    // node listening on each address, and its queue of new connections
+    listeners: HashMap<String, (String, Sender<SimStream>)>,
+    // broken by partitions and crashes
+    conns: Vec<Weak<Conn>>,
+}
+
+impl State {
+    /// Whether the next operation fails, the only draw of the generator
+    fn drop_next(&mut self) -> bool {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let x = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        ((x >> 11) as f64 / (1u64 << 53) as f64) < self.drop_rate
+    }
+
+    fn conns_of(&mut self, node: &str) -> Vec<Arc<Conn>> {
+        self.conns.retain(|c| c.strong_count() > 0);
+        self.conns
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|c| c.nodes.0 == node || c.nodes.1 == node)
+            .collect()
+    }
+}
+
+fn pair(a: &str, b: &str) -> (String, String) {
+    if a <= b {
+        (a.to_owned(), b.to_owned())
+    } else {
+        (b.to_owned(), a.to_owned())
+    }
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        let rng = match seed ^ 0x9E37_79B9_7F4A_7C15 {
+            0 => 1,
+            rng => rng,
+        };
+        Self {
+            state: Arc::new(Mutex::new(State {
+                rng,
+                latency: Duration::ZERO,
+                drop_rate: 0.0,
+                partitions: HashSet::new(),
+                listeners: HashMap::new(),
+                conns: Vec::new(),
+            })),
+        }
+    }
+
+    /// Data written is readable by the peer this long after
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Probability for a connect or a write to fail
+    /// A failed write breaks its connection, like a reset.
+    pub fn set_drop_rate(&self, rate: f64) {
+        self.state.lock().unwrap().drop_rate = rate;
+    }
+
+    /// Cut nodes `a` and `b` off each other, breaking their connections
+    pub fn partition(&self, a: &str, b: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.partitions.insert(pair(a, b));
+        for conn in state.conns_of(a) {
+            if pair(&conn.nodes.0, &conn.nodes.1) == pair(a, b) {
+                conn.break_off();
+            }
+        }
+    }
+
+    pub fn heal(&self, a: &str, b: &str) {
+        self.state.lock().unwrap().partitions.remove(&pair(a, b));
+    }
+
+    /// Stop every listener of `node` and break all its connections
+    pub fn crash(&self, node: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.listeners.retain(|_, (owner, _)| owner != node);
+        for conn in state.conns_of(node) {
+            conn.break_off();
+        }
+    }
+
+    /// The network as seen from node `name`
+    pub fn node(&self, name: &str) -> SimTransport {
+        SimTransport {
+            net: self.clone(),
+            node: name.to_owned(),
+        }
+    }
+
+    /// Decide the fate of a write on `conn`, return when it is delivered
+    fn transmit(&self, conn: &Conn) -> io::Result<Instant> {
+        let mut state = self.state.lock().unwrap();
+        if state.drop_next() {
+            drop(state);
+            conn.break_off();
+            return Err(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "dropped by the simulated network",
+            ));
+        }
+        Ok(Instant::now() + state.latency)
+    }
+}
+
+/// Connections opened and accepted by one node
+#[derive(Clone)]
+pub struct SimTransport {
+    net: SimNetwork,
+    node: String,
+}
+
+impl Transport for SimTransport {
+    type Stream = SimStream;
+    type Listener = SimListener;
+
+    fn bind(&self, addr: &str) -> io::Result<SimListener> {
+        let mut state = self.net.state.lock().unwrap();
+        if state.listeners.contains_key(addr) {
+            return Err(ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = channel();
+        state
+            .listeners
+            .insert(addr.to_owned(), (self.node.clone(), tx));
+        Ok(SimListener { incoming: rx })
+    }
+
+    fn connect(&self, addr: &str) -> io::Result<SimStream> {
+        let mut state = self.net.state.lock().unwrap();
+        let (owner, incoming) = match state.listeners.get(addr) {
+            Some((owner, incoming)) => (owner.clone(), incoming.clone()),
+            None => return Err(ErrorKind::ConnectionRefused.into()),
+        };
+        if state.partitions.contains(&pair(&self.node, &owner)) || state.drop_next() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        let conn = Arc::new(Conn {
+            nodes: (self.node.clone(), owner),
+            pipes: [Pipe::default(), Pipe::default()],
+        });
+        state.conns.push(Arc::downgrade(&conn));
+        if incoming.send(SimStream::new(&self.net, &conn, 1)).is_err() {
+            state.listeners.remove(addr);
+            return Err(ErrorKind::ConnectionRefused.into());
+        }
+        Ok(SimStream::new(&self.net, &conn, 0))
+    }
+}
+
+pub struct SimListener {
+    incoming: Receiver<SimStream>,
+}
+
+impl Listener for SimListener {
+    type Stream = SimStream;
+
+    /// Fails once the node crashed
+    fn accept(&self) -> io::Result<SimStream> {
+        self.incoming
+            .recv()
+            .map_err(|_| ErrorKind::ConnectionAborted.into())
+    }
+}
+
+/// Two pipes, one per direction
+struct Conn {
+    // the node which connected, then the one which accepted
+    nodes: (String, String),
+    pipes: [Pipe; 2],
+}
+
+impl Conn {
+    fn break_off(&self) {
+        for pipe in self.pipes.iter() {
+            pipe.state.lock().unwrap().broken = true;
+            pipe.ready.notify_all();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    // written bytes and when they become readable
+    chunks: VecDeque<(Instant, Vec<u8>)>,
+    // one end hung up, the rest is read, then end of stream
+    closed: bool,
+    // reset, every read and write fails
+    broken: bool,
+}
+
+/// One end of a simulated connection
+pub struct SimStream {
+    net: SimNetwork,
+    conn: Arc<Conn>,
+    // 0 on the end which connected, 1 on the end which accepted
+    side: usize,
+    // shared by the clones, like the options of a socket
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+    // closes the connection once every clone is dropped
+    _end: Arc<End>,
+}
+
+struct End {
+    conn: Arc<Conn>,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        for pipe in self.conn.pipes.iter() {
+            pipe.state.lock().unwrap().closed = true;
+            pipe.ready.notify_all();
+        }
+    }
+}
+
+impl SimStream {
+    fn new(net: &SimNetwork, conn: &Arc<Conn>, side: usize) -> Self {
+        Self {
+            net: net.clone(),
+            conn: Arc::clone(conn),
+            side,
+            read_timeout: Arc::new(Mutex::new(None)),
+            _end: Arc::new(End {
+                conn: Arc::clone(conn),
+            }),
+        }
+    }
+
+    fn incoming(&self) -> &Pipe {
+        &self.conn.pipes[1 - self.side]
+    }
+
+    fn outgoing(&self) -> &Pipe {
+        &self.conn.pipes[self.side]
+    }
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self
+            .read_timeout
+            .lock()
+            .unwrap()
+            .map(|t| Instant::now() + t);
+        let pipe = self.incoming();
+        let mut state = pipe.state.lock().unwrap();
+        loop {
+            if state.broken {
+                return Err(ErrorKind::ConnectionReset.into());
+            }
+            let now = Instant::now();
+            let closed = state.closed;
+            let wake = match state.chunks.front_mut() {
+                Some((at, chunk)) if *at <= now => {
+                    let n = buf.len().min(chunk.len());
+                    buf[..n].copy_from_slice(&chunk[..n]);
+                    chunk.drain(..n);
+                    if chunk.is_empty() {
+                        state.chunks.pop_front();
+                    }
+                    return Ok(n);
+                }
+                Some((at, _)) => Some(*at),
+                None if closed => return Ok(0),
+                None => None,
+            };
+            let wake = match (wake, deadline) {
+                (_, Some(deadline)) if deadline <= now => {
+                    return Err(ErrorKind::WouldBlock.into());
+                }
+                (Some(wake), Some(deadline)) => Some(wake.min(deadline)),
+                (wake, deadline) => wake.or(deadline),
+            };
+            state = match wake {
+                Some(wake) => pipe.ready.wait_timeout(state, wake - now).unwrap().0,
+                None => pipe.ready.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let at = self.net.transmit(&self.conn)?;
+        let pipe = self.outgoing();
+        let mut state = pipe.state.lock().unwrap();
+        if state.broken {
+            return Err(ErrorKind::ConnectionReset.into());
+        }
+        if state.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        // bytes never overtake each other
+        let at = state.chunks.back().map_or(at, |(last, _)| at.max(*last));
+        state.chunks.push_back((at, buf.to_vec()));
+        pipe.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for SimStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            net: self.net.clone(),
+            conn: Arc::clone(&self.conn),
+            side: self.side,
+            read_timeout: Arc::clone(&self.read_timeout),
+            _end: Arc::clone(&self._end),
+        })
+    }
+
+    fn peer_closed(&self) -> bool {
+        let state = self.incoming().state.lock().unwrap();
+        state.broken || state.closed || !state.chunks.is_empty()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
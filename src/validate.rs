@@ -0,0 +1,197 @@
+//! Checks of the values written under a prefix, run by the server on sets
+//!
+//! A keyspace shared by many writers, e.g. the configuration of services under
+//! `config/`, is only as good as its worst write. A server started with
+//! validators refuses a set whose value does not pass the rules of its key
+//! before it reaches the store, and tells the client which part is wrong.
+//! The store itself takes any value, so embedding it checks nothing.
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::error::{KvsError, Result};
+use crate::protocol::Request;
+
+/// The rules of every prefix, all the rules of a key apply
+#[derive(Debug, Default)]
+pub struct Validators {
+    rules: Vec<(String, Validator)>,
+}
+
+/// What a value under a prefix must be
+#[derive(Debug)]
+pub enum Validator {
+    /// Any JSON document
+    Json,
+    /// An i64 in decimal, like the values `incr` works on
+    Integer,
+    /// A JSON document matching the schema, see `Schema`
+    Schema(Schema),
+}
+
+/// A subset of JSON Schema: `type`, `enum`, `required`, `properties` and `items`
+///
+/// Other keywords are ignored, so a full schema may be used as is, but only
+/// those parts of it are checked. A property not listed is allowed.
+#[derive(Debug)]
+pub struct Schema(Value);
+
+impl Validators {
+    /// Build the validators from their descriptions on the command line
+    ///
+    /// `PREFIX=json`, `PREFIX=integer` or `PREFIX=schema:PATH`. The schema
+    /// files are read once, a restart picks up their changes.
+    pub fn from_specs(specs: &[String]) -> Result<Self> {
+        let rules = specs
+            .iter()
+            .map(|spec| {
+                let invalid = || {
+                    KvsError::StringError(format!(
+                        "invalid validator {:?}, expect PREFIX=json, PREFIX=integer or PREFIX=schema:PATH",
+                        spec
+                    ))
+                };
+                let (prefix, kind) = spec.split_once('=').ok_or_else(invalid)?;
+                let validator = match kind.split_once(':') {
+                    None if kind == "json" => Validator::Json,
+                    None if kind == "integer" => Validator::Integer,
+                    Some(("schema", path)) => Validator::Schema(Schema::load(path)?),
+                    _ => return Err(invalid()),
+                };
+                Ok((prefix.to_owned(), validator))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Add the rule of `prefix`
+    pub fn add(&mut self, prefix: impl Into<String>, validator: Validator) {
+        self.rules.push((prefix.into(), validator));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Check the value `request` would write, if any
+    pub fn check_request(&self, request: &Request) -> Result<()> {
+        match request.inner() {
+            Request::Set { key, value }
+            | Request::SetWithEnvelope { key, value, .. }
+            | Request::SetIfAbsent { key, value }
+            | Request::SetIfPresent { key, value }
+            | Request::GetSet { key, value }
+            | Request::SetWhen { key, value, .. } => self.check(key, value),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail with `KvsError::InvalidValue` if `value` breaks a rule of `key`
+    pub fn check(&self, key: &str, value: &str) -> Result<()> {
+        for (_, validator) in self.rules.iter().filter(|(p, _)| key.starts_with(p)) {
+            validator
+                .check(value)
+                .map_err(|reason| KvsError::InvalidValue {
+                    key: key.to_owned(),
+                    reason,
+                })?;
+        }
+        Ok(())
+    }
+}
+
+impl Validator {
+    fn check(&self, value: &str) -> std::result::Result<(), String> {
+        match self {
+            Validator::Integer => value
+                .parse::<i64>()
+                .map(|_| ())
+                .map_err(|_| "not an integer".to_owned()),
+            Validator::Json => parse(value).map(|_| ()),
+            Validator::Schema(schema) => schema.check(&parse(value)?, ""),
+        }
+    }
+}
+
+impl Schema {
+    /// Read the schema, a JSON object, from `path`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let schema: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if !schema.is_object() {
+            return Err(KvsError::StringError(format!(
+                "schema {} is not a JSON object",
+                path.display()
+            )));
+        }
+        Ok(Self(schema))
+    }
+
+    pub fn new(schema: Value) -> Self {
+        Self(schema)
+    }
+
+    /// `at` is the JSON pointer of `value` in the whole document
+    fn check(&self, value: &Value, at: &str) -> std::result::Result<(), String> {
+        check_schema(&self.0, value, at)
+    }
+}
+
+fn check_schema(schema: &Value, value: &Value, at: &str) -> std::result::Result<(), String> {
+    let name = if at.is_empty() { "value" } else { at };
+    if let Some(ty) = schema.get("type").and_then(Value::as_str)
+        && !has_type(value, ty)
+    {
+        return Err(format!("{} is not of type {}", name, ty));
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array)
+        && !allowed.contains(value)
+    {
+        return Err(format!("{} is not one of the allowed values", name));
+    }
+    if let Some(fields) = value.as_object() {
+        let required = schema.get("required").and_then(Value::as_array);
+        for field in required.into_iter().flatten().filter_map(Value::as_str) {
+            if !fields.contains_key(field) {
+                return Err(format!("{} misses the field {}", name, field));
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (field, schema) in properties.into_iter().flatten() {
+            if let Some(value) = fields.get(field) {
+                check_schema(schema, value, &format!("{}/{}", at, escape(field)))?;
+            }
+        }
+    }
+    if let (Some(items), Some(schema)) = (value.as_array(), schema.get("items")) {
+        for (i, value) in items.iter().enumerate() {
+            check_schema(schema, value, &format!("{}/{}", at, i))?;
+        }
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    match ty {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        // unknown to this subset, not checked
+        _ => true,
+    }
+}
+
+fn parse(value: &str) -> std::result::Result<Value, String> {
+    serde_json::from_str(value).map_err(|e| format!("not JSON: {}", e))
+}
+
+/// Escape a field name for a JSON pointer, RFC 6901
+fn escape(field: &str) -> String {
+    field.replace('~', "~0").replace('/', "~1")
+}
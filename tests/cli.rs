@@ -1,6 +1,12 @@
 use assert_cmd::prelude::*;
+use kvs::client;
+use kvs::engine::KvsEngine;
+use kvs::engine::kvs::KvStore;
+use kvs::protocol::{ReadModifiers, Request};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -151,6 +157,29 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// Completion scripts and man pages are printed without a server
+#[test]
+fn cli_completions_and_man() {
+    let temp_dir = TempDir::new().unwrap();
+    for bin in ["kvs-client", "kvs-server"] {
+        Command::cargo_bin(bin)
+            .unwrap()
+            .args(&["completions", "bash"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains(bin));
+        Command::cargo_bin(bin)
+            .unwrap()
+            .args(&["man"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains(".TH"));
+    }
+    assert!(fs::read_dir(&temp_dir).unwrap().next().is_none());
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +364,1049 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Environment variables override the config file, flags override both
+#[test]
+fn server_cli_env_overrides() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let config = temp_dir.path().join("config.json");
+    fs::write(
+        &config,
+        r#"{"addr": "127.0.0.1:4007", "engine": "sled", "threads": 4}"#,
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--config", config.to_str().unwrap()])
+        .env("KVS_ADDR", "127.0.0.1:4006")
+        .env("KVS_DATA_DIR", &data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    child.kill().expect("server exited before killed");
+
+    assert!(data_dir.join("meta").exists());
+    assert!(!temp_dir.path().join("meta").exists());
+}
+
+// The server should announce readiness and log JSON lines when asked to
+#[test]
+fn server_cli_ready_and_json_logs() {
+    let temp_dir = TempDir::new().unwrap();
+    let stdout_path = temp_dir.path().join("stdout");
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--addr", "127.0.0.1:4008", "--log-format", "json"])
+        .env("RUST_LOG", "trace")
+        .current_dir(&temp_dir)
+        .stdout(File::create(&stdout_path).unwrap())
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+
+    let stdout = fs::read_to_string(&stdout_path).expect("unable to read from stdout file");
+    assert!(stdout.contains("Ready to accept connections on 127.0.0.1:4008"));
+    let stderr = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(!stderr.is_empty());
+    for line in stderr.lines() {
+        let log: serde_json::Value = serde_json::from_str(line).expect("log line is not JSON");
+        assert!(log["level"].is_string());
+    }
+}
+
+// Databases hosted by one server are independent of each other
+#[test]
+fn cli_select_database() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4009", "--databases", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "key1",
+            "value1",
+            "--db",
+            "1",
+            "--addr",
+            "127.0.0.1:4009",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--db", "1", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--db", "2", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid database 2"));
+    child.kill().expect("server exited before killed");
+}
+
+// Read modifiers are applied by the server before the value is sent back
+#[test]
+fn cli_get_with_modifiers() {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(br#"{"user": {"name": "jack"}}"#).unwrap();
+    let compressed = STANDARD.encode(encoder.finish().unwrap());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &compressed, "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--decompress", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"user\": {\"name\": \"jack\"}}\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "get",
+            "key1",
+            "--decompress",
+            "--json-pointer",
+            "/user/name",
+        ])
+        .args(&["--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("\"jack\"\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "get",
+            "key1",
+            "--json-pointer",
+            "/user",
+            "--addr",
+            "127.0.0.1:4010",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("fail to transform value"));
+    child.kill().expect("server exited before killed");
+}
+
+// A value set as compressed is decompressed on get, unless asked raw
+#[test]
+fn cli_value_envelope() {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use flate2::{Compression, write::GzEncoder};
+    use std::io::Write;
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(br#"{"name": "jack"}"#).unwrap();
+    let compressed = STANDARD.encode(encoder.finish().unwrap());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", &compressed, "--compressed"])
+        .args(&["--content-type", "application/json"])
+        .args(&["--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"name\": \"jack\"}\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--raw", "--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(format!("{}\n", compressed));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stat", "key1", "--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("content type: application/json\ncompressed: yes"));
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_standby_promote() {
+    let primary_dir = TempDir::new().unwrap();
+    let standby_dir = TempDir::new().unwrap();
+    let mut primary = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4011"])
+        .current_dir(&primary_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4011"])
+        .current_dir(&primary_dir)
+        .assert()
+        .success();
+
+    let mut standby = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4012", "--standby-of", "127.0.0.1:4011"])
+        .current_dir(&standby_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", "127.0.0.1:4011"])
+        .current_dir(&primary_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4012"])
+        .current_dir(&standby_dir)
+        .assert()
+        .failure()
+        .stderr(contains("standby"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["promote", "--addr", "127.0.0.1:4012"])
+        .current_dir(&standby_dir)
+        .assert()
+        .success();
+
+    for (key, value) in [("key1", "value1\n"), ("key2", "value2\n")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", key, "--addr", "127.0.0.1:4012"])
+            .current_dir(&standby_dir)
+            .assert()
+            .success()
+            .stdout(value);
+    }
+    standby.kill().expect("server exited before killed");
+    primary.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_client_failover() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // nothing listens on the first address
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "key1",
+            "value1",
+            "--addr",
+            "127.0.0.1:4013,127.0.0.1:4014",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("fail over to 127.0.0.1:4014"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4014,127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n")
+        .stderr(is_empty());
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_info_traffic() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4015", "--databases", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "key1",
+            "value1",
+            "--db",
+            "1",
+            "--addr",
+            "127.0.0.1:4015",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // the request line and `"Ok"` with their line feeds
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("db1 requests 1 bytes_in 40 bytes_out 5"));
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4025"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4025"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--prefix", "key", "--addr", "127.0.0.1:4025"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n"))
+        .stdout(contains("prefix keys: 1\n"));
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_flush_all() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4028"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["flush-all", "--addr", "127.0.0.1:4028"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("flush all is disabled"));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4028", "--allow-flush-all"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4028"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["flush-all", "--addr", "127.0.0.1:4028"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4028"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_drain_and_resume() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["drain", "--retry-after", "250", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("server is draining, retry in 250 ms"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["resume", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4027"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_cancel_unknown_operation() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cancel", "42", "--addr", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("no operation 42 is running"));
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "hits", "--addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "hits", "-5", "--addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-4\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "name", "kvs", "--addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "name", "--addr", "127.0.0.1:4030"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("value of key name is not an integer"));
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_sync_window_and_batch() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            "127.0.0.1:4023",
+            "--sync-window",
+            "500",
+            "--sync-batch",
+            "8",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("db0 syncs 1 synced_writes 1 batches 1:1"));
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_scan_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in [("user:2", "bob"), ("user:1", "alice"), ("post:1", "hi")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", "127.0.0.1:4016"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan-prefix", "user:", "--addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:1 alice\nuser:2 bob\n");
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_set_when() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4017"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let set_when = |value: &str, condition: &str| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set-when", "count", value, condition])
+            .args(&["--addr", "127.0.0.1:4017"])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+    set_when("1", "not exists").success().stdout(is_empty());
+    set_when("5", "value < 1")
+        .success()
+        .stdout("Condition not met\n");
+    set_when("5", "value >= 1 and value != 3")
+        .success()
+        .stdout(is_empty());
+    set_when("6", "value <").failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "count", "--addr", "127.0.0.1:4017"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("5\n");
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_idempotency_token() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4018"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "count", "1", "--addr", "127.0.0.1:4018"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let increment = |token: Option<&str>| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(&["set-when", "count", "2", "value == 1"])
+            .args(&["--addr", "127.0.0.1:4018"]);
+        if let Some(token) = token {
+            cmd.args(&["--token", token]);
+        }
+        cmd.current_dir(&temp_dir).assert()
+    };
+    increment(Some("retry-1")).success().stdout(is_empty());
+    // the retry gets the first response, although the value changed
+    increment(Some("retry-1")).success().stdout(is_empty());
+    increment(None).success().stdout("Condition not met\n");
+    server.kill().expect("server exited before killed");
+}
+
+// Every `--addr` is listened on, including unix sockets
+#[test]
+fn cli_multiple_addrs() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4020", "--addr", "127.0.0.1:4021"])
+        .arg("--addr")
+        .arg(format!("unix:{}", socket.display()))
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4020"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4021"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    let stream = UnixStream::connect(&socket).unwrap();
+    let request = Request::Get {
+        key: "key1".to_owned(),
+        modifiers: ReadModifiers::default(),
+    };
+    assert_eq!(
+        client::send_and_recv(request, stream).unwrap(),
+        Some("value1".to_owned())
+    );
+    server.kill().expect("server exited before killed");
+}
+
+// The log filter changes without a restart, by request or by SIGUSR1
+#[test]
+fn cli_log_filter_at_runtime() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4022"])
+        .env_remove("RUST_LOG")
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let requests_logged = || {
+        fs::read_to_string(&stderr_path)
+            .unwrap()
+            .matches("start to retrieve info from the stream")
+            .count()
+    };
+    let get = || {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", "127.0.0.1:4022"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    };
+    get();
+    assert_eq!(requests_logged(), 0);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["log-filter", "trace", "--addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let before = requests_logged();
+    get();
+    assert!(requests_logged() > before);
+
+    // Back to the filter the server started with
+    Command::new("kill")
+        .args(&["-USR1", &server.id().to_string()])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+    let before = requests_logged();
+    get();
+    assert_eq!(requests_logged(), before);
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_verify_on_start() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+    let log = temp_dir.path().join("log/1.log");
+    let mut content = fs::read(&log).unwrap();
+    let at = content.windows(6).position(|w| w == b"value1").unwrap();
+    content[at..at + 6].copy_from_slice(b"valueX");
+    fs::write(&log, content).unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4024", "--verify-on-start"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("CorruptRecord"));
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4024", "--verify-on-start=repair"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", "127.0.0.1:4024"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_auth_token_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("tokens"), "# ops\nsecret1\n").unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4026", "--auth", "token-file:tokens"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4026"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "get",
+            "key1",
+            "--secret",
+            "wrong",
+            "--addr",
+            "127.0.0.1:4026",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid credentials"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4026"])
+        .env("KVS_SECRET", "secret1")
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_validate_prefix() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("service.json"),
+        r#"{"type": "object", "required": ["port"], "properties": {"port": {"type": "integer"}}}"#,
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            "127.0.0.1:4031",
+            "--validate",
+            "config/=schema:service.json",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "config/web",
+            r#"{"port": "80"}"#,
+            "--addr",
+            "127.0.0.1:4031",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(
+            "invalid value of key config/web: /port is not of type integer",
+        ));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set",
+            "config/web",
+            r#"{"port": 80}"#,
+            "--addr",
+            "127.0.0.1:4031",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "other", "not json", "--addr", "127.0.0.1:4031"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_setnx() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4032"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["setnx", "leader", "node1", "--addr", "127.0.0.1:4032"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["setnx", "leader", "node2", "--addr", "127.0.0.1:4032"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key already exists\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "leader", "--addr", "127.0.0.1:4032"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("node1\n");
+    server.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_namespace() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "users", "--namespace", "users"])
+        .args(&["--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "teams", "--namespace", "teams"])
+        .args(&["--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--namespace", "users"])
+        .args(&["--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("users\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["keys", "*", "--namespace", "teams"])
+        .args(&["--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4033"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    server.kill().expect("server exited before killed");
+}
+
+// Idle connections hold no thread another client waits for, whatever `--threads`
+#[test]
+fn cli_more_connections_than_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4034", "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let idle: Vec<TcpStream> = (0..2)
+        .map(|_| TcpStream::connect("127.0.0.1:4034").unwrap())
+        .collect();
+    // a third client would wait forever for a worker, a timeout fails it
+    let stream = TcpStream::connect("127.0.0.1:4034").unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let request = Request::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    assert_eq!(client::send_and_recv(request, stream).unwrap(), None);
+    drop(idle);
+    server.kill().expect("server exited before killed");
+}
@@ -1,9 +1,34 @@
+use kvs::auth;
+use kvs::client::{self, KvsClient};
 use kvs::engine::KvsEngine;
-use kvs::engine::kvs::KvStore;
-use kvs::error::Result;
+use kvs::engine::condition::Condition;
+use kvs::engine::kvs::{
+    Change, ChangeEvent, Compression, EngineEvent, Envelope, IndexKind, KvReplica, KvSnapshot,
+    KvStore, MemoryBudget, RecoveryProgress, RegionChange, SyncPolicy, Verify, WatchFilter,
+    WriteBatch, WriteKind, WriteStatus,
+};
+use kvs::engine::mem::MemStore;
+use kvs::engine::pattern::Pattern;
+use kvs::error::{KvsError, Result};
+use kvs::limits::Limits;
+use kvs::manifest::{LogLayout, Manifest};
+use kvs::protocol::{ReadModifiers, Request};
+use kvs::server;
+use kvs::testing::TestServer;
 use kvs::thread_pool::ThreadPool;
-use std::sync::{Arc, Barrier};
+use kvs::transport::Transport;
+use kvs::transport::sim::SimNetwork;
+use kvs::validate::{Schema, Validator, Validators};
+use std::fs;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -212,3 +237,3004 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn conditional_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_if_present("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(store.set_if_present("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn take_and_insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.insert("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.insert("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.take("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.take("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn key_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.metadata("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let meta = store.metadata("key1".to_owned())?.unwrap();
+    assert_eq!(meta.value_size, 6);
+    assert!(meta.last_modified.is_some());
+    assert_eq!(meta.ttl, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metadata("key1".to_owned())?, Some(meta));
+
+    Ok(())
+}
+
+// Values read from a stream are stored in chunks, none is left behind
+#[test]
+fn chunked_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().chunk_size(16).open(temp_dir.path())?;
+    let chunks = |store: &KvStore| store.stats().map(|stats| stats.reserved_keys);
+
+    // a character is never cut in two
+    let value = "blob-é-".repeat(100);
+    assert_eq!(
+        store.set_from_reader("key1".to_owned(), value.as_bytes())?,
+        value.len() as u64
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(
+        store.get_many(&["key1".to_owned()])?,
+        vec![Some(value.clone())]
+    );
+    assert_eq!(
+        store.snapshot()?.get("key1".to_owned())?,
+        Some(value.clone())
+    );
+    let mut read = String::new();
+    let mut reader = store.get_reader("key1".to_owned())?.unwrap();
+    assert_eq!(reader.len(), value.len() as u64);
+    reader.read_to_string(&mut read)?;
+    assert_eq!(read, value);
+    let written = chunks(&store)?;
+    assert!(written >= value.len() / 16);
+    // the chunks are not keys of their own
+    assert_eq!(store.len()?, 1);
+    assert_eq!(store.keys(&Pattern::new("*"))?, vec!["key1".to_owned()]);
+    assert_eq!(
+        store.scan(..)?.collect::<Result<Vec<_>>>()?,
+        vec![("key1".to_owned(), value.clone())]
+    );
+    let meta = store.metadata("key1".to_owned())?.unwrap();
+    assert!(meta.envelope.is_plain());
+
+    // an overwrite removes the chunks of the old value
+    let shorter = "blob-".repeat(10);
+    store.set_from_reader("key1".to_owned(), shorter.as_bytes())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(shorter.clone()));
+    assert!(chunks(&store)? < written);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(chunks(&store)?, 0);
+
+    // a short value is stored whole
+    store.set_from_reader("key2".to_owned(), "value2".as_bytes())?;
+    assert_eq!(chunks(&store)?, 0);
+    assert!(
+        store
+            .set_from_reader("key3".to_owned(), &[0xff; 64][..])
+            .is_err()
+    );
+
+    store.set_from_reader("key4".to_owned(), value.as_bytes())?;
+    drop(store);
+    let store = KvStore::builder().chunk_size(16).open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some(value));
+    store.remove("key4".to_owned())?;
+    assert_eq!(chunks(&store)?, 0);
+
+    Ok(())
+}
+
+// Merge operands are folded by reads, and written back once there are many
+#[test]
+fn merge_operator_folds_operands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .merge_operator(|_, value, operands| {
+                let base: u64 = value.map_or(0, |v| v.parse().unwrap());
+                let sum: u64 = operands.iter().map(|o| o.parse::<u64>().unwrap()).sum();
+                (base + sum).to_string()
+            })
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    let operands = |store: &KvStore| store.stats().map(|stats| stats.reserved_keys);
+
+    for _ in 0..100 {
+        store.merge("key1".to_owned(), "1".to_owned())?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("100".to_owned()));
+    assert!(operands(&store)? < 64);
+    // the operands are not keys of their own
+    assert_eq!(store.len()?, 1);
+    assert_eq!(store.count_prefix("")?, 1);
+    assert_eq!(store.keys(&Pattern::new("*"))?, vec!["key1".to_owned()]);
+
+    // a set or a remove drops the operands
+    store.set("key2".to_owned(), "5".to_owned())?;
+    store.merge("key2".to_owned(), "2".to_owned())?;
+    assert_eq!(
+        store.get_many(&["key2".to_owned(), "key3".to_owned()])?,
+        vec![Some("7".to_owned()), None]
+    );
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.merge("key2".to_owned(), "3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("3".to_owned()));
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("3".to_owned()));
+    store.set("key2".to_owned(), "4".to_owned())?;
+    store.merge("key2".to_owned(), "1".to_owned())?;
+    store.compact()?;
+    assert_eq!(operands(&store)?, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("100".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("5".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.merge("key1".to_owned(), "1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Writes after a point in time are told apart by their timestamps
+#[test]
+fn scan_modified_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.last_modified("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    thread::sleep(Duration::from_millis(5));
+    let since = SystemTime::now();
+    thread::sleep(Duration::from_millis(5));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    assert!(store.last_modified("key1".to_owned())?.unwrap() < since);
+    assert!(store.last_modified("key2".to_owned())?.unwrap() > since);
+    let modified = store
+        .scan_modified(..)?
+        .filter(|pair| !matches!(pair, Ok((_, _, t)) if t.unwrap() <= since))
+        .map(|pair| pair.map(|(key, value, _)| (key, value)))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        modified,
+        vec![
+            ("key2".to_owned(), "value3".to_owned()),
+            ("key3".to_owned(), "value4".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn remove_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..10 {
+        store.set(format!("a{}", i), format!("value{}", i))?;
+        store.set(format!("b{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(store.remove_range("a3".to_owned().."a6".to_owned())?, 3);
+    assert_eq!(store.remove_range("a6".to_owned().."a3".to_owned())?, 0);
+    assert_eq!(store.remove_prefix("b")?, 10);
+    assert_eq!(store.remove_prefix("b")?, 0);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..10 {
+            let expect = (!(3..6).contains(&i)).then(|| format!("value{}", i));
+            assert_eq!(store.get(format!("a{}", i))?, expect);
+            assert_eq!(store.get(format!("b{}", i))?, None);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+#[test]
+fn remove_prefix_purges_lazily() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(1024)
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("tmp:{}", i), format!("value{}", i))?;
+    }
+    store.set("kept".to_owned(), "value".to_owned())?;
+    let snapshot = store.snapshot()?;
+
+    assert_eq!(store.remove_prefix("tmp:")?, 100);
+    // written right after the purge, maybe in the same millisecond
+    store.set("tmp:1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("tmp:0".to_owned())?, None);
+    assert_eq!(store.get("tmp:1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.count_prefix("tmp:")?, 1);
+    assert_eq!(store.len()?, 2);
+    assert_eq!(snapshot.get("tmp:0".to_owned())?, Some("value0".to_owned()));
+    drop(snapshot);
+
+    store.compact()?;
+    assert_eq!(store.stats()?.keys, 2);
+    assert_eq!(store.get("tmp:2".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("tmp:2".to_owned())?, None);
+    assert_eq!(store.get("tmp:1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("kept".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn count_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..10 {
+        store.set(format!("user:{}", i), format!("value{}", i))?;
+    }
+    store.set("users".to_owned(), "value".to_owned())?;
+    store.remove("user:0".to_owned())?;
+
+    assert_eq!(store.count_prefix("user:")?, 9);
+    assert_eq!(store.count_prefix("user")?, 10);
+    assert_eq!(store.count_prefix("")?, 10);
+    assert_eq!(store.count_prefix("group:")?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn dead_bytes_and_write_status() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats()?.dead_bytes, 0);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let overwritten = store.stats()?.dead_bytes;
+    assert!(overwritten > 0);
+    store.remove("key1".to_owned())?;
+    let stats = store.stats()?;
+    assert!(stats.dead_bytes > overwritten);
+    assert_eq!(stats.write_status, WriteStatus::Normal);
+
+    // Dead bytes are recounted when replaying the log
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.dead_bytes, stats.dead_bytes);
+
+    Ok(())
+}
+
+#[test]
+fn disk_full_is_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.set_disk_reserve(u64::MAX);
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::DiskFull)
+    ));
+    assert!(store.stats()?.disk_full);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set_disk_reserve(0);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!store.stats()?.disk_full);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn disk_quota_fails_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // only a full compaction reclaims the overwritten values
+    let store = KvStore::builder()
+        .disk_quota(4096)
+        .compaction_threshold(0.0)
+        .open(temp_dir.path().join("strict"))?;
+    let value = "v".repeat(100);
+    let mut result = Ok(());
+    for _ in 0..100 {
+        result = store.set("key1".to_owned(), value.clone());
+        if result.is_err() {
+            break;
+        }
+    }
+    assert!(matches!(
+        result,
+        Err(KvsError::QuotaExceeded { quota: 4096, .. })
+    ));
+    assert!(store.stats()?.disk_bytes <= 4096);
+    assert_eq!(store.stats()?.disk_quota, Some(4096));
+    // removals free space, they are never refused
+    store.remove("key1".to_owned())?;
+
+    // Compacting makes room for overwrites, not for more live keys
+    let store = KvStore::builder()
+        .disk_quota(4096)
+        .compaction_threshold(0.0)
+        .compact_over_quota(true)
+        .open(temp_dir.path().join("compacting"))?;
+    for _ in 0..100 {
+        store.set("key1".to_owned(), value.clone())?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    let mut result = Ok(());
+    for key_id in 0..100 {
+        result = store.set(format!("key{}", key_id), value.clone());
+        if result.is_err() {
+            break;
+        }
+    }
+    assert!(matches!(result, Err(KvsError::QuotaExceeded { .. })));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_rate(Some(200 * 1024));
+    assert_eq!(store.stats()?.compaction_rate, Some(200 * 1024));
+
+    let mut iter = 0;
+    while store.stats()?.compaction_throttled.is_zero() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", iter - 1))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn value_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_value_checksum(true);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // Flip one byte of a value behind the store's back
+    patch_log(&temp_dir.path().join("log/1.log"), "value1", "valueX")?;
+
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::Corruption { key, segment: 1 }) if key == "key1"
+    ));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.stats()?.corrupted_reads, 1);
+
+    Ok(())
+}
+
+#[test]
+fn verify_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    patch_log(&temp_dir.path().join("log/1.log"), "value1", "valueX")?;
+
+    assert!(matches!(
+        KvStore::builder()
+            .verify(Verify::Check)
+            .open(temp_dir.path()),
+        Err(KvsError::CorruptRecord { segment: 1, .. })
+    ));
+
+    let store = KvStore::builder()
+        .verify(Verify::Repair)
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats()?.skipped_records, 1);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::builder()
+        .verify(Verify::Check)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn engine_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.subscribe();
+
+    let mut iter = 0;
+    loop {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+        let received: Vec<EngineEvent> = events.try_iter().collect();
+        assert!(
+            received
+                .iter()
+                .any(|e| matches!(e, EngineEvent::SegmentSealed { .. }))
+        );
+        if received.contains(&EngineEvent::IndexRebuilt { keys: 100 }) {
+            assert!(received.iter().any(
+                |e| matches!(e, EngineEvent::CompactionFinished { reclaimed } if *reclaimed > 0)
+            ));
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Readers keep getting values while compaction swaps the index and logs
+#[test]
+fn get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.subscribe();
+    store.set("stable".to_owned(), "value".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for _ in 0..2000 {
+                assert_eq!(
+                    store.get("stable".to_owned()).unwrap(),
+                    Some("value".to_owned())
+                );
+            }
+        });
+        handles.push(handle);
+    }
+
+    let mut iter = 0;
+    while !events
+        .try_iter()
+        .any(|e| matches!(e, EngineEvent::IndexRebuilt { .. }))
+    {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("stable".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// The skiplist index should behave like the default one
+#[test]
+fn concurrent_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .index(IndexKind::Concurrent)
+        .open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{:02}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(store.remove_prefix("key1")?, 10);
+    assert_eq!(store.count_prefix("key")?, 90);
+    store.remove("key20".to_owned())?;
+    assert_eq!(store.get("key20".to_owned())?, None);
+    assert_eq!(store.get("key21".to_owned())?, Some("199".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::builder()
+        .index(IndexKind::Concurrent)
+        .open(temp_dir.path())?;
+    assert_eq!(store.count_prefix("key")?, 89);
+    assert_eq!(store.get("key15".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("199".to_owned()));
+
+    Ok(())
+}
+
+// The sharded index should scan in key order like the default one
+#[test]
+fn sharded_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .index(IndexKind::Sharded(8))
+        .open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in (t..100).step_by(4) {
+                    store.set(format!("key{:02}", key_id), format!("{}", key_id))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.remove_prefix("key1")?, 10);
+    assert_eq!(
+        store.remove_range("key50".to_owned().."key60".to_owned())?,
+        10
+    );
+    let keys: Vec<String> = store
+        .scan(..)?
+        .map(|pair| pair.map(|(k, _)| k))
+        .collect::<Result<_>>()?;
+    let expected: Vec<String> = (0..100)
+        .filter(|id| !(10..20).contains(id) && !(50..60).contains(id))
+        .map(|id| format!("key{:02}", id))
+        .collect();
+    assert_eq!(keys, expected);
+    let page = store.scan_page(None, 3, None)?;
+    assert_eq!(page.entries[2], ("key02".to_owned(), "2".to_owned()));
+
+    drop(store);
+    let store = KvStore::builder()
+        .index(IndexKind::Sharded(8))
+        .open(temp_dir.path())?;
+    assert_eq!(store.len()?, 80);
+    assert_eq!(store.get("key99".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// A store which is not closed should be verified on the next open
+#[test]
+fn unclean_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_value_checksum(true);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.stats()?.unclean_shutdown);
+    // Simulate a crash, the store is never closed
+    std::mem::forget(store);
+
+    patch_log(&temp_dir.path().join("log/1.log"), "value1", "valueX")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert!(stats.unclean_shutdown);
+    assert_eq!(stats.skipped_records, 1);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Glob patterns select keys without reading values
+#[test]
+fn keys_matching_pattern() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in [
+        "user:1:profile",
+        "user:2:profile",
+        "user:2:posts",
+        "user:10:profile",
+        "admin:1:profile",
+        "user*",
+    ] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+
+    let keys = |glob: &str| store.keys(&Pattern::new(glob));
+    assert_eq!(
+        keys("user:*:profile")?,
+        vec!["user:10:profile", "user:1:profile", "user:2:profile"]
+    );
+    assert_eq!(keys("user:?:p*")?.len(), 3);
+    assert_eq!(keys("*:1:*")?, vec!["admin:1:profile", "user:1:profile"]);
+    assert_eq!(keys("user\\*")?, vec!["user*"]);
+    assert_eq!(keys("*")?.len(), 6);
+    assert!(keys("guest:*")?.is_empty());
+
+    Ok(())
+}
+
+// A scan neither skips nor repeats keys when compaction runs in between
+#[test]
+fn scan_across_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.subscribe();
+    for key_id in 0..300 {
+        store.set(format!("key{:03}", key_id), "value".to_owned())?;
+    }
+
+    let mut page = store.scan_page(None, 50, None)?;
+    let mut keys: Vec<String> = page.entries.iter().map(|(k, _)| k.clone()).collect();
+
+    // Compact, add a key and remove a key which is not scanned yet
+    let mut iter = 0;
+    while !events
+        .try_iter()
+        .any(|e| matches!(e, EngineEvent::IndexRebuilt { .. }))
+    {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..100 {
+            store.set(format!("key{:03}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    store.set("key150a".to_owned(), "value".to_owned())?;
+    store.remove("key200".to_owned())?;
+
+    while let Some(cursor) = page.cursor {
+        page = store.scan_page(Some(cursor), 50, None)?;
+        keys.extend(page.entries.iter().map(|(k, _)| k.clone()));
+    }
+    let expected: Vec<String> = (0..300)
+        .filter(|&key_id| key_id != 200)
+        .map(|key_id| format!("key{:03}", key_id))
+        .collect();
+    assert_eq!(keys, expected);
+
+    // A pattern only returns the matching keys
+    let page = store.scan_page(None, 100, Some(&Pattern::new("key1?0")))?;
+    assert_eq!(page.entries.len(), 10);
+    assert_eq!(page.cursor, None);
+    assert!(matches!(
+        store.scan_page(Some("garbage".to_owned()), 10, None),
+        Err(KvsError::InvalidCursor)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn standby_follows_changefeed() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let standby_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(primary_dir.path())?;
+    let standby = KvStore::builder().standby(true).open(standby_dir.path())?;
+
+    primary.set("key1".to_owned(), "value1".to_owned())?;
+    let feed = primary.changefeed();
+    primary.set("key2".to_owned(), "value2".to_owned())?;
+    primary.remove("key1".to_owned())?;
+
+    // A standby serves nothing, but applies the changes of the primary
+    assert!(matches!(
+        standby.get("key2".to_owned()),
+        Err(KvsError::Standby)
+    ));
+    standby.apply(Change::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    for change in feed.try_iter() {
+        standby.apply(change)?;
+    }
+
+    standby.promote();
+    assert!(!standby.is_standby());
+    assert_eq!(standby.get("key1".to_owned())?, None);
+    assert_eq!(standby.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        standby.apply(Change::Remove {
+            key: "key2".to_owned()
+        }),
+        Err(KvsError::NotStandby)
+    ));
+    Ok(())
+}
+
+#[test]
+fn watch_follows_durable_writes_under_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .sync(SyncPolicy::Always)
+        .open(temp_dir.path())?;
+
+    store.set("user:0".to_owned(), "before".to_owned())?;
+    let events = store.watch("user:");
+    store.set("user:1".to_owned(), "jack".to_owned())?;
+    store.set("team:1".to_owned(), "kvs".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    store.remove_prefix("user")?;
+
+    let wait = Duration::from_secs(5);
+    let set = ChangeEvent::Set {
+        key: "user:1".to_owned(),
+        value: "jack".to_owned(),
+    };
+    assert_eq!(events.recv_timeout(wait).unwrap(), set);
+    assert_eq!(
+        events.recv_timeout(wait).unwrap(),
+        ChangeEvent::Rm {
+            key: "user:1".to_owned()
+        }
+    );
+    assert_eq!(
+        events.recv_timeout(wait).unwrap(),
+        ChangeEvent::RmPrefix {
+            prefix: "user".to_owned()
+        }
+    );
+    assert!(events.try_recv().is_err());
+
+    // The same writes over the wire
+    let server = TestServer::start()?;
+    let stream = server.connect()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let _ = client::watch(0, "user:".to_owned(), stream, |event| {
+            let _ = tx.send(event);
+            Err(KvsError::StringError("stop".to_owned()))
+        });
+    });
+    // until the server watches
+    let deadline = Instant::now() + wait;
+    let event = loop {
+        server.store().set("user:1".to_owned(), "jack".to_owned())?;
+        if let Ok(event) = rx.recv_timeout(Duration::from_millis(50)) {
+            break event;
+        }
+        assert!(Instant::now() < deadline, "no event over the wire");
+    };
+    assert_eq!(event, set);
+    Ok(())
+}
+
+// A watch of several keys and prefixes gets only the writes its filter lets through
+#[test]
+fn watch_filters_keys_and_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .sync(SyncPolicy::Always)
+        .open(temp_dir.path())?;
+
+    let keys = store.watch_filtered(WatchFilter {
+        keys: vec!["cfg".to_owned()],
+        prefixes: vec!["user:".to_owned(), "team:".to_owned()],
+        ..WatchFilter::default()
+    })?;
+    let sets = store.watch_filtered(WatchFilter {
+        prefixes: vec!["user:".to_owned()],
+        only: Some(WriteKind::Set),
+        value: Some("^j".to_owned()),
+        ..WatchFilter::default()
+    })?;
+    let removes = store.watch_filtered(WatchFilter {
+        only: Some(WriteKind::Remove),
+        ..WatchFilter::default()
+    })?;
+    assert!(matches!(
+        store.watch_filtered(WatchFilter {
+            value: Some("(".to_owned()),
+            ..WatchFilter::default()
+        }),
+        Err(KvsError::InvalidWatch(_))
+    ));
+
+    let set = |key: &str, value: &str| ChangeEvent::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    store.set("user:1".to_owned(), "jack".to_owned())?;
+    store.set("user:2".to_owned(), "rose".to_owned())?;
+    store.set("cfg".to_owned(), "on".to_owned())?;
+    store.set("cfg2".to_owned(), "off".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    store.remove_range("cfg".to_owned().."cfg0".to_owned())?;
+    store.remove_prefix("cfg2")?;
+
+    let wait = Duration::from_secs(5);
+    let rm = ChangeEvent::Rm {
+        key: "user:1".to_owned(),
+    };
+    let rm_range = ChangeEvent::RmRange {
+        start: Bound::Included("cfg".to_owned()),
+        end: Bound::Excluded("cfg0".to_owned()),
+    };
+    let rm_prefix = ChangeEvent::RmPrefix {
+        prefix: "cfg2".to_owned(),
+    };
+    for event in [
+        set("user:1", "jack"),
+        set("user:2", "rose"),
+        set("cfg", "on"),
+        rm.clone(),
+        rm_range.clone(),
+    ] {
+        assert_eq!(keys.recv_timeout(wait).unwrap(), event);
+    }
+    assert_eq!(sets.recv_timeout(wait).unwrap(), set("user:1", "jack"));
+    for event in [rm, rm_range, rm_prefix] {
+        assert_eq!(removes.recv_timeout(wait).unwrap(), event);
+    }
+    // the last write reached every watcher, none got more
+    assert!(keys.try_recv().is_err());
+    assert!(sets.try_recv().is_err());
+
+    // The filter runs on the server
+    let server = TestServer::start()?;
+    let stream = server.connect()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let filter = WatchFilter {
+            keys: vec!["cfg".to_owned()],
+            only: Some(WriteKind::Set),
+            ..WatchFilter::default()
+        };
+        let _ = client::watch_filtered(0, filter, stream, |event| {
+            let _ = tx.send(event);
+            Err(KvsError::StringError("stop".to_owned()))
+        });
+    });
+    let deadline = Instant::now() + wait;
+    let event = loop {
+        server.store().set("other".to_owned(), "x".to_owned())?;
+        server.store().remove_prefix("cfg")?;
+        server.store().set("cfg".to_owned(), "on".to_owned())?;
+        if let Ok(event) = rx.recv_timeout(Duration::from_millis(50)) {
+            break event;
+        }
+        assert!(Instant::now() < deadline, "no event over the wire");
+    };
+    assert_eq!(event, set("cfg", "on"));
+
+    // A filter which does not compile gets an error line, not a stream
+    let stream = server.connect()?;
+    stream.set_read_timeout(Some(wait))?;
+    let filter = WatchFilter {
+        value: Some("(".to_owned()),
+        ..WatchFilter::default()
+    };
+    let expected = store.watch_filtered(filter.clone()).unwrap_err();
+    let err = client::watch_filtered(0, filter, stream.try_clone()?, |_| Ok(())).unwrap_err();
+    assert_eq!(err.to_string(), expected.to_string());
+    // and the connection still serves requests
+    let request = Request::Get {
+        key: "cfg".to_owned(),
+        modifiers: ReadModifiers::default(),
+    };
+    assert_eq!(
+        client::send_and_recv(request, stream)?,
+        Some("on".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn transactions_commit_or_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // A key read missing conflicts with its creation
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key1".to_owned())?, None);
+    txn.set("key2".to_owned(), "value2".to_owned());
+    assert_eq!(txn.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::Conflict(key)) if key == "key1"));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Transfers between two keys keep their sum, retrying on conflicts
+    store.set("from".to_owned(), "100".to_owned())?;
+    store.set("to".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..10 {
+                    loop {
+                        let mut txn = store.transaction();
+                        let from: i64 = txn.get("from".to_owned())?.unwrap().parse()?;
+                        let to: i64 = txn.get("to".to_owned())?.unwrap().parse()?;
+                        txn.set("from".to_owned(), (from - 1).to_string());
+                        txn.set("to".to_owned(), (to + 1).to_string());
+                        match txn.commit() {
+                            Err(KvsError::Conflict(_)) => continue,
+                            result => break result?,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("from".to_owned())?, Some("60".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("40".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn open_flags_tell_new_stores_from_existing_ones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data");
+
+    assert!(matches!(
+        KvStore::builder().create_if_missing(false).open(&path),
+        Err(KvsError::StoreNotFound(_))
+    ));
+    assert!(!path.exists());
+
+    let store = KvStore::builder().error_if_exists(true).open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert!(matches!(
+        KvStore::builder().error_if_exists(true).open(&path),
+        Err(KvsError::StoreExists(_))
+    ));
+    let store = KvStore::builder()
+        .create_if_missing(false)
+        .repair(true)
+        .open(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn checkpoint_keeps_the_store_as_it_was() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(256)
+        .open(temp_dir.path().join("data"))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let checkpoint = temp_dir.path().join("checkpoint");
+    store.checkpoint(&checkpoint)?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.compact()?;
+    assert!(matches!(
+        store.checkpoint(&checkpoint),
+        Err(KvsError::StoreExists(_))
+    ));
+
+    let copy = KvStore::builder()
+        .create_if_missing(false)
+        .verify(Verify::Check)
+        .open(&checkpoint)?;
+    assert_eq!(copy.len()?, 99);
+    assert_eq!(copy.get("key0".to_owned())?, None);
+    assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(copy.get("key99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn export_imports_into_another_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value\n{}", i))?;
+    }
+
+    let mut dump = Vec::new();
+    assert_eq!(store.export(&mut dump)?, 50);
+    let mem = MemStore::open(temp_dir.path().join("mem"))?;
+    assert_eq!(mem.import(dump.as_slice())?, 50);
+    assert_eq!(mem.get("key7".to_owned())?, Some("value\n7".to_owned()));
+
+    // and back, the pairs are the same
+    let copy = KvStore::open(temp_dir.path().join("copy"))?;
+    let mut again = Vec::new();
+    mem.export(&mut again)?;
+    copy.import(again.as_slice())?;
+    let pairs: Vec<_> = copy.scan(..)?.collect::<Result<_>>()?;
+    let expected: Vec<_> = store.scan(..)?.collect::<Result<_>>()?;
+    assert_eq!(pairs, expected);
+
+    let newer = b"{\"dump_version\":99}\n";
+    assert!(matches!(
+        copy.import(&newer[..]),
+        Err(KvsError::IncompatibleStore(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn incr_updates_integers_atomically() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    store.incr("counter".to_owned(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    assert_eq!(store.incr("counter".to_owned(), -500)?, -100);
+
+    store.set("name".to_owned(), "kvs".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(KvsError::NotAnInteger(_))
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("max".to_owned(), 1),
+        Err(KvsError::IncrOverflow(_))
+    ));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("-100".to_owned()));
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    Ok(())
+}
+
+#[test]
+fn regions_keep_the_last_writer() -> Result<()> {
+    let east_dir = TempDir::new().expect("unable to create temporary working directory");
+    let west_dir = TempDir::new().expect("unable to create temporary working directory");
+    let east = KvStore::builder().region("east").open(east_dir.path())?;
+    let west = KvStore::builder().region("west").open(west_dir.path())?;
+    let east_feed = east.region_feed();
+    let west_feed = west.region_feed();
+
+    east.set("key1".to_owned(), "east".to_owned())?;
+    let change = east_feed.try_recv().unwrap();
+    assert_eq!(change.origin, "east");
+    assert!(west.apply_remote(change.clone())?);
+    assert_eq!(west.get("key1".to_owned())?, Some("east".to_owned()));
+    // keeps its region, so it is not sent back, and applying it again is a no-op
+    assert_eq!(west_feed.try_recv().unwrap(), change);
+    assert!(!west.apply_remote(change)?);
+
+    // an older write loses, even against a removal
+    west.set("key2".to_owned(), "west".to_owned())?;
+    west.remove("key1".to_owned())?;
+    for key in ["key1", "key2"] {
+        let stale = RegionChange {
+            origin: "east".to_owned(),
+            ts: 1,
+            change: Change::Set {
+                key: key.to_owned(),
+                value: "stale".to_owned(),
+            },
+        };
+        assert!(!west.apply_remote(stale)?);
+    }
+    assert_eq!(west.get("key1".to_owned())?, None);
+    assert_eq!(west.get("key2".to_owned())?, Some("west".to_owned()));
+    let conflicts = west.conflicts()?;
+    assert_eq!(conflicts.len(), 2);
+    assert_eq!(conflicts[1].change.origin, "east");
+    assert!(conflicts[1].local_ts > 1);
+
+    let newer = RegionChange {
+        origin: "east".to_owned(),
+        ts: u64::MAX / 2,
+        change: Change::Remove {
+            key: "key2".to_owned(),
+        },
+    };
+    assert!(west.apply_remote(newer)?);
+    assert_eq!(west.get("key2".to_owned())?, None);
+
+    // the peer copies the pairs of the server first, then follows its writes
+    let server = TestServer::start_with(KvStore::builder().region("north"))?;
+    server.store().set("key3".to_owned(), "north".to_owned())?;
+    let stream = server.connect()?;
+    let result = client::follow_region(0, "west".to_owned(), stream, |change| {
+        west.apply_remote(change)?;
+        Err(KvsError::StringError("stop".to_owned()))
+    });
+    assert!(matches!(result, Err(KvsError::StringError(_))));
+    assert_eq!(west.get("key3".to_owned())?, Some("north".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn durable_writes_share_syncs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .durable(Duration::from_millis(1))
+        .open(temp_dir.path())?;
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for key_id in 0..50 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key, "value".to_owned()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let sync = store.stats()?.sync.expect("the store is durable");
+    assert!(sync.syncs > 0);
+    assert!(sync.synced_writes <= 400);
+    assert_eq!(sync.queue_depth, 0);
+    assert!(sync.max_latency <= sync.total_latency);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key7-49".to_owned())?, Some("value".to_owned()));
+    assert!(store.stats()?.sync.is_none());
+    Ok(())
+}
+
+#[test]
+fn full_sync_batch_skips_the_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .durable(Duration::from_secs(60))
+        .sync_batch(1)
+        .open(temp_dir.path())?;
+    let start = Instant::now();
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert!(start.elapsed() < Duration::from_secs(30));
+
+    let sync = store.sync_stats().expect("the store is durable");
+    assert_eq!(sync.syncs, 3);
+    assert_eq!(sync.batch_sizes[0], 3);
+    assert_eq!(sync.batch_sizes.iter().sum::<u64>(), sync.syncs);
+    Ok(())
+}
+
+// Writes do not wait for a periodic sync, the thread syncs them meanwhile
+#[test]
+fn periodic_sync_runs_in_the_background() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .sync(SyncPolicy::Periodic(Duration::from_secs(60)))
+        .open(temp_dir.path())?;
+    let start = Instant::now();
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert!(start.elapsed() < Duration::from_secs(30));
+
+    // the first sync does not wait out the interval, the next one does
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.sync_stats().expect("the store syncs").syncs == 0 {
+        assert!(Instant::now() < deadline, "the thread never synced");
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100));
+    let sync = store.sync_stats().expect("the store syncs");
+    assert_eq!(sync.syncs, 1);
+    assert_eq!(sync.queue_depth, 0);
+    assert!(sync.synced_writes <= 100);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A write through one clone is readable through every other clone
+// as soon as `set` returns, even by a reader which buffered that log
+#[test]
+fn clones_read_latest_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer = KvStore::open(temp_dir.path())?;
+    let reader = writer.clone();
+    for key_id in 0..500 {
+        let key = format!("key{}", key_id);
+        writer.set(key.clone(), format!("value{}", key_id))?;
+        assert_eq!(reader.get(key)?, Some(format!("value{}", key_id)));
+        // read an older key too, so the buffer is reused between writes
+        let old = format!("key{}", key_id / 2);
+        assert_eq!(reader.get(old)?, Some(format!("value{}", key_id / 2)));
+    }
+    Ok(())
+}
+
+#[test]
+fn writer_lease_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.writer_stats().longest, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let writer = store.stats()?.writer;
+    assert_eq!(writer.holder, None);
+    let longest = writer.longest.expect("the writer was held");
+    assert_eq!(longest.op, "set");
+    assert!(!longest.thread.is_empty());
+    Ok(())
+}
+
+#[test]
+fn sharded_log_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let layout = LogLayout {
+        shards: 4,
+        name_width: 8,
+        ..LogLayout::default()
+    };
+    let store = KvStore::builder().layout(layout).open(temp_dir.path())?;
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+    let logs: Vec<_> = WalkDir::new(temp_dir.path().join("log"))
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.path().extension() == Some("log".as_ref()))
+        .collect();
+    assert!(!logs.is_empty());
+    for log in logs {
+        assert_eq!(log.depth(), 2);
+        assert_eq!(log.file_name().len(), "00000001.log".len());
+    }
+
+    // The manifest keeps the layout, and a foreign file is skipped
+    fs::write(temp_dir.path().join("log/000/00000004.log~"), "backup")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key42".to_owned())?, Some("19".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn builder_tunes_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .log_dir("data")
+        .active_log_size(256)
+        .compaction_threshold(0.3)
+        .read_cache(2)
+        .sync(SyncPolicy::Always)
+        .open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let stats = store.stats()?;
+    assert!(stats.segments > 3);
+    assert_eq!(stats.compaction_threshold, 0.3);
+    assert!(stats.sync.is_some());
+    assert_eq!(store.limits().max_open_segments, 2);
+    assert!(temp_dir.path().join("data/1.log").exists());
+    assert!(!temp_dir.path().join("log").exists());
+    drop(store);
+
+    // The manifest keeps the directory name
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// A small read cache keeps the logs read lately open, and still reads all
+#[test]
+fn read_cache_bounds_open_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(256)
+        .read_cache(2)
+        .open(temp_dir.path())?;
+    for key_id in 0..60 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(store.stats()?.segments > 4);
+    // a hot key between reads of every log, so it is never the one closed
+    for key_id in (0..60).rev() {
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn replica_follows_sealed_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::builder()
+        .active_log_size(256)
+        .open(temp_dir.path())?;
+    for key_id in 0..50 {
+        primary.set(format!("key{}", key_id), "first".to_owned())?;
+    }
+    let replica = KvReplica::open(temp_dir.path(), Duration::from_secs(3600))?;
+    assert_eq!(replica.get("key0".to_owned())?, Some("first".to_owned()));
+    assert!(matches!(
+        replica.set("key0".to_owned(), "x".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    // Compactions of the primary replace the logs the replica replayed
+    let events = primary.subscribe();
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            primary.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if iter == 10 {
+            replica.refresh()?;
+        }
+    }
+    primary.remove("key7".to_owned())?;
+    drop(primary);
+    assert!(
+        events
+            .try_iter()
+            .any(|e| matches!(e, EngineEvent::CompactionFinished { .. }))
+    );
+
+    assert!(replica.refresh()?);
+    assert!(!replica.refresh()?);
+    assert_eq!(replica.get("key7".to_owned())?, None);
+    assert_eq!(replica.get("key42".to_owned())?, Some("19".to_owned()));
+    assert_eq!(replica.count_prefix("key")?, 49);
+    Ok(())
+}
+
+#[test]
+fn value_envelope_is_kept() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_value_checksum(true);
+    let envelope = Envelope {
+        content_type: Some("application/json".to_owned()),
+        compressed: false,
+        checksum: None,
+    };
+    store.set_with_envelope("key1".to_owned(), "{}".to_owned(), envelope.clone())?;
+    store.set("key2".to_owned(), "plain".to_owned())?;
+
+    let (value, stored) = store.get_with_envelope("key1".to_owned())?.unwrap();
+    assert_eq!(value, "{}");
+    assert_eq!(stored.content_type, envelope.content_type);
+    assert_eq!(stored.checksum, Some(crc32fast::hash(b"{}")));
+    let meta = store.metadata("key2".to_owned())?.unwrap();
+    assert_eq!(meta.envelope.content_type, None);
+
+    // Kept through hints, compaction and a reopen
+    for iter in 0..100 {
+        store.set("key2".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let meta = store.metadata("key1".to_owned())?.unwrap();
+    assert_eq!(meta.envelope.content_type, envelope.content_type);
+    Ok(())
+}
+
+#[test]
+fn scan_range_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in (0..300).rev() {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+
+    let pairs = store
+        .scan("key100".to_owned().."key250".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 150);
+    assert_eq!(pairs[0], ("key100".to_owned(), "value100".to_owned()));
+    assert_eq!(pairs[149], ("key249".to_owned(), "value249".to_owned()));
+
+    // a key removed before the scan reaches it is skipped
+    let mut scan = store.scan(.."key010".to_owned())?;
+    assert_eq!(
+        scan.next().transpose()?.map(|(k, _)| k),
+        Some("key000".to_owned())
+    );
+    store.remove("key005".to_owned())?;
+    assert_eq!(scan.count(), 8);
+    assert_eq!(store.scan("key9".to_owned()..)?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn mem_store_snapshot_and_append_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MemStore::builder()
+        .snapshot_interval(Duration::from_secs(3600))
+        .append_log(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.save()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    // a crash keeps the snapshot and the append log, but no final snapshot
+    mem::forget(store);
+
+    let store = MemStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Without the append log only the last snapshot survives a crash
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.save()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    mem::forget(store);
+    let store = MemStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn keys_expire_after_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "ttl-short".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "ttl-long".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(
+        store.get("ttl-short".to_owned())?,
+        Some("value1".to_owned())
+    );
+    let ttl = store.metadata("ttl-long".to_owned())?.unwrap().ttl.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("ttl-short".to_owned())?, None);
+    assert_eq!(store.metadata("ttl-short".to_owned())?, None);
+    assert!(matches!(
+        store.remove("ttl-short".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // The expiry survives a restart
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("ttl-short".to_owned())?, None);
+    assert_eq!(store.get("ttl-long".to_owned())?, Some("value2".to_owned()));
+    assert!(
+        store
+            .metadata("ttl-long".to_owned())?
+            .unwrap()
+            .ttl
+            .is_some()
+    );
+
+    // Compaction reclaims expired keys
+    store.set_with_ttl(
+        "ttl-short".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(store.count_prefix("ttl-")?, 1);
+    Ok(())
+}
+
+#[test]
+fn conditional_updates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let below_10 = Condition::parse("value < 10")?;
+
+    // a missing key fails every comparison
+    assert!(!store.set_when("count".to_owned(), "1".to_owned(), &below_10)?);
+    let absent = Condition::parse("not exists or value < 10")?;
+    assert!(store.set_when("count".to_owned(), "9".to_owned(), &absent)?);
+    // compared as numbers, "9" < "10" would be false as strings
+    assert!(store.set_when("count".to_owned(), "10".to_owned(), &below_10)?);
+    assert!(!store.set_when("count".to_owned(), "11".to_owned(), &below_10)?);
+    assert_eq!(store.get("count".to_owned())?, Some("10".to_owned()));
+
+    let state = Condition::parse(r#"(value == "in progress" or value == new) and exists"#)?;
+    store.set("job".to_owned(), "in progress".to_owned())?;
+    assert!(store.set_when("job".to_owned(), "done".to_owned(), &state)?);
+    assert!(!store.set_when("job".to_owned(), "new".to_owned(), &state)?);
+
+    for expr in [
+        "value",
+        "value < ",
+        "exists and",
+        "(exists",
+        "value = 1",
+        "exists exists",
+    ] {
+        assert!(matches!(
+            Condition::parse(expr),
+            Err(KvsError::InvalidCondition(_))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn write_batch_all_or_none() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("a".to_owned(), "1".to_owned())
+        .set("b".to_owned(), "2".to_owned())
+        .remove("b".to_owned())
+        .remove("c".to_owned())
+        .remove("missing".to_owned());
+    store.apply_batch(batch)?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, None);
+    drop(store);
+
+    // A crash in the middle of a batch leaves its head and a torn record,
+    // cut here from the same batch written by another store
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("x".to_owned(), "1".to_owned())
+        .set("y".to_owned(), "2".to_owned())
+        .set("w".to_owned(), "3".to_owned());
+    other.apply_batch(batch)?;
+    drop(other);
+    let written = fs::read(other_dir.path().join("log/1.log"))?;
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("log/1.log"))?;
+    log.write_all(&written[1..written.len() - 3])?;
+    drop(log);
+
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("x".to_owned())?, None);
+        assert_eq!(store.get("y".to_owned())?, None);
+        assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+        store.set("z".to_owned(), "26".to_owned())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn buckets_keep_keys_apart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.bucket("users")?;
+    let teams = store.bucket("teams")?;
+    store.set("jack".to_owned(), "root".to_owned())?;
+    users.set("jack".to_owned(), "1".to_owned())?;
+    users.set("jill".to_owned(), "2".to_owned())?;
+    users.set("*rose".to_owned(), "3".to_owned())?;
+    teams.set("jack".to_owned(), "ops".to_owned())?;
+
+    assert_eq!(users.len()?, 3);
+    assert_eq!(users.get("jack".to_owned())?, Some("1".to_owned()));
+    assert_eq!(teams.get("jill".to_owned())?, None);
+    assert_eq!(
+        users.keys(&Pattern::new("j*"))?,
+        vec!["jack".to_owned(), "jill".to_owned()]
+    );
+    assert_eq!(users.keys(&Pattern::new("\\**"))?, vec!["*rose".to_owned()]);
+    assert_eq!(
+        users.scan_prefix("ji")?,
+        vec![("jill".to_owned(), "2".to_owned())]
+    );
+    assert_eq!(users.remove_range("j".to_owned()..)?, 2);
+    assert_eq!(users.len()?, 1);
+    drop((users, teams, store));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("jack".to_owned())?, Some("root".to_owned()));
+    assert_eq!(
+        store.bucket("teams")?.get("jack".to_owned())?,
+        Some("ops".to_owned())
+    );
+    assert_eq!(store.bucket("users")?.len()?, 1);
+    assert!(matches!(store.bucket(""), Err(KvsError::InvalidBucket(_))));
+    assert!(store.bucket("a\0b").is_err());
+    Ok(())
+}
+
+#[test]
+fn versions_are_kept_up_to_the_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .keep_versions(2)
+        .active_log_size(256)
+        .open(temp_dir.path())?;
+    for value in ["a", "b", "c", "d"] {
+        store.set("key".to_owned(), value.to_owned())?;
+    }
+    store.set("key\0x".to_owned(), "other".to_owned())?;
+    store.set("key\0x".to_owned(), "other2".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.versions("key")?, vec![2, 3, 4]);
+        assert_eq!(store.get_at("key".to_owned(), 1)?, None);
+        assert_eq!(store.get_at("key".to_owned(), 2)?, Some("b".to_owned()));
+        assert_eq!(store.get_at("key".to_owned(), 3)?, Some("c".to_owned()));
+        assert_eq!(store.get_at("key".to_owned(), 4)?, Some("d".to_owned()));
+        assert_eq!(store.get("key".to_owned())?, Some("d".to_owned()));
+        assert_eq!(store.versions("missing")?, Vec::<u64>::new());
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    // without the option only the current value is there
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "a".to_owned())?;
+    store.set("key".to_owned(), "b".to_owned())?;
+    assert_eq!(store.versions("key")?, vec![1]);
+    assert_eq!(store.get_at("key".to_owned(), 1)?, Some("b".to_owned()));
+    Ok(())
+}
+
+// Every overwrite keeps a version, and the versions stay out of listings
+#[test]
+fn history_is_kept_apart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().keep_versions(8).open(temp_dir.path())?;
+    let watched = store.watch("");
+    store.set("key".to_owned(), "0".to_owned())?;
+    store.set("key".to_owned(), "1".to_owned())?;
+    store.insert("key".to_owned(), "2".to_owned())?;
+    assert!(store.set_if_present("key".to_owned(), "3".to_owned())?);
+    assert!(store.set_when(
+        "key".to_owned(),
+        "4".to_owned(),
+        &Condition::parse("value < 10")?
+    )?);
+    store.incr("key".to_owned(), 1)?;
+    store.set_with_ttl("key".to_owned(), "6".to_owned(), Duration::from_secs(60))?;
+    store.set_with_envelope("key".to_owned(), "7".to_owned(), Envelope::default())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key".to_owned(), "8".to_owned());
+    store.apply_batch(batch)?;
+    store.set("other".to_owned(), "9".to_owned())?;
+    store.rename("other".to_owned(), "key".to_owned())?;
+    let mut txn = store.transaction();
+    txn.set("key".to_owned(), "10".to_owned());
+    txn.commit()?;
+    assert_eq!(store.versions("key")?, (3..=11).collect::<Vec<_>>());
+    assert_eq!(store.get_at("key".to_owned(), 6)?, Some("5".to_owned()));
+    assert_eq!(store.get_at("key".to_owned(), 10)?, Some("9".to_owned()));
+
+    // none of the versions is watched
+    store.set("end".to_owned(), String::new())?;
+    let events: Vec<_> = watched.iter().take(14).collect();
+    assert!(events.iter().all(|e| match e {
+        ChangeEvent::Set { key, .. } | ChangeEvent::Rm { key } => !key.starts_with('\u{10FFFF}'),
+        _ => false,
+    }));
+    assert!(matches!(&events[13], ChangeEvent::Set { key, .. } if key == "end"));
+    store.remove("end".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.len()?, 1);
+        assert_eq!(store.count_prefix("")?, 1);
+        assert_eq!(store.keys(&Pattern::new("*"))?, vec!["key".to_owned()]);
+        assert_eq!(store.scan(..)?.count(), 1);
+        assert_eq!(store.scan_page(None, 100, None)?.entries.len(), 1);
+        assert_eq!(store.snapshot()?.len()?, 1);
+        let mut dump = Vec::new();
+        assert_eq!(store.export(&mut dump)?, 1);
+        assert_eq!(store.stats()?.reserved_keys, 8);
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+
+    // a user key may not look like one of them
+    let reserved = "\u{10FFFF}\u{0}key".to_owned();
+    assert!(matches!(
+        store.set(reserved.clone(), "x".to_owned()),
+        Err(KvsError::ReservedKey(_))
+    ));
+    let mut batch = WriteBatch::new();
+    batch.remove(reserved.clone());
+    assert!(matches!(
+        store.apply_batch(batch),
+        Err(KvsError::ReservedKey(_))
+    ));
+    assert!(store.remove_prefix("\u{10FFFF}").is_err());
+    assert!(store.remove_range(reserved..).is_err());
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+#[test]
+fn rename_moves_the_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+
+    store.rename("a".to_owned(), "c".to_owned())?;
+    store.rename("c".to_owned(), "b".to_owned())?;
+    store.rename("b".to_owned(), "b".to_owned())?;
+    assert!(matches!(
+        store.rename("a".to_owned(), "d".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, None);
+    assert_eq!(store.get("b".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+#[test]
+fn expired_keys_are_published() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = store.subscribe();
+    let expired = |events: &Receiver<EngineEvent>| -> Vec<EngineEvent> {
+        events
+            .try_iter()
+            .filter(|e| matches!(e, EngineEvent::Expired { .. }))
+            .collect()
+    };
+    for key in ["session:1", "session:2"] {
+        store.set_with_ttl(key.to_owned(), "user".to_owned(), Duration::from_millis(50))?;
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    // Dropped by the first read, once
+    assert_eq!(store.get("session:1".to_owned())?, None);
+    assert_eq!(store.get("session:1".to_owned())?, None);
+    assert_eq!(
+        expired(&events),
+        vec![EngineEvent::Expired {
+            key: "session:1".to_owned()
+        }]
+    );
+
+    // The other one by compaction
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert_eq!(
+        expired(&events),
+        vec![EngineEvent::Expired {
+            key: "session:2".to_owned()
+        }]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_server_end_to_end() -> Result<()> {
+    let server = TestServer::start()?;
+    let mut client = server.client();
+    client.call(|stream| {
+        let request = Request::Set {
+            key: "jack".to_owned(),
+            value: "2024".to_owned(),
+        };
+        client::send_and_recv(request, stream)
+    })?;
+    assert_eq!(
+        server.store().get("jack".to_owned())?,
+        Some("2024".to_owned())
+    );
+
+    // A retried write with the same token is applied once
+    server.store().set("count".to_owned(), "1".to_owned())?;
+    let increment = || Request::SetWhen {
+        key: "count".to_owned(),
+        value: "2".to_owned(),
+        condition: "value == 1".to_owned(),
+    };
+    for _ in 0..2 {
+        let request = increment().with_token("retry-1".to_owned());
+        assert!(client::send_and_recv_flag(request, server.connect()?)?);
+    }
+    assert!(!client::send_and_recv_flag(increment(), server.connect()?)?);
+
+    // A retry runs again once the first attempt failed without an answer
+    let store = server.store();
+    assert_eq!(store.claim_token("retry-2")?, None);
+    assert!(matches!(
+        store.claim_token("retry-2"),
+        Err(KvsError::TokenInFlight(_))
+    ));
+    store.release_token("retry-2");
+    store.set("count".to_owned(), "1".to_owned())?;
+    let request = increment().with_token("retry-2".to_owned());
+    assert!(client::send_and_recv_flag(request, server.connect()?)?);
+    assert_eq!(store.get("count".to_owned())?, Some("2".to_owned()));
+
+    // A retry while the first run is not over gets an error line back
+    assert_eq!(store.claim_token("retry-3")?, None);
+    let stream = server.connect()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request = increment().with_token("retry-3".to_owned());
+    let err = client::send_and_recv_flag(request, stream).unwrap_err();
+    assert!(err.to_string().contains("still running"));
+    store.release_token("retry-3");
+
+    // So does a request which may not be wrapped
+    let stream = server.connect()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let request = Request::Promote.with_token("retry-4".to_owned());
+    let err = client::send_and_recv(request, stream).unwrap_err();
+    assert_eq!(err.to_string(), KvsError::UnexpectedType.to_string());
+    Ok(())
+}
+
+#[test]
+fn hang_ups_are_io_errors() -> Result<()> {
+    // a server which drops the connection without an answer
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let handle = thread::spawn(move || drop(listener.accept()));
+    let request = Request::Get {
+        key: "key".to_owned(),
+        modifiers: Default::default(),
+    };
+    let result = client::send_and_recv(request, TcpStream::connect(addr)?);
+    handle.join().unwrap();
+    assert!(matches!(result, Err(KvsError::IoError(_))));
+
+    // a client which hangs up before its answer leaves the server serving
+    let server = TestServer::start()?;
+    server.store().set("key".to_owned(), "x".repeat(1 << 20))?;
+    for _ in 0..8 {
+        let mut stream = server.connect()?;
+        let line = serde_json::to_string(&Request::ScanPrefix {
+            prefix: String::new(),
+        })?;
+        stream.write_all(format!("{}\n", line).as_bytes())?;
+    }
+    let request = Request::Get {
+        key: "other".to_owned(),
+        modifiers: Default::default(),
+    };
+    assert_eq!(client::send_and_recv(request, server.connect()?)?, None);
+    Ok(())
+}
+
+#[test]
+fn get_many_in_request_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // values spread over several logs
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..200).step_by(7) {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    store.remove("key3".to_owned())?;
+
+    let keys: Vec<String> = [150, 3, 7, 0, 199, 7, 500]
+        .iter()
+        .map(|id| format!("key{}", id))
+        .collect();
+    let expected = keys
+        .iter()
+        .map(|key| store.get(key.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(store.get_many(&keys)?, expected);
+    assert_eq!(expected[2], Some("new7".to_owned()));
+    assert_eq!(expected[1], None);
+    assert_eq!(store.get_many(&[])?, Vec::<Option<String>>::new());
+    Ok(())
+}
+
+#[test]
+fn replication_over_simulated_network() -> Result<()> {
+    let net = SimNetwork::new(42);
+    net.set_latency(Duration::from_millis(5));
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(primary_dir.path())?;
+    let listener = net.node("primary").bind("primary:4000")?;
+    let databases = vec![primary.clone()];
+    thread::spawn(move || server::serve(listener, databases));
+
+    let mut app = KvsClient::with_transport(vec!["primary:4000".to_owned()], net.node("app"));
+    let set = |key: &str| Request::Set {
+        key: key.to_owned(),
+        value: "v".to_owned(),
+    };
+    app.call(|stream| client::send_and_recv(set("a"), stream))?;
+
+    // The replica copies the store, then follows every change
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica = KvStore::builder().standby(true).open(replica_dir.path())?;
+    let (applied, changes) = std::sync::mpsc::channel();
+    let follower = {
+        let replica = replica.clone();
+        let stream = net.node("replica").connect("primary:4000")?;
+        thread::spawn(move || {
+            client::follow(0, stream, |change| {
+                replica.apply(change)?;
+                applied.send(()).unwrap();
+                Ok(())
+            })
+        })
+    };
+    changes.recv_timeout(Duration::from_secs(1)).unwrap();
+    app.call(|stream| client::send_and_recv(set("b"), stream))?;
+    changes.recv_timeout(Duration::from_secs(1)).unwrap();
+
+    // A partition cuts the replica off, the primary still serves the app
+    net.partition("replica", "primary");
+    assert!(follower.join().unwrap().is_err());
+    assert!(net.node("replica").connect("primary:4000").is_err());
+    app.call(|stream| client::send_and_recv(set("c"), stream))?;
+    replica.promote();
+    assert_eq!(replica.get("b".to_owned())?, Some("v".to_owned()));
+    assert_eq!(replica.get("c".to_owned())?, None);
+
+    net.set_drop_rate(1.0);
+    assert!(
+        app.call(|stream| client::send_and_recv(set("d"), stream))
+            .is_err()
+    );
+    assert_eq!(primary.get("d".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn limits_are_enforced() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limits = Limits {
+        max_key: 8,
+        max_value: 16,
+        max_batch: 2,
+        max_open_segments: 1,
+        max_connections: 1,
+        flush_all: false,
+    };
+    let store = KvStore::builder().limits(limits).open(temp_dir.path())?;
+    assert!(matches!(
+        store.set("k".repeat(9), "v".to_owned()),
+        Err(KvsError::KeyTooLarge { len: 9, max: 8 })
+    ));
+    assert!(matches!(
+        store.set("k".to_owned(), "v".repeat(17)),
+        Err(KvsError::ValueTooLarge { len: 17, max: 16 })
+    ));
+    let mut batch = WriteBatch::new();
+    batch
+        .set("a".to_owned(), "1".to_owned())
+        .set("b".to_owned(), "2".to_owned())
+        .remove("c".to_owned());
+    assert!(matches!(
+        store.apply_batch(batch),
+        Err(KvsError::BatchTooLarge { len: 3, max: 2 })
+    ));
+    assert_eq!(store.get("a".to_owned())?, None);
+
+    // Values spread over several logs, read with one log open at a time
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..200).rev() {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // The server refuses a second connection and an oversized key
+    let net = SimNetwork::new(1);
+    let listener = net.node("server").bind("server:4000")?;
+    let databases = vec![store.clone()];
+    thread::spawn(move || server::serve(listener, databases));
+    let app = net.node("app");
+    let held = client::probe_with(&app, "server:4000")?;
+    assert!(client::probe_with(&app, "server:4000").is_err());
+    drop(held);
+    // the slot is free once the server sees the hang-up
+    let mut stream = client::probe_with(&app, "server:4000");
+    for _ in 0..100 {
+        if stream.is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        stream = client::probe_with(&app, "server:4000");
+    }
+    let request = Request::Get {
+        key: "k".repeat(9),
+        modifiers: Default::default(),
+    };
+    assert!(request.check(&limits).is_err());
+    assert!(client::send_and_recv(request, stream?).is_err());
+    let request = Request::RmPrefix {
+        prefix: "k".repeat(9),
+    };
+    assert!(matches!(
+        request.check(&limits),
+        Err(KvsError::KeyTooLarge { len: 9, max: 8 })
+    ));
+    Ok(())
+}
+
+#[test]
+fn record_checksum() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("log/1.log");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let offset = fs::metadata(&log)?.len() as usize;
+    store.remove("key1".to_owned())?;
+    let end = fs::metadata(&log)?.len() as usize;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // A flipped byte in the middle of a log fails the replay, here the last
+    // byte of the key the removal names
+    let mut content = fs::read(&log)?;
+    let mut flipped = content.clone();
+    flipped[end - 1] ^= 1;
+    fs::write(&log, &flipped)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::CorruptRecord { segment: 1, offset: o }) if o == offset
+    ));
+
+    // A last record which does not match its checksum is a torn write
+    content.extend_from_slice(&flipped[offset..end]);
+    fs::write(&log, content)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Logs of format version 2 and before hold JSON lines, they are read as they
+// are until a compaction rewrites them
+#[test]
+fn legacy_json_logs_are_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("log/1.log");
+    fs::create_dir_all(temp_dir.path().join("log"))?;
+    fs::write(
+        &log,
+        concat!(
+            "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n",
+            "{\"Set\":{\"key\":\"key2\",\"value\":\"value2\",\"ts\":1}}\n",
+            "{\"Rm\":{\"key\":\"key2\"}}\n",
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert!(!log.exists());
+    drop(store);
+
+    // every log left starts with the format byte
+    for entry in fs::read_dir(temp_dir.path().join("log"))? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            assert_eq!(fs::read(&path)?.first(), Some(&3));
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn recovery_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let reports: Arc<Mutex<Vec<RecoveryProgress>>> = Arc::default();
+    let store = {
+        let reports = Arc::clone(&reports);
+        KvStore::builder()
+            .on_recovery(move |p| reports.lock().unwrap().push(p.clone()))
+            .open(temp_dir.path())?
+    };
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() > 1);
+    for (i, p) in reports.iter().enumerate() {
+        assert_eq!(p.segments_done, i + 1);
+        assert_eq!(p.segments_total, reports.len());
+    }
+    let last = reports.last().unwrap();
+    assert_eq!(last.keys_indexed, 200);
+    assert_eq!(last.eta, Some(Duration::ZERO));
+    assert_eq!(store.get("key7".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn torn_tail_is_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // The process died in the middle of an append, of a record like the first
+    let log = temp_dir.path().join("log/1.log");
+    let content = fs::read(&log)?;
+    let len = content.len() as u64;
+    let mut file = fs::OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&content[1..content.len() - 3])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sealed_logs_have_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let hint = temp_dir.path().join("log/1.hint");
+    assert!(hint.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    // Hints which do not describe the log are ignored
+    fs::write(&hint, "0\n")?;
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn unfinished_compaction_is_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // The process died while compacting into log 2
+    let tmp = temp_dir.path().join("log/2.log.tmp");
+    fs::write(&tmp, "{\"Set\":{\"key\":\"key1\",\"val")?;
+    let mut manifest = Manifest::load(temp_dir.path())?.unwrap();
+    manifest.compacting = Some(2);
+    manifest.store(temp_dir.path())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!tmp.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The manifest names the logs, other files among them are not read
+#[test]
+fn open_reads_the_logs_named_by_the_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let manifest = Manifest::load(temp_dir.path())?.unwrap();
+    assert_eq!(manifest.sealed, vec![1]);
+    assert_eq!(manifest.active, Some(1));
+    fs::write(temp_dir.path().join("log/7.log"), "not a log")?;
+    fs::write(temp_dir.path().join("log/notes.txt"), "not a log either")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let manifest = Manifest::load(temp_dir.path())?.unwrap();
+    assert_eq!(manifest.sealed, vec![1, 2]);
+    assert_eq!(
+        manifest.sizes[&1],
+        fs::metadata(temp_dir.path().join("log/1.log"))?.len()
+    );
+    Ok(())
+}
+
+// A log a finished compaction replaced is deleted on open, its values are stale
+#[test]
+fn replaced_logs_are_deleted_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "stale".to_owned())?;
+    drop(store);
+    let first = temp_dir.path().join("log/1.log");
+    let stale = fs::read(&first)?;
+
+    // The removal hides the stale value, both logs are merged away
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    for iter in 0..200 {
+        store.set(format!("key{}", iter % 10 + 2), format!("{}", iter))?;
+    }
+    drop(store);
+    assert!(!first.exists());
+
+    // The process died after the manifest recorded the compaction, before
+    // the old logs were deleted
+    fs::write(&first, stale)?;
+    let mut manifest = Manifest::load(temp_dir.path())?.unwrap();
+    manifest.obsolete = vec![1];
+    manifest.store(temp_dir.path())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!first.exists());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Writes go on while a slow compaction runs, and win over its older copies
+#[test]
+fn writes_during_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_rate(Some(20 * 1024));
+    let events = store.subscribe();
+
+    let mut iter = 0;
+    let mut compacting = false;
+    let mut sealed_meanwhile = 0;
+    'outer: loop {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+        for event in events.try_iter() {
+            match event {
+                EngineEvent::CompactionStarted { .. } => compacting = true,
+                EngineEvent::SegmentSealed { .. } if compacting => sealed_meanwhile += 1,
+                EngineEvent::CompactionFinished { .. } => break 'outer,
+                _ => {}
+            }
+        }
+    }
+    assert!(sealed_meanwhile > 0);
+
+    for _ in 0..2 {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}", iter - 1))
+            );
+        }
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}", iter - 1))
+        );
+    }
+    Ok(())
+}
+
+// Only the logs which turned mostly into garbage are compacted
+#[test]
+fn compaction_picks_logs_by_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.compaction_threshold, 0.5);
+    let events = store.subscribe();
+
+    // Distinct keys, every sealed log stays live
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert!(store.stats()?.segments > 2);
+    assert!(
+        !events
+            .try_iter()
+            .any(|e| matches!(e, EngineEvent::CompactionStarted { .. }))
+    );
+
+    // Overwrite most keys of the first log only
+    let mut iter = 0;
+    while temp_dir.path().join("log/1.log").exists() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..15 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        iter += 1;
+    }
+    assert!(temp_dir.path().join("log/2.log").exists());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        let expected = match key_id {
+            0..15 => format!("{}", iter - 1),
+            _ => "value".to_owned(),
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(expected));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_reads_follow_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(256)
+        .open(temp_dir.path())?;
+    for iter in 0..3 {
+        for key_id in 0..30 {
+            store.set(format!("key{}", key_id), format!("{}-{}", key_id, iter))?;
+        }
+        // sealed logs are mapped, the active one is read as before
+        for key_id in 0..30 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("{}-{}", key_id, iter))
+            );
+        }
+    }
+    store.compact()?;
+    for key_id in 0..30 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-2", key_id))
+        );
+    }
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn latency_histograms() -> Result<()> {
+    use kvs::engine::kvs::Histogram;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.get(format!("key{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    let latency = store.stats()?.latency;
+    assert_eq!(latency.set.count(), 10);
+    assert_eq!(latency.get.count(), 10);
+    assert_eq!(latency.remove.count(), 1);
+    assert!(latency.set.percentile(0.5) <= latency.set.max());
+
+    // Percentiles are within 1/16 of the recorded values
+    let mut histogram = Histogram::default();
+    for micros in 1..=1000 {
+        histogram.record(Duration::from_micros(micros));
+    }
+    assert_eq!(histogram.percentile(0.0), Duration::from_micros(1));
+    let p50 = histogram.percentile(0.5).as_micros();
+    assert!((500..=500 + 500 / 16).contains(&p50), "p50 is {}", p50);
+    assert_eq!(histogram.percentile(1.0), Duration::from_micros(1000));
+    assert_eq!(histogram.mean(), Duration::from_micros(500));
+    Ok(())
+}
+
+#[test]
+fn memory_budget_is_shared() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let budget = MemoryBudget::new(64 * 1024);
+    let first = KvStore::builder()
+        .memory_budget(&budget)
+        .open(temp_dir.path().join("first"))?;
+    let second = KvStore::builder()
+        .memory_budget(&budget)
+        .open(temp_dir.path().join("second"))?;
+    assert!(!second.stats()?.memory_pressure);
+
+    // The index of one store is enough to exceed the budget of both
+    for key_id in 0..1000 {
+        first.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let stats = second.stats()?;
+    assert!(stats.memory_pressure);
+    assert!(budget.usage() > budget.limit());
+    assert!(first.stats()?.memory_usage > stats.memory_usage);
+    // Reads still work with the caches shrunk
+    for key_id in (0..1000).step_by(97) {
+        assert_eq!(
+            first.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    // purged keys leave the index at the next compaction
+    first.remove_prefix("key")?;
+    first.compact()?;
+    assert!(!second.stats()?.memory_pressure);
+    Ok(())
+}
+
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(256)
+        .open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "first".to_owned())?;
+    }
+    let snapshot: KvSnapshot = store.snapshot()?;
+    let events = store.subscribe();
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key7".to_owned())?;
+    assert!(
+        events
+            .try_iter()
+            .any(|e| matches!(e, EngineEvent::CompactionFinished { .. }))
+    );
+
+    assert_eq!(snapshot.get("key7".to_owned())?, Some("first".to_owned()));
+    assert_eq!(snapshot.count_prefix("key")?, 50);
+    let pairs = snapshot.scan_prefix("key")?;
+    assert_eq!(pairs.len(), 50);
+    assert!(pairs.iter().all(|(_, value)| value == "first"));
+    assert!(matches!(
+        snapshot.set("key1".to_owned(), "x".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    // The replaced logs go away with the last snapshot reading them
+    let logs = || {
+        fs::read_dir(temp_dir.path().join("log"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    let pinned = logs();
+    drop(snapshot);
+    assert!(logs() < pinned);
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.get("key42".to_owned())?, Some("19".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn compacted_logs_are_compressed() -> Result<()> {
+    let value = |key_id: usize| format!("{}{}", "compressible ".repeat(100), key_id);
+    let mut disk_bytes = Vec::new();
+    for compression in [Compression::None, Compression::Zstd(0)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .compression(compression)
+            .open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), value(key_id))?;
+        }
+        store.compact()?;
+        disk_bytes.push(store.stats()?.disk_bytes);
+        assert_eq!(store.get("key42".to_owned())?, Some(value(42)));
+        drop(store);
+
+        // the codec is read from the log, not from the options
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id)));
+        }
+    }
+    assert!(disk_bytes[1] * 4 < disk_bytes[0]);
+    Ok(())
+}
+
+#[test]
+fn write_growth_survives_restarts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(256)
+        .open(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.compact()?;
+    let growth = store.stats()?.growth;
+    assert_eq!(growth.last_hour.writes, 100);
+    assert_eq!(growth.last_day, growth.last_hour);
+    assert!(growth.last_hour.bytes_reclaimed > 0);
+    assert!(growth.last_hour.bytes_written > growth.last_hour.bytes_reclaimed);
+    assert!(growth.full_in.is_some());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.growth.last_hour, growth.last_hour);
+    store.set("key0".to_owned(), "5".to_owned())?;
+    assert_eq!(store.stats()?.growth.last_hour.writes, 101);
+    Ok(())
+}
+
+#[test]
+fn manual_compaction_merges_every_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(256)
+        .compaction_threshold(0.0)
+        .open(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key3".to_owned())?;
+    let stats = store.stats()?;
+    assert!(stats.segments > 1);
+    assert!(stats.dead_bytes > 0);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.segments, 1);
+    assert_eq!(stats.dead_bytes, 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key7".to_owned())?, Some("4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn clear_empties_the_store_and_its_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .active_log_size(256)
+        .open(temp_dir.path())?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(store.clear()?, 50);
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 0);
+    assert_eq!(stats.disk_bytes, 0);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn value_cache_serves_hot_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().value_cache(64).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stats()?.cache_hits, 1);
+
+    // a write makes the cached value stale
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // values larger than the cache are read from disk every time
+    store.set("key2".to_owned(), "v".repeat(100))?;
+    store.get("key2".to_owned())?;
+    store.get("key2".to_owned())?;
+    assert_eq!(store.stats()?.cache_hits, 1);
+    Ok(())
+}
+
+// A miss is answered by the index, the value cache and the logs are not read
+#[test]
+fn misses_skip_the_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().value_cache(1024).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the logs are gone, only the index may answer
+    fs::remove_dir_all(temp_dir.path().join("log"))?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("missing{}", key_id))?, None);
+    }
+    let keys = ["missing0".to_owned(), "missing1".to_owned()];
+    assert_eq!(store.get_many(&keys)?, vec![None, None]);
+    let stats = store.stats()?;
+    assert_eq!(stats.index_misses, 102);
+    assert_eq!(stats.false_positives, 0);
+    assert_eq!(stats.cache_hits, 0);
+    Ok(())
+}
+
+#[test]
+fn concurrent_gets_share_one_read() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .coalesce_reads(true)
+        .open(temp_dir.path())?;
+    let value = "v".repeat(4 << 20);
+    store.set("hot".to_owned(), value.clone())?;
+
+    // the gets only overlap by chance, retry a few bursts
+    for _ in 0..20 {
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.get("hot".to_owned())
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap()?, Some(value.clone()));
+        }
+        if store.stats()?.coalesced_reads > 0 {
+            break;
+        }
+    }
+    assert!(store.stats()?.coalesced_reads > 0);
+
+    // a get after a write never joins a read of the old value
+    store.set("hot".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Gets of different keys in one log are served by one sweep, each with its own value
+#[test]
+fn concurrent_gets_merge_into_one_sweep() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // one log holds every key
+    let store = KvStore::builder()
+        .active_log_size(16 << 20)
+        .open(temp_dir.path())?;
+    let value = |i: usize| format!("{}{}", i, "v".repeat(64 << 10));
+    for i in 0..16 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+
+    // the gets only overlap by chance, retry a few bursts
+    for _ in 0..50 {
+        let barrier = Arc::new(Barrier::new(16));
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                let store = store.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    store.get(format!("key{}", i))
+                })
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().unwrap()?, Some(value(i)));
+        }
+        if store.stats()?.merged_reads > 0 {
+            break;
+        }
+    }
+    assert!(store.stats()?.merged_reads > 0);
+    Ok(())
+}
+
+#[test]
+fn operations_are_cancelled_cooperatively() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let op = store.start_operation("export");
+    let ops = store.operations();
+    assert_eq!(ops.len(), 1);
+    assert_eq!(ops[0].id, op.id());
+    assert_eq!(ops[0].op, "export");
+    assert!(!ops[0].cancelled);
+    op.check()?;
+
+    assert!(store.cancel_operation(op.id()));
+    assert!(store.operations()[0].cancelled);
+    assert!(matches!(op.check(), Err(KvsError::Cancelled(id)) if id == op.id()));
+
+    // gone once the operation stops, e.g. at its next check
+    let id = op.id();
+    drop(op);
+    assert!(store.operations().is_empty());
+    assert!(!store.cancel_operation(id));
+    Ok(())
+}
+
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.last_compaction, None);
+    assert!(stats.dead_bytes > 0);
+    assert!(stats.disk_bytes > stats.dead_bytes);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.dead_bytes, 0);
+    assert!(stats.last_compaction.is_some());
+    Ok(())
+}
+
+#[test]
+fn estimate_prefix_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(100);
+    for key_id in 0..3000 {
+        store.set(format!("user:{:04}", key_id), value.clone())?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("team:{}", key_id), value.clone())?;
+    }
+
+    let team = store.estimate_size("team:")?;
+    assert_eq!(team.keys, 10);
+    assert_eq!(team.samples, 10);
+    let record = team.bytes / 10;
+    assert!(record > 100);
+
+    // every record of the prefix has the same length, so sampling is exact
+    let user = store.estimate_size("user:")?;
+    assert_eq!(user.keys, 3000);
+    assert!(user.samples <= 1024);
+    assert!(user.samples < user.keys);
+    assert_eq!(user.bytes, 3000 * (record + 3));
+
+    assert_eq!(store.estimate_size("nobody:")?.bytes, 0);
+    Ok(())
+}
+
+#[test]
+fn authenticators() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // the base64 SHA-256 of `hunter2`
+    let passwords = temp_dir.path().join("passwords");
+    fs::write(
+        &passwords,
+        "alice:{SHA256}9S+9MrKzuG/4jvbEkGKChfSCrxXdyylUH5S89Saj9sc=\n",
+    )?;
+    let file = auth::from_spec(&format!("htpasswd:{}", passwords.display()))?;
+    assert!(file.authenticate(Some("alice"), "hunter2")?);
+    assert!(!file.authenticate(Some("alice"), "hunter3")?);
+    assert!(!file.authenticate(Some("bob"), "hunter2")?);
+    assert!(!file.authenticate(None, "hunter2")?);
+
+    let hook =
+        auth::from_spec(r#"command:[ "$KVS_AUTH_USER" = alice ] && [ "$(cat)" = hunter2 ]"#)?;
+    assert!(hook.authenticate(Some("alice"), "hunter2")?);
+    assert!(!hook.authenticate(Some("alice"), "hunter3")?);
+
+    assert!(auth::from_spec("ldap:somewhere").is_err());
+    Ok(())
+}
+
+#[test]
+fn validators() -> Result<()> {
+    let mut validators = Validators::from_specs(&["counters/=integer".to_owned()])?;
+    validators.add(
+        "config/",
+        Validator::Schema(Schema::new(serde_json::json!({
+            "type": "object",
+            "required": ["hosts"],
+            "properties": {
+                "hosts": {"type": "array", "items": {"type": "string"}},
+                "mode": {"enum": ["active", "standby"]},
+            },
+        }))),
+    );
+    validators.check("counters/visits", "42")?;
+    validators.check("config/db", r#"{"hosts": ["a", "b"], "mode": "active"}"#)?;
+    validators.check("other", "anything")?;
+
+    let reason = |key: &str, value: &str| match validators.check(key, value) {
+        Err(KvsError::InvalidValue { reason, .. }) => reason,
+        other => panic!("expect an invalid value, got {:?}", other),
+    };
+    assert_eq!(reason("counters/visits", "many"), "not an integer");
+    assert_eq!(reason("config/db", "{}"), "value misses the field hosts");
+    assert_eq!(
+        reason("config/db", r#"{"hosts": ["a", 1]}"#),
+        "/hosts/1 is not of type string"
+    );
+    assert_eq!(
+        reason("config/db", r#"{"hosts": [], "mode": "off"}"#),
+        "/mode is not one of the allowed values"
+    );
+    assert!(reason("config/db", "{").starts_with("not JSON"));
+
+    assert!(Validators::from_specs(&["config/=xml".to_owned()]).is_err());
+    Ok(())
+}
+
+fn check_introspection<E: KvsEngine>(store: E) -> Result<()> {
+    assert!(store.is_empty()?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.len()?, 2);
+    assert!(!store.is_empty()?);
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key3")?);
+    store.remove("key1".to_owned())?;
+    assert!(!store.contains_key("key1")?);
+    assert_eq!(store.len()?, 1);
+    Ok(())
+}
+
+#[test]
+fn len_and_contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_introspection(store.clone())?;
+    store.set_with_ttl("short".to_owned(), "lived".to_owned(), Duration::ZERO)?;
+    assert!(!store.contains_key("short")?);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_introspection(MemStore::open(temp_dir.path())?)
+}
+
+/// Replace every `from` in the log at `path` by `to`, of the same length
+fn patch_log(path: &Path, from: &str, to: &str) -> Result<()> {
+    let mut content = fs::read(path)?;
+    let mut at = 0;
+    while let Some(i) = content[at..]
+        .windows(from.len())
+        .position(|w| w == from.as_bytes())
+    {
+        content[at + i..at + i + to.len()].copy_from_slice(to.as_bytes());
+        at += i + to.len();
+    }
+    fs::write(path, content)?;
+    Ok(())
+}